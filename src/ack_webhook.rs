@@ -0,0 +1,212 @@
+use crate::chaos;
+use crate::error::ACK_WEBHOOK_ADAPTER;
+use crate::http::HttpConfig;
+use crate::ordering::{KeyedQueue, Turn};
+use crate::processor::AlertContext;
+use crate::truncate;
+use crate::{unix_time, AlertId, Error, Result};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// Calls a webhook whenever an alert is acknowledged or resolved, so
+/// upstream systems such as ticketing learn about the ack state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckWebhookConfig {
+    url: String,
+    // Sent as `Authorization: Bearer ...`.
+    token: Option<String>,
+    // Request body, see `AckWebhook::body` for the placeholders. Defaults to a
+    // JSON object with all of them.
+    template: Option<String>,
+    // Defaults to `application/json`. Values are escaped for JSON templates.
+    content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckEvent {
+    Acknowledged,
+    Resolved,
+}
+
+impl AckEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AckEvent::Acknowledged => "acknowledged",
+            AckEvent::Resolved => "resolved",
+        }
+    }
+}
+
+pub struct AckWebhook {
+    config: AckWebhookConfig,
+    client: reqwest::Client,
+    // Events of the same alert are forwarded in order.
+    queue: KeyedQueue<AlertId>,
+}
+
+impl AckWebhook {
+    pub fn new(config: AckWebhookConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(AckWebhook {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(ACK_WEBHOOK_ADAPTER, err))?,
+            queue: Default::default(),
+        })
+    }
+    /// Reserves the turn of an event of the alert, see `KeyedQueue`.
+    pub fn enqueue(&self, id: AlertId) -> Turn {
+        self.queue.enqueue([id])
+    }
+    fn content_type(&self) -> &str {
+        self.config
+            .content_type
+            .as_deref()
+            .unwrap_or(DEFAULT_CONTENT_TYPE)
+    }
+    /// Renders the template, replacing `{event}`, `{id}`, `{route}`,
+    /// `{alert_name}`, `{severity}`, `{message}`, `{user}` and `{timestamp}`.
+    fn body(&self, event: AckEvent, alert: &AlertContext, user: &str, timestamp: u64) -> String {
+        let values = [
+            ("event", event.as_str().to_string()),
+            ("id", alert.id.to_string()),
+            ("route", alert.route.clone()),
+            ("alert_name", alert.alert.labels.alert_name.clone()),
+            ("severity", alert.alert.labels.severity.clone()),
+            (
+                "message",
+                truncate::annotation(
+                    alert
+                        .alert
+                        .annotations
+                        .message
+                        .as_deref()
+                        .unwrap_or_default(),
+                    Some(alert.id),
+                )
+                .into_owned(),
+            ),
+            ("user", user.to_string()),
+            ("timestamp", timestamp.to_string()),
+        ];
+
+        let template = match &self.config.template {
+            Some(template) => template,
+            None => {
+                let object: serde_json::Map<String, serde_json::Value> = values
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.clone().into()))
+                    .collect();
+
+                return serde_json::Value::Object(object).to_string();
+            }
+        };
+
+        let is_json = self.content_type().contains("json");
+        let mut body = template.clone();
+        for (key, value) in &values {
+            let value = if is_json {
+                // Strip the quotes of the JSON string.
+                let escaped = serde_json::Value::from(value.as_str()).to_string();
+                escaped[1..escaped.len() - 1].to_string()
+            } else {
+                value.clone()
+            };
+
+            body = body.replace(&format!("{{{}}}", key), &value);
+        }
+
+        body
+    }
+    pub async fn forward(&self, event: AckEvent, alert: &AlertContext, user: &str) -> Result<()> {
+        chaos::inject(ACK_WEBHOOK_ADAPTER).await?;
+
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(reqwest::header::CONTENT_TYPE, self.content_type())
+            .body(self.body(event, alert, user, unix_time()));
+
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+
+        request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(ACK_WEBHOOK_ADAPTER, err))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_string, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn config(url: String, template: Option<&str>) -> AckWebhookConfig {
+        AckWebhookConfig {
+            url,
+            token: Some(String::from("secret")),
+            template: template.map(String::from),
+            content_type: None,
+        }
+    }
+
+    #[test]
+    fn renders_templates() {
+        let alert = alert_context(7, "team-a");
+
+        let webhook = AckWebhook::new(config(String::new(), None), &HttpConfig::default()).unwrap();
+        let body: serde_json::Value =
+            serde_json::from_str(&webhook.body(AckEvent::Acknowledged, &alert, "@a:b", 10))
+                .unwrap();
+        assert_eq!(body["event"], "acknowledged");
+        assert_eq!(body["id"], "7");
+        assert_eq!(body["route"], "team-a");
+        assert_eq!(body["user"], "@a:b");
+
+        let template = r#"{"text": "{alert_name} {event} by {user}"}"#;
+        let webhook = AckWebhook::new(
+            config(String::new(), Some(template)),
+            &HttpConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            webhook.body(AckEvent::Resolved, &alert, "\"quoted\"", 10),
+            r#"{"text": "Alert7 resolved by \"quoted\""}"#
+        );
+    }
+
+    #[actix_web::test]
+    async fn forwards_ack_state() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("authorization", "Bearer secret"))
+            .and(body_string("7 acknowledged"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let webhook = AckWebhook::new(
+            config(server.uri(), Some("{id} {event}")),
+            &HttpConfig::default(),
+        )
+        .unwrap();
+        webhook
+            .forward(AckEvent::Acknowledged, &alert_context(7, "team-a"), "@a:b")
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,844 @@
+//! Chat and paging services which are notified in addition to the Matrix
+//! rooms, e.g. Telegram. Each adapter maps the escalation levels of a route to
+//! its own destinations, such as chats.
+pub mod discord;
+pub mod mattermost;
+pub mod mqtt;
+pub mod opsgenie;
+pub mod sns;
+pub mod telegram;
+pub mod twilio;
+pub mod victorops;
+pub mod zulip;
+
+use crate::database::{Database, NotificationKey, NotificationRecord};
+use crate::matrix::{parse_command, MatrixClient};
+use crate::ordering::KeyedQueue;
+use crate::processor::{
+    AlertContext, Processor, RemoteAck, UserAction, UserConfirmation, ACK_KIND, ALERT_KIND,
+    ESCALATION_KIND,
+};
+use crate::render::{Format, Message, NotificationRenderer, Section};
+use crate::selftest::Check;
+use crate::truncate;
+use crate::{metrics, unix_time, AlertId, Error, Result};
+use actix::SystemService;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Seconds until a notification is abandoned, i.e. a delivery which started
+// earlier is no longer in progress and may be retried.
+const DELIVERY_TIMEOUT: u64 = 60;
+
+/// What adapters are notified about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    // New alerts, at their entry level.
+    Alert(Vec<AlertContext>),
+    Escalation(Vec<AlertContext>),
+    // Acknowledged elsewhere, e.g. in Matrix or another adapter.
+    Acknowledged {
+        id: AlertId,
+        user: String,
+        via: String,
+    },
+    // Of the `selftest` command, by the given user. Concerns no alert.
+    SelfTest {
+        user: String,
+    },
+}
+
+impl Notification {
+    /// Renders the notification, a header followed by its alerts, if any.
+    pub fn render(&self, renderer: &dyn NotificationRenderer) -> Message {
+        let (header, alerts) = match self {
+            Notification::Alert(alerts) => (String::from("⚠️ Alert occurred!"), alerts.as_slice()),
+            Notification::Escalation(alerts) => {
+                (String::from("🚨 ESCALATION OCCURRED!"), alerts.as_slice())
+            }
+            Notification::Acknowledged { id, user, via } => (
+                format!(
+                    "✅ Alert {} has been acknowledged by {} via {}",
+                    id, user, via
+                ),
+                &[][..],
+            ),
+            Notification::SelfTest { user } => (
+                format!(
+                    "🧪 Self-test by {}, this is a test message and can be ignored",
+                    user
+                ),
+                &[][..],
+            ),
+        };
+
+        let sections: Vec<Section> = alerts.iter().cloned().map(Section::new).collect();
+        renderer.render(&header, &sections)
+    }
+    /// The state of the alerts, e.g. `escalation`, as published by
+    /// integrations such as SNS.
+    pub fn state(&self) -> &'static str {
+        match self {
+            Notification::Alert(_) => "alert",
+            Notification::Escalation(_) => "escalation",
+            Notification::Acknowledged { .. } => "acknowledged",
+            Notification::SelfTest { .. } => "selftest",
+        }
+    }
+    /// The notification as JSON events, one per alert, or a single one for
+    /// acks and self-tests. Texts are limited to `max_text` bytes.
+    pub fn events(&self, route: &str, level: usize, max_text: usize) -> Vec<serde_json::Value> {
+        let state = self.state();
+
+        match self {
+            Notification::Alert(alerts) | Notification::Escalation(alerts) => alerts
+                .iter()
+                .map(|alert| {
+                    let labels = &alert.alert.labels;
+                    let text = alert.to_string();
+                    serde_json::json!({
+                        "state": state,
+                        "id": alert.id,
+                        "route": route,
+                        "level": level,
+                        "name": labels.alert_name,
+                        "severity": labels.severity,
+                        "text": truncate::message_within(text.trim(), max_text),
+                    })
+                })
+                .collect(),
+            Notification::Acknowledged { id, user, via } => vec![serde_json::json!({
+                "state": state,
+                "id": id,
+                "route": route,
+                "level": level,
+                "user": user,
+                "via": via,
+            })],
+            Notification::SelfTest { user } => vec![serde_json::json!({
+                "state": state,
+                "route": route,
+                "level": level,
+                "user": user,
+            })],
+        }
+    }
+    /// The alerts the notification is about.
+    pub fn alerts(&self) -> &[AlertContext] {
+        match self {
+            Notification::Alert(alerts) | Notification::Escalation(alerts) => alerts,
+            Notification::Acknowledged { .. } | Notification::SelfTest { .. } => &[],
+        }
+    }
+    fn ids(&self) -> Vec<AlertId> {
+        match self {
+            Notification::Acknowledged { id, .. } => vec![*id],
+            _ => self.alerts().iter().map(|alert| alert.id).collect(),
+        }
+    }
+    /// The kind of delivery records of the notification. Acks carry who
+    /// acknowledged the alert and where, so they can be retried, see
+    /// `UnsentAck`. Self-tests are not tracked.
+    fn kind(&self) -> Option<String> {
+        match self {
+            Notification::Alert(_) => Some(ALERT_KIND.to_string()),
+            Notification::Escalation(_) => Some(ESCALATION_KIND.to_string()),
+            Notification::Acknowledged { user, via, .. } => {
+                Some(format!("{}:{}:{}", ACK_KIND, via, user))
+            }
+            Notification::SelfTest { .. } => None,
+        }
+    }
+    /// The same notification about the given alerts only.
+    fn only(&self, ids: &[AlertId]) -> Self {
+        let alerts = || {
+            self.alerts()
+                .iter()
+                .filter(|alert| ids.contains(&alert.id))
+                .cloned()
+                .collect()
+        };
+
+        match self {
+            Notification::Alert(_) => Notification::Alert(alerts()),
+            Notification::Escalation(_) => Notification::Escalation(alerts()),
+            other => other.clone(),
+        }
+    }
+}
+
+/// An ack which has not been propagated to an adapter yet, as the delivery
+/// failed or was interrupted. Retried by `Adapters::retry`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnsentAck {
+    pub id: AlertId,
+    pub adapter: String,
+    pub level: usize,
+    pub user: String,
+    pub via: String,
+    // Of the last attempt.
+    pub attempted_at: u64,
+}
+
+impl UnsentAck {
+    fn from_record(record: &NotificationRecord) -> Option<Self> {
+        // Adapter names, i.e. where alerts are acknowledged, contain no colons.
+        let (via, user) = record
+            .key
+            .kind
+            .strip_prefix(ACK_KIND)?
+            .strip_prefix(':')?
+            .split_once(':')?;
+
+        Some(UnsentAck {
+            id: record.key.alert_id,
+            adapter: record.key.channel.clone(),
+            level: record.key.escalation_idx,
+            user: user.to_string(),
+            via: via.to_string(),
+            attempted_at: record.sent_at,
+        })
+    }
+}
+
+#[async_trait]
+pub trait Adapter: Send + Sync {
+    /// Identifies the adapter in logs and acks, e.g. `Telegram`.
+    fn name(&self) -> &'static str;
+    /// Whether the level of the route has a destination in this adapter.
+    fn covers(&self, route: &str, level: usize) -> bool;
+    /// Formats notifications for the service, plain text unless it supports
+    /// more, see `render::Format`.
+    fn renderer(&self) -> &dyn NotificationRenderer {
+        Format::Plain.renderer()
+    }
+    /// Notifies the destination of the level of the route.
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()>;
+}
+
+/// Whether an adapter is notified, see `Adapters::set_enabled`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct AdapterState {
+    pub name: String,
+    pub enabled: bool,
+}
+
+/// The configured adapters. Notifications are sent in the background, those
+/// of the same alert in order. With a database, the delivery of alerts is
+/// tracked per alert, level and adapter, just like the exec hook, so failed
+/// deliveries are retried by `retry`.
+#[derive(Clone)]
+pub struct Adapters {
+    adapters: Vec<Arc<dyn Adapter>>,
+    // Names of the adapters which are taken out of the rotation at runtime,
+    // e.g. during an outage of the service. Their failed deliveries are
+    // retried once they are enabled again.
+    disabled: Arc<RwLock<HashSet<&'static str>>>,
+    queue: Arc<KeyedQueue<(&'static str, AlertId)>>,
+    db: Option<Arc<Database>>,
+    // See `DELIVERY_TIMEOUT`.
+    timeout: u64,
+}
+
+impl Default for Adapters {
+    fn default() -> Self {
+        Adapters::new(vec![])
+    }
+}
+
+impl Adapters {
+    pub fn new(adapters: Vec<Arc<dyn Adapter>>) -> Self {
+        Adapters {
+            adapters,
+            disabled: Default::default(),
+            queue: Default::default(),
+            db: None,
+            timeout: DELIVERY_TIMEOUT,
+        }
+    }
+    pub fn with_database(mut self, db: Option<Arc<Database>>) -> Self {
+        self.db = db;
+        self
+    }
+    /// Notifies the adapters which cover the level of the route, except the
+    /// one named `except`, e.g. the one an ack originates from.
+    pub fn forward(
+        &self,
+        route: &str,
+        level: usize,
+        notification: Notification,
+        except: Option<&str>,
+    ) {
+        for adapter in self
+            .enabled()
+            .into_iter()
+            .filter(|adapter| Some(adapter.name()) != except && adapter.covers(route, level))
+        {
+            self.spawn(adapter, route, level, notification.clone());
+        }
+    }
+    /// The adapters which are not disabled.
+    fn enabled(&self) -> Vec<Arc<dyn Adapter>> {
+        let disabled = self.disabled.read().unwrap();
+        self.adapters
+            .iter()
+            .filter(|adapter| !disabled.contains(adapter.name()))
+            .cloned()
+            .collect()
+    }
+    /// Takes the adapter out of the rotation, or back in. Returns the name of
+    /// the adapter, `None` if no adapter of that name (case-insensitive) is
+    /// configured. Alerts keep their escalation levels either way.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Option<&'static str> {
+        let name = self.adapter(name)?.name();
+
+        let mut disabled = self.disabled.write().unwrap();
+        if enabled {
+            disabled.remove(name);
+        } else {
+            disabled.insert(name);
+        }
+
+        Some(name)
+    }
+    /// Whether each configured adapter is enabled.
+    pub fn states(&self) -> Vec<AdapterState> {
+        let disabled = self.disabled.read().unwrap();
+        self.adapters
+            .iter()
+            .map(|adapter| AdapterState {
+                name: adapter.name().to_string(),
+                enabled: !disabled.contains(adapter.name()),
+            })
+            .collect()
+    }
+    /// Notifies the adapter in the background, after the previous
+    /// notifications of the same alerts.
+    fn spawn(
+        &self,
+        adapter: Arc<dyn Adapter>,
+        route: &str,
+        level: usize,
+        notification: Notification,
+    ) {
+        let turn = self.queue.enqueue(
+            notification
+                .ids()
+                .into_iter()
+                .map(|id| (adapter.name(), id)),
+        );
+
+        let db = self.db.clone();
+        let timeout = self.timeout;
+        let route = route.to_string();
+
+        actix::spawn(async move {
+            let _turn = turn.wait().await;
+
+            let res = deliver(
+                db.as_deref(),
+                adapter.as_ref(),
+                &route,
+                level,
+                &notification,
+                timeout,
+            )
+            .await;
+
+            if let Err(err) = res {
+                error!(
+                    "Failed to notify {} on level {} of route '{}': {:?}",
+                    adapter.name(),
+                    level,
+                    route,
+                    err
+                );
+            }
+        });
+    }
+    /// The deliveries to configured adapters which failed or were
+    /// interrupted.
+    async fn failed(&self, db: &Database) -> Result<Vec<NotificationRecord>> {
+        let now = unix_time();
+
+        Ok(db
+            .get_inflight_notifications()
+            .await?
+            .into_iter()
+            .filter(|record| {
+                record.sent_at + self.timeout < now && self.adapter(&record.key.channel).is_some()
+            })
+            .collect())
+    }
+    /// The acks which have not been propagated to all adapters yet, e.g. for
+    /// the `failures` command. Acks are only tracked with a database.
+    pub async fn unsent_acks(&self) -> Result<Vec<UnsentAck>> {
+        let db = match &self.db {
+            Some(db) if !self.adapters.is_empty() => db,
+            _ => return Ok(vec![]),
+        };
+
+        Ok(self
+            .failed(db)
+            .await?
+            .iter()
+            .filter_map(UnsentAck::from_record)
+            .collect())
+    }
+    /// Retries the notifications whose delivery failed or was interrupted,
+    /// e.g. by a restart, as long as the alert is pending on the same level.
+    /// Acks are retried until they are delivered.
+    pub async fn retry(&self) -> Result<()> {
+        let db = match &self.db {
+            Some(db) if !self.adapters.is_empty() => db,
+            _ => return Ok(()),
+        };
+
+        let failed = self.failed(db).await?;
+
+        let unsent: Vec<UnsentAck> = failed.iter().filter_map(UnsentAck::from_record).collect();
+        for adapter in &self.adapters {
+            let count = unsent
+                .iter()
+                .filter(|ack| ack.adapter == adapter.name())
+                .count();
+            metrics::set_unsent_acks(adapter.name(), count as u64);
+        }
+
+        let failed: Vec<_> = failed
+            .into_iter()
+            .filter(|record| !self.is_disabled(&record.key.channel))
+            .collect();
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        let pending: HashMap<AlertId, AlertContext> = db
+            .get_pending(None)
+            .await?
+            .into_iter()
+            .map(|alert| (alert.id, alert))
+            .collect();
+
+        for record in failed {
+            let key = &record.key;
+            let adapter = self.adapter(&key.channel);
+
+            if let (Some(ack), Some(adapter)) = (UnsentAck::from_record(&record), adapter) {
+                // Acknowledged alerts are no longer pending.
+                match db.get_alert(ack.id).await? {
+                    Some(alert) => {
+                        info!(
+                            "Retrying {} ack propagation of {}",
+                            adapter.name(),
+                            alert.trace()
+                        );
+                        self.spawn(
+                            Arc::clone(adapter),
+                            &alert.route,
+                            ack.level,
+                            Notification::Acknowledged {
+                                id: ack.id,
+                                user: ack.user,
+                                via: ack.via,
+                            },
+                        );
+                    }
+                    None => db.complete_notification(key).await?,
+                }
+
+                continue;
+            }
+
+            match (pending.get(&key.alert_id), adapter) {
+                (Some(alert), Some(adapter)) if alert.escalation_idx == key.escalation_idx => {
+                    info!(
+                        "Retrying {} notification of {}",
+                        adapter.name(),
+                        alert.trace()
+                    );
+
+                    let alerts = vec![alert.clone()];
+                    let notification = match key.kind.as_str() {
+                        ALERT_KIND => Notification::Alert(alerts),
+                        _ => Notification::Escalation(alerts),
+                    };
+                    self.spawn(
+                        Arc::clone(adapter),
+                        &alert.route,
+                        key.escalation_idx,
+                        notification,
+                    );
+                }
+                // Acknowledged or escalated further in the meantime.
+                _ => db.complete_notification(key).await?,
+            }
+        }
+
+        Ok(())
+    }
+    fn adapter(&self, name: &str) -> Option<&Arc<dyn Adapter>> {
+        self.adapters
+            .iter()
+            .find(|adapter| adapter.name().eq_ignore_ascii_case(name))
+    }
+    fn is_disabled(&self, name: &str) -> bool {
+        self.disabled.read().unwrap().contains(name)
+    }
+    /// Notifies each level of the route, up to `levels`, about a self-test.
+    /// Unlike `forward`, waits for the adapters and returns their outcomes.
+    pub async fn test(
+        &self,
+        route: &str,
+        levels: usize,
+        notification: &Notification,
+    ) -> Vec<Check> {
+        let mut checks = vec![];
+        for adapter in self.enabled() {
+            for level in (0..levels).filter(|level| adapter.covers(route, *level)) {
+                checks.push(
+                    Check::run(
+                        format!("{}, level {}", adapter.name(), level),
+                        adapter.notify(route, level, notification),
+                    )
+                    .await,
+                );
+            }
+        }
+
+        checks
+    }
+    /// Notifies the entry levels of new alerts.
+    pub fn forward_alerts(&self, alerts: &[AlertContext]) {
+        if self.adapters.is_empty() {
+            return;
+        }
+
+        let mut levels: BTreeMap<(&str, usize), Vec<AlertContext>> = BTreeMap::new();
+        for alert in alerts {
+            levels
+                .entry((&alert.route, alert.escalation_idx))
+                .or_default()
+                .push(alert.clone());
+        }
+
+        for ((route, level), alerts) in levels {
+            self.forward(route, level, Notification::Alert(alerts), None);
+        }
+    }
+}
+
+/// Notifies the adapter, within the delivery timeout. The delivery of each
+/// alert (or ack) is recorded, those which are already being delivered are
+/// skipped.
+async fn deliver(
+    db: Option<&Database>,
+    adapter: &dyn Adapter,
+    route: &str,
+    level: usize,
+    notification: &Notification,
+    timeout: u64,
+) -> Result<()> {
+    let notify = |notification: Notification| async move {
+        actix::clock::timeout(
+            Duration::from_secs(timeout),
+            adapter.notify(route, level, &notification),
+        )
+        .await
+        .map_err(|_| Error::adapter(adapter.name(), format!("timed out after {}s", timeout)))?
+    };
+
+    let (db, kind) = match (db, notification.kind()) {
+        (Some(db), Some(kind)) => (db, kind),
+        _ => return notify(notification.clone()).await,
+    };
+
+    let mut keys = vec![];
+    let mut ids = vec![];
+    for id in notification.ids() {
+        let key = NotificationKey {
+            alert_id: id,
+            channel: adapter.name().to_string(),
+            escalation_idx: level,
+            kind: kind.clone(),
+        };
+
+        if db.claim_notification(&key, timeout).await? {
+            keys.push(key);
+            ids.push(id);
+        } else {
+            debug!("Skipping duplicate notification: {:?}", key);
+        }
+    }
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    // Failed deliveries remain claimed until retried.
+    notify(notification.only(&ids)).await?;
+
+    for key in &keys {
+        db.complete_notification(key).await?;
+    }
+
+    Ok(())
+}
+
+/// Runs a command received by an adapter from the given level of a route,
+/// returns the reply if it was a command. Acks are announced in the room of
+/// the level and to the other adapters.
+pub async fn run_command(
+    origin: &'static str,
+    route: &str,
+    level: usize,
+    user: &str,
+    text: &str,
+) -> Result<Option<String>> {
+    let command = match parse_command(text, user) {
+        Some(Ok(command)) => command,
+        Some(Err(usage)) => return Ok(Some(UserConfirmation::Usage(usage).to_string())),
+        None => return Ok(None),
+    };
+
+    let confirmation = Processor::from_registry()
+        .send(UserAction {
+            route: route.to_string(),
+            escalation_idx: level,
+            command,
+        })
+        .await?;
+
+    if let UserConfirmation::AlertAcknowledged(id) = confirmation {
+        info!("Alert {} acknowledged by {} via {}", id, user, origin);
+
+        let ack = RemoteAck {
+            route: route.to_string(),
+            escalation_idx: level,
+            id,
+            user: user.to_string(),
+            via: origin.to_string(),
+        };
+
+        Processor::from_registry().do_send(ack.clone());
+        let res = MatrixClient::from_registry().send(ack).await;
+        if let Err(err) = res.map_err(Error::from).and_then(|res| res) {
+            error!("Failed to inform room about ack of alert {}: {:?}", id, err);
+        }
+    }
+
+    Ok(Some(confirmation.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{alert_context, test_database};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails the first `failures` notifications, or never answers.
+    struct Flaky {
+        failures: usize,
+        hangs: bool,
+        calls: AtomicUsize,
+    }
+
+    impl Flaky {
+        fn new(failures: usize) -> Self {
+            Flaky {
+                failures,
+                hangs: false,
+                calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Adapter for Flaky {
+        fn name(&self) -> &'static str {
+            "Flaky"
+        }
+        fn covers(&self, _route: &str, _level: usize) -> bool {
+            true
+        }
+        async fn notify(&self, _route: &str, _level: usize, _: &Notification) -> Result<()> {
+            if self.hangs {
+                futures::future::pending::<()>().await;
+            }
+
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(Error::adapter("Flaky", "unavailable"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn renders_notifications() {
+        let alert = alert_context(1, "team-a");
+        let plain = Format::Plain.renderer();
+        let rendered = Notification::Escalation(vec![alert.clone()]).render(plain);
+        assert_eq!(
+            rendered.body,
+            format!("🚨 ESCALATION OCCURRED!\n\n{}", alert)
+        );
+
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Matrix"),
+        };
+        assert_eq!(
+            ack.render(plain).body,
+            "✅ Alert 1 has been acknowledged by @ops:matrix.org via Matrix"
+        );
+        assert_eq!(ack.ids(), vec![AlertId::from(1)]);
+
+        let test = Notification::SelfTest {
+            user: String::from("@admin:matrix.org"),
+        };
+        assert_eq!(
+            test.render(plain).body,
+            "🧪 Self-test by @admin:matrix.org, this is a test message and can be ignored"
+        );
+        assert!(test.ids().is_empty());
+    }
+
+    #[test]
+    fn tracks_acks_for_retries() {
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Telegram"),
+        };
+        let record = |kind: Option<String>| -> NotificationRecord {
+            serde_json::from_value(serde_json::json!({
+                "alert_id": AlertId::from(1),
+                "channel": "Mattermost",
+                "escalation_idx": 1,
+                "kind": kind,
+                "sent_at": 1704067200,
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            UnsentAck::from_record(&record(ack.kind())),
+            Some(UnsentAck {
+                id: AlertId::from(1),
+                adapter: String::from("Mattermost"),
+                level: 1,
+                user: String::from("@ops:matrix.org"),
+                via: String::from("Telegram"),
+                attempted_at: 1704067200,
+            })
+        );
+
+        let escalation = Notification::Escalation(vec![alert_context(1, "team-a")]);
+        assert_eq!(UnsentAck::from_record(&record(escalation.kind())), None);
+    }
+
+    #[actix_web::test]
+    async fn disabled_adapters_are_skipped() {
+        let adapters = Adapters::new(vec![Arc::new(Flaky::new(0))]);
+        let test = Notification::SelfTest {
+            user: String::from("@admin:matrix.org"),
+        };
+
+        assert_eq!(adapters.set_enabled("flaky", false), Some("Flaky"));
+        assert_eq!(adapters.set_enabled("Other", false), None);
+        assert_eq!(
+            adapters.states(),
+            vec![AdapterState {
+                name: String::from("Flaky"),
+                enabled: false,
+            }]
+        );
+        assert!(adapters.test("team-a", 2, &test).await.is_empty());
+
+        // Clones share the state, e.g. those of the processor.
+        adapters.clone().set_enabled("FLAKY", true);
+        assert_eq!(adapters.test("team-a", 2, &test).await.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn deliveries_time_out() {
+        let adapter = Flaky {
+            hangs: true,
+            ..Flaky::new(0)
+        };
+        let notification = Notification::Escalation(vec![alert_context(1, "team-a")]);
+
+        let err = deliver(None, &adapter, "team-a", 1, &notification, 0)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(deliver(None, &Flaky::new(0), "team-a", 1, &notification, 0)
+            .await
+            .is_ok());
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn failed_deliveries_are_retried() {
+        let db = Arc::new(test_database().await);
+        let mut alert = alert_context(1, "team-a");
+        alert.escalation_idx = 1;
+        db.insert_alerts(&[alert.clone()]).await.unwrap();
+
+        let flaky = Arc::new(Flaky::new(1));
+        let adapters = Adapters {
+            timeout: 0,
+            ..Adapters::new(vec![Arc::clone(&flaky) as _]).with_database(Some(Arc::clone(&db)))
+        };
+
+        let notification = Notification::Escalation(vec![alert]);
+        assert!(
+            deliver(Some(&db), flaky.as_ref(), "team-a", 1, &notification, 0)
+                .await
+                .is_err()
+        );
+        assert_eq!(db.get_inflight_notifications().await.unwrap().len(), 1);
+
+        // Deliveries which started in the same second may still be running.
+        actix::clock::sleep(Duration::from_millis(1100)).await;
+        adapters.retry().await.unwrap();
+        actix::clock::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 2);
+        assert!(db.get_inflight_notifications().await.unwrap().is_empty());
+
+        // Acks are retried until they are delivered.
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Matrix"),
+        };
+        assert!(deliver(Some(&db), flaky.as_ref(), "team-a", 1, &ack, 0)
+            .await
+            .is_ok());
+
+        let acks = Arc::new(Flaky::new(1));
+        let adapters = Adapters {
+            timeout: 0,
+            ..Adapters::new(vec![Arc::clone(&acks) as _]).with_database(Some(Arc::clone(&db)))
+        };
+        assert!(deliver(Some(&db), acks.as_ref(), "team-a", 1, &ack, 0)
+            .await
+            .is_err());
+
+        actix::clock::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(adapters.unsent_acks().await.unwrap().len(), 1);
+        adapters.retry().await.unwrap();
+        actix::clock::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(acks.calls.load(Ordering::SeqCst), 2);
+        assert!(adapters.unsent_acks().await.unwrap().is_empty());
+
+        db.drop_database().await.unwrap();
+    }
+}
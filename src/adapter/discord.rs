@@ -0,0 +1,544 @@
+//! Discord bot, notifies channels and accepts commands from them, either as
+//! text (e.g. `ack 1`, received via the gateway) or as slash commands (e.g.
+//! `/ack 1`). Each channel of a route corresponds to an escalation level, like
+//! rooms.
+mod gateway;
+
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::DISCORD_ADAPTER;
+use crate::http::HttpConfig;
+use crate::processor::{IsStandby, Processor, COMMANDS};
+use crate::render::{Format, NotificationRenderer};
+use crate::truncate;
+use crate::{Error, Result};
+use actix::SystemService;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const DEFAULT_API_URL: &str = "https://discord.com/api/v10";
+// Seconds until the gateway is connected again after a failure.
+const RECONNECT_DELAY: u64 = 5;
+// Discord rejects longer messages.
+const MAX_MESSAGE: usize = 2000;
+// See https://discord.com/developers/docs/interactions/receiving-and-responding
+const INTERACTION_PING: u8 = 1;
+const INTERACTION_COMMAND: u8 = 2;
+const RESPONSE_PONG: u8 = 1;
+const RESPONSE_MESSAGE: u8 = 4;
+const OPTION_STRING: u8 = 3;
+// The single option of slash commands, the arguments of the text command.
+const ARGUMENTS_OPTION: &str = "arguments";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscordConfig {
+    // Token of the bot user.
+    token: String,
+    // Defaults to `https://discord.com/api/v10`.
+    api_url: Option<String>,
+    // Channel IDs of each route, in the order of escalation levels. Alerts
+    // beyond the last channel stay in the last one.
+    channels: HashMap<String, Vec<String>>,
+    // Enables slash commands: the ID of the application, whose commands are
+    // registered on startup, and its public key (hex) to verify interactions
+    // on `/webhook-discord`.
+    application_id: Option<String>,
+    public_key: Option<String>,
+}
+
+impl DiscordConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.channels.keys().map(String::as_str)
+    }
+}
+
+/// A message posted in a channel, as received from the gateway.
+#[derive(Debug, Deserialize)]
+pub struct DiscordMessage {
+    channel_id: String,
+    content: String,
+    author: User,
+}
+
+/// See https://discord.com/developers/docs/topics/gateway#get-gateway-bot
+#[derive(Deserialize)]
+struct GatewayBot {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    username: String,
+    #[serde(default)]
+    bot: bool,
+}
+
+/// An interaction, as received on `/webhook-discord`.
+#[derive(Debug, Deserialize)]
+pub struct Interaction {
+    #[serde(rename = "type")]
+    kind: u8,
+    channel_id: Option<String>,
+    data: Option<CommandData>,
+    // Set in guilds, `user` in direct messages.
+    member: Option<Member>,
+    user: Option<User>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandData {
+    name: String,
+    #[serde(default)]
+    options: Vec<CommandOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandOption {
+    value: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Member {
+    user: User,
+}
+
+impl Interaction {
+    /// The text command of a slash command, e.g. `ack 1`.
+    fn command(&self) -> Option<String> {
+        let data = self.data.as_ref()?;
+        let mut command = data.name.clone();
+        for option in &data.options {
+            let value = match &option.value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+
+            command.push(' ');
+            command.push_str(&value);
+        }
+
+        Some(command.trim_end().to_string())
+    }
+    fn user(&self) -> Option<&User> {
+        self.member
+            .as_ref()
+            .map(|member| &member.user)
+            .or(self.user.as_ref())
+    }
+}
+
+pub struct Discord {
+    config: DiscordConfig,
+    client: reqwest::Client,
+    public_key: Option<PKey<Public>>,
+    // Options of the gateway connection.
+    http: HttpConfig,
+}
+
+impl Discord {
+    pub fn new(config: DiscordConfig, http: &HttpConfig) -> Result<Self> {
+        gateway::check_proxy(http)?;
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        let public_key = match &config.public_key {
+            Some(key) => {
+                let key = decode_hex(key)
+                    .ok_or_else(|| Error::Config(String::from("Invalid Discord public key")))?;
+                Some(
+                    PKey::public_key_from_raw_bytes(&key, Id::ED25519)
+                        .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Discord {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?,
+            public_key,
+            http: http.clone(),
+        })
+    }
+    /// Whether slash commands are accepted on `/webhook-discord`.
+    pub fn has_interactions(&self) -> bool {
+        self.public_key.is_some()
+    }
+    /// Calls the REST API.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let url = format!(
+            "{}{}",
+            self.config
+                .api_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/'),
+            path
+        );
+
+        let mut request = self.client.request(method, url).header(
+            reqwest::header::AUTHORIZATION,
+            format!("Bot {}", self.config.token),
+        );
+        if let Some(body) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+
+        request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?
+            .bytes()
+            .await
+            .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))
+            .and_then(|body| {
+                serde_json::from_slice(&body).map_err(|err| Error::adapter(DISCORD_ADAPTER, err))
+            })
+    }
+    async fn send_message(&self, channel_id: &str, text: &str) -> Result<()> {
+        self.call::<serde_json::Value>(
+            reqwest::Method::POST,
+            &format!("/channels/{}/messages", channel_id),
+            Some(serde_json::json!({
+                "content": truncate::message_within(text, MAX_MESSAGE),
+                // Alerts must not ping everyone.
+                "allowed_mentions": {"parse": []},
+            })),
+        )
+        .await
+        .map(|_| ())
+    }
+    /// The route and escalation level of a channel.
+    fn find_channel(&self, channel_id: &str) -> Option<(&str, usize)> {
+        self.config.channels.iter().find_map(|(route, channels)| {
+            channels
+                .iter()
+                .position(|id| id == channel_id)
+                .map(|level| (route.as_str(), level))
+        })
+    }
+    /// Registers a slash command for each command of rooms, replacing the
+    /// previous ones.
+    pub async fn register_commands(&self) -> Result<()> {
+        let application_id = match &self.config.application_id {
+            Some(application_id) => application_id,
+            None => return Ok(()),
+        };
+
+        let commands: Vec<serde_json::Value> = COMMANDS
+            .iter()
+            .map(|info| {
+                let mut command = serde_json::json!({
+                    "name": info.name,
+                    "description": info.summary,
+                });
+                if info.usage != info.name {
+                    command["options"] = serde_json::json!([{
+                        "type": OPTION_STRING,
+                        "name": ARGUMENTS_OPTION,
+                        "description": info.usage,
+                        "required": false,
+                    }]);
+                }
+
+                command
+            })
+            .collect();
+
+        self.call::<serde_json::Value>(
+            reqwest::Method::PUT,
+            &format!("/applications/{}/commands", application_id),
+            Some(serde_json::Value::from(commands)),
+        )
+        .await
+        .map(|_| ())
+    }
+    /// Verifies the signature of an interaction, see
+    /// https://discord.com/developers/docs/interactions/overview#setting-up-an-endpoint
+    pub fn verify(&self, signature: &str, timestamp: &str, body: &[u8]) -> Result<()> {
+        let key = self
+            .public_key
+            .as_ref()
+            .ok_or_else(|| Error::adapter(DISCORD_ADAPTER, "Interactions are not configured"))?;
+        let signature = decode_hex(signature)
+            .ok_or_else(|| Error::adapter(DISCORD_ADAPTER, "Invalid signature encoding"))?;
+
+        let mut signed = timestamp.as_bytes().to_vec();
+        signed.extend_from_slice(body);
+
+        let valid = Verifier::new_without_digest(key)
+            .and_then(|mut verifier| verifier.verify_oneshot(&signature, &signed))
+            .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+        if !valid {
+            return Err(Error::adapter(DISCORD_ADAPTER, "Invalid signature"));
+        }
+
+        Ok(())
+    }
+    /// Runs a slash command, returns the response to the interaction.
+    pub async fn interaction(&self, interaction: Interaction) -> Result<serde_json::Value> {
+        if interaction.kind == INTERACTION_PING {
+            return Ok(serde_json::json!({ "type": RESPONSE_PONG }));
+        }
+
+        let command = interaction.command();
+        let found = interaction
+            .channel_id
+            .as_deref()
+            .and_then(|channel_id| self.find_channel(channel_id));
+
+        let content = match (interaction.kind, found, command, interaction.user()) {
+            (INTERACTION_COMMAND, Some((route, level)), Some(command), Some(user)) => {
+                let user = format!("discord:{}", user.username);
+                debug!("Received Discord command from {}: {}", user, command);
+
+                adapter::run_command(DISCORD_ADAPTER, route, level, &user, &command)
+                    .await?
+                    .unwrap_or_else(|| format!("Unknown command: {}", command))
+            }
+            (INTERACTION_COMMAND, None, _, _) => {
+                String::from("This channel is not part of any escalation route.")
+            }
+            _ => {
+                return Err(Error::adapter(
+                    DISCORD_ADAPTER,
+                    format!("Unsupported interaction of type {}", interaction.kind),
+                ))
+            }
+        };
+
+        Ok(serde_json::json!({
+            "type": RESPONSE_MESSAGE,
+            "data": {
+                "content": truncate::message_within(&content, MAX_MESSAGE),
+                "allowed_mentions": {"parse": []},
+            },
+        }))
+    }
+    /// Runs a text command posted in a channel and replies to it.
+    async fn handle_message(&self, message: DiscordMessage) -> Result<()> {
+        // Own messages are not commands.
+        if message.author.bot {
+            return Ok(());
+        }
+
+        let (route, level) = match self.find_channel(&message.channel_id) {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        let user = format!("discord:{}", message.author.username);
+        if let Some(reply) =
+            adapter::run_command(DISCORD_ADAPTER, route, level, &user, message.content.trim())
+                .await?
+        {
+            self.send_message(&message.channel_id, &reply).await?;
+        }
+
+        Ok(())
+    }
+    /// Connects to the gateway, or resumes the session, and runs the text
+    /// commands until the connection ends.
+    async fn receive_commands(
+        self: &Arc<Self>,
+        session: &mut Option<gateway::Session>,
+    ) -> Result<()> {
+        let url = match session {
+            Some(session) => session.resume_url.clone(),
+            None => {
+                self.call::<GatewayBot>(reqwest::Method::GET, "/gateway/bot", None)
+                    .await?
+                    .url
+            }
+        };
+
+        let socket = gateway::connect(&url, &self.http).await?;
+        debug!("Connected to the Discord gateway");
+
+        gateway::run(socket, &self.config.token, session, |message| {
+            let discord = Arc::clone(self);
+            actix::spawn(async move {
+                if let Err(err) = discord.handle_message(message).await {
+                    warn!("Failed to handle Discord command: {:?}", err);
+                }
+            });
+        })
+        .await
+    }
+    /// Receives text commands from the gateway, until the service stops.
+    /// Standby instances leave them to the active instance.
+    pub async fn serve_commands(self: Arc<Self>) {
+        let mut session = None;
+
+        loop {
+            let is_standby = Processor::from_registry()
+                .send(IsStandby)
+                .await
+                .unwrap_or(true);
+
+            if !is_standby {
+                match self.receive_commands(&mut session).await {
+                    // The gateway asked to reconnect.
+                    Ok(()) => continue,
+                    Err(err) => warn!("Discord gateway connection failed: {:?}", err),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Adapter for Discord {
+    fn name(&self) -> &'static str {
+        DISCORD_ADAPTER
+    }
+    fn covers(&self, route: &str, _level: usize) -> bool {
+        self.config
+            .channels
+            .get(route)
+            .map(|channels| !channels.is_empty())
+            .unwrap_or(false)
+    }
+    fn renderer(&self) -> &dyn NotificationRenderer {
+        Format::Markdown.renderer()
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(DISCORD_ADAPTER).await?;
+
+        let channels = self
+            .config
+            .channels
+            .get(route)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let channel_id = match channels.get(level).or_else(|| channels.last()) {
+            Some(channel_id) => channel_id,
+            None => return Ok(()),
+        };
+
+        self.send_message(channel_id, &notification.render(self.renderer()).body)
+            .await
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Section;
+    use crate::testing::alert_context;
+    use openssl::sign::Signer;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn discord(api_url: String, public_key: Option<String>) -> Discord {
+        Discord::new(
+            DiscordConfig {
+                token: String::from("secret"),
+                api_url: Some(api_url),
+                channels: [(
+                    String::from("team-a"),
+                    vec![String::from("100"), String::from("200")],
+                )]
+                .into(),
+                application_id: None,
+                public_key,
+            },
+            &HttpConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn verifies_interactions() {
+        let key = PKey::generate_ed25519().unwrap();
+        let public_key: String = key
+            .raw_public_key()
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let discord = discord(String::new(), Some(public_key));
+
+        let body = br#"{"type":1}"#;
+        let signature: String = Signer::new_without_digest(&key)
+            .unwrap()
+            .sign_oneshot_to_vec(&[b"1700000000".as_slice(), body.as_slice()].concat())
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        assert!(discord.verify(&signature, "1700000000", body).is_ok());
+        assert!(discord.verify(&signature, "1700000001", body).is_err());
+        assert!(discord.verify("zz", "1700000000", body).is_err());
+
+        let interaction: Interaction = serde_json::from_value(serde_json::json!({
+            "type": 2,
+            "channel_id": "200",
+            "data": {"name": "ack", "options": [{"name": "arguments", "type": 3, "value": "5"}]},
+            "member": {"user": {"username": "alice"}},
+        }))
+        .unwrap();
+        assert_eq!(interaction.command(), Some(String::from("ack 5")));
+        assert_eq!(interaction.user().unwrap().username, "alice");
+        assert_eq!(discord.find_channel("200"), Some(("team-a", 1)));
+        assert_eq!(discord.find_channel("300"), None);
+    }
+
+    #[actix_web::test]
+    async fn notifies_the_channel_of_the_level() {
+        let server = MockServer::start().await;
+        let alert = alert_context(1, "team-a");
+
+        Mock::given(method("POST"))
+            .and(path("/channels/200/messages"))
+            .and(header("authorization", "Bot secret"))
+            .and(body_json(serde_json::json!({
+                "content": Format::Markdown
+                    .renderer()
+                    .render("🚨 ESCALATION OCCURRED!", &[Section::new(alert.clone())])
+                    .body,
+                "allowed_mentions": {"parse": []},
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({"id": "1"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Levels beyond the last channel notify the last one.
+        let discord = discord(server.uri(), None);
+        discord
+            .notify("team-a", 4, &Notification::Escalation(vec![alert]))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,499 @@
+//! Connection to the Discord gateway, which pushes the messages posted in the
+//! channels, see https://discord.com/developers/docs/topics/gateway
+use super::DiscordMessage;
+use crate::error::DISCORD_ADAPTER;
+use crate::http::HttpConfig;
+use crate::{Error, Result};
+use futures::{SinkExt, StreamExt};
+use openssl::base64;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+const API_VERSION: u8 = 10;
+// See https://discord.com/developers/docs/topics/opcodes-and-status-codes
+const OP_DISPATCH: u8 = 0;
+const OP_HEARTBEAT: u8 = 1;
+const OP_IDENTIFY: u8 = 2;
+const OP_RESUME: u8 = 6;
+const OP_RECONNECT: u8 = 7;
+const OP_INVALID_SESSION: u8 = 9;
+const OP_HELLO: u8 = 10;
+const OP_HEARTBEAT_ACK: u8 = 11;
+// Messages in guild channels (`GUILD_MESSAGES`), including their content
+// (`MESSAGE_CONTENT`).
+const INTENTS: u64 = (1 << 9) | (1 << 15);
+// Limits the response of a proxy to `CONNECT`.
+const MAX_PROXY_RESPONSE: usize = 8192;
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload {
+    op: u8,
+    #[serde(default)]
+    d: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    s: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    t: Option<String>,
+}
+
+impl Payload {
+    fn new(op: u8, d: serde_json::Value) -> Self {
+        Payload {
+            op,
+            d,
+            s: None,
+            t: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Ready {
+    session_id: String,
+    resume_gateway_url: String,
+}
+
+/// A session which is resumed after a disconnect, so that the messages in
+/// between are not missed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    id: String,
+    pub resume_url: String,
+    // Of the last payload received.
+    sequence: Option<u64>,
+}
+
+/// Rejects proxies which cannot tunnel the gateway connection.
+pub fn check_proxy(http: &HttpConfig) -> Result<()> {
+    match http.proxy.as_deref().map(Url::parse) {
+        Some(Ok(proxy)) if proxy.scheme() != "http" => Err(Error::Config(format!(
+            "The Discord gateway requires an HTTP proxy, not {}",
+            proxy.scheme()
+        ))),
+        Some(Err(err)) => Err(Error::Config(format!("Invalid proxy: {}", err))),
+        _ => Ok(()),
+    }
+}
+
+/// Connects to the gateway at the given URL, through the proxy if any.
+pub async fn connect(url: &str, http: &HttpConfig) -> Result<Socket> {
+    let url = Url::parse(&format!(
+        "{}/?v={}&encoding=json",
+        url.trim_end_matches('/'),
+        API_VERSION
+    ))
+    .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::adapter(DISCORD_ADAPTER, "Gateway URL without host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let stream = match &http.proxy {
+        Some(proxy) => tunnel(proxy, host, port).await?,
+        None => TcpStream::connect((host, port))
+            .await
+            .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?,
+    };
+
+    let connector = Connector::NativeTls(http.tls_connector()?);
+    let (socket, _) = tokio_tungstenite::client_async_tls_with_config(
+        url.as_str(),
+        stream,
+        None,
+        Some(connector),
+    )
+    .await
+    .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+
+    Ok(socket)
+}
+
+/// Opens a tunnel to the host through an HTTP proxy.
+async fn tunnel(proxy: &str, host: &str, port: u16) -> Result<TcpStream> {
+    let proxy =
+        Url::parse(proxy).map_err(|err| Error::Config(format!("Invalid proxy: {}", err)))?;
+    let proxy_host = proxy
+        .host_str()
+        .ok_or_else(|| Error::Config(String::from("Proxy URL without host")))?;
+
+    let mut stream = TcpStream::connect((proxy_host, proxy.port_or_known_default().unwrap_or(80)))
+        .await
+        .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            proxy.username(),
+            proxy.password().unwrap_or_default()
+        );
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode_block(credentials.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+
+    // Read byte-wise, so that nothing of the tunneled connection is consumed.
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+        response.push(byte);
+
+        if response.len() > MAX_PROXY_RESPONSE {
+            return Err(Error::adapter(
+                DISCORD_ADAPTER,
+                "Proxy response is too long",
+            ));
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(Error::adapter(
+            DISCORD_ADAPTER,
+            format!("Proxy refused the tunnel: {}", status),
+        ));
+    }
+
+    Ok(stream)
+}
+
+/// Identifies, or resumes the session, then passes the messages to
+/// `on_message` until the gateway asks to reconnect. Fails if the connection
+/// breaks, e.g. if heartbeats are not acknowledged.
+pub async fn run(
+    mut socket: Socket,
+    token: &str,
+    session: &mut Option<Session>,
+    mut on_message: impl FnMut(DiscordMessage),
+) -> Result<()> {
+    let hello = receive(&mut socket).await?;
+    let interval = match (hello.op, hello.d["heartbeat_interval"].as_u64()) {
+        (OP_HELLO, Some(interval)) => Duration::from_millis(interval),
+        _ => {
+            return Err(Error::adapter(
+                DISCORD_ADAPTER,
+                format!("Expected hello from the gateway, got {:?}", hello),
+            ))
+        }
+    };
+
+    let start = match session {
+        Some(session) => Payload::new(
+            OP_RESUME,
+            serde_json::json!({
+                "token": token,
+                "session_id": session.id,
+                "seq": session.sequence,
+            }),
+        ),
+        None => Payload::new(
+            OP_IDENTIFY,
+            serde_json::json!({
+                "token": token,
+                "intents": INTENTS,
+                "properties": {
+                    "os": std::env::consts::OS,
+                    "browser": "matrixbot-ack",
+                    "device": "matrixbot-ack",
+                },
+            }),
+        ),
+    };
+    send(&mut socket, &start).await?;
+
+    let mut sequence = session.as_ref().and_then(|session| session.sequence);
+    let mut heartbeat = tokio::time::interval_at(Instant::now() + interval, interval);
+    let mut acked = true;
+
+    loop {
+        let payload = tokio::select! {
+            _ = heartbeat.tick() => None,
+            payload = receive(&mut socket) => Some(payload?),
+        };
+
+        let payload = match payload {
+            Some(payload) => payload,
+            None => {
+                // The connection is considered dead if the previous heartbeat
+                // was not acknowledged.
+                if !acked {
+                    return Err(Error::adapter(
+                        DISCORD_ADAPTER,
+                        "Gateway did not acknowledge the heartbeat",
+                    ));
+                }
+
+                acked = false;
+                send(&mut socket, &Payload::new(OP_HEARTBEAT, sequence.into())).await?;
+                continue;
+            }
+        };
+
+        if payload.s.is_some() {
+            sequence = payload.s;
+            if let Some(session) = session.as_mut() {
+                session.sequence = sequence;
+            }
+        }
+
+        match payload.op {
+            OP_DISPATCH => match payload.t.as_deref() {
+                Some("READY") => {
+                    let ready: Ready = serde_json::from_value(payload.d)
+                        .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))?;
+                    *session = Some(Session {
+                        id: ready.session_id,
+                        resume_url: ready.resume_gateway_url,
+                        sequence,
+                    });
+                }
+                Some("MESSAGE_CREATE") => match serde_json::from_value(payload.d) {
+                    Ok(message) => on_message(message),
+                    Err(err) => warn!("Ignoring invalid Discord message: {:?}", err),
+                },
+                _ => {}
+            },
+            OP_HEARTBEAT => send(&mut socket, &Payload::new(OP_HEARTBEAT, sequence.into())).await?,
+            OP_HEARTBEAT_ACK => acked = true,
+            OP_RECONNECT => return Ok(()),
+            // The session can only be resumed if `d` is true.
+            OP_INVALID_SESSION => {
+                if payload.d != serde_json::Value::Bool(true) {
+                    *session = None;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn receive(socket: &mut Socket) -> Result<Payload> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => {
+                return serde_json::from_str(&text)
+                    .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))
+            }
+            Some(Ok(Message::Close(frame))) => {
+                return Err(Error::adapter(
+                    DISCORD_ADAPTER,
+                    format!("Gateway closed the connection: {:?}", frame),
+                ))
+            }
+            // Pings are answered by the socket.
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(Error::adapter(DISCORD_ADAPTER, err)),
+            None => {
+                return Err(Error::adapter(
+                    DISCORD_ADAPTER,
+                    "Gateway closed the connection",
+                ))
+            }
+        }
+    }
+}
+
+async fn send(socket: &mut Socket, payload: &Payload) -> Result<()> {
+    let text = serde_json::to_string(payload).map_err(|err| Error::Internal(err.to_string()))?;
+    socket
+        .send(Message::Text(text))
+        .await
+        .map_err(|err| Error::adapter(DISCORD_ADAPTER, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    type ServerSocket = WebSocketStream<TcpStream>;
+
+    async fn accept(listener: &TcpListener, heartbeat_interval: u64) -> ServerSocket {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        push(
+            &mut socket,
+            serde_json::json!({"op": OP_HELLO, "d": {"heartbeat_interval": heartbeat_interval}}),
+        )
+        .await;
+        socket
+    }
+
+    async fn push(socket: &mut ServerSocket, payload: serde_json::Value) {
+        socket
+            .send(Message::Text(payload.to_string()))
+            .await
+            .unwrap();
+    }
+
+    async fn pull(socket: &mut ServerSocket) -> serde_json::Value {
+        loop {
+            if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn identifies_and_resumes_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = actix::spawn({
+            let url = url.clone();
+            async move {
+                let mut socket = accept(&listener, 45000).await;
+                let identify = pull(&mut socket).await;
+                assert_eq!(identify["op"], OP_IDENTIFY);
+                assert_eq!(identify["d"]["token"], "secret");
+                assert_eq!(identify["d"]["intents"], INTENTS);
+
+                push(
+                    &mut socket,
+                    serde_json::json!({"op": 0, "t": "READY", "s": 1, "d": {
+                        "session_id": "abc",
+                        "resume_gateway_url": url,
+                    }}),
+                )
+                .await;
+                push(
+                    &mut socket,
+                    serde_json::json!({"op": 0, "t": "MESSAGE_CREATE", "s": 2, "d": {
+                        "channel_id": "100",
+                        "content": "ack 1",
+                        "author": {"username": "alice"},
+                    }}),
+                )
+                .await;
+                push(
+                    &mut socket,
+                    serde_json::json!({"op": OP_RECONNECT, "d": null}),
+                )
+                .await;
+
+                // Resumes after the last message, cannot be resumed again.
+                let mut socket = accept(&listener, 45000).await;
+                let resume = pull(&mut socket).await;
+                assert_eq!(resume["op"], OP_RESUME);
+                assert_eq!(resume["d"]["session_id"], "abc");
+                assert_eq!(resume["d"]["seq"], 2);
+                push(
+                    &mut socket,
+                    serde_json::json!({"op": OP_INVALID_SESSION, "d": false}),
+                )
+                .await;
+            }
+        });
+
+        let http = HttpConfig::default();
+        let mut session = None;
+        let mut messages = vec![];
+        let socket = connect(&url, &http).await.unwrap();
+        run(socket, "secret", &mut session, |message| {
+            messages.push(message)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            session,
+            Some(Session {
+                id: String::from("abc"),
+                resume_url: url.clone(),
+                sequence: Some(2),
+            })
+        );
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].channel_id, "100");
+        assert_eq!(messages[0].content, "ack 1");
+
+        let socket = connect(&url, &http).await.unwrap();
+        run(socket, "secret", &mut session, |_| {}).await.unwrap();
+        assert_eq!(session, None);
+
+        server.await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn fails_without_heartbeat_acks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let server = actix::spawn(async move {
+            let mut socket = accept(&listener, 20).await;
+            assert_eq!(pull(&mut socket).await["op"], OP_IDENTIFY);
+            assert_eq!(pull(&mut socket).await["op"], OP_HEARTBEAT);
+            // Keeps the connection open without acknowledging.
+            while socket.next().await.is_some() {}
+        });
+
+        let socket = connect(&url, &HttpConfig::default()).await.unwrap();
+        let err = run(socket, "secret", &mut None, |_| {}).await.unwrap_err();
+        assert!(err.to_string().contains("heartbeat"));
+
+        server.await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn tunnels_through_http_proxies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://bot:secret@{}", listener.local_addr().unwrap());
+
+        // Acts as the gateway once the tunnel is established.
+        let server = actix::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            assert!(request.starts_with("CONNECT gateway.discord.test:80 HTTP/1.1\r\n"));
+            assert!(request.contains("Proxy-Authorization: Basic Ym90OnNlY3JldA==\r\n"));
+
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap();
+        });
+
+        let http = HttpConfig {
+            proxy: Some(proxy),
+            tls: None,
+        };
+        connect("ws://gateway.discord.test", &http).await.unwrap();
+
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn requires_http_proxies() {
+        let proxy = |proxy: &str| HttpConfig {
+            proxy: Some(proxy.to_string()),
+            tls: None,
+        };
+
+        assert!(check_proxy(&HttpConfig::default()).is_ok());
+        assert!(check_proxy(&proxy("http://proxy.example.com:3128")).is_ok());
+        assert!(check_proxy(&proxy("socks5://proxy.example.com:1080")).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Mattermost, posts to a channel per escalation level via incoming webhooks.
+//! Alerts come with a button to acknowledge them, which Mattermost sends to
+//! `/webhook-mattermost-action`. Slash commands such as `/matrixbot ack 1` are
+//! received on `/webhook-mattermost-command`.
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::MATTERMOST_ADAPTER;
+use crate::http::HttpConfig;
+use crate::render::{Format, NotificationRenderer};
+use crate::truncate;
+use crate::{AlertId, Error, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+// Mattermost rejects longer messages.
+const MAX_MESSAGE: usize = 16383;
+// Path of the buttons, relative to `public_url`.
+const ACTION_PATH: &str = "/webhook-mattermost-action";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MattermostConfig {
+    // Channels of each route, in the order of escalation levels. Alerts
+    // beyond the last channel stay in the last one.
+    channels: HashMap<String, Vec<MattermostChannel>>,
+    // Where Mattermost reaches the listener, e.g.
+    // `https://matrixbot.example.com`. Alerts only come with buttons if set.
+    public_url: Option<String>,
+    // Required as `?token=...` by the endpoints of buttons and slash
+    // commands, which are only served if set.
+    token: Option<String>,
+}
+
+impl MattermostConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.channels.keys().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MattermostChannel {
+    // The incoming webhook, which posts to the channel.
+    webhook_url: String,
+    // Buttons and slash commands of the channel act on its level.
+    channel_id: String,
+}
+
+/// A click on a button of a notification.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct MattermostAction {
+    channel_id: String,
+    user_name: Option<String>,
+    // As set on the button.
+    context: MattermostContext,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct MattermostContext {
+    // The alert to acknowledge.
+    alert: AlertId,
+}
+
+/// A slash command, e.g. `/matrixbot ack 1`.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct MattermostCommand {
+    channel_id: String,
+    user_name: String,
+    // The arguments of the command, e.g. `ack 1`.
+    text: String,
+}
+
+pub struct Mattermost {
+    config: MattermostConfig,
+    client: reqwest::Client,
+}
+
+impl Mattermost {
+    pub fn new(config: MattermostConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(Mattermost {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(MATTERMOST_ADAPTER, err))?,
+        })
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.config.token.as_deref()
+    }
+    /// The route and escalation level of a channel.
+    fn find_channel(&self, channel_id: &str) -> Option<(&str, usize)> {
+        self.config.channels.iter().find_map(|(route, channels)| {
+            channels
+                .iter()
+                .position(|channel| channel.channel_id == channel_id)
+                .map(|level| (route.as_str(), level))
+        })
+    }
+    /// Buttons to acknowledge the alerts, if they can reach the listener.
+    fn buttons(&self, notification: &Notification) -> Vec<serde_json::Value> {
+        let (public_url, token) = match (&self.config.public_url, &self.config.token) {
+            (Some(public_url), Some(token)) => (public_url, token),
+            _ => return vec![],
+        };
+
+        let url = format!(
+            "{}{}?token={}",
+            public_url.trim_end_matches('/'),
+            ACTION_PATH,
+            token
+        );
+
+        notification
+            .alerts()
+            .iter()
+            .map(|alert| {
+                serde_json::json!({
+                    // Only alphanumeric IDs are supported.
+                    "id": format!("ack{}", alert.id),
+                    "name": format!("Acknowledge {}", alert.id),
+                    "integration": {
+                        "url": url,
+                        "context": MattermostContext { alert: alert.id },
+                    },
+                })
+            })
+            .collect()
+    }
+    /// Runs the command in the channel, returns the reply.
+    async fn run(&self, channel_id: &str, user: &str, command: &str) -> Result<String> {
+        let (route, level) = match self.find_channel(channel_id) {
+            Some(found) => found,
+            None => {
+                return Ok(String::from(
+                    "This channel is not part of any escalation route.",
+                ))
+            }
+        };
+
+        let user = format!("mattermost:{}", user);
+        debug!("Received Mattermost command from {}: {}", user, command);
+
+        Ok(
+            adapter::run_command(MATTERMOST_ADAPTER, route, level, &user, command)
+                .await?
+                .unwrap_or_else(|| format!("Unknown command: {}", command)),
+        )
+    }
+    /// Acknowledges the alert of a button, returns the response shown to the
+    /// user who clicked it.
+    pub async fn handle_action(&self, action: MattermostAction) -> Result<serde_json::Value> {
+        let user = action.user_name.as_deref().unwrap_or("unknown");
+        let reply = self
+            .run(
+                &action.channel_id,
+                user,
+                &format!("ack {}", action.context.alert),
+            )
+            .await?;
+
+        Ok(serde_json::json!({ "ephemeral_text": reply }))
+    }
+    /// Runs a slash command, returns the response posted to the channel.
+    pub async fn handle_command(&self, command: MattermostCommand) -> Result<serde_json::Value> {
+        let reply = self
+            .run(&command.channel_id, &command.user_name, command.text.trim())
+            .await?;
+
+        Ok(serde_json::json!({
+            "response_type": "in_channel",
+            "text": truncate::message_within(&reply, MAX_MESSAGE),
+        }))
+    }
+}
+
+#[async_trait]
+impl Adapter for Mattermost {
+    fn name(&self) -> &'static str {
+        MATTERMOST_ADAPTER
+    }
+    fn covers(&self, route: &str, _level: usize) -> bool {
+        self.config
+            .channels
+            .get(route)
+            .map(|channels| !channels.is_empty())
+            .unwrap_or(false)
+    }
+    fn renderer(&self) -> &dyn NotificationRenderer {
+        Format::Markdown.renderer()
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(MATTERMOST_ADAPTER).await?;
+
+        let channels = self
+            .config
+            .channels
+            .get(route)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let channel = match channels.get(level).or_else(|| channels.last()) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+
+        let text = notification.render(self.renderer()).body;
+        let mut body = serde_json::json!({
+            "text": truncate::message_within(&text, MAX_MESSAGE),
+        });
+
+        let buttons = self.buttons(notification);
+        if !buttons.is_empty() {
+            body["attachments"] = serde_json::json!([{ "actions": buttons }]);
+        }
+
+        self.client
+            .post(&channel.webhook_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(MATTERMOST_ADAPTER, err))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn mattermost(server: &str, public_url: Option<&str>) -> Mattermost {
+        let config: MattermostConfig = serde_json::from_value(serde_json::json!({
+            "channels": {
+                "team-a": [
+                    {"webhook_url": format!("{}/hooks/first", server), "channel_id": "first"},
+                    {"webhook_url": format!("{}/hooks/second", server), "channel_id": "second"},
+                ],
+            },
+            "public_url": public_url,
+            "token": "secret",
+        }))
+        .unwrap();
+
+        Mattermost::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn finds_channels() {
+        let mattermost = mattermost("", None);
+        assert_eq!(mattermost.find_channel("second"), Some(("team-a", 1)));
+        assert_eq!(mattermost.find_channel("other"), None);
+        assert!(mattermost.covers("team-a", 5));
+        assert!(!mattermost.covers("default", 0));
+
+        let action: MattermostAction = serde_json::from_value(serde_json::json!({
+            "user_id": "u1",
+            "user_name": "alice",
+            "channel_id": "first",
+            "post_id": "p1",
+            "context": {"alert": 5},
+        }))
+        .unwrap();
+        assert_eq!(action.context.alert, AlertId::from(5));
+    }
+
+    #[actix_web::test]
+    async fn notifies_with_buttons() {
+        let server = MockServer::start().await;
+        let alert = alert_context(1, "team-a");
+        let notification = Notification::Escalation(vec![alert]);
+
+        // Levels beyond the last channel notify the last one.
+        Mock::given(method("POST"))
+            .and(path("/hooks/second"))
+            .and(body_json(serde_json::json!({
+                "text": notification.render(Format::Markdown.renderer()).body,
+                "attachments": [{
+                    "actions": [{
+                        "id": "ack1",
+                        "name": "Acknowledge 1",
+                        "integration": {
+                            "url": "https://matrixbot.example.com/webhook-mattermost-action?token=secret",
+                            "context": {"alert": 1},
+                        },
+                    }],
+                }],
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Without buttons, e.g. for acks.
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Matrix"),
+        };
+        Mock::given(method("POST"))
+            .and(path("/hooks/first"))
+            .and(body_json(serde_json::json!({
+                "text": ack.render(Format::Markdown.renderer()).body,
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mattermost = mattermost(&server.uri(), Some("https://matrixbot.example.com/"));
+        mattermost.notify("team-a", 3, &notification).await.unwrap();
+        mattermost.notify("team-a", 0, &ack).await.unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! MQTT, e.g. for IoT and edge monitoring. Alerts published to a topic are
+//! inserted like those of the webhook, and alerts, escalations and acks are
+//! published as JSON events to `<events_topic>/<route>/<state>`.
+use crate::adapter::{Adapter, Notification};
+use crate::chaos;
+use crate::error::MQTT_ADAPTER;
+use crate::processor::{InsertAlerts, IsStandby, Processor};
+use crate::{Error, Result, DEFAULT_ROUTE};
+use actix::SystemService;
+use bson::oid::ObjectId;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, Incoming, MqttOptions, QoS, TlsConfiguration, Transport,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_CLIENT_ID: &str = "matrixbot-ack";
+const KEEP_ALIVE: u64 = 30;
+// Requests which are queued until the connection catches up.
+const CAPACITY: usize = 100;
+// Delay before reconnecting after a failure.
+const RETRY_DELAY: u64 = 5;
+// Brokers commonly limit packets to 256 KiB.
+const MAX_TEXT: usize = 200 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    // E.g. `mqtt://broker.example.com:1883`, or `mqtts://...` for TLS.
+    url: String,
+    // Defaults to `matrixbot-ack`, must be unique per broker.
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    // Alerts published to this topic (wildcards allowed) are inserted, in
+    // the format of the webhook. Nothing is received if not set.
+    alerts_topic: Option<String>,
+    // Route of the received alerts, defaults to the default route.
+    route: Option<String>,
+    // Prefix of the topics events are published to. Nothing is published if
+    // not set.
+    events_topic: Option<String>,
+}
+
+impl MqttConfig {
+    pub fn route(&self) -> &str {
+        self.route.as_deref().unwrap_or(DEFAULT_ROUTE)
+    }
+    fn options(&self) -> Result<MqttOptions> {
+        let url = Url::parse(&self.url)
+            .map_err(|err| Error::Config(format!("Invalid MQTT URL: {}", err)))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::Config(String::from("MQTT URL without host")))?;
+
+        let (transport, default_port) = match url.scheme() {
+            "mqtt" | "tcp" => (Transport::tcp(), 1883),
+            "mqtts" | "ssl" => (Transport::tls_with_config(TlsConfiguration::Native), 8883),
+            scheme => {
+                return Err(Error::Config(format!(
+                    "Unsupported MQTT URL scheme: {}",
+                    scheme
+                )))
+            }
+        };
+
+        let client_id = self.client_id.as_deref().unwrap_or(DEFAULT_CLIENT_ID);
+        if client_id.is_empty() || client_id.starts_with(' ') {
+            return Err(Error::Config(format!(
+                "Invalid MQTT client ID: '{}'",
+                client_id
+            )));
+        }
+
+        let mut options = MqttOptions::new(client_id, host, url.port().unwrap_or(default_port));
+        options
+            .set_transport(transport)
+            .set_keep_alive(Duration::from_secs(KEEP_ALIVE));
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        Ok(options)
+    }
+}
+
+pub struct Mqtt {
+    config: MqttConfig,
+    client: AsyncClient,
+    // Taken by `serve`, which drives the connection.
+    event_loop: Mutex<Option<EventLoop>>,
+}
+
+impl Mqtt {
+    pub fn new(config: MqttConfig) -> Result<Self> {
+        if config.alerts_topic.is_none() && config.events_topic.is_none() {
+            return Err(Error::Config(String::from(
+                "MQTT requires an alerts topic, an events topic or both",
+            )));
+        }
+
+        let (client, event_loop) = AsyncClient::new(config.options()?, CAPACITY);
+
+        Ok(Mqtt {
+            config,
+            client,
+            event_loop: Mutex::new(Some(event_loop)),
+        })
+    }
+    /// Keeps the connection to the broker, until the service stops. Alerts
+    /// received by standby instances are left to the active instance.
+    pub async fn serve(self: Arc<Self>) {
+        let mut event_loop = match self.event_loop.lock().unwrap().take() {
+            Some(event_loop) => event_loop,
+            None => return,
+        };
+
+        loop {
+            match event_loop.poll().await {
+                // Subscriptions do not survive reconnects of clean sessions.
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}", self.config.url);
+
+                    if let Some(topic) = &self.config.alerts_topic {
+                        if let Err(err) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            error!("Failed to subscribe to MQTT topic {}: {:?}", topic, err);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                    let alerts = parse_alerts(&publish.payload);
+                    let route = self.config.route().to_string();
+                    actix::spawn(async move {
+                        if let Err(err) = insert(alerts, route).await {
+                            error!(
+                                "Error when trying to process MQTT alerts of {}: {:?}",
+                                publish.topic, err
+                            );
+                        }
+                    });
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("MQTT connection failed, reconnecting: {:?}", err);
+                    tokio::time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+                }
+            }
+        }
+    }
+    fn topic(&self, route: &str, state: &str) -> Option<String> {
+        self.config
+            .events_topic
+            .as_deref()
+            .map(|prefix| format!("{}/{}/{}", prefix.trim_end_matches('/'), route, state))
+    }
+}
+
+#[async_trait]
+impl Adapter for Mqtt {
+    fn name(&self) -> &'static str {
+        MQTT_ADAPTER
+    }
+    fn covers(&self, _route: &str, _level: usize) -> bool {
+        self.config.events_topic.is_some()
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(MQTT_ADAPTER).await?;
+
+        let topic = match self.topic(route, notification.state()) {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+
+        // Queued, published once the broker is connected.
+        for event in notification.events(route, level, MAX_TEXT) {
+            self.client
+                .publish(&topic, QoS::AtLeastOnce, false, event.to_string())
+                .await
+                .map_err(|err| Error::adapter(MQTT_ADAPTER, err))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_alerts(payload: &[u8]) -> Result<InsertAlerts> {
+    serde_json::from_slice(payload).map_err(|err| Error::adapter(MQTT_ADAPTER, err))
+}
+
+async fn insert(alerts: Result<InsertAlerts>, route: String) -> Result<()> {
+    let mut alerts = alerts?;
+
+    if Processor::from_registry().send(IsStandby).await? {
+        return Ok(());
+    }
+
+    alerts.route = route;
+    alerts.request_id = Some(ObjectId::new().to_hex());
+    debug!("New alerts received via MQTT: {:?}", alerts);
+
+    Processor::from_registry().send(alerts).await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn config(url: &str, events_topic: Option<&str>) -> MqttConfig {
+        serde_json::from_value(serde_json::json!({
+            "url": url,
+            "username": "matrixbot",
+            "password": "secret",
+            "alerts_topic": "monitoring/alerts",
+            "events_topic": events_topic,
+        }))
+        .unwrap()
+    }
+
+    /// Reads a packet of the client, returns its type and flags and its body.
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let kind = stream.read_u8().await.unwrap();
+
+        let mut len = 0;
+        let mut shift = 0;
+        loop {
+            let byte = stream.read_u8().await.unwrap();
+            len |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        (kind, body)
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(Mqtt::new(config("mqtt://localhost", Some("events"))).is_ok());
+        assert!(Mqtt::new(config("mqtts://localhost:8883", None)).is_ok());
+        assert!(Mqtt::new(config("http://localhost", None)).is_err());
+
+        let mut neither = config("mqtt://localhost", None);
+        neither.alerts_topic = None;
+        assert!(Mqtt::new(neither).is_err());
+
+        let alerts = parse_alerts(
+            br#"{"alerts": [{"labels": {"alertname": "Down", "severity": "critical"},
+                "annotations": {"message": "Node down"}}]}"#,
+        );
+        assert!(alerts.is_ok());
+        assert!(parse_alerts(b"Node down").is_err());
+    }
+
+    #[actix_web::test]
+    async fn subscribes_and_publishes_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+
+        let mqtt = Arc::new(Mqtt::new(config(&url, Some("matrixbot/events/"))).unwrap());
+        assert!(mqtt.covers("team-a", 0));
+        actix::spawn(Arc::clone(&mqtt).serve());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (kind, connect) = read_packet(&mut stream).await;
+        assert_eq!(kind, 0x10);
+        assert!(String::from_utf8_lossy(&connect).contains("matrixbot"));
+        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+        let (kind, subscribe) = read_packet(&mut stream).await;
+        assert_eq!(kind, 0x82);
+        assert!(String::from_utf8_lossy(&subscribe).contains("monitoring/alerts"));
+        stream
+            .write_all(&[0x90, 0x03, subscribe[0], subscribe[1], 0x01])
+            .await
+            .unwrap();
+
+        let notification = Notification::Escalation(vec![alert_context(1, "team-a")]);
+        mqtt.notify("team-a", 1, &notification).await.unwrap();
+
+        // At least once, i.e. with a packet ID after the topic.
+        let (kind, publish) = read_packet(&mut stream).await;
+        assert_eq!(kind & 0xf6, 0x32);
+        let topic_len = u16::from_be_bytes([publish[0], publish[1]]) as usize;
+        assert_eq!(
+            &publish[2..2 + topic_len],
+            b"matrixbot/events/team-a/escalation"
+        );
+
+        let event: serde_json::Value = serde_json::from_slice(&publish[4 + topic_len..]).unwrap();
+        assert_eq!(event["state"], "escalation");
+        assert_eq!(event["id"], 1);
+        assert_eq!(event["level"], 1);
+    }
+}
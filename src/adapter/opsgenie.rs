@@ -0,0 +1,436 @@
+//! Opsgenie, creates an alert for every alert of the bot and adds the
+//! responders of each escalation level. Acks in Opsgenie are received by its
+//! webhook integration on `/webhook-opsgenie`.
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::OPSGENIE_ADAPTER;
+use crate::http::HttpConfig;
+use crate::processor::{AlertContext, GetAlert, Processor};
+use crate::truncate;
+use crate::{AlertId, Error, Result};
+use actix::SystemService;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const DEFAULT_API_URL: &str = "https://api.opsgenie.com";
+// Aliases of the alerts in Opsgenie, followed by the ID.
+const ALIAS_PREFIX: &str = "matrixbot-";
+// Source of the alerts and acks of the bot, its own acks are not received.
+const SOURCE: &str = "matrixbot";
+// Opsgenie rejects longer values.
+const MAX_TITLE: usize = 130;
+const MAX_DESCRIPTION: usize = 15000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpsgenieConfig {
+    // Key of an API integration.
+    api_key: String,
+    // Defaults to `https://api.opsgenie.com`, e.g. `https://api.eu.opsgenie.com`.
+    api_url: Option<String>,
+    // Responders and priority of each route, in the order of escalation
+    // levels. Alerts beyond the last level stay on the last one.
+    routes: HashMap<String, Vec<OpsgenieLevel>>,
+    // Required as `?token=...` by `/webhook-opsgenie`, which is only served
+    // if set.
+    token: Option<String>,
+}
+
+impl OpsgenieConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpsgenieLevel {
+    #[serde(default)]
+    responders: Vec<Responder>,
+    // Defaults to `P3`, as in Opsgenie.
+    priority: Option<Priority>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Responder {
+    #[serde(rename = "type")]
+    kind: ResponderKind,
+    // The username of users, the name of teams, schedules and escalations.
+    name: String,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponderKind {
+    Team,
+    User,
+    Escalation,
+    Schedule,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Priority {
+    P1,
+    P2,
+    P3,
+    P4,
+    P5,
+}
+
+impl Responder {
+    fn to_json(&self) -> serde_json::Value {
+        match self.kind {
+            ResponderKind::User => serde_json::json!({
+                "type": self.kind,
+                "username": self.name,
+            }),
+            _ => serde_json::json!({
+                "type": self.kind,
+                "name": self.name,
+            }),
+        }
+    }
+}
+
+/// An event of the Opsgenie webhook integration.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct OpsgenieEvent {
+    // E.g. `Create`, `Acknowledge` or `Close`.
+    action: String,
+    alert: OpsgenieAlert,
+    source: Option<OpsgenieSource>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct OpsgenieAlert {
+    alias: Option<String>,
+    // The user who took the action.
+    username: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct OpsgenieSource {
+    name: Option<String>,
+}
+
+impl OpsgenieEvent {
+    /// The ID of the acknowledged alert, unless it was acknowledged by the
+    /// bot itself.
+    fn acknowledged(&self) -> Option<AlertId> {
+        let by_bot = self
+            .source
+            .as_ref()
+            .and_then(|source| source.name.as_deref())
+            == Some(SOURCE);
+        if self.action != "Acknowledge" || by_bot {
+            return None;
+        }
+
+        self.alert
+            .alias
+            .as_deref()?
+            .strip_prefix(ALIAS_PREFIX)?
+            .parse::<u64>()
+            .ok()
+            .map(AlertId::from)
+    }
+}
+
+pub struct Opsgenie {
+    config: OpsgenieConfig,
+    client: reqwest::Client,
+}
+
+impl Opsgenie {
+    pub fn new(config: OpsgenieConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(Opsgenie {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(OPSGENIE_ADAPTER, err))?,
+        })
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.config.token.as_deref()
+    }
+    fn level(&self, route: &str, level: usize) -> Option<&OpsgenieLevel> {
+        let levels = self.config.routes.get(route)?;
+        levels.get(level).or_else(|| levels.last())
+    }
+    /// Calls the Alert API, e.g. `alerts` to create an alert. A null body is
+    /// not sent.
+    async fn call(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<()> {
+        let url = format!(
+            "{}/v2/{}",
+            self.config
+                .api_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/'),
+            path
+        );
+
+        let mut request = self.client.request(method, url).header(
+            reqwest::header::AUTHORIZATION,
+            format!("GenieKey {}", self.config.api_key),
+        );
+        if !body.is_null() {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+
+        // Requests are processed asynchronously by Opsgenie.
+        request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(OPSGENIE_ADAPTER, err))?;
+
+        Ok(())
+    }
+    async fn create(&self, alert: &AlertContext, level: &OpsgenieLevel) -> Result<()> {
+        let description = alert.to_string();
+        let labels = &alert.alert.labels;
+
+        let mut body = serde_json::json!({
+            "message": labels.alert_name.chars().take(MAX_TITLE).collect::<String>(),
+            "alias": alias(alert.id),
+            "description": truncate::message_within(description.trim(), MAX_DESCRIPTION),
+            "responders": level.responders.iter().map(Responder::to_json).collect::<Vec<_>>(),
+            "tags": [SOURCE, &alert.route, &labels.severity],
+            "details": {
+                "id": alert.id.to_string(),
+                "route": alert.route,
+                "severity": labels.severity,
+            },
+            "source": SOURCE,
+        });
+        if let Some(priority) = level.priority {
+            body["priority"] = serde_json::json!(priority);
+        }
+
+        self.call(reqwest::Method::POST, "alerts", body).await
+    }
+    /// Adds the responders of the new level, the priority is raised if set.
+    async fn escalate(&self, id: AlertId, level: &OpsgenieLevel) -> Result<()> {
+        let alias = alias(id);
+
+        for responder in &level.responders {
+            self.call(
+                reqwest::Method::POST,
+                &format!("alerts/{}/responders?identifierType=alias", alias),
+                serde_json::json!({
+                    "responder": responder.to_json(),
+                    "source": SOURCE,
+                }),
+            )
+            .await?;
+        }
+
+        if let Some(priority) = level.priority {
+            self.call(
+                reqwest::Method::PUT,
+                &format!("alerts/{}/priority?identifierType=alias", alias),
+                serde_json::json!({ "priority": priority }),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+    /// Acknowledges the alert of the event in the bot, returns the reply if
+    /// the event is an ack.
+    pub async fn handle_event(&self, event: OpsgenieEvent) -> Result<Option<String>> {
+        let id = match event.acknowledged() {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let alert = match Processor::from_registry().send(GetAlert(id)).await?? {
+            Some(alert) => alert,
+            None => return Ok(Some(format!("Alert {} does not exist", id))),
+        };
+
+        let user = format!(
+            "opsgenie:{}",
+            event.alert.username.as_deref().unwrap_or("unknown")
+        );
+
+        // Acked from the alert's own level, like acks via the API.
+        adapter::run_command(
+            OPSGENIE_ADAPTER,
+            &alert.route,
+            alert.escalation_idx,
+            &user,
+            &format!("ack {}", id),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Adapter for Opsgenie {
+    fn name(&self) -> &'static str {
+        OPSGENIE_ADAPTER
+    }
+    fn covers(&self, route: &str, _level: usize) -> bool {
+        self.config
+            .routes
+            .get(route)
+            .map(|levels| !levels.is_empty())
+            .unwrap_or(false)
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(OPSGENIE_ADAPTER).await?;
+
+        let opsgenie_level = match self.level(route, level) {
+            Some(opsgenie_level) => opsgenie_level,
+            None => return Ok(()),
+        };
+
+        match notification {
+            Notification::Alert(alerts) => {
+                for alert in alerts {
+                    self.create(alert, opsgenie_level).await?;
+                }
+            }
+            Notification::Escalation(alerts) => {
+                for alert in alerts {
+                    self.escalate(alert.id, opsgenie_level).await?;
+                }
+            }
+            Notification::Acknowledged { id, user, via } => {
+                self.call(
+                    reqwest::Method::POST,
+                    &format!("alerts/{}/acknowledge?identifierType=alias", alias(*id)),
+                    serde_json::json!({
+                        "user": user,
+                        "source": SOURCE,
+                        "note": format!("Acknowledged by {} via {}", user, via),
+                    }),
+                )
+                .await?;
+            }
+            // Creating an alert would page the responders, counting the
+            // alerts verifies the API key instead.
+            Notification::SelfTest { .. } => {
+                self.call(
+                    reqwest::Method::GET,
+                    "alerts/count",
+                    serde_json::Value::Null,
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn alias(id: AlertId) -> String {
+    format!("{}{}", ALIAS_PREFIX, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn opsgenie(api_url: String) -> Opsgenie {
+        let config: OpsgenieConfig = serde_yaml::from_str(&format!(
+            r#"
+            api_key: secret
+            api_url: {}
+            routes:
+              team-a:
+                - responders: [{{type: team, name: ops}}]
+                - responders: [{{type: user, name: lead@example.com}}]
+                  priority: P1
+            "#,
+            api_url
+        ))
+        .unwrap();
+
+        Opsgenie::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn parses_acks_of_others() {
+        let event = |action: &str, alias: &str, source: &str| -> OpsgenieEvent {
+            serde_json::from_value(serde_json::json!({
+                "action": action,
+                "alert": {"alias": alias, "username": "alice@example.com"},
+                "source": {"name": source, "type": "web"},
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            event("Acknowledge", "matrixbot-5", "").acknowledged(),
+            Some(AlertId::from(5))
+        );
+        assert_eq!(event("Close", "matrixbot-5", "").acknowledged(), None);
+        assert_eq!(event("Acknowledge", "other-5", "").acknowledged(), None);
+        assert_eq!(
+            event("Acknowledge", "matrixbot-5", SOURCE).acknowledged(),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn creates_and_escalates_alerts() {
+        let server = MockServer::start().await;
+        let alert = alert_context(1, "team-a");
+
+        Mock::given(method("POST"))
+            .and(path("/v2/alerts"))
+            .and(header("authorization", "GenieKey secret"))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Levels beyond the last one add its responders again.
+        Mock::given(method("POST"))
+            .and(path("/v2/alerts/matrixbot-1/responders"))
+            .and(query_param("identifierType", "alias"))
+            .and(body_json(serde_json::json!({
+                "responder": {"type": "user", "username": "lead@example.com"},
+                "source": SOURCE,
+            })))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        Mock::given(method("PUT"))
+            .and(path("/v2/alerts/matrixbot-1/priority"))
+            .and(body_json(serde_json::json!({"priority": "P1"})))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let opsgenie = opsgenie(server.uri());
+        opsgenie
+            .notify("team-a", 0, &Notification::Alert(vec![alert.clone()]))
+            .await
+            .unwrap();
+        opsgenie
+            .notify("team-a", 2, &Notification::Escalation(vec![alert]))
+            .await
+            .unwrap();
+        assert!(!opsgenie.covers("default", 0));
+    }
+}
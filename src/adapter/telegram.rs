@@ -0,0 +1,372 @@
+//! Telegram bot, notifies chats and accepts commands such as `/ack 1` from
+//! them. Each chat of a route corresponds to an escalation level, like rooms.
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::TELEGRAM_ADAPTER;
+use crate::http::HttpConfig;
+use crate::processor::{IsStandby, Processor};
+use crate::truncate;
+use crate::{Error, Result};
+use actix::SystemService;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const DEFAULT_API_URL: &str = "https://api.telegram.org";
+const DEFAULT_POLL_TIMEOUT: u64 = 30;
+// Telegram rejects longer messages.
+const MAX_MESSAGE: usize = 4096;
+// Delay before polling again after a failure or while in standby.
+const RETRY_DELAY: u64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelegramConfig {
+    // Token of the bot, as issued by @BotFather.
+    token: String,
+    // Defaults to `https://api.telegram.org`.
+    api_url: Option<String>,
+    // Chat IDs of each route, in the order of escalation levels. Alerts
+    // beyond the last chat stay in the last one.
+    chats: HashMap<String, Vec<i64>>,
+    // Seconds to wait for new commands per request, defaults to 30.
+    poll_timeout: Option<u64>,
+}
+
+impl TelegramConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.chats.keys().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<TelegramMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramMessage {
+    chat: Chat,
+    from: Option<User>,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: i64,
+    username: Option<String>,
+}
+
+impl User {
+    /// The name of the user in acks, e.g. `telegram:alice`.
+    fn name(&self) -> String {
+        match &self.username {
+            Some(username) => format!("telegram:{}", username),
+            None => format!("telegram:{}", self.id),
+        }
+    }
+}
+
+pub struct Telegram {
+    config: TelegramConfig,
+    client: reqwest::Client,
+}
+
+impl Telegram {
+    pub fn new(config: TelegramConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http.client_builder()?;
+
+        Ok(Telegram {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(TELEGRAM_ADAPTER, err))?,
+        })
+    }
+    fn poll_timeout(&self) -> u64 {
+        self.config.poll_timeout.unwrap_or(DEFAULT_POLL_TIMEOUT)
+    }
+    /// Calls a method of the bot API.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: serde_json::Value,
+        timeout: u64,
+    ) -> Result<T> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config
+                .api_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/'),
+            self.config.token,
+            method
+        );
+
+        let resp: Response<T> = self
+            .client
+            .post(url)
+            .timeout(Duration::from_secs(timeout))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|err| Error::adapter(TELEGRAM_ADAPTER, err))?
+            .bytes()
+            .await
+            .map_err(|err| Error::adapter(TELEGRAM_ADAPTER, err))
+            .and_then(|body| {
+                serde_json::from_slice(&body).map_err(|err| Error::adapter(TELEGRAM_ADAPTER, err))
+            })?;
+
+        match resp.result {
+            Some(result) if resp.ok => Ok(result),
+            _ => Err(Error::adapter(
+                TELEGRAM_ADAPTER,
+                format!(
+                    "{} failed: {}",
+                    method,
+                    resp.description.unwrap_or_default()
+                ),
+            )),
+        }
+    }
+    async fn send_message(&self, chat_id: i64, text: &str) -> Result<()> {
+        self.call::<serde_json::Value>(
+            "sendMessage",
+            serde_json::json!({
+                "chat_id": chat_id,
+                "text": truncate::message_within(text, MAX_MESSAGE),
+            }),
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map(|_| ())
+    }
+    /// The route and escalation level of a chat.
+    fn find_chat(&self, chat_id: i64) -> Option<(&str, usize)> {
+        self.config.chats.iter().find_map(|(route, chats)| {
+            chats
+                .iter()
+                .position(|id| *id == chat_id)
+                .map(|level| (route.as_str(), level))
+        })
+    }
+    async fn handle_update(&self, update: Update) -> Result<()> {
+        let message = match update.message {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+
+        // Only process configured chats.
+        let (route, level) = match self.find_chat(message.chat.id) {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        let (text, user) = match (message.text.as_deref().and_then(command), message.from) {
+            (Some(text), Some(user)) => (text, user.name()),
+            // Ignore casual chatter in chats.
+            _ => return Ok(()),
+        };
+
+        debug!("Received Telegram command from {}: {}", user, text);
+
+        if let Some(reply) =
+            adapter::run_command(TELEGRAM_ADAPTER, route, level, &user, &text).await?
+        {
+            self.send_message(message.chat.id, &reply).await?;
+        }
+
+        Ok(())
+    }
+    /// Polls the bot for commands, until the service stops. Standby instances
+    /// leave them to the active instance.
+    pub async fn serve_commands(self: Arc<Self>) {
+        let mut offset = 0;
+
+        loop {
+            if Processor::from_registry()
+                .send(IsStandby)
+                .await
+                .unwrap_or(true)
+            {
+                tokio::time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+                continue;
+            }
+
+            let updates: Vec<Update> = match self
+                .call(
+                    "getUpdates",
+                    serde_json::json!({
+                        "offset": offset,
+                        "timeout": self.poll_timeout(),
+                        "allowed_updates": ["message"],
+                    }),
+                    self.poll_timeout() + REQUEST_TIMEOUT,
+                )
+                .await
+            {
+                Ok(updates) => updates,
+                Err(err) => {
+                    warn!("Failed to poll Telegram commands: {:?}", err);
+                    tokio::time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                offset = offset.max(update.update_id + 1);
+
+                if let Err(err) = self.handle_update(update).await {
+                    error!("Error when trying to process Telegram command {:?}", err);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Adapter for Telegram {
+    fn name(&self) -> &'static str {
+        TELEGRAM_ADAPTER
+    }
+    fn covers(&self, route: &str, _level: usize) -> bool {
+        self.config
+            .chats
+            .get(route)
+            .map(|chats| !chats.is_empty())
+            .unwrap_or(false)
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(TELEGRAM_ADAPTER).await?;
+
+        let chats = self
+            .config
+            .chats
+            .get(route)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let chat_id = match chats.get(level).or_else(|| chats.last()) {
+            Some(chat_id) => *chat_id,
+            None => return Ok(()),
+        };
+
+        self.send_message(chat_id, &notification.render(self.renderer()).body)
+            .await
+    }
+}
+
+/// Converts a bot command, e.g. `/ack@matrixbot 1`, into the command of rooms.
+/// Returns `None` if the message is not a bot command.
+fn command(text: &str) -> Option<String> {
+    let text = text.trim().strip_prefix('/')?;
+    let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let name = name.split('@').next().unwrap_or_default();
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(format!("{} {}", name, args.trim()).trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use crate::AlertId;
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn telegram(api_url: String) -> Telegram {
+        Telegram::new(
+            TelegramConfig {
+                token: String::from("secret"),
+                api_url: Some(api_url),
+                chats: [(String::from("team-a"), vec![-100, -200])].into(),
+                poll_timeout: None,
+            },
+            &HttpConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn converts_bot_commands() {
+        assert_eq!(command("/ack 12"), Some(String::from("ack 12")));
+        assert_eq!(command("/ack@matrixbot  12 "), Some(String::from("ack 12")));
+        assert_eq!(command("/pending"), Some(String::from("pending")));
+        assert_eq!(command("/help@matrixbot"), Some(String::from("help")));
+        assert_eq!(command("ack 12"), None);
+        assert_eq!(command("/ "), None);
+
+        let telegram = telegram(String::new());
+        assert_eq!(telegram.find_chat(-200), Some(("team-a", 1)));
+        assert_eq!(telegram.find_chat(-300), None);
+        assert!(telegram.covers("team-a", 5));
+        assert!(!telegram.covers("default", 0));
+    }
+
+    #[actix_web::test]
+    async fn notifies_the_chat_of_the_level() {
+        let server = MockServer::start().await;
+        let alert = alert_context(1, "team-a");
+
+        Mock::given(method("POST"))
+            .and(path("/botsecret/sendMessage"))
+            .and(body_json(serde_json::json!({
+                "chat_id": -100,
+                "text": format!("⚠️ Alert occurred!\n\n{}", alert),
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ok": true,
+                "result": {"message_id": 1},
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Levels beyond the last chat notify the last one.
+        Mock::given(method("POST"))
+            .and(path("/botsecret/sendMessage"))
+            .and(body_json(serde_json::json!({
+                "chat_id": -200,
+                "text": "✅ Alert 1 has been acknowledged by @ops:matrix.org via Matrix",
+            })))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "ok": false,
+                "description": "Bad Request: chat not found",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let telegram = telegram(server.uri());
+        telegram
+            .notify("team-a", 0, &Notification::Alert(vec![alert]))
+            .await
+            .unwrap();
+
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Matrix"),
+        };
+        assert!(telegram.notify("team-a", 3, &ack).await.is_err());
+    }
+}
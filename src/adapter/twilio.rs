@@ -0,0 +1,438 @@
+//! Twilio, pages phone numbers by SMS, e.g. on the final escalation levels
+//! when nobody is watching the rooms. Replies such as `ack 1` are received by
+//! the messaging webhook of the number on `/webhook-twilio-sms`.
+pub mod voice;
+
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::TWILIO_SMS_ADAPTER;
+use crate::http::HttpConfig;
+use crate::matrix::parse_command;
+use crate::processor::{AckTarget, Command, GetAlert, Processor};
+use crate::render::{Message, NotificationRenderer, Section};
+use crate::truncate;
+use crate::{Error, Result};
+use actix::SystemService;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::{base64, memcmp};
+use std::collections::HashMap;
+use std::time::Duration;
+use url::form_urlencoded;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const DEFAULT_API_URL: &str = "https://api.twilio.com";
+// Twilio rejects longer messages.
+const MAX_MESSAGE: usize = 1600;
+
+/// The credentials of a Twilio account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwilioAccount {
+    account_sid: String,
+    // Also signs the webhook requests of Twilio.
+    auth_token: String,
+    // Defaults to `https://api.twilio.com`.
+    api_url: Option<String>,
+}
+
+impl TwilioAccount {
+    /// Verifies the `X-Twilio-Signature` of a webhook request to the given
+    /// URL, see https://www.twilio.com/docs/usage/security#validating-requests
+    pub fn verify(&self, url: &str, body: &[u8], signature: &str) -> Result<()> {
+        let mut params: Vec<(String, String)> = form_urlencoded::parse(body).into_owned().collect();
+        params.sort();
+
+        let mut signed = url.to_string();
+        for (name, value) in params {
+            signed.push_str(&name);
+            signed.push_str(&value);
+        }
+
+        let expected = PKey::hmac(self.auth_token.as_bytes())
+            .and_then(|key| {
+                let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+                signer.update(signed.as_bytes())?;
+                signer.sign_to_vec()
+            })
+            .map(|hmac| base64::encode_block(&hmac))
+            .map_err(|err| Error::Internal(err.to_string()))?;
+
+        if signature.len() != expected.len()
+            || !memcmp::eq(signature.as_bytes(), expected.as_bytes())
+        {
+            return Err(Error::Permission(String::from("Invalid Twilio signature")));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwilioSmsConfig {
+    #[serde(flatten)]
+    account: TwilioAccount,
+    // The Twilio number messages are sent from, e.g. `+15005550006`.
+    from: String,
+    // Phone numbers (E.164) of each route, in the order of escalation levels.
+    // Levels without numbers are not paged, alerts beyond the last level
+    // page the numbers of the last one.
+    routes: HashMap<String, Vec<Vec<String>>>,
+    // The URL Twilio reaches the listener on, e.g. `https://bot.example.com`,
+    // which is part of the signature of requests. Defaults to the scheme and
+    // host of the request.
+    public_url: Option<String>,
+    // Required as `?token=...` by `/webhook-twilio-sms`, if set.
+    token: Option<String>,
+}
+
+impl TwilioSmsConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+}
+
+/// An inbound message, as posted by the messaging webhook of Twilio.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct TwilioMessage {
+    #[serde(rename = "From")]
+    from: String,
+    #[serde(rename = "Body")]
+    body: String,
+}
+
+/// A client of the REST API of an account.
+pub struct TwilioClient {
+    account: TwilioAccount,
+    client: reqwest::Client,
+}
+
+impl TwilioClient {
+    pub fn new(account: TwilioAccount, http: &HttpConfig, name: &'static str) -> Result<Self> {
+        let to_err = |err: reqwest::Error| Error::adapter(name, err);
+
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(TwilioClient {
+            account,
+            client: builder.build().map_err(to_err)?,
+        })
+    }
+    fn url(&self, resource: Option<&str>) -> String {
+        let mut url = format!(
+            "{}/2010-04-01/Accounts/{}",
+            self.account
+                .api_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/'),
+            self.account.account_sid,
+        );
+        if let Some(resource) = resource {
+            url.push('/');
+            url.push_str(resource);
+        }
+
+        url + ".json"
+    }
+    /// Creates a resource of the account, e.g. `Messages`.
+    pub async fn create(
+        &self,
+        resource: &str,
+        params: &[(&str, &str)],
+    ) -> std::result::Result<(), reqwest::Error> {
+        self.client
+            .post(self.url(Some(resource)))
+            .basic_auth(&self.account.account_sid, Some(&self.account.auth_token))
+            .form(params)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map(|_| ())
+    }
+    /// Fetches the account, which verifies the credentials.
+    pub async fn fetch_account(&self) -> std::result::Result<(), reqwest::Error> {
+        self.client
+            .get(self.url(None))
+            .basic_auth(&self.account.account_sid, Some(&self.account.auth_token))
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map(|_| ())
+    }
+}
+
+pub struct TwilioSms {
+    config: TwilioSmsConfig,
+    client: TwilioClient,
+}
+
+impl TwilioSms {
+    pub fn new(config: TwilioSmsConfig, http: &HttpConfig) -> Result<Self> {
+        Ok(TwilioSms {
+            client: TwilioClient::new(config.account.clone(), http, TWILIO_SMS_ADAPTER)?,
+            config,
+        })
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.config.token.as_deref()
+    }
+    pub fn public_url(&self) -> Option<&str> {
+        self.config.public_url.as_deref()
+    }
+    pub fn verify(&self, url: &str, body: &[u8], signature: &str) -> Result<()> {
+        self.config.account.verify(url, body, signature)
+    }
+    /// Runs the command of a reply, returns the answer to it. Messages of
+    /// unknown numbers are ignored.
+    pub async fn handle_message(&self, message: TwilioMessage) -> Result<Option<String>> {
+        let found = find_number(&self.config.routes, &message.from);
+        let user = format!("sms:{}", message.from);
+
+        // Acks are run on the route of the alert, if the number is part of it.
+        let mut target = found.first().copied();
+        if let Some(Ok(Command::Ack(AckTarget::Id(id), _))) = parse_command(&message.body, &user) {
+            if let Some(alert) = Processor::from_registry().send(GetAlert(id)).await?? {
+                if let Some(found) = found.iter().find(|(route, _)| *route == alert.route) {
+                    target = Some(*found);
+                }
+            }
+        }
+
+        let (route, level) = match target {
+            Some(target) => target,
+            None => {
+                warn!("Ignoring SMS of unknown number {}", message.from);
+                return Ok(None);
+            }
+        };
+
+        debug!("Received SMS command from {}: {}", user, message.body);
+
+        adapter::run_command(TWILIO_SMS_ADAPTER, route, level, &user, &message.body).await
+    }
+    async fn send_message(&self, to: &str, body: &str) -> Result<()> {
+        self.client
+            .create(
+                "Messages",
+                &[
+                    ("To", to),
+                    ("From", &self.config.from),
+                    ("Body", &truncate::message_within(body, MAX_MESSAGE)),
+                ],
+            )
+            .await
+            .map_err(|err| Error::adapter(TWILIO_SMS_ADAPTER, err))
+    }
+}
+
+#[async_trait]
+impl Adapter for TwilioSms {
+    fn name(&self) -> &'static str {
+        TWILIO_SMS_ADAPTER
+    }
+    fn covers(&self, route: &str, level: usize) -> bool {
+        !numbers(&self.config.routes, route, level).is_empty()
+    }
+    fn renderer(&self) -> &dyn NotificationRenderer {
+        &SmsRenderer
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(TWILIO_SMS_ADAPTER).await?;
+
+        let body = notification.render(self.renderer()).body;
+        for number in numbers(&self.config.routes, route, level) {
+            self.send_message(number, &body).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The phone numbers of the level of the route, those of the last level
+/// beyond it.
+fn numbers<'a>(
+    routes: &'a HashMap<String, Vec<Vec<String>>>,
+    route: &str,
+    level: usize,
+) -> &'a [String] {
+    let levels = routes.get(route).map(Vec::as_slice).unwrap_or_default();
+
+    levels
+        .get(level)
+        .or_else(|| levels.last())
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+/// The routes and escalation levels of a number.
+fn find_number<'a>(
+    routes: &'a HashMap<String, Vec<Vec<String>>>,
+    number: &str,
+) -> Vec<(&'a str, usize)> {
+    let mut found: Vec<(&str, usize)> = routes
+        .iter()
+        .filter_map(|(route, levels)| {
+            levels
+                .iter()
+                .position(|numbers| numbers.iter().any(|other| other == number))
+                .map(|level| (route.as_str(), level))
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+/// A short summary of the notification, the full alerts rarely fit into an
+/// SMS.
+pub struct SmsRenderer;
+
+impl NotificationRenderer for SmsRenderer {
+    fn render(&self, header: &str, sections: &[Section]) -> Message {
+        let mut body = header.to_string();
+        for alert in sections.iter().map(|section| &section.alert) {
+            body.push_str(&format!(
+                "\n{}: {} ({}, route {})",
+                alert.id, alert.alert.labels.alert_name, alert.alert.labels.severity, alert.route
+            ));
+        }
+        if let Some(section) = sections.first() {
+            body.push_str(&format!(
+                "\nReply \"ack {}\" to acknowledge.",
+                section.alert.id
+            ));
+        }
+
+        Message { body, html: None }
+    }
+}
+
+/// Answers an inbound message with a reply (TwiML).
+pub fn reply(text: &str) -> String {
+    twiml(&format!("<Message>{}</Message>", escape(text)))
+}
+
+fn twiml(verbs: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response>{}</Response>",
+        verbs
+    )
+}
+
+/// Escapes text and attribute values of TwiML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn sms(api_url: String) -> TwilioSms {
+        let config: TwilioSmsConfig = serde_yaml::from_str(&format!(
+            r#"
+            account_sid: AC123
+            auth_token: secret
+            api_url: {}
+            from: "+15005550006"
+            routes:
+              team-a:
+                - []
+                - ["+15550001", "+15550002"]
+            "#,
+            api_url
+        ))
+        .unwrap();
+
+        TwilioSms::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        // The example of the Twilio documentation.
+        let account = TwilioAccount {
+            account_sid: String::from("AC123"),
+            auth_token: String::from("12345"),
+            api_url: None,
+        };
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        let body = b"To=%2B18005551212&CallSid=CA1234567890ABCDE&Digits=1234\
+                     &From=%2B12349013030&Caller=%2B12349013030";
+
+        account
+            .verify(url, body, "0/KCTR6DLpKmkAf8muzZqo1nDgQ=")
+            .unwrap();
+        assert!(matches!(
+            account.verify(url, body, "RSOYDt4T1cUTdK1PDd93/VVr8B8="),
+            Err(Error::Permission(_))
+        ));
+        assert!(account.verify(url, body, "").is_err());
+        assert!(account
+            .verify(
+                "https://mycompany.com/other.php",
+                body,
+                "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn pages_only_levels_with_numbers() {
+        let sms = sms(String::new());
+        assert!(!sms.covers("team-a", 0));
+        assert!(sms.covers("team-a", 1));
+        assert!(sms.covers("team-a", 3));
+        assert!(!sms.covers("default", 1));
+
+        assert_eq!(
+            find_number(&sms.config.routes, "+15550002"),
+            vec![("team-a", 1)]
+        );
+        assert!(find_number(&sms.config.routes, "+15559999").is_empty());
+
+        let alert = alert_context(7, "team-a");
+        assert_eq!(
+            Notification::Escalation(vec![alert])
+                .render(&SmsRenderer)
+                .body,
+            "🚨 ESCALATION OCCURRED!\n7: Alert7 (critical, route team-a)\n\
+             Reply \"ack 7\" to acknowledge."
+        );
+        assert_eq!(
+            reply("Alert <7> & more"),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response><Message>\
+             Alert &lt;7&gt; &amp; more</Message></Response>"
+        );
+    }
+
+    #[actix_web::test]
+    async fn sends_a_message_to_each_number() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/2010-04-01/Accounts/AC123/Messages.json"))
+            .and(header("authorization", "Basic QUMxMjM6c2VjcmV0"))
+            .and(body_string_contains("From=%2B15005550006"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        sms(server.uri())
+            .notify(
+                "team-a",
+                2,
+                &Notification::Escalation(vec![alert_context(1, "team-a")]),
+            )
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Twilio Voice, calls phone numbers and reads the alert via text-to-speech,
+//! e.g. to wake up on-call on the final escalation level. Pressing 1 during
+//! the call acknowledges the alert, the keypress is received on
+//! `/webhook-twilio-voice`.
+use super::{escape, find_number, numbers, twiml, TwilioAccount, TwilioClient};
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::TWILIO_VOICE_ADAPTER;
+use crate::http::HttpConfig;
+use crate::processor::{AlertContext, GetAlert, Processor};
+use crate::{AlertId, Error, Result};
+use actix::SystemService;
+use std::collections::HashMap;
+
+const WEBHOOK_PATH: &str = "/webhook-twilio-voice";
+const ACK_DIGIT: &str = "1";
+// Seconds to wait for the keypress after the alert has been read.
+const GATHER_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwilioVoiceConfig {
+    #[serde(flatten)]
+    account: TwilioAccount,
+    // The Twilio number calls are placed from.
+    from: String,
+    // Phone numbers (E.164) of each route, in the order of escalation levels.
+    // Levels without numbers are not called, alerts beyond the last level
+    // call the numbers of the last one.
+    routes: HashMap<String, Vec<Vec<String>>>,
+    // The URL Twilio reaches the listener on, e.g. `https://bot.example.com`.
+    // Keypresses are sent to `/webhook-twilio-voice` there.
+    public_url: String,
+    // Required as `?token=...` by `/webhook-twilio-voice`, if set.
+    token: Option<String>,
+}
+
+impl TwilioVoiceConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+}
+
+/// The alert a call is about, passed back by the keypress.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceQuery {
+    alert: u64,
+    pub token: Option<String>,
+}
+
+/// The result of the keypress of a call, as posted by Twilio.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct TwilioGather {
+    // The number which was called.
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Digits", default)]
+    digits: Option<String>,
+}
+
+pub struct TwilioVoice {
+    config: TwilioVoiceConfig,
+    client: TwilioClient,
+}
+
+impl TwilioVoice {
+    pub fn new(config: TwilioVoiceConfig, http: &HttpConfig) -> Result<Self> {
+        Ok(TwilioVoice {
+            client: TwilioClient::new(config.account.clone(), http, TWILIO_VOICE_ADAPTER)?,
+            config,
+        })
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.config.token.as_deref()
+    }
+    pub fn public_url(&self) -> &str {
+        &self.config.public_url
+    }
+    pub fn verify(&self, url: &str, body: &[u8], signature: &str) -> Result<()> {
+        self.config.account.verify(url, body, signature)
+    }
+    /// Instructions of a call about the alert: reads it, then waits for the
+    /// keypress.
+    fn call_twiml(&self, alert: &AlertContext) -> String {
+        let mut action = format!(
+            "{}{}?alert={}",
+            self.config.public_url.trim_end_matches('/'),
+            WEBHOOK_PATH,
+            alert.id
+        );
+        if let Some(token) = &self.config.token {
+            action.push_str(&format!("&token={}", token));
+        }
+
+        twiml(&format!(
+            "<Gather numDigits=\"1\" timeout=\"{}\" action=\"{}\" method=\"POST\">\
+             <Say>{}</Say></Gather><Say>No key was pressed. Goodbye.</Say>",
+            GATHER_TIMEOUT,
+            escape(&action),
+            escape(&speech(alert))
+        ))
+    }
+    async fn call(&self, to: &str, alert: &AlertContext) -> Result<()> {
+        self.client
+            .create(
+                "Calls",
+                &[
+                    ("To", to),
+                    ("From", &self.config.from),
+                    ("Twiml", &self.call_twiml(alert)),
+                ],
+            )
+            .await
+            .map_err(|err| Error::adapter(TWILIO_VOICE_ADAPTER, err))
+    }
+    /// Acknowledges the alert of the call if 1 was pressed, returns the
+    /// instructions for the rest of the call.
+    pub async fn handle_gather(&self, query: VoiceQuery, gather: TwilioGather) -> Result<String> {
+        let id = AlertId::from(query.alert);
+        if gather.digits.as_deref() != Some(ACK_DIGIT) {
+            return Ok(twiml("<Say>The alert was not acknowledged. Goodbye.</Say>"));
+        }
+
+        let alert = match Processor::from_registry().send(GetAlert(id)).await?? {
+            Some(alert) => alert,
+            None => return Ok(twiml("<Say>The alert no longer exists. Goodbye.</Say>")),
+        };
+
+        // Only numbers of the route of the alert may acknowledge it.
+        let level = match find_number(&self.config.routes, &gather.to)
+            .into_iter()
+            .find(|(route, _)| *route == alert.route)
+        {
+            Some((_, level)) => level,
+            None => {
+                warn!("Ignoring keypress of unknown number {}", gather.to);
+                return Ok(twiml("<Hangup/>"));
+            }
+        };
+
+        let user = format!("voice:{}", gather.to);
+        let reply = adapter::run_command(
+            TWILIO_VOICE_ADAPTER,
+            &alert.route,
+            level,
+            &user,
+            &format!("ack {}", id),
+        )
+        .await?
+        .unwrap_or_default();
+
+        Ok(twiml(&format!("<Say>{}</Say>", escape(&reply))))
+    }
+}
+
+#[async_trait]
+impl Adapter for TwilioVoice {
+    fn name(&self) -> &'static str {
+        TWILIO_VOICE_ADAPTER
+    }
+    fn covers(&self, route: &str, level: usize) -> bool {
+        !numbers(&self.config.routes, route, level).is_empty()
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(TWILIO_VOICE_ADAPTER).await?;
+
+        // Nobody should be woken up by a self-test.
+        if let Notification::SelfTest { .. } = notification {
+            return self
+                .client
+                .fetch_account()
+                .await
+                .map_err(|err| Error::adapter(TWILIO_VOICE_ADAPTER, err));
+        }
+
+        // Acks elsewhere are not worth a call, one call per alert otherwise.
+        for alert in notification.alerts() {
+            for number in numbers(&self.config.routes, route, level) {
+                self.call(number, alert).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What is read to the callee.
+fn speech(alert: &AlertContext) -> String {
+    let labels = &alert.alert.labels;
+    let mut text = format!(
+        "Alert {} on route {}: {}, severity {}.",
+        alert.id, alert.route, labels.alert_name, labels.severity
+    );
+
+    if let Some(summary) = alert
+        .slo
+        .as_ref()
+        .map(|slo| slo.summary.as_str())
+        .or(alert.alert.annotations.summary.as_deref())
+        .or(alert.alert.annotations.message.as_deref())
+    {
+        text.push(' ');
+        text.push_str(summary.trim_end_matches('.'));
+        text.push('.');
+    }
+
+    text.push_str(" Press 1 to acknowledge.");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn voice(api_url: String) -> TwilioVoice {
+        let config: TwilioVoiceConfig = serde_yaml::from_str(&format!(
+            r#"
+            account_sid: AC123
+            auth_token: secret
+            api_url: {}
+            from: "+15005550006"
+            public_url: https://bot.example.com/
+            token: s3cret
+            routes:
+              team-a:
+                - []
+                - ["+15550001"]
+            "#,
+            api_url
+        ))
+        .unwrap();
+
+        TwilioVoice::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn reads_the_alert_and_gathers_the_keypress() {
+        let voice = voice(String::new());
+        let alert = alert_context(7, "team-a");
+
+        assert_eq!(
+            speech(&alert),
+            "Alert 7 on route team-a: Alert7, severity critical. Message of alert 7. \
+             Press 1 to acknowledge."
+        );
+        assert_eq!(
+            voice.call_twiml(&alert),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response>\
+             <Gather numDigits=\"1\" timeout=\"10\" \
+             action=\"https://bot.example.com/webhook-twilio-voice?alert=7&amp;token=s3cret\" \
+             method=\"POST\"><Say>Alert 7 on route team-a: Alert7, severity critical. \
+             Message of alert 7. Press 1 to acknowledge.</Say></Gather>\
+             <Say>No key was pressed. Goodbye.</Say></Response>"
+        );
+        assert!(!voice.covers("team-a", 0));
+        assert!(voice.covers("team-a", 1));
+    }
+
+    #[actix_web::test]
+    async fn calls_only_about_alerts() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/2010-04-01/Accounts/AC123/Calls.json"))
+            .and(body_string_contains("To=%2B15550001"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let voice = voice(server.uri());
+        voice
+            .notify(
+                "team-a",
+                1,
+                &Notification::Escalation(vec![alert_context(1, "team-a")]),
+            )
+            .await
+            .unwrap();
+
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Matrix"),
+        };
+        voice.notify("team-a", 1, &ack).await.unwrap();
+
+        // A self-test verifies the credentials instead.
+        Mock::given(method("GET"))
+            .and(path("/2010-04-01/Accounts/AC123.json"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let test = Notification::SelfTest {
+            user: String::from("@admin:matrix.org"),
+        };
+        voice.notify("team-a", 1, &test).await.unwrap();
+    }
+}
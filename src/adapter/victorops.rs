@@ -0,0 +1,500 @@
+//! Splunk On-Call (VictorOps), creates an incident per alert and escalation
+//! level via the REST integration, routed by the routing key of the level.
+//! Acks in Splunk On-Call are received by polling the incidents of its public
+//! API, if credentials are set.
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::VICTOROPS_ADAPTER;
+use crate::http::HttpConfig;
+use crate::processor::{AlertContext, GetAlert, IsStandby, Processor};
+use crate::truncate;
+use crate::{AlertId, Error, Result};
+use actix::SystemService;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const DEFAULT_REST_URL: &str = "https://alert.victorops.com/integrations/generic/20131114/alert";
+const DEFAULT_API_URL: &str = "https://api.victorops.com";
+const DEFAULT_POLL_INTERVAL: u64 = 60;
+// Entity IDs of the incidents, followed by the alert ID and the level.
+const ENTITY_PREFIX: &str = "matrixbot-";
+// Of the self-test, which is informational and pages nobody.
+const SELFTEST_ENTITY: &str = "matrixbot-selftest";
+const MONITORING_TOOL: &str = "matrixbot";
+// Splunk On-Call truncates longer values.
+const MAX_DISPLAY_NAME: usize = 255;
+const MAX_STATE_MESSAGE: usize = 15000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VictorOpsConfig {
+    // Key of the REST integration, part of its URL.
+    rest_key: String,
+    // Defaults to the REST endpoint of Splunk On-Call.
+    rest_url: Option<String>,
+    // ID and key of the public API, acks are only polled if both are set.
+    api_id: Option<String>,
+    api_key: Option<String>,
+    // Defaults to `https://api.victorops.com`.
+    api_url: Option<String>,
+    // Seconds between polls of the incidents, defaults to one minute.
+    poll_interval: Option<u64>,
+    // Routing keys of each route, in the order of escalation levels. Alerts
+    // beyond the last level stay on the last one.
+    routes: HashMap<String, Vec<String>>,
+}
+
+impl VictorOpsConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Incidents {
+    incidents: Vec<Incident>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Incident {
+    entity_id: Option<String>,
+    // `UNACKED`, `ACKED` or `RESOLVED`.
+    current_phase: String,
+    #[serde(default)]
+    transitions: Vec<Transition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Transition {
+    name: String,
+    by: Option<String>,
+}
+
+impl Incident {
+    /// The ID of the alert, if the incident was created by the bot.
+    fn alert_id(&self) -> Option<AlertId> {
+        let (id, _level) = self
+            .entity_id
+            .as_deref()?
+            .strip_prefix(ENTITY_PREFIX)?
+            .split_once('-')?;
+        id.parse::<u64>().ok().map(AlertId::from)
+    }
+    fn acked_by(&self) -> Option<&str> {
+        if self.current_phase != "ACKED" {
+            return None;
+        }
+
+        Some(
+            self.transitions
+                .iter()
+                .rev()
+                .find(|transition| transition.name == "ACKED")
+                .and_then(|transition| transition.by.as_deref())
+                .unwrap_or("unknown"),
+        )
+    }
+}
+
+pub struct VictorOps {
+    config: VictorOpsConfig,
+    client: reqwest::Client,
+    // Alerts whose incidents were acknowledged, by the bot or in Splunk
+    // On-Call, as long as they are open.
+    acked: Mutex<HashSet<AlertId>>,
+}
+
+impl VictorOps {
+    pub fn new(config: VictorOpsConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(VictorOps {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(VICTOROPS_ADAPTER, err))?,
+            acked: Default::default(),
+        })
+    }
+    /// Whether acks are polled, i.e. the credentials of the public API are
+    /// set.
+    pub fn polls_acks(&self) -> bool {
+        self.config.api_id.is_some() && self.config.api_key.is_some()
+    }
+    fn routing_key(&self, route: &str, level: usize) -> Option<&str> {
+        let keys = self.config.routes.get(route)?;
+        keys.get(level).or_else(|| keys.last()).map(String::as_str)
+    }
+    /// Sends an event to the REST integration.
+    async fn send(&self, routing_key: &str, body: serde_json::Value) -> Result<()> {
+        let url = format!(
+            "{}/{}/{}",
+            self.config
+                .rest_url
+                .as_deref()
+                .unwrap_or(DEFAULT_REST_URL)
+                .trim_end_matches('/'),
+            self.config.rest_key,
+            routing_key
+        );
+
+        self.client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(VICTOROPS_ADAPTER, err))?;
+
+        Ok(())
+    }
+    async fn create(&self, alert: &AlertContext, level: usize, routing_key: &str) -> Result<()> {
+        let labels = &alert.alert.labels;
+        let description = alert.to_string();
+
+        self.send(
+            routing_key,
+            serde_json::json!({
+                "message_type": "CRITICAL",
+                "entity_id": entity_id(alert.id, level),
+                "entity_display_name": format!("{}: {}", alert.id, labels.alert_name)
+                    .chars()
+                    .take(MAX_DISPLAY_NAME)
+                    .collect::<String>(),
+                "state_message": truncate::message_within(description.trim(), MAX_STATE_MESSAGE),
+                "monitoring_tool": MONITORING_TOOL,
+                "matrixbot_id": alert.id.to_string(),
+                "matrixbot_route": alert.route,
+                "matrixbot_severity": labels.severity,
+            }),
+        )
+        .await
+    }
+    /// Acknowledges the incidents of all levels up to the given one, those
+    /// of earlier levels are still open.
+    async fn acknowledge(
+        &self,
+        route: &str,
+        level: usize,
+        id: AlertId,
+        user: &str,
+        via: &str,
+    ) -> Result<()> {
+        self.acked.lock().unwrap().insert(id);
+
+        for level in 0..=level {
+            let routing_key = match self.routing_key(route, level) {
+                Some(routing_key) => routing_key,
+                None => continue,
+            };
+
+            self.send(
+                routing_key,
+                serde_json::json!({
+                    "message_type": "ACKNOWLEDGEMENT",
+                    "entity_id": entity_id(id, level),
+                    "state_message": format!("Acknowledged by {} via {}", user, via),
+                    "monitoring_tool": MONITORING_TOOL,
+                }),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+    /// The open incidents.
+    async fn incidents(&self) -> Result<Vec<Incident>> {
+        let url = format!(
+            "{}/api-public/v1/incidents",
+            self.config
+                .api_url
+                .as_deref()
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/')
+        );
+
+        let body = self
+            .client
+            .get(url)
+            .header(
+                "X-VO-Api-Id",
+                self.config.api_id.as_deref().unwrap_or_default(),
+            )
+            .header(
+                "X-VO-Api-Key",
+                self.config.api_key.as_deref().unwrap_or_default(),
+            )
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(VICTOROPS_ADAPTER, err))?
+            .bytes()
+            .await
+            .map_err(|err| Error::adapter(VICTOROPS_ADAPTER, err))?;
+
+        serde_json::from_slice::<Incidents>(&body)
+            .map(|incidents| incidents.incidents)
+            .map_err(|err| Error::adapter(VICTOROPS_ADAPTER, err))
+    }
+    /// Returns the alerts acknowledged in Splunk On-Call since the last poll,
+    /// with the user. Alerts whose incidents are all resolved are forgotten.
+    fn new_acks(&self, incidents: &[Incident]) -> Vec<(AlertId, String)> {
+        let mut acked = self.acked.lock().unwrap();
+
+        let open: HashSet<AlertId> = incidents.iter().filter_map(Incident::alert_id).collect();
+        acked.retain(|id| open.contains(id));
+
+        let mut new = vec![];
+        for incident in incidents {
+            if let (Some(id), Some(user)) = (incident.alert_id(), incident.acked_by()) {
+                if acked.insert(id) {
+                    new.push((id, user.to_string()));
+                }
+            }
+        }
+
+        new
+    }
+    /// Acknowledges the alert in the bot, from the alert's own level like
+    /// acks via the API.
+    async fn handle_ack(&self, id: AlertId, user: &str) -> Result<()> {
+        let alert = match Processor::from_registry().send(GetAlert(id)).await?? {
+            Some(alert) => alert,
+            None => return Ok(()),
+        };
+
+        let user = format!("victorops:{}", user);
+        if let Some(reply) = adapter::run_command(
+            VICTOROPS_ADAPTER,
+            &alert.route,
+            alert.escalation_idx,
+            &user,
+            &format!("ack {}", id),
+        )
+        .await?
+        {
+            debug!("Splunk On-Call ack of alert {}: {}", id, reply);
+        }
+
+        Ok(())
+    }
+    /// Polls the incidents for acks, until the service stops. Standby
+    /// instances leave them to the active instance.
+    pub async fn poll_acks(self: Arc<Self>) {
+        let interval = self.config.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL);
+
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+
+            if Processor::from_registry()
+                .send(IsStandby)
+                .await
+                .unwrap_or(true)
+            {
+                continue;
+            }
+
+            let incidents = match self.incidents().await {
+                Ok(incidents) => incidents,
+                Err(err) => {
+                    warn!("Failed to poll Splunk On-Call incidents: {:?}", err);
+                    continue;
+                }
+            };
+
+            for (id, user) in self.new_acks(&incidents) {
+                if let Err(err) = self.handle_ack(id, &user).await {
+                    error!(
+                        "Error when trying to process Splunk On-Call ack of alert {}: {:?}",
+                        id, err
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Adapter for VictorOps {
+    fn name(&self) -> &'static str {
+        VICTOROPS_ADAPTER
+    }
+    fn covers(&self, route: &str, _level: usize) -> bool {
+        self.config
+            .routes
+            .get(route)
+            .map(|keys| !keys.is_empty())
+            .unwrap_or(false)
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(VICTOROPS_ADAPTER).await?;
+
+        let routing_key = match self.routing_key(route, level) {
+            Some(routing_key) => routing_key,
+            None => return Ok(()),
+        };
+
+        match notification {
+            // Each level has an incident of its own, routed to its team.
+            Notification::Alert(alerts) | Notification::Escalation(alerts) => {
+                for alert in alerts {
+                    self.create(alert, level, routing_key).await?;
+                }
+            }
+            Notification::Acknowledged { id, user, via } => {
+                self.acknowledge(route, level, *id, user, via).await?;
+            }
+            Notification::SelfTest { user } => {
+                self.send(
+                    routing_key,
+                    serde_json::json!({
+                        "message_type": "INFO",
+                        "entity_id": SELFTEST_ENTITY,
+                        "state_message": format!("Self-test by {}", user),
+                        "monitoring_tool": MONITORING_TOOL,
+                    }),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn entity_id(id: AlertId, level: usize) -> String {
+    format!("{}{}-{}", ENTITY_PREFIX, id, level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn victorops(url: &str) -> VictorOps {
+        let config: VictorOpsConfig = serde_json::from_value(serde_json::json!({
+            "rest_key": "secret",
+            "rest_url": format!("{}/alert", url),
+            "api_id": "id",
+            "api_key": "key",
+            "api_url": url,
+            "routes": {"team-a": ["ops", "leads"]},
+        }))
+        .unwrap();
+
+        VictorOps::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn creates_and_acknowledges_incidents() {
+        let server = MockServer::start().await;
+        let alert = alert_context(1, "team-a");
+
+        // Levels beyond the last one use its routing key.
+        Mock::given(method("POST"))
+            .and(path("/alert/secret/leads"))
+            .and(body_partial_json(serde_json::json!({
+                "message_type": "CRITICAL",
+                "entity_id": "matrixbot-1-2",
+                "monitoring_tool": MONITORING_TOOL,
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Acks cover the incidents of all levels so far.
+        for (routing_key, entity_id) in [
+            ("ops", "matrixbot-1-0"),
+            ("leads", "matrixbot-1-1"),
+            ("leads", "matrixbot-1-2"),
+        ] {
+            Mock::given(method("POST"))
+                .and(path(format!("/alert/secret/{}", routing_key)))
+                .and(body_partial_json(serde_json::json!({
+                    "message_type": "ACKNOWLEDGEMENT",
+                    "entity_id": entity_id,
+                })))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let victorops = victorops(&server.uri());
+        victorops
+            .notify("team-a", 2, &Notification::Escalation(vec![alert]))
+            .await
+            .unwrap();
+
+        let ack = Notification::Acknowledged {
+            id: AlertId::from(1),
+            user: String::from("@ops:matrix.org"),
+            via: String::from("Matrix"),
+        };
+        victorops.notify("team-a", 2, &ack).await.unwrap();
+        assert!(victorops.acked.lock().unwrap().contains(&AlertId::from(1)));
+    }
+
+    #[actix_web::test]
+    async fn polls_acks_of_others() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/api-public/v1/incidents"))
+            .and(header("X-VO-Api-Id", "id"))
+            .and(header("X-VO-Api-Key", "key"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "incidents": [
+                    {
+                        "incidentNumber": "10",
+                        "entityId": "matrixbot-1-0",
+                        "currentPhase": "ACKED",
+                        "transitions": [
+                            {"name": "ACKED", "at": "2024-01-01T00:00:00Z", "by": "alice"},
+                        ],
+                    },
+                    {
+                        "incidentNumber": "11",
+                        "entityId": "matrixbot-2-0",
+                        "currentPhase": "ACKED",
+                        "transitions": [],
+                    },
+                    {
+                        "incidentNumber": "12",
+                        "entityId": "matrixbot-3-0",
+                        "currentPhase": "UNACKED",
+                    },
+                    {
+                        "incidentNumber": "13",
+                        "entityId": "other-4-0",
+                        "currentPhase": "ACKED",
+                    },
+                ],
+            })))
+            .mount(&server)
+            .await;
+
+        let victorops = victorops(&server.uri());
+        assert!(victorops.polls_acks());
+
+        // Acked by the bot itself, and no longer open.
+        victorops.acked.lock().unwrap().insert(AlertId::from(2));
+        victorops.acked.lock().unwrap().insert(AlertId::from(5));
+
+        let incidents = victorops.incidents().await.unwrap();
+        assert_eq!(
+            victorops.new_acks(&incidents),
+            vec![(AlertId::from(1), String::from("alice"))]
+        );
+        assert!(victorops.new_acks(&incidents).is_empty());
+        assert!(!victorops.acked.lock().unwrap().contains(&AlertId::from(5)));
+    }
+}
@@ -0,0 +1,490 @@
+//! Zulip, posts to a stream topic per escalation level and accepts commands
+//! such as `ack 1` sent to those topics. Topics of a route correspond to
+//! escalation levels, like rooms.
+use crate::adapter::{self, Adapter, Notification};
+use crate::chaos;
+use crate::error::ZULIP_ADAPTER;
+use crate::http::HttpConfig;
+use crate::processor::{IsStandby, Processor};
+use crate::render::{Format, NotificationRenderer};
+use crate::truncate;
+use crate::{Error, Result};
+use actix::SystemService;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: u64 = 10;
+// Zulip answers event requests within a minute, with a heartbeat if nothing
+// happened.
+const POLL_TIMEOUT: u64 = 120;
+// Zulip rejects longer messages.
+const MAX_MESSAGE: usize = 10000;
+// Delay before polling again after a failure or while in standby.
+const RETRY_DELAY: u64 = 10;
+// The event queue was garbage collected, e.g. after a long standby.
+const BAD_EVENT_QUEUE_ID: &str = "BAD_EVENT_QUEUE_ID";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipConfig {
+    // E.g. `https://example.zulipchat.com`.
+    site: String,
+    // Email address and API key of the bot.
+    email: String,
+    api_key: String,
+    // Stream and topic of each route, in the order of escalation levels.
+    // Alerts beyond the last topic stay in the last one.
+    topics: HashMap<String, Vec<ZulipTopic>>,
+}
+
+impl ZulipConfig {
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.topics.keys().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ZulipTopic {
+    stream: String,
+    topic: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Queue {
+    queue_id: String,
+    last_event_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Events {
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    id: i64,
+    // Messages and heartbeats.
+    message: Option<ZulipMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ZulipMessage {
+    // The stream of stream messages, the recipients of direct messages.
+    display_recipient: serde_json::Value,
+    subject: String,
+    content: String,
+    sender_email: String,
+}
+
+pub struct Zulip {
+    config: ZulipConfig,
+    client: reqwest::Client,
+}
+
+impl Zulip {
+    pub fn new(config: ZulipConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http.client_builder()?;
+
+        Ok(Zulip {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(ZULIP_ADAPTER, err))?,
+        })
+    }
+    /// Calls an endpoint of the REST API, e.g. `messages`. Returns the
+    /// response, failed or not, since some failures are expected.
+    async fn call(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        params: &[(&str, String)],
+        timeout: u64,
+    ) -> Result<serde_json::Value> {
+        let url = format!("{}/api/v1/{}", self.config.site.trim_end_matches('/'), path);
+
+        let mut request = self
+            .client
+            .request(method.clone(), url)
+            .timeout(Duration::from_secs(timeout))
+            .basic_auth(&self.config.email, Some(&self.config.api_key));
+        request = match method {
+            reqwest::Method::GET => request.query(params),
+            _ => request.form(params),
+        };
+
+        request
+            .send()
+            .await
+            .map_err(|err| Error::adapter(ZULIP_ADAPTER, err))?
+            .bytes()
+            .await
+            .map_err(|err| Error::adapter(ZULIP_ADAPTER, err))
+            .and_then(|body| {
+                serde_json::from_slice(&body).map_err(|err| Error::adapter(ZULIP_ADAPTER, err))
+            })
+    }
+    async fn send_message(&self, topic: &ZulipTopic, content: &str) -> Result<()> {
+        let resp = self
+            .call(
+                reqwest::Method::POST,
+                "messages",
+                &[
+                    ("type", String::from("stream")),
+                    ("to", topic.stream.clone()),
+                    ("topic", topic.topic.clone()),
+                    (
+                        "content",
+                        truncate::message_within(content, MAX_MESSAGE).into_owned(),
+                    ),
+                ],
+                REQUEST_TIMEOUT,
+            )
+            .await?;
+
+        parse::<serde_json::Value>("messages", resp).map(|_| ())
+    }
+    /// Registers a queue for new messages.
+    async fn register(&self) -> Result<Queue> {
+        let resp = self
+            .call(
+                reqwest::Method::POST,
+                "register",
+                &[
+                    ("event_types", String::from(r#"["message"]"#)),
+                    // Commands are read as sent, not rendered as HTML.
+                    ("apply_markdown", String::from("false")),
+                ],
+                REQUEST_TIMEOUT,
+            )
+            .await?;
+
+        parse("register", resp)
+    }
+    /// Waits for the events of the queue after the given one. Returns `None`
+    /// if the queue expired.
+    async fn events(&self, queue: &Queue) -> Result<Option<Vec<Event>>> {
+        let resp = self
+            .call(
+                reqwest::Method::GET,
+                "events",
+                &[
+                    ("queue_id", queue.queue_id.clone()),
+                    ("last_event_id", queue.last_event_id.to_string()),
+                ],
+                POLL_TIMEOUT,
+            )
+            .await?;
+
+        if resp["code"] == BAD_EVENT_QUEUE_ID {
+            return Ok(None);
+        }
+
+        parse::<Events>("events", resp).map(|events| Some(events.events))
+    }
+    /// The route and escalation level of a topic.
+    fn find_topic(&self, stream: &str, topic: &str) -> Option<(&str, usize)> {
+        self.config.topics.iter().find_map(|(route, topics)| {
+            topics
+                .iter()
+                .position(|t| t.stream == stream && t.topic.eq_ignore_ascii_case(topic))
+                .map(|level| (route.as_str(), level))
+        })
+    }
+    async fn handle_message(&self, message: ZulipMessage) -> Result<()> {
+        // Ignore own messages.
+        if message.sender_email == self.config.email {
+            return Ok(());
+        }
+
+        // Only process configured topics, direct messages have no stream.
+        let stream = match message.display_recipient.as_str() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let (route, level) = match self.find_topic(stream, &message.subject) {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        let user = format!("zulip:{}", message.sender_email);
+        let text = command(&message.content);
+
+        debug!("Received Zulip message from {}: {}", user, text);
+
+        if let Some(reply) = adapter::run_command(ZULIP_ADAPTER, route, level, &user, text).await? {
+            let topic = ZulipTopic {
+                stream: stream.to_string(),
+                topic: message.subject.clone(),
+            };
+            self.send_message(&topic, &reply).await?;
+        }
+
+        Ok(())
+    }
+    /// Polls the event queue for commands, until the service stops. Standby
+    /// instances leave them to the active instance.
+    pub async fn serve_commands(self: Arc<Self>) {
+        let mut queue = None;
+
+        loop {
+            if Processor::from_registry()
+                .send(IsStandby)
+                .await
+                .unwrap_or(true)
+            {
+                tokio::time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+                continue;
+            }
+
+            let current = match queue.take() {
+                Some(current) => current,
+                None => match self.register().await {
+                    Ok(registered) => registered,
+                    Err(err) => {
+                        warn!("Failed to register Zulip event queue: {:?}", err);
+                        tokio::time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+                        continue;
+                    }
+                },
+            };
+
+            let events = match self.events(&current).await {
+                Ok(Some(events)) => events,
+                Ok(None) => {
+                    warn!("Zulip event queue expired, registering a new one");
+                    continue;
+                }
+                Err(err) => {
+                    warn!("Failed to poll Zulip commands: {:?}", err);
+                    queue = Some(current);
+                    tokio::time::sleep(Duration::from_secs(RETRY_DELAY)).await;
+                    continue;
+                }
+            };
+
+            let mut last_event_id = current.last_event_id;
+            for event in events {
+                last_event_id = last_event_id.max(event.id);
+
+                if let Some(message) = event.message {
+                    if let Err(err) = self.handle_message(message).await {
+                        error!("Error when trying to process Zulip command {:?}", err);
+                    }
+                }
+            }
+
+            queue = Some(Queue {
+                last_event_id,
+                ..current
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Adapter for Zulip {
+    fn name(&self) -> &'static str {
+        ZULIP_ADAPTER
+    }
+    fn covers(&self, route: &str, _level: usize) -> bool {
+        self.config
+            .topics
+            .get(route)
+            .map(|topics| !topics.is_empty())
+            .unwrap_or(false)
+    }
+    fn renderer(&self) -> &dyn NotificationRenderer {
+        Format::Markdown.renderer()
+    }
+    async fn notify(&self, route: &str, level: usize, notification: &Notification) -> Result<()> {
+        chaos::inject(ZULIP_ADAPTER).await?;
+
+        let topics = self
+            .config
+            .topics
+            .get(route)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let topic = match topics.get(level).or_else(|| topics.last()) {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+
+        self.send_message(topic, &notification.render(self.renderer()).body)
+            .await
+    }
+}
+
+/// The result of a successful response.
+fn parse<T: DeserializeOwned>(path: &str, resp: serde_json::Value) -> Result<T> {
+    if resp["result"] != "success" {
+        return Err(Error::adapter(
+            ZULIP_ADAPTER,
+            format!(
+                "{} failed: {}",
+                path,
+                resp["msg"].as_str().unwrap_or_default()
+            ),
+        ));
+    }
+
+    serde_json::from_value(resp).map_err(|err| Error::adapter(ZULIP_ADAPTER, err))
+}
+
+/// Strips a leading mention of the bot, e.g. `@**matrixbot** ack 1`.
+fn command(text: &str) -> &str {
+    let text = text.trim();
+    match text
+        .strip_prefix("@**")
+        .and_then(|rest| rest.split_once("**"))
+    {
+        Some((_, command)) => command.trim(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+    use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn zulip(site: String) -> Zulip {
+        let config: ZulipConfig = serde_yaml::from_str(&format!(
+            r#"
+            site: {}
+            email: matrixbot-bot@example.com
+            api_key: secret
+            topics:
+              team-a:
+                - {{stream: ops, topic: alerts}}
+                - {{stream: leads, topic: escalations}}
+            "#,
+            site
+        ))
+        .unwrap();
+
+        Zulip::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn finds_commands_of_topics() {
+        assert_eq!(command("ack 12"), "ack 12");
+        assert_eq!(command(" @**matrixbot** ack 12 "), "ack 12");
+        assert_eq!(command("@**matrixbot"), "@**matrixbot");
+
+        let zulip = zulip(String::new());
+        assert_eq!(
+            zulip.find_topic("leads", "Escalations"),
+            Some(("team-a", 1))
+        );
+        assert_eq!(zulip.find_topic("ops", "escalations"), None);
+        assert!(zulip.covers("team-a", 5));
+        assert!(!zulip.covers("default", 0));
+    }
+
+    #[actix_web::test]
+    async fn notifies_the_topic_of_the_level() {
+        let server = MockServer::start().await;
+        let alert = alert_context(1, "team-a");
+
+        // Levels beyond the last topic notify the last one.
+        Mock::given(method("POST"))
+            .and(path("/api/v1/messages"))
+            .and(header(
+                "authorization",
+                "Basic bWF0cml4Ym90LWJvdEBleGFtcGxlLmNvbTpzZWNyZXQ=",
+            ))
+            .and(body_string_contains("to=leads"))
+            .and(body_string_contains("topic=escalations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "result": "success",
+                "msg": "",
+                "id": 42,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path("/api/v1/messages"))
+            .and(body_string_contains("to=ops"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "result": "error",
+                "msg": "Stream 'ops' does not exist",
+                "code": "STREAM_DOES_NOT_EXIST",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let zulip = zulip(server.uri());
+        zulip
+            .notify("team-a", 3, &Notification::Escalation(vec![alert.clone()]))
+            .await
+            .unwrap();
+
+        let err = zulip
+            .notify("team-a", 0, &Notification::Alert(vec![alert]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Stream 'ops' does not exist"));
+    }
+
+    #[actix_web::test]
+    async fn detects_expired_event_queues() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/api/v1/events"))
+            .and(query_param("queue_id", "expired"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "result": "error",
+                "msg": "Bad event queue ID: expired",
+                "code": BAD_EVENT_QUEUE_ID,
+            })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/api/v1/events"))
+            .and(query_param("queue_id", "active"))
+            .and(query_param("last_event_id", "4"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "result": "success",
+                "msg": "",
+                "events": [
+                    {"type": "heartbeat", "id": 5},
+                    {
+                        "type": "message",
+                        "id": 6,
+                        "message": {
+                            "type": "stream",
+                            "display_recipient": "ops",
+                            "subject": "alerts",
+                            "content": "ack 1",
+                            "sender_email": "alice@example.com",
+                        },
+                    },
+                ],
+            })))
+            .mount(&server)
+            .await;
+
+        let zulip = zulip(server.uri());
+        let queue = |queue_id: &str| Queue {
+            queue_id: queue_id.to_string(),
+            last_event_id: 4,
+        };
+
+        assert!(zulip.events(&queue("expired")).await.unwrap().is_none());
+
+        let events = zulip.events(&queue("active")).await.unwrap().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].message.is_none());
+        assert_eq!(events[1].message.as_ref().unwrap().content, "ack 1");
+    }
+}
@@ -0,0 +1,380 @@
+//! Long-term archive of the history in S3-compatible object storage. Alerts
+//! past the retention window are moved out of the database into gzip
+//! compressed JSON lines, one object per day and batch.
+use crate::aws::{canonical_query, hex, uri_encode, SignedRequest};
+use crate::chaos;
+use crate::database::{AlertAcknowledged, Database};
+use crate::error::ARCHIVE_ADAPTER;
+use crate::http::HttpConfig;
+use crate::{unix_time, Error, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use openssl::sha::sha256;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+const REQUEST_TIMEOUT: u64 = 60;
+const DEFAULT_INTERVAL: u64 = 24 * 60 * 60; // one day
+                                            // Alerts removed from the database at once.
+const BATCH_SIZE: i64 = 10_000;
+const OBJECT_SUFFIX: &str = ".jsonl.gz";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    // Base URL of the service, e.g. `https://s3.eu-central-1.amazonaws.com`.
+    // Buckets are addressed path-style.
+    endpoint: String,
+    region: String,
+    bucket: String,
+    // Prepended to all object keys, e.g. `matrixbot/`.
+    #[serde(default)]
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+    // Acknowledged alerts are archived once they were resolved (or
+    // acknowledged, if never resolved) this many seconds ago.
+    retention: u64,
+    // Seconds between archive runs, defaults to one day.
+    interval: Option<u64>,
+}
+
+pub struct Archiver {
+    config: ArchiveConfig,
+    endpoint: Url,
+    client: reqwest::Client,
+}
+
+impl Archiver {
+    pub fn new(config: ArchiveConfig, http: &HttpConfig) -> Result<Self> {
+        let endpoint = Url::parse(&config.endpoint)?;
+        if config.retention == 0 || config.interval == Some(0) {
+            return Err(Error::Config(String::from(
+                "Archive retention and interval must be positive",
+            )));
+        }
+
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(Archiver {
+            config,
+            endpoint,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(ARCHIVE_ADAPTER, err))?,
+        })
+    }
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval.unwrap_or(DEFAULT_INTERVAL))
+    }
+    /// Moves the alerts past the retention window into the archive. They are
+    /// only removed from the database once uploaded. Returns the number of
+    /// archived alerts.
+    pub async fn archive(&self, db: &Database) -> Result<usize> {
+        let cutoff = unix_time().saturating_sub(self.config.retention);
+        let mut archived = 0;
+
+        loop {
+            let batch = db.get_archivable(cutoff, BATCH_SIZE).await?;
+            if batch.is_empty() {
+                break;
+            }
+
+            // Uploading the same batch again, e.g. after a failed removal,
+            // replaces the previous objects.
+            for (day, acked) in by_day(&batch) {
+                self.put(&self.object_key(day, &acked), encode(&acked)?)
+                    .await?;
+            }
+
+            db.remove_history(&batch).await?;
+            archived += batch.len();
+
+            if batch.len() < BATCH_SIZE as usize {
+                break;
+            }
+        }
+
+        Ok(archived)
+    }
+    /// Returns the archived alerts of the given days (UTC, inclusive).
+    pub async fn query(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<AlertAcknowledged>> {
+        let mut archived = vec![];
+        for day in from.iter_days().take_while(|day| *day <= to) {
+            let prefix = format!("{}{}/", self.config.prefix, day);
+            for key in self.list(&prefix).await? {
+                archived.extend(decode(&self.get(&key).await?)?);
+            }
+        }
+
+        Ok(archived)
+    }
+    /// The key of the object of the given day, made of the first and last
+    /// alert ID of the batch.
+    fn object_key(&self, day: NaiveDate, acked: &[&AlertAcknowledged]) -> String {
+        format!(
+            "{}{}/{}-{}{}",
+            self.config.prefix,
+            day,
+            acked[0].alert.id,
+            acked[acked.len() - 1].alert.id,
+            OBJECT_SUFFIX
+        )
+    }
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<()> {
+        self.request(reqwest::Method::PUT, key, &[], body).await?;
+        Ok(())
+    }
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.request(reqwest::Method::GET, key, &[], vec![]).await
+    }
+    /// Lists the keys of all objects with the given prefix.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let key_re = Regex::new("<Key>([^<]*)</Key>").unwrap();
+        let token_re =
+            Regex::new("<NextContinuationToken>([^<]*)</NextContinuationToken>").unwrap();
+
+        let mut keys = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_string()),
+                ("prefix", prefix.to_string()),
+            ];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+
+            let body = self
+                .request(reqwest::Method::GET, "", &query, vec![])
+                .await?;
+            let body = String::from_utf8_lossy(&body);
+
+            keys.extend(
+                key_re
+                    .captures_iter(&body)
+                    .map(|caps| unescape_xml(&caps[1]))
+                    .filter(|key| key.ends_with(OBJECT_SUFFIX)),
+            );
+
+            match token_re.captures(&body) {
+                Some(caps) => token = Some(unescape_xml(&caps[1])),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+    /// Sends a request signed with AWS Signature Version 4.
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        chaos::inject(ARCHIVE_ADAPTER).await?;
+
+        let path = format!(
+            "{}/{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.config.bucket, false),
+            uri_encode(key, true)
+        );
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+
+        let request = SignedRequest {
+            service: "s3",
+            method: method.as_str(),
+            host: &host,
+            path: &path,
+            query,
+            payload_hash: hex(&sha256(&body)),
+            timestamp: unix_time(),
+        };
+
+        let mut url = format!("{}{}", self.endpoint.origin().ascii_serialization(), path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query(query));
+        }
+
+        let response = self
+            .client
+            .request(method.clone(), &url)
+            .header("x-amz-content-sha256", &request.payload_hash)
+            .header("x-amz-date", request.amz_date())
+            .header(
+                reqwest::header::AUTHORIZATION,
+                request.authorization(
+                    &self.config.region,
+                    &self.config.access_key_id,
+                    &self.config.secret_access_key,
+                )?,
+            )
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| Error::adapter(ARCHIVE_ADAPTER, err))?;
+
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|err| Error::adapter(ARCHIVE_ADAPTER, err))?;
+        if !status.is_success() {
+            return Err(Error::adapter(
+                ARCHIVE_ADAPTER,
+                format!(
+                    "{} responded with {}: {}",
+                    url,
+                    status,
+                    String::from_utf8_lossy(&body)
+                ),
+            ));
+        }
+
+        Ok(body.to_vec())
+    }
+}
+
+/// Writes the archived alerts of the given days to a file, as JSON lines.
+pub async fn query(archiver: &Archiver, from: NaiveDate, to: NaiveDate, out: &Path) -> Result<()> {
+    let archived = archiver.query(from, to).await?;
+
+    let mut file = BufWriter::new(File::create(out).map_err(|err| Error::Storage(err.into()))?);
+    for acked in &archived {
+        serde_json::to_writer(&mut file, acked).map_err(|err| Error::Storage(err.into()))?;
+        file.write_all(b"\n")
+            .map_err(|err| Error::Storage(err.into()))?;
+    }
+    file.flush().map_err(|err| Error::Storage(err.into()))?;
+
+    info!(
+        "Exported {} archived alerts of {} to {} to {}",
+        archived.len(),
+        from,
+        to,
+        out.display()
+    );
+
+    Ok(())
+}
+
+/// Restores the archived alerts of the given days into the history. Alerts
+/// which are already part of the history are replaced.
+pub async fn restore(
+    db: &Database,
+    archiver: &Archiver,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<()> {
+    let archived = archiver.query(from, to).await?;
+    let restored = db.restore_archived(&archived).await?;
+
+    info!(
+        "Restored {} archived alerts of {} to {} ({} were not archived anymore)",
+        restored,
+        from,
+        to,
+        archived.len() - restored
+    );
+
+    Ok(())
+}
+
+/// Groups the alerts by the day they were archived for, in the order given.
+fn by_day(batch: &[AlertAcknowledged]) -> BTreeMap<NaiveDate, Vec<&AlertAcknowledged>> {
+    let mut days: BTreeMap<NaiveDate, Vec<&AlertAcknowledged>> = BTreeMap::new();
+    for acked in batch {
+        let timestamp = acked.resolved_timestamp.unwrap_or(acked.acked_timestamp);
+        let day = NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+            .map(|datetime| datetime.date())
+            .unwrap_or_default();
+
+        days.entry(day).or_default().push(acked);
+    }
+
+    days
+}
+
+fn encode(acked: &[&AlertAcknowledged]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    for acked in acked {
+        serde_json::to_writer(&mut encoder, acked).map_err(|err| Error::Storage(err.into()))?;
+        encoder
+            .write_all(b"\n")
+            .map_err(|err| Error::Storage(err.into()))?;
+    }
+
+    encoder.finish().map_err(|err| Error::Storage(err.into()))
+}
+
+fn decode(object: &[u8]) -> Result<Vec<AlertAcknowledged>> {
+    let mut archived = vec![];
+    for line in BufReader::new(GzDecoder::new(object)).lines() {
+        let line = line.map_err(|err| Error::Storage(err.into()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        archived.push(serde_json::from_str(&line).map_err(|err| Error::Storage(err.into()))?);
+    }
+
+    Ok(archived)
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+
+    #[test]
+    fn archives_alerts_by_day() {
+        let acked =
+            |id: u64, acked_timestamp: u64, resolved_timestamp: Option<u64>| AlertAcknowledged {
+                alert: alert_context(id, "default"),
+                acked_by: String::from("@ops:matrix.org"),
+                acked_timestamp,
+                resolved_by: None,
+                resolved_timestamp,
+                handed_off_to: None,
+                expired_timestamp: None,
+            };
+
+        // 2024-01-01 and 2024-01-02, UTC.
+        let batch = vec![
+            acked(1, 1704067200, None),
+            acked(2, 1704067200, Some(1704153600)),
+            acked(3, 1704070800, None),
+        ];
+
+        let days = by_day(&batch);
+        let day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[&day].len(), 2);
+
+        let decoded = decode(&encode(&days[&day]).unwrap()).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1].alert.id, batch[2].alert.id);
+    }
+}
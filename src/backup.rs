@@ -0,0 +1,109 @@
+use crate::database::{Backup, Database};
+use crate::{Error, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Exports the database to the given file, see `Database::export`.
+pub async fn backup(db: &Database, path: &Path) -> Result<()> {
+    let backup = db.export().await?;
+    write_backup(&backup, path)?;
+
+    info!(
+        "Exported {} pending and {} acknowledged alerts, {} routes, {} overrides, {} watches \
+         and {} API keys to {}",
+        backup.pending.len(),
+        backup.history.len(),
+        backup.routes.len(),
+        backup.overrides.len(),
+        backup.watches.len(),
+        backup.api_keys.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Restores the given file into an empty database.
+pub async fn restore(db: &Database, path: &Path) -> Result<()> {
+    let backup = read_backup(path)?;
+    db.restore(&backup).await?;
+
+    info!(
+        "Restored {} pending and {} acknowledged alerts from {}",
+        backup.pending.len(),
+        backup.history.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+// Backups are gzip compressed if the file name ends with `.gz`.
+fn is_gzip(path: &Path) -> bool {
+    path.extension().map(|ext| ext == "gz").unwrap_or(false)
+}
+
+fn write_backup(backup: &Backup, path: &Path) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path).map_err(|err| Error::Storage(err.into()))?);
+
+    if is_gzip(path) {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        serde_json::to_writer(&mut encoder, backup).map_err(|err| Error::Storage(err.into()))?;
+        file = encoder.finish().map_err(|err| Error::Storage(err.into()))?;
+    } else {
+        serde_json::to_writer(&mut file, backup).map_err(|err| Error::Storage(err.into()))?;
+    }
+
+    file.flush().map_err(|err| Error::Storage(err.into()))
+}
+
+fn read_backup(path: &Path) -> Result<Backup> {
+    let file = BufReader::new(File::open(path).map_err(|err| Error::Storage(err.into()))?);
+
+    let reader: Box<dyn Read> = if is_gzip(path) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    serde_json::from_reader(reader).map_err(|err| Error::Storage(err.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{ApiKey, Watch};
+
+    #[test]
+    fn backup_roundtrip() {
+        let backup = Backup {
+            latest_id: 42,
+            watches: vec![Watch {
+                user: String::from("@alice:matrix.org"),
+                labels: vec![(String::from("team"), String::from("infra"))]
+                    .into_iter()
+                    .collect(),
+            }],
+            api_keys: vec![ApiKey {
+                user: String::from("@alice:matrix.org"),
+                key_hash: String::from("abc"),
+                created_at: 1,
+            }],
+            ..Default::default()
+        };
+
+        for name in ["matrixbot-backup.json", "matrixbot-backup.json.gz"] {
+            let path = std::env::temp_dir().join(name);
+            write_backup(&backup, &path).unwrap();
+            let read = read_backup(&path).unwrap();
+            assert_eq!(read.latest_id, 42);
+            assert_eq!(read.watches, backup.watches);
+            assert_eq!(read.api_keys[0].key_hash, "abc");
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{Error, Result};
+use chrono::{Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Weekday};
+use chrono_tz::Tz;
+
+/// The hours in which the lower escalation levels of a route are staffed.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct BusinessHoursConfig {
+    // IANA time zone of the times below, e.g. `Europe/Berlin`, which follows
+    // daylight saving time. Defaults to UTC.
+    timezone: Option<String>,
+    // Fixed UTC offset instead of a time zone, e.g. `+01:00`.
+    utc_offset: Option<String>,
+    // Defaults to Monday to Friday.
+    days: Option<Vec<String>>,
+    // Local times, e.g. `09:00`. The hours may span midnight.
+    start: String,
+    end: String,
+    // Dates without business hours, e.g. `2024-12-25`.
+    #[serde(default)]
+    holidays: Vec<String>,
+    // Alerts outside business hours enter the escalation chain at this level
+    // (index of `rooms`), or at a higher one.
+    after_hours_level: usize,
+}
+
+/// The time zone of business hours.
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    Offset(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    fn local(&self, utc: &NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Zone::Offset(offset) => offset.from_utc_datetime(utc).naive_local(),
+            Zone::Named(tz) => tz.from_utc_datetime(utc).naive_local(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BusinessHours {
+    zone: Zone,
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    holidays: Vec<NaiveDate>,
+    after_hours_level: usize,
+}
+
+impl BusinessHours {
+    pub fn new(config: BusinessHoursConfig) -> Result<Self> {
+        let zone = match (config.timezone.as_deref(), config.utc_offset.as_deref()) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Config(String::from(
+                    "Business hours configure both a time zone and a UTC offset",
+                )))
+            }
+            (Some(timezone), None) => Zone::Named(
+                timezone
+                    .parse::<Tz>()
+                    .map_err(|_| Error::Config(format!("Unknown time zone '{}'", timezone)))?,
+            ),
+            (None, Some(offset)) => Zone::Offset(
+                parse_offset(offset)
+                    .ok_or_else(|| Error::Config(format!("Invalid UTC offset '{}'", offset)))?,
+            ),
+            (None, None) => Zone::Offset(FixedOffset::east_opt(0).unwrap()),
+        };
+
+        let days = match config.days {
+            Some(days) => days
+                .iter()
+                .map(|day| {
+                    day.parse::<Weekday>()
+                        .map_err(|_| Error::Config(format!("Invalid day '{}'", day)))
+                })
+                .collect::<Result<_>>()?,
+            None => vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+        };
+
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| Error::Config(format!("Invalid time '{}', expected HH:MM", time)))
+        };
+
+        Ok(BusinessHours {
+            zone,
+            days,
+            start: parse_time(&config.start)?,
+            end: parse_time(&config.end)?,
+            holidays: config
+                .holidays
+                .iter()
+                .map(|date| {
+                    NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| {
+                        Error::Config(format!("Invalid holiday '{}', expected YYYY-MM-DD", date))
+                    })
+                })
+                .collect::<Result<_>>()?,
+            after_hours_level: config.after_hours_level,
+        })
+    }
+    pub fn after_hours_level(&self) -> usize {
+        self.after_hours_level
+    }
+    /// Whether the given UNIX timestamp is within business hours.
+    pub fn is_open(&self, timestamp: u64) -> bool {
+        let local = match NaiveDateTime::from_timestamp_opt(timestamp as i64, 0) {
+            Some(utc) => self.zone.local(&utc),
+            None => return false,
+        };
+
+        let time = local.time();
+        let date = if self.start <= self.end {
+            if time < self.start || time >= self.end {
+                return false;
+            }
+
+            local.date()
+        } else {
+            // Hours after midnight belong to the business day before.
+            if time >= self.start {
+                local.date()
+            } else if time < self.end {
+                match local.date().pred_opt() {
+                    Some(date) => date,
+                    None => return false,
+                }
+            } else {
+                return false;
+            }
+        };
+
+        self.days.contains(&date.weekday()) && !self.holidays.contains(&date)
+    }
+}
+
+/// Parses offsets like `+01:00` or `-05:30`.
+fn parse_offset(offset: &str) -> Option<FixedOffset> {
+    let (sign, rest) = match offset.chars().next()? {
+        '+' => (1, &offset[1..]),
+        '-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+
+    let (hours, minutes) = rest.split_once(':')?;
+    let secs = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+
+    FixedOffset::east_opt(sign * secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(datetime: &str) -> u64 {
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M")
+            .unwrap()
+            .timestamp() as u64
+    }
+
+    fn config(start: &str, end: &str) -> BusinessHoursConfig {
+        BusinessHoursConfig {
+            timezone: None,
+            utc_offset: Some(String::from("+02:00")),
+            days: None,
+            start: start.to_string(),
+            end: end.to_string(),
+            holidays: vec![String::from("2024-12-25")],
+            after_hours_level: 1,
+        }
+    }
+
+    #[test]
+    fn checks_business_hours() {
+        let hours = BusinessHours::new(config("09:00", "17:00")).unwrap();
+
+        // Monday, 09:30 local time.
+        assert!(hours.is_open(timestamp("2024-12-23 07:30")));
+        // Monday, 08:30 and 17:00 local time.
+        assert!(!hours.is_open(timestamp("2024-12-23 06:30")));
+        assert!(!hours.is_open(timestamp("2024-12-23 15:00")));
+        // Holiday and Saturday.
+        assert!(!hours.is_open(timestamp("2024-12-25 10:00")));
+        assert!(!hours.is_open(timestamp("2024-12-28 10:00")));
+
+        // Night shift from Friday to Saturday.
+        let hours = BusinessHours::new(config("22:00", "06:00")).unwrap();
+        assert!(hours.is_open(timestamp("2024-12-27 21:00")));
+        assert!(hours.is_open(timestamp("2024-12-28 02:00")));
+        assert!(!hours.is_open(timestamp("2024-12-28 21:00")));
+
+        // Berlin is at +01:00 in winter and at +02:00 in summer.
+        let hours = BusinessHours::new(BusinessHoursConfig {
+            timezone: Some(String::from("Europe/Berlin")),
+            utc_offset: None,
+            ..config("09:00", "17:00")
+        })
+        .unwrap();
+        assert!(hours.is_open(timestamp("2024-12-23 08:30")));
+        assert!(!hours.is_open(timestamp("2024-07-01 15:30")));
+        assert!(hours.is_open(timestamp("2024-07-01 14:30")));
+
+        let unknown = BusinessHoursConfig {
+            timezone: Some(String::from("Mars/Olympus")),
+            utc_offset: None,
+            ..config("09:00", "17:00")
+        };
+        assert!(BusinessHours::new(unknown).is_err());
+        let both = BusinessHoursConfig {
+            timezone: Some(String::from("Europe/Berlin")),
+            ..config("09:00", "17:00")
+        };
+        assert!(BusinessHours::new(both).is_err());
+
+        assert!(BusinessHours::new(config("9am", "17:00")).is_err());
+        assert!(parse_offset("01:00").is_none());
+        assert_eq!(
+            parse_offset("-05:30"),
+            FixedOffset::west_opt(5 * 3600 + 1800)
+        );
+    }
+}
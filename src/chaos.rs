@@ -0,0 +1,165 @@
+//! Fault injection for adapter calls, to verify that retries, the outbox and
+//! account failover behave before relying on them.
+use crate::{Error, Result};
+use once_cell::sync::OnceCell;
+use std::time::Duration;
+
+const DEFAULT_MAX_DELAY: u64 = 5000;
+
+static CHAOS: OnceCell<Chaos> = OnceCell::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChaosConfig {
+    // Probability (0 to 1) that an adapter call fails.
+    #[serde(default)]
+    failure_rate: f64,
+    // Probability (0 to 1) that an adapter call is delayed, by up to
+    // `max_delay` milliseconds (defaults to 5000).
+    #[serde(default)]
+    delay_rate: f64,
+    max_delay: Option<u64>,
+    // Names of the adapters to inject faults into, e.g. `Matrix`,
+    // `Prometheus`, `Ack webhook`, `Exec hook` or `Telegram`. All if empty.
+    #[serde(default)]
+    adapters: Vec<String>,
+}
+
+/// The error of injected failures. Treated like a transient failure of the
+/// adapter, e.g. messages are queued for retry.
+#[derive(Debug, thiserror::Error)]
+#[error("injected fault (chaos mode)")]
+pub struct InjectedFault;
+
+#[derive(Debug, PartialEq)]
+enum Fault {
+    None,
+    Fail,
+    Delay(Duration),
+}
+
+#[derive(Debug)]
+struct Chaos {
+    config: ChaosConfig,
+}
+
+impl Chaos {
+    fn new(config: ChaosConfig) -> Result<Self> {
+        for (name, rate) in [
+            ("failure_rate", config.failure_rate),
+            ("delay_rate", config.delay_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(Error::Config(format!(
+                    "Chaos {} must be between 0 and 1, got {}",
+                    name, rate
+                )));
+            }
+        }
+
+        Ok(Chaos { config })
+    }
+    fn applies_to(&self, adapter: &str) -> bool {
+        self.config.adapters.is_empty()
+            || self
+                .config
+                .adapters
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(adapter))
+    }
+    /// Decides the fault of a call, given random numbers in `[0, 1)`.
+    fn fault(&self, adapter: &str, rolls: [f64; 3]) -> Fault {
+        if !self.applies_to(adapter) {
+            return Fault::None;
+        }
+
+        if rolls[0] < self.config.failure_rate {
+            Fault::Fail
+        } else if rolls[1] < self.config.delay_rate {
+            let max_delay = self.config.max_delay.unwrap_or(DEFAULT_MAX_DELAY);
+            Fault::Delay(Duration::from_millis((rolls[2] * max_delay as f64) as u64))
+        } else {
+            Fault::None
+        }
+    }
+}
+
+/// Enables fault injection. Must only be called once, on startup.
+pub fn configure(config: ChaosConfig) -> Result<()> {
+    let chaos = Chaos::new(config)?;
+    warn!(
+        "Chaos mode is enabled, adapter calls fail or are delayed at random: {:?}",
+        chaos.config
+    );
+
+    CHAOS
+        .set(chaos)
+        .map_err(|_| Error::Internal(String::from("Chaos mode is already configured")))
+}
+
+/// Fails or delays the call of the adapter at random, if chaos mode is
+/// enabled. Called before each adapter call.
+pub async fn inject(adapter: &'static str) -> Result<()> {
+    let chaos = match CHAOS.get() {
+        Some(chaos) => chaos,
+        None => return Ok(()),
+    };
+
+    match chaos.fault(adapter, [random(), random(), random()]) {
+        Fault::None => Ok(()),
+        Fault::Fail => {
+            warn!("Injecting failure into {} call", adapter);
+            Err(Error::Adapter {
+                name: adapter,
+                source: Box::new(InjectedFault),
+            })
+        }
+        Fault::Delay(delay) => {
+            warn!("Delaying {} call by {:?}", adapter, delay);
+            tokio::time::sleep(delay).await;
+            Ok(())
+        }
+    }
+}
+
+/// A random number in `[0, 1)`.
+fn random() -> f64 {
+    let mut bytes = [0; 4];
+    // Falls back to no fault if randomness is unavailable.
+    if openssl::rand::rand_bytes(&mut bytes).is_err() {
+        return 1.0;
+    }
+
+    u32::from_le_bytes(bytes) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injects_faults_at_rate() {
+        let chaos = Chaos::new(ChaosConfig {
+            failure_rate: 0.1,
+            delay_rate: 0.5,
+            max_delay: Some(1000),
+            adapters: vec![String::from("matrix")],
+        })
+        .unwrap();
+
+        assert_eq!(chaos.fault("Matrix", [0.05, 0.9, 0.0]), Fault::Fail);
+        assert_eq!(
+            chaos.fault("Matrix", [0.5, 0.2, 0.25]),
+            Fault::Delay(Duration::from_millis(250))
+        );
+        assert_eq!(chaos.fault("Matrix", [0.5, 0.9, 0.25]), Fault::None);
+        assert_eq!(chaos.fault("Prometheus", [0.0, 0.0, 0.0]), Fault::None);
+
+        assert!(Chaos::new(ChaosConfig {
+            failure_rate: 1.5,
+            delay_rate: 0.0,
+            max_delay: None,
+            adapters: vec![],
+        })
+        .is_err());
+    }
+}
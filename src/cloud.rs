@@ -0,0 +1,322 @@
+use crate::webhook::{Alert, Annotations, Labels};
+use crate::DEFAULT_ROUTE;
+use std::collections::BTreeMap;
+
+const DEFAULT_SEVERITY: &str = "critical";
+
+/// Enables the webhook of a cloud monitoring service, i.e. `/webhook-azure`
+/// or `/webhook-gcp`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloudConfig {
+    route: Option<String>,
+    // Passed as `?token=...`, since custom headers are not supported by all
+    // notification channels.
+    token: Option<String>,
+}
+
+impl CloudConfig {
+    pub fn route(&self) -> &str {
+        self.route.as_deref().unwrap_or(DEFAULT_ROUTE)
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+}
+
+/// An alert of Azure Monitor in the common alert schema.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureAlert {
+    schema_id: String,
+    data: AzureAlertData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct AzureAlertData {
+    essentials: AzureEssentials,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureEssentials {
+    alert_id: Option<String>,
+    alert_rule: String,
+    // `Sev0` (critical) to `Sev4` (verbose).
+    severity: Option<String>,
+    signal_type: Option<String>,
+    // `Fired` or `Resolved`.
+    monitor_condition: String,
+    monitoring_service: Option<String>,
+    #[serde(rename = "alertTargetIDs", default)]
+    alert_target_ids: Vec<String>,
+    #[serde(default)]
+    configuration_items: Vec<String>,
+    description: Option<String>,
+}
+
+impl AzureAlert {
+    /// Only fired alerts are inserted, resolutions are ignored.
+    pub fn is_fired(&self) -> bool {
+        self.data
+            .essentials
+            .monitor_condition
+            .eq_ignore_ascii_case("fired")
+    }
+}
+
+impl From<AzureAlert> for Alert {
+    fn from(alert: AzureAlert) -> Self {
+        let AzureEssentials {
+            alert_id,
+            alert_rule,
+            severity,
+            signal_type,
+            monitor_condition: _,
+            monitoring_service,
+            alert_target_ids,
+            configuration_items,
+            description,
+        } = alert.data.essentials;
+
+        let severity = match severity.as_deref() {
+            Some("Sev0") => "critical",
+            Some("Sev1") => "error",
+            Some("Sev2") => "warning",
+            Some("Sev3") => "informational",
+            Some("Sev4") => "verbose",
+            _ => DEFAULT_SEVERITY,
+        };
+
+        let mut labels = BTreeMap::new();
+        labels.insert("alertId", alert_id);
+        labels.insert("signalType", signal_type);
+        labels.insert("monitoringService", monitoring_service);
+        labels.insert("schemaId", Some(alert.schema_id));
+        labels.insert("targets", join(alert_target_ids));
+        labels.insert("configurationItems", join(configuration_items));
+
+        Alert {
+            annotations: Annotations {
+                message: description,
+                description: format_labels(labels),
+                ..Default::default()
+            },
+            labels: Labels {
+                severity: severity.to_string(),
+                alert_name: alert_rule,
+                team: None,
+                ..Default::default()
+            },
+            generator_url: None,
+        }
+    }
+}
+
+/// A notification of a Google Cloud Monitoring webhook channel.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct GcpNotification {
+    version: Option<String>,
+    incident: GcpIncident,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct GcpIncident {
+    incident_id: Option<String>,
+    scoping_project_id: Option<String>,
+    url: Option<String>,
+    // `open` or `closed`.
+    state: String,
+    summary: Option<String>,
+    policy_name: Option<String>,
+    condition_name: Option<String>,
+    // `Critical`, `Error`, `Warning` or `No severity`.
+    severity: Option<String>,
+    resource: Option<GcpResource>,
+    metric: Option<GcpResource>,
+    metadata: Option<GcpMetadata>,
+}
+
+/// A monitored resource or metric, e.g. `gce_instance`.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct GcpResource {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct GcpMetadata {
+    #[serde(default)]
+    system_labels: BTreeMap<String, String>,
+    #[serde(default)]
+    user_labels: BTreeMap<String, String>,
+}
+
+impl GcpNotification {
+    /// Only open incidents are inserted, closed ones are ignored.
+    pub fn is_open(&self) -> bool {
+        self.incident.state.eq_ignore_ascii_case("open")
+    }
+}
+
+impl From<GcpNotification> for Alert {
+    fn from(notification: GcpNotification) -> Self {
+        let GcpIncident {
+            incident_id,
+            scoping_project_id,
+            url,
+            state: _,
+            summary,
+            policy_name,
+            condition_name,
+            severity,
+            resource,
+            metric,
+            metadata,
+        } = notification.incident;
+
+        let severity = severity
+            .map(|severity| severity.to_lowercase())
+            .filter(|severity| severity != "no severity")
+            .unwrap_or_else(|| DEFAULT_SEVERITY.to_string());
+
+        let alert_name = policy_name
+            .or(condition_name)
+            .unwrap_or_else(|| String::from("GCP incident"));
+
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("incident_id"), incident_id);
+        labels.insert(String::from("project_id"), scoping_project_id);
+        labels.insert(String::from("url"), url);
+
+        for (prefix, resource) in [("resource", resource), ("metric", metric)] {
+            if let Some(resource) = resource {
+                labels.insert(format!("{}.type", prefix), resource.kind);
+                for (key, value) in resource.labels {
+                    labels.insert(format!("{}.{}", prefix, key), Some(value));
+                }
+            }
+        }
+
+        if let Some(metadata) = metadata {
+            for (key, value) in metadata
+                .system_labels
+                .into_iter()
+                .chain(metadata.user_labels)
+            {
+                labels.insert(key, Some(value));
+            }
+        }
+
+        Alert {
+            annotations: Annotations {
+                message: summary,
+                description: format_labels(labels),
+                ..Default::default()
+            },
+            labels: Labels {
+                severity,
+                alert_name,
+                team: None,
+                ..Default::default()
+            },
+            generator_url: None,
+        }
+    }
+}
+
+fn join(values: Vec<String>) -> Option<String> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+/// Provider specific labels are kept in the description, one per line.
+fn format_labels<K: std::fmt::Display>(labels: BTreeMap<K, Option<String>>) -> Option<String> {
+    let lines: Vec<String> = labels
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| format!("{}: {}", key, value)))
+        .collect();
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_azure_alert() {
+        let alert: AzureAlert = serde_json::from_str(
+            r#"{
+                "schemaId": "azureMonitorCommonAlertSchema",
+                "data": {
+                    "essentials": {
+                        "alertId": "/subscriptions/1/providers/Microsoft.AlertsManagement/alerts/2",
+                        "alertRule": "HighCPU",
+                        "severity": "Sev1",
+                        "signalType": "Metric",
+                        "monitorCondition": "Fired",
+                        "monitoringService": "Platform",
+                        "alertTargetIDs": ["/subscriptions/1/resourcegroups/rg/providers/microsoft.compute/virtualmachines/vm1"],
+                        "configurationItems": ["vm1"],
+                        "description": "CPU above 90%"
+                    },
+                    "alertContext": {}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(alert.is_fired());
+
+        let alert = Alert::from(alert);
+        assert_eq!(alert.labels.alert_name, "HighCPU");
+        assert_eq!(alert.labels.severity, "error");
+        assert_eq!(alert.annotations.message.as_deref(), Some("CPU above 90%"));
+
+        let description = alert.annotations.description.unwrap();
+        assert!(description.contains("monitoringService: Platform"));
+        assert!(description.contains("configurationItems: vm1"));
+    }
+
+    #[test]
+    fn maps_gcp_incident() {
+        let notification: GcpNotification = serde_json::from_str(
+            r#"{
+                "version": "1.2",
+                "incident": {
+                    "incident_id": "0.abc",
+                    "scoping_project_id": "acme",
+                    "url": "https://console.cloud.google.com/monitoring/alerting/incidents/0.abc",
+                    "state": "open",
+                    "summary": "CPU utilization for vm1 is above the threshold",
+                    "policy_name": "High CPU",
+                    "condition_name": "VM Instance - CPU utilization",
+                    "severity": "Critical",
+                    "resource": {"type": "gce_instance", "labels": {"zone": "europe-west3-a"}},
+                    "metadata": {"system_labels": {}, "user_labels": {"team": "infra"}}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(notification.is_open());
+
+        let alert = Alert::from(notification);
+        assert_eq!(alert.labels.alert_name, "High CPU");
+        assert_eq!(alert.labels.severity, "critical");
+
+        let description = alert.annotations.description.unwrap();
+        assert!(description.contains("resource.type: gce_instance"));
+        assert!(description.contains("resource.zone: europe-west3-a"));
+        assert!(description.contains("team: infra"));
+    }
+}
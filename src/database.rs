@@ -1,19 +1,44 @@
-use crate::processor::{AlertContext, UserConfirmation};
+use crate::processor::{
+    AckScope, ActiveDeployWindow, AlertContext, EscalationStats, NoiseStats, PolicyOverride,
+    TimelineKind, UserConfirmation, UserStats,
+};
 use crate::webhook::Alert;
-use crate::{unix_time, AlertId, Result};
+use crate::{unix_time, AlertId, Error, Result, RouteConfig, DEFAULT_ROUTE};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 // TODO: Can this be avoided somehow?
+use bson::oid::ObjectId;
 use bson::{doc, to_bson};
 use futures::stream::StreamExt;
+use mongodb::error::{ErrorKind, WriteFailure};
 use mongodb::IndexModel;
 use mongodb::{
-    options::{FindOneAndUpdateOptions, ReplaceOptions, ReturnDocument},
+    options::{
+        CountOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions,
+        ReplaceOptions, ReturnDocument, UpdateOptions,
+    },
     Client, Database as MongoDb,
 };
-use std::collections::HashMap;
 
 const PENDING: &str = "pending";
 const HISTORY: &str = "history";
 const ID_CURSOR: &str = "id_cursor";
+const NOTIFICATIONS: &str = "notifications";
+const OUTBOX: &str = "outbox";
+const DEAD_LETTERS: &str = "dead_letters";
+const REMINDERS: &str = "reminders";
+const ROUTES: &str = "routes";
+const OVERRIDES: &str = "overrides";
+const WATCHES: &str = "watches";
+const DIRECT_ROOMS: &str = "direct_rooms";
+const INCIDENT_ROOMS: &str = "incident_rooms";
+const API_KEYS: &str = "api_keys";
+const PROBES: &str = "probes";
+const MUTES: &str = "mutes";
+const DEPLOY_WINDOWS: &str = "deploy_windows";
+const REPORTS: &str = "reports";
+
+const DUPLICATE_KEY_CODE: i32 = 11000;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatabaseConfig {
@@ -30,15 +55,258 @@ struct IdCursor {
     latest_id: u64,
 }
 
+/// An acknowledged alert, kept as history.
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct AlertAcknowledged {
+    pub alert: AlertContext,
+    pub acked_by: String,
+    pub acked_timestamp: u64,
+    #[serde(default)]
+    pub resolved_by: Option<String>,
+    #[serde(default)]
+    pub resolved_timestamp: Option<u64>,
+    // The user now responsible for the alert, if it has been handed off
+    // since it was acknowledged by `acked_by`.
+    #[serde(default)]
+    pub handed_off_to: Option<String>,
+    // Set once the acknowledgement expired and the alert returned to pending,
+    // the alert is then acknowledged by a record of its own.
+    #[serde(default)]
+    pub expired_timestamp: Option<u64>,
+}
+
+impl AlertAcknowledged {
+    /// Identifies the record, an alert can be acknowledged more than once.
+    fn key(&self) -> Result<bson::Document> {
+        Ok(doc! {
+            "alert.id": to_bson(&self.alert.id)?,
+            "acked_timestamp": self.acked_timestamp as i64,
+        })
+    }
+}
+
+/// Identifies a notification sent to a channel, used to avoid duplicate
+/// notifications.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NotificationKey {
+    pub alert_id: AlertId,
+    pub channel: String,
+    pub escalation_idx: usize,
+    pub kind: String,
+}
+
+/// A notification claimed by `claim_notification`. It remains `sending` until
+/// the alert state reflects it, see `complete_notification`.
 #[derive(Debug, Serialize, Deserialize)]
-struct AlertAcknowledged {
-    alert: AlertContext,
-    acked_by: String,
-    acked_timestamp: u64,
+pub struct NotificationRecord {
+    #[serde(flatten)]
+    pub key: NotificationKey,
+    pub sent_at: u64,
+    // Records of older versions were completed when claimed.
+    #[serde(default)]
+    state: DeliveryState,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeliveryState {
+    Sending,
+    #[default]
+    Sent,
+}
+
+/// A Matrix message which could not be delivered yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    // Assigned by the database, preserves the order of insertion.
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    pub room_id: String,
+    pub body: String,
+    // HTML rendition of the body, see `render::Format`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    pub queued_at: u64,
+}
+
+/// A queued message which cannot be delivered, e.g. since the bot was removed
+/// from the room. Kept for inspection instead of blocking the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(flatten)]
+    pub message: QueuedMessage,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+/// The Matrix room used for direct messages to a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectRoom {
+    pub user: String,
+    pub room_id: String,
+}
+
+/// The Matrix room of a major incident, open until its alert is resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncidentRoom {
+    pub alert: AlertId,
+    pub room_id: String,
+}
+
+/// A key of the HTTP API, which attributes acks to its user. Only the hash of
+/// the key is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub user: String,
+    pub key_hash: String,
+    pub created_at: u64,
+}
+
+/// An API key as listed by the admin API, without its hash.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct ApiKeyInfo {
+    pub user: String,
+    pub created_at: u64,
+}
+
+/// The state of the service in a storage-agnostic format, see
+/// `Database::export`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Backup {
+    pub latest_id: u64,
+    pub pending: Vec<AlertContext>,
+    pub history: Vec<AlertAcknowledged>,
+    #[serde(default)]
+    pub reminders: Vec<Reminder>,
+    // Managed via the admin API and commands.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub overrides: Vec<PolicyOverride>,
+    #[serde(default)]
+    pub watches: Vec<Watch>,
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+}
+
+/// A reminder about an alert, requested by a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reminder {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    pub alert_id: AlertId,
+    // The room which requested the reminder.
+    pub route: String,
+    pub escalation_idx: usize,
+    pub requested_by: String,
+    pub due: u64,
+}
+
+impl Reminder {
+    pub fn new(
+        alert_id: AlertId,
+        route: String,
+        escalation_idx: usize,
+        requested_by: String,
+        due: u64,
+    ) -> Self {
+        Reminder {
+            id: None,
+            alert_id,
+            route,
+            escalation_idx,
+            requested_by,
+            due,
+        }
+    }
+}
+
+/// When a periodic report was last sent, see `claim_report`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SentReport {
+    name: String,
+    sent_at: u64,
+}
+
+/// The active mute of all notifications, see `mute`. At most one is stored,
+/// so that it survives restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MuteState {
+    // The room which issued the mute, informed once it expires.
+    pub route: String,
+    pub escalation_idx: usize,
+    pub until: u64,
+    // Alerts which were received while muted.
+    #[serde(default)]
+    pub alerts: Vec<AlertContext>,
+}
+
+/// A subscription of a user to alerts with the given labels, see `watch`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Watch {
+    pub user: String,
+    // Names (see `Labels::NAMES`) and values of labels, all of which must
+    // match, ignoring case.
+    pub labels: BTreeMap<String, String>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct PendingAlertsEntry(HashMap<AlertId, Alert>);
+impl Watch {
+    pub fn matches(&self, alert: &Alert) -> bool {
+        alert.labels.matches(&self.labels)
+    }
+}
+
+impl std::fmt::Display for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+
+        write!(f, "{}", labels.join(" "))
+    }
+}
+
+/// Matches the pending alert if it belongs to the route and the scope allows
+/// acknowledging it from the given escalation level, see `AckScope::allows`.
+fn ack_filter(
+    route: &str,
+    escalation_idx: usize,
+    scope: AckScope,
+    alert_id: AlertId,
+) -> Result<bson::Document> {
+    let mut filter = doc! {
+        "id": to_bson(&alert_id)?,
+    };
+
+    // Alerts stored before routes were introduced belong to the default route.
+    if route == DEFAULT_ROUTE {
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "route": route },
+                doc! { "route": { "$exists": false } },
+            ],
+        );
+    } else {
+        filter.insert("route", route);
+    }
+
+    if let Some(max_idx) = scope.max_alert_idx(escalation_idx) {
+        filter.insert("escalation_idx", doc! { "$lte": to_bson(&max_idx)? });
+    }
+
+    Ok(filter)
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(err)) => err.code == DUPLICATE_KEY_CODE,
+        ErrorKind::Command(err) => err.code == DUPLICATE_KEY_CODE,
+        _ => false,
+    }
+}
 
 impl Database {
     pub async fn new(config: DatabaseConfig) -> Result<Self> {
@@ -58,16 +326,94 @@ impl Database {
             .create_index(index_model, None)
             .await?;
 
+        // Looked up per alert name by adaptive escalation, see
+        // `escalation_stats`.
+        let index_model = IndexModel::builder()
+            .keys(doc! {
+                "alert.alert.labels.alertname": 1,
+                "acked_timestamp": 1,
+            })
+            .build();
+
+        db.collection::<AlertAcknowledged>(HISTORY)
+            .create_index(index_model, None)
+            .await?;
+
+        // A notification can only be recorded once, see `claim_notification`.
+        let index_model = IndexModel::builder()
+            .keys(doc! {
+                "alert_id": 1,
+                "channel": 1,
+                "escalation_idx": 1,
+                "kind": 1,
+            })
+            .options({
+                let mut ops = IndexOptions::default();
+                ops.unique = Some(true);
+                ops
+            })
+            .build();
+
+        db.collection::<NotificationRecord>(NOTIFICATIONS)
+            .create_index(index_model, None)
+            .await?;
+
+        // A report can only be claimed once per period, see `claim_report`.
+        let index_model = IndexModel::builder()
+            .keys(doc! { "name": 1 })
+            .options({
+                let mut ops = IndexOptions::default();
+                ops.unique = Some(true);
+                ops
+            })
+            .build();
+
+        db.collection::<SentReport>(REPORTS)
+            .create_index(index_model, None)
+            .await?;
+
         Ok(Database { db })
     }
+    /// Removes the database, e.g. after a test.
+    #[cfg(test)]
+    pub async fn drop_database(&self) -> Result<()> {
+        Ok(self.db.drop(None).await?)
+    }
     /// Simply checks if a connection could be established to the database.
     pub async fn connectivity_check(&self) -> Result<()> {
         self.db
             .list_collection_names(None)
             .await
-            .map_err(|err| anyhow!("Failed to connect to database: {:?}", err))
+            .map_err(|err| Error::Storage(err.into()))
             .map(|_| ())
     }
+    /// Writes, reads and removes a probe, returns how long the write and the
+    /// read took.
+    pub async fn latency_check(&self) -> Result<(Duration, Duration)> {
+        let probes = self.db.collection::<bson::Document>(PROBES);
+        let id = ObjectId::new();
+
+        let started = Instant::now();
+        probes
+            .insert_one(
+                doc! { "_id": id, "written_at": to_bson(&unix_time())? },
+                None,
+            )
+            .await?;
+        let write = started.elapsed();
+
+        let started = Instant::now();
+        let found = probes.find_one(doc! { "_id": id }, None).await?;
+        let read = started.elapsed();
+
+        probes.delete_one(doc! { "_id": id }, None).await?;
+
+        if found.is_none() {
+            return Err(Error::Storage("the written probe could not be read".into()));
+        }
+
+        Ok((write, read))
+    }
     pub async fn insert_alerts(&self, alerts: &[AlertContext]) -> Result<()> {
         if alerts.is_empty() {
             return Ok(());
@@ -120,39 +466,32 @@ impl Database {
 
         Ok(id)
     }
+    /// Acknowledges a pending alert of the route, if the scope allows it from
+    /// the given escalation level. The alert is removed from the pending ones
+    /// in a single operation conditioned on its route and level, so of
+    /// concurrent acks (e.g. via Matrix and the HTTP API) exactly one
+    /// succeeds.
     pub async fn acknowledge_alert(
         &self,
+        route: &str,
         escalation_idx: usize,
+        scope: AckScope,
         alert_id: AlertId,
         acked_by: String,
     ) -> Result<UserConfirmation> {
         let pending = self.db.collection::<AlertContext>(PENDING);
         let history = self.db.collection::<AlertAcknowledged>(HISTORY);
 
-        let alert = pending
-            .find_one(
-                doc! {
-                    "id": to_bson(&alert_id)?,
-                },
-                None,
-            )
+        let claimed = pending
+            .find_one_and_delete(ack_filter(route, escalation_idx, scope, alert_id)?, None)
             .await?;
 
-        if let Some(alert) = alert {
-            if alert.escalation_idx <= escalation_idx {
-                history
-                    .insert_one(
-                        AlertAcknowledged {
-                            alert,
-                            acked_by,
-                            acked_timestamp: unix_time(),
-                        },
-                        None,
-                    )
-                    .await?;
-
-                pending
-                    .delete_one(
+        let mut alert = match claimed {
+            Some(alert) => alert,
+            None => {
+                // Only distinguishes the reply, the alert is left as is.
+                let alert = pending
+                    .find_one(
                         doc! {
                             "id": to_bson(&alert_id)?,
                         },
@@ -160,14 +499,210 @@ impl Database {
                     )
                     .await?;
 
-                Ok(UserConfirmation::AlertAcknowledged(alert_id))
-            } else {
-                Ok(UserConfirmation::AlertOutOfScope)
+                return Ok(match alert.filter(|alert| alert.route == route) {
+                    Some(_) => UserConfirmation::AlertOutOfScope,
+                    None => UserConfirmation::AlertNotFound,
+                });
             }
+        };
+
+        alert.record(TimelineKind::Acknowledged, escalation_idx);
+
+        let res = history
+            .insert_one(
+                AlertAcknowledged {
+                    alert: alert.clone(),
+                    acked_by,
+                    acked_timestamp: unix_time(),
+                    resolved_by: None,
+                    resolved_timestamp: None,
+                    handed_off_to: None,
+                    expired_timestamp: None,
+                },
+                None,
+            )
+            .await;
+
+        if let Err(err) = res {
+            // Return the alert to pending, so it cannot get lost.
+            alert.timeline.pop();
+            self.insert_alerts(&[alert]).await?;
+            return Err(err.into());
+        }
+
+        Ok(UserConfirmation::AlertAcknowledged(alert_id))
+    }
+    /// Marks an acknowledged alert as resolved, its acknowledgement does no
+    /// longer expire.
+    pub async fn resolve_alert(
+        &self,
+        route: &str,
+        alert_id: AlertId,
+        resolved_by: String,
+    ) -> Result<UserConfirmation> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let acked = history
+            .find_one(
+                doc! {
+                    "alert.id": to_bson(&alert_id)?,
+                    "resolved_timestamp": null,
+                    "expired_timestamp": null,
+                },
+                None,
+            )
+            .await?;
+
+        if let Some(mut acked) = acked.filter(|acked| acked.alert.route == route) {
+            let escalation_idx = acked.alert.escalation_idx;
+            acked.alert.record(TimelineKind::Resolved, escalation_idx);
+            acked.resolved_by = Some(resolved_by);
+            acked.resolved_timestamp = Some(unix_time());
+
+            history.replace_one(acked.key()?, &acked, None).await?;
+
+            Ok(UserConfirmation::AlertResolved(alert_id))
         } else {
             Ok(UserConfirmation::AlertNotFound)
         }
     }
+    /// Hands the unresolved alerts `from` is responsible for on the given
+    /// route over to `to`. Who acknowledged them is kept.
+    pub async fn handoff_alerts(
+        &self,
+        route: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<AlertContext>> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let mut cursor = history
+            .find(
+                doc! {
+                    "alert.route": route,
+                    "$or": [
+                        { "handed_off_to": from },
+                        { "handed_off_to": null, "acked_by": from },
+                    ],
+                    "resolved_timestamp": null,
+                    "expired_timestamp": null,
+                },
+                None,
+            )
+            .await?;
+
+        let mut handed_off = vec![];
+        while let Some(acked) = cursor.next().await {
+            handed_off.push(acked?);
+        }
+
+        let mut alerts = vec![];
+        for mut acked in handed_off {
+            let escalation_idx = acked.alert.escalation_idx;
+            acked.alert.record(TimelineKind::HandedOff, escalation_idx);
+            acked.handed_off_to = Some(to.to_string());
+
+            history.replace_one(acked.key()?, &acked, None).await?;
+
+            alerts.push(acked.alert);
+        }
+
+        Ok(alerts)
+    }
+    /// Returns acknowledged alerts of the route which have not been resolved
+    /// within the TTL back to pending, at their previous escalation level.
+    /// Their acknowledgements are kept as history, marked as expired.
+    pub async fn expire_acknowledgements(
+        &self,
+        route: &str,
+        ack_ttl: u64,
+    ) -> Result<Vec<AlertContext>> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        let now = unix_time();
+
+        let mut cursor = history
+            .find(
+                doc! {
+                    "alert.route": route,
+                    "resolved_timestamp": null,
+                    "expired_timestamp": null,
+                    "acked_timestamp": {
+                        "$lt": now.saturating_sub(ack_ttl) as i64,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        let mut acks = vec![];
+        while let Some(acked) = cursor.next().await {
+            acks.push(acked?);
+        }
+
+        let expired: Vec<AlertContext> = acks
+            .iter()
+            .map(|acked| {
+                let mut alert = acked.alert.clone();
+                let escalation_idx = alert.escalation_idx;
+                alert.last_notified = now;
+                alert.record(TimelineKind::AckExpired, escalation_idx);
+                alert
+            })
+            .collect();
+
+        // Insert first, so alerts cannot get lost.
+        self.insert_alerts(&expired).await?;
+
+        for acked in &acks {
+            history
+                .update_one(
+                    acked.key()?,
+                    doc! {
+                        "$set": {
+                            "expired_timestamp": now as i64,
+                        }
+                    },
+                    None,
+                )
+                .await?;
+        }
+
+        Ok(expired)
+    }
+    /// Looks up an alert, including acknowledged ones.
+    pub async fn get_alert(&self, alert_id: AlertId) -> Result<Option<AlertContext>> {
+        let pending = self.db.collection::<AlertContext>(PENDING);
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let alert = pending
+            .find_one(
+                doc! {
+                    "id": to_bson(&alert_id)?,
+                },
+                None,
+            )
+            .await?;
+
+        if alert.is_some() {
+            return Ok(alert);
+        }
+
+        // The most recent acknowledgement, earlier ones may have expired.
+        let acked = history
+            .find_one(
+                doc! {
+                    "alert.id": to_bson(&alert_id)?,
+                },
+                {
+                    let mut ops = FindOneOptions::default();
+                    ops.sort = Some(doc! { "acked_timestamp": -1 });
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(acked.map(|acked| acked.alert))
+    }
     pub async fn get_pending(&self, escalation_window: Option<u64>) -> Result<Vec<AlertContext>> {
         let pending = self.db.collection::<AlertContext>(PENDING);
 
@@ -191,4 +726,1436 @@ impl Database {
 
         Ok(pending)
     }
+    /// Stores acknowledged alerts as history, e.g. imported ones.
+    pub async fn insert_acknowledged(&self, acked: &[AlertAcknowledged]) -> Result<()> {
+        if acked.is_empty() {
+            return Ok(());
+        }
+
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        history.insert_many(acked, None).await?;
+
+        Ok(())
+    }
+    /// Returns the pending and acknowledged alerts which were first notified
+    /// since the given time.
+    pub async fn get_raised_since(
+        &self,
+        since: u64,
+    ) -> Result<(Vec<AlertContext>, Vec<AlertAcknowledged>)> {
+        let pending = self.db.collection::<AlertContext>(PENDING);
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let mut cursor = pending
+            .find(
+                doc! {
+                    "timeline.0.timestamp": { "$gte": since as i64 },
+                },
+                None,
+            )
+            .await?;
+
+        let mut raised = vec![];
+        while let Some(alert) = cursor.next().await {
+            raised.push(alert?);
+        }
+
+        let mut cursor = history
+            .find(
+                doc! {
+                    "alert.timeline.0.timestamp": { "$gte": since as i64 },
+                    // Pending or acknowledged again.
+                    "expired_timestamp": null,
+                },
+                None,
+            )
+            .await?;
+
+        let mut acked = vec![];
+        while let Some(alert) = cursor.next().await {
+            acked.push(alert?);
+        }
+
+        Ok((raised, acked))
+    }
+    /// Returns up to `limit` acknowledged alerts which were resolved (or
+    /// acknowledged, if never resolved) before the given time, ordered by ID.
+    pub async fn get_archivable(&self, before: u64, limit: i64) -> Result<Vec<AlertAcknowledged>> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let mut cursor = history
+            .find(
+                doc! {
+                    "$or": [
+                        { "resolved_timestamp": { "$lt": before as i64 } },
+                        {
+                            "resolved_timestamp": null,
+                            "acked_timestamp": { "$lt": before as i64 },
+                        },
+                    ]
+                },
+                {
+                    let mut ops = FindOptions::default();
+                    ops.sort = Some(doc! { "alert.id": 1 });
+                    ops.limit = Some(limit);
+                    ops
+                },
+            )
+            .await?;
+
+        let mut archivable = vec![];
+        while let Some(acked) = cursor.next().await {
+            archivable.push(acked?);
+        }
+
+        Ok(archivable)
+    }
+    /// Removes the given records from the history, once archived.
+    pub async fn remove_history(&self, acked: &[AlertAcknowledged]) -> Result<()> {
+        if acked.is_empty() {
+            return Ok(());
+        }
+
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        let keys = acked
+            .iter()
+            .map(AlertAcknowledged::key)
+            .collect::<Result<Vec<_>>>()?;
+
+        history.delete_many(doc! { "$or": keys }, None).await?;
+
+        Ok(())
+    }
+    /// Removes the current acknowledgements of the given alerts, e.g. to
+    /// return them to pending.
+    pub async fn remove_acknowledgements(&self, ids: &[AlertId]) -> Result<()> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        history
+            .delete_many(
+                doc! {
+                    "alert.id": { "$in": to_bson(ids)? },
+                    "expired_timestamp": null,
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Stores archived alerts as history again, replacing those which are
+    /// still part of it. Returns the number of alerts which were not.
+    pub async fn restore_archived(&self, archived: &[AlertAcknowledged]) -> Result<usize> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let mut restored = 0;
+        for acked in archived {
+            let res = history
+                .replace_one(acked.key()?, acked, {
+                    let mut ops = ReplaceOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                })
+                .await?;
+
+            if res.upserted_id.is_some() {
+                restored += 1;
+            }
+        }
+
+        Ok(restored)
+    }
+    /// Counts the alerts of the given name acknowledged since the given time,
+    /// and how many of them had to be escalated before.
+    pub async fn escalation_stats(&self, alert_name: &str, since: u64) -> Result<EscalationStats> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let mut query = doc! {
+            "alert.alert.labels.alertname": alert_name,
+            "acked_timestamp": {
+                "$gte": since as i64,
+            },
+            "expired_timestamp": null,
+        };
+
+        let acknowledged = history.count_documents(query.clone(), None).await?;
+
+        query.insert("alert.timeline.kind", to_bson(&TimelineKind::Escalated)?);
+        let escalated = history.count_documents(query, None).await?;
+
+        Ok(EscalationStats {
+            acknowledged,
+            escalated,
+        })
+    }
+    /// Counts the alerts of a route per alert name: the ones acknowledged
+    /// since the given time, how many of them have been resolved, and the
+    /// ones which are still pending.
+    pub async fn noise_stats(&self, route: &str, since: u64) -> Result<Vec<NoiseStats>> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        let mut stats: BTreeMap<String, NoiseStats> = BTreeMap::new();
+
+        // Alerts whose acknowledgement expired are pending or acknowledged
+        // again.
+        let mut cursor = history
+            .find(
+                doc! {
+                    "acked_timestamp": {
+                        "$gte": since as i64,
+                    },
+                    "expired_timestamp": null,
+                },
+                None,
+            )
+            .await?;
+
+        while let Some(acked) = cursor.next().await {
+            let acked = acked?;
+            if acked.alert.route == route {
+                let resolved = acked
+                    .alert
+                    .timeline
+                    .iter()
+                    .any(|event| event.kind == TimelineKind::Resolved);
+                let alert_name = acked.alert.alert.labels.alert_name;
+                let entry = stats
+                    .entry(alert_name.clone())
+                    .or_insert_with(|| NoiseStats {
+                        alert_name,
+                        ..Default::default()
+                    });
+
+                entry.acknowledged += 1;
+                if resolved {
+                    entry.resolved += 1;
+                }
+            }
+        }
+
+        for alert in self.get_pending(None).await? {
+            if alert.route == route {
+                let alert_name = alert.alert.labels.alert_name;
+                stats
+                    .entry(alert_name.clone())
+                    .or_insert_with(|| NoiseStats {
+                        alert_name,
+                        ..Default::default()
+                    })
+                    .pending += 1;
+            }
+        }
+
+        Ok(stats.into_values().collect())
+    }
+    /// Counts the alerts each user acknowledged since the given time, on the
+    /// given route or all of them, along with the time it took them.
+    pub async fn user_stats(&self, route: Option<&str>, since: u64) -> Result<Vec<UserStats>> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        // Acknowledged alerts and times to acknowledge, per user.
+        let mut acks: BTreeMap<String, (u64, Vec<u64>)> = BTreeMap::new();
+
+        let mut cursor = history
+            .find(
+                doc! {
+                    "acked_timestamp": {
+                        "$gte": since as i64,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        while let Some(acked) = cursor.next().await {
+            let acked = acked?;
+            if matches!(route, Some(route) if acked.alert.route != route) {
+                continue;
+            }
+
+            let notified = acked
+                .alert
+                .timeline
+                .iter()
+                .find(|event| event.kind == TimelineKind::Notified)
+                .map(|event| event.timestamp);
+
+            let (count, times) = acks.entry(acked.acked_by).or_default();
+            *count += 1;
+            if let Some(notified) = notified {
+                times.push(acked.acked_timestamp.saturating_sub(notified));
+            }
+        }
+
+        Ok(acks
+            .into_iter()
+            .map(|(user, (acknowledged, times))| UserStats::new(user, acknowledged, times))
+            .collect())
+    }
+    /// Exports pending alerts, the history, reminders, the ID cursor and
+    /// everything managed via the admin API and commands, i.e. routes,
+    /// overrides, watches and API keys.
+    pub async fn export(&self) -> Result<Backup> {
+        let id_cursor = self.db.collection::<IdCursor>(ID_CURSOR);
+        let pending = self.db.collection::<AlertContext>(PENDING);
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        let reminders = self.db.collection::<Reminder>(REMINDERS);
+        let api_keys = self.db.collection::<ApiKey>(API_KEYS);
+
+        let mut backup = Backup {
+            latest_id: id_cursor
+                .find_one(doc! {}, None)
+                .await?
+                .map(|cursor| cursor.latest_id)
+                .unwrap_or(0),
+            ..Default::default()
+        };
+
+        let mut cursor = pending
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "id": 1 });
+                ops
+            })
+            .await?;
+
+        while let Some(alert) = cursor.next().await {
+            backup.pending.push(alert?);
+        }
+
+        let mut cursor = history
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "alert.id": 1 });
+                ops
+            })
+            .await?;
+
+        while let Some(acked) = cursor.next().await {
+            backup.history.push(acked?);
+        }
+
+        let mut cursor = reminders.find(doc! {}, None).await?;
+        while let Some(reminder) = cursor.next().await {
+            // Object IDs are specific to MongoDB.
+            backup.reminders.push(Reminder {
+                id: None,
+                ..reminder?
+            });
+        }
+
+        backup.routes = self.get_routes().await?;
+        backup.overrides = self.get_overrides().await?;
+        backup.watches = self.get_watches(None).await?;
+
+        let mut cursor = api_keys
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "user": 1, "created_at": 1 });
+                ops.projection = Some(doc! { "_id": 0 });
+                ops
+            })
+            .await?;
+
+        while let Some(key) = cursor.next().await {
+            backup.api_keys.push(key?);
+        }
+
+        Ok(backup)
+    }
+    /// Restores an export into an empty database.
+    pub async fn restore(&self, backup: &Backup) -> Result<()> {
+        let id_cursor = self.db.collection::<IdCursor>(ID_CURSOR);
+        let pending = self.db.collection::<AlertContext>(PENDING);
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+        let reminders = self.db.collection::<Reminder>(REMINDERS);
+
+        for name in [
+            PENDING, HISTORY, REMINDERS, ROUTES, OVERRIDES, WATCHES, API_KEYS,
+        ] {
+            let collection = self.db.collection::<bson::Document>(name);
+            if collection.count_documents(doc! {}, None).await? > 0 {
+                return Err(Error::Config(String::from(
+                    "Backups can only be restored into an empty database",
+                )));
+            }
+        }
+
+        if !backup.pending.is_empty() {
+            pending.insert_many(&backup.pending, None).await?;
+        }
+        if !backup.history.is_empty() {
+            history.insert_many(&backup.history, None).await?;
+        }
+        if !backup.reminders.is_empty() {
+            reminders.insert_many(&backup.reminders, None).await?;
+        }
+        if !backup.routes.is_empty() {
+            self.db
+                .collection::<RouteConfig>(ROUTES)
+                .insert_many(&backup.routes, None)
+                .await?;
+        }
+        if !backup.overrides.is_empty() {
+            self.db
+                .collection::<PolicyOverride>(OVERRIDES)
+                .insert_many(&backup.overrides, None)
+                .await?;
+        }
+        if !backup.watches.is_empty() {
+            self.db
+                .collection::<Watch>(WATCHES)
+                .insert_many(&backup.watches, None)
+                .await?;
+        }
+        if !backup.api_keys.is_empty() {
+            self.db
+                .collection::<ApiKey>(API_KEYS)
+                .insert_many(&backup.api_keys, None)
+                .await?;
+        }
+
+        // Never hand out IDs of restored alerts again.
+        id_cursor
+            .update_one(
+                doc! {},
+                doc! {
+                    "$max": {
+                        "latest_id": backup.latest_id as i64,
+                    }
+                },
+                {
+                    let mut ops = UpdateOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Returns up to `limit` pending alerts with an ID greater than `after`,
+    /// ordered by ID.
+    pub async fn get_pending_page(
+        &self,
+        after: Option<AlertId>,
+        limit: i64,
+    ) -> Result<Vec<AlertContext>> {
+        let pending = self.db.collection::<AlertContext>(PENDING);
+
+        let query = match after {
+            Some(after) => doc! { "id": { "$gt": to_bson(&after)? } },
+            None => doc! {},
+        };
+
+        let mut cursor = pending
+            .find(query, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "id": 1 });
+                ops.limit = Some(limit);
+                ops
+            })
+            .await?;
+
+        let mut page = vec![];
+        while let Some(alert) = cursor.next().await {
+            page.push(alert?);
+        }
+
+        Ok(page)
+    }
+    /// Returns up to `limit` acknowledged alerts with an ID greater than
+    /// `after`, ordered by ID.
+    pub async fn get_history_page(
+        &self,
+        after: Option<AlertId>,
+        limit: i64,
+    ) -> Result<Vec<AlertAcknowledged>> {
+        let history = self.db.collection::<AlertAcknowledged>(HISTORY);
+
+        let query = match after {
+            Some(after) => doc! { "alert.id": { "$gt": to_bson(&after)? } },
+            None => doc! {},
+        };
+
+        let mut cursor = history
+            .find(query, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "alert.id": 1 });
+                ops.limit = Some(limit);
+                ops
+            })
+            .await?;
+
+        let mut page = vec![];
+        while let Some(acked) = cursor.next().await {
+            page.push(acked?);
+        }
+
+        Ok(page)
+    }
+    /// Queues a message for delivery once the homeserver is reachable again.
+    pub async fn queue_message(&self, room_id: &str, body: &str, html: Option<&str>) -> Result<()> {
+        let outbox = self.db.collection::<QueuedMessage>(OUTBOX);
+
+        outbox
+            .insert_one(
+                QueuedMessage {
+                    id: None,
+                    room_id: room_id.to_string(),
+                    body: body.to_string(),
+                    html: html.map(String::from),
+                    queued_at: unix_time(),
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Whether messages to the room are queued.
+    pub async fn has_queued_messages(&self, room_id: &str) -> Result<bool> {
+        let outbox = self.db.collection::<QueuedMessage>(OUTBOX);
+
+        let count = outbox
+            .count_documents(doc! { "room_id": room_id }, {
+                let mut ops = CountOptions::default();
+                ops.limit = Some(1);
+                ops
+            })
+            .await?;
+
+        Ok(count > 0)
+    }
+    /// Returns the queued messages, oldest first.
+    pub async fn get_queued_messages(&self) -> Result<Vec<QueuedMessage>> {
+        let outbox = self.db.collection::<QueuedMessage>(OUTBOX);
+
+        let mut cursor = outbox
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "_id": 1 });
+                ops
+            })
+            .await?;
+
+        let mut queued = vec![];
+        while let Some(message) = cursor.next().await {
+            queued.push(message?);
+        }
+
+        Ok(queued)
+    }
+    /// Moves the queued message to the dead letters, along with the error
+    /// which prevents its delivery.
+    pub async fn dead_letter_message(&self, message: &QueuedMessage, error: &str) -> Result<()> {
+        let dead_letters = self.db.collection::<DeadLetter>(DEAD_LETTERS);
+
+        dead_letters
+            .insert_one(
+                DeadLetter {
+                    message: message.clone(),
+                    error: error.to_string(),
+                    failed_at: unix_time(),
+                },
+                None,
+            )
+            .await?;
+
+        self.remove_queued_message(message).await
+    }
+    pub async fn remove_queued_message(&self, message: &QueuedMessage) -> Result<()> {
+        let outbox = self.db.collection::<QueuedMessage>(OUTBOX);
+
+        outbox
+            .delete_one(
+                doc! {
+                    "_id": message.id,
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+    pub async fn insert_reminder(&self, reminder: &Reminder) -> Result<()> {
+        let reminders = self.db.collection::<Reminder>(REMINDERS);
+        reminders.insert_one(reminder, None).await?;
+
+        Ok(())
+    }
+    pub async fn get_due_reminders(&self) -> Result<Vec<Reminder>> {
+        let reminders = self.db.collection::<Reminder>(REMINDERS);
+
+        let mut cursor = reminders
+            .find(
+                doc! {
+                    "due": {
+                        "$lte": unix_time() as i64,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        let mut due = vec![];
+        while let Some(reminder) = cursor.next().await {
+            due.push(reminder?);
+        }
+
+        Ok(due)
+    }
+    pub async fn remove_reminder(&self, reminder: &Reminder) -> Result<()> {
+        let reminders = self.db.collection::<Reminder>(REMINDERS);
+
+        reminders
+            .delete_one(
+                doc! {
+                    "_id": reminder.id,
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Starts a mute, or extends or shortens the active one. Alerts held back
+    /// by the active mute are kept.
+    pub async fn upsert_mute(&self, route: &str, escalation_idx: usize, until: u64) -> Result<()> {
+        let mutes = self.db.collection::<MuteState>(MUTES);
+
+        mutes
+            .update_one(
+                doc! {},
+                doc! {
+                    "$set": {
+                        "route": route,
+                        "escalation_idx": to_bson(&escalation_idx)?,
+                        "until": to_bson(&until)?,
+                    }
+                },
+                {
+                    let mut ops = UpdateOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+    pub async fn get_mute(&self) -> Result<Option<MuteState>> {
+        let mutes = self.db.collection::<MuteState>(MUTES);
+        Ok(mutes
+            .find_one(doc! {}, {
+                let mut ops = FindOneOptions::default();
+                ops.projection = Some(doc! { "_id": 0 });
+                ops
+            })
+            .await?)
+    }
+    /// Adds the alerts to the active mute. Returns `false` if there is none,
+    /// i.e. the mute has expired in the meantime.
+    pub async fn hold_muted_alerts(&self, alerts: &[AlertContext]) -> Result<bool> {
+        let mutes = self.db.collection::<MuteState>(MUTES);
+
+        let res = mutes
+            .update_one(
+                doc! {},
+                doc! {
+                    "$push": {
+                        "alerts": {
+                            "$each": to_bson(alerts)?,
+                        }
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(res.matched_count > 0)
+    }
+    /// Ends the active mute, returning it along with the held back alerts.
+    pub async fn remove_mute(&self) -> Result<Option<MuteState>> {
+        let mutes = self.db.collection::<MuteState>(MUTES);
+        Ok(mutes.find_one_and_delete(doc! {}, None).await?)
+    }
+    /// Returns the routes stored via the admin API, ordered by name.
+    pub async fn get_routes(&self) -> Result<Vec<RouteConfig>> {
+        let routes = self.db.collection::<RouteConfig>(ROUTES);
+
+        let mut cursor = routes
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "name": 1 });
+                ops.projection = Some(doc! { "_id": 0 });
+                ops
+            })
+            .await?;
+
+        let mut stored = vec![];
+        while let Some(route) = cursor.next().await {
+            stored.push(route?);
+        }
+
+        Ok(stored)
+    }
+    /// Inserts the route or replaces the stored one of the same name.
+    pub async fn upsert_route(&self, route: &RouteConfig) -> Result<()> {
+        let routes = self.db.collection::<RouteConfig>(ROUTES);
+
+        routes
+            .replace_one(
+                doc! {
+                    "name": &route.name,
+                },
+                route,
+                {
+                    let mut ops = ReplaceOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Removes the stored route. Returns `false` if there was none.
+    pub async fn remove_route(&self, name: &str) -> Result<bool> {
+        let routes = self.db.collection::<RouteConfig>(ROUTES);
+
+        let res = routes
+            .delete_one(
+                doc! {
+                    "name": name,
+                },
+                None,
+            )
+            .await?;
+
+        Ok(res.deleted_count > 0)
+    }
+    pub async fn insert_api_key(&self, key: &ApiKey) -> Result<()> {
+        let keys = self.db.collection::<ApiKey>(API_KEYS);
+        keys.insert_one(key, None).await?;
+
+        Ok(())
+    }
+    /// Returns the user of the key with the given hash, if any.
+    pub async fn get_api_key_user(&self, key_hash: &str) -> Result<Option<String>> {
+        let keys = self.db.collection::<ApiKey>(API_KEYS);
+
+        let key = keys.find_one(doc! { "key_hash": key_hash }, None).await?;
+        Ok(key.map(|key| key.user))
+    }
+    pub async fn get_api_keys(&self) -> Result<Vec<ApiKeyInfo>> {
+        let keys = self.db.collection::<ApiKey>(API_KEYS);
+
+        let mut cursor = keys
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "user": 1, "created_at": 1 });
+                ops
+            })
+            .await?;
+
+        let mut stored = vec![];
+        while let Some(key) = cursor.next().await {
+            let key = key?;
+            stored.push(ApiKeyInfo {
+                user: key.user,
+                created_at: key.created_at,
+            });
+        }
+
+        Ok(stored)
+    }
+    /// Revokes all keys of the user. Returns `false` if there were none.
+    pub async fn remove_api_keys(&self, user: &str) -> Result<bool> {
+        let keys = self.db.collection::<ApiKey>(API_KEYS);
+
+        let res = keys.delete_many(doc! { "user": user }, None).await?;
+        Ok(res.deleted_count > 0)
+    }
+    pub async fn get_direct_room(&self, user: &str) -> Result<Option<String>> {
+        let rooms = self.db.collection::<DirectRoom>(DIRECT_ROOMS);
+
+        let room = rooms.find_one(doc! { "user": user }, None).await?;
+        Ok(room.map(|room| room.room_id))
+    }
+    pub async fn upsert_direct_room(&self, room: &DirectRoom) -> Result<()> {
+        let rooms = self.db.collection::<DirectRoom>(DIRECT_ROOMS);
+
+        rooms
+            .replace_one(doc! { "user": &room.user }, room, {
+                let mut ops = ReplaceOptions::default();
+                ops.upsert = Some(true);
+                ops
+            })
+            .await?;
+
+        Ok(())
+    }
+    pub async fn get_incident_rooms(&self) -> Result<Vec<IncidentRoom>> {
+        let rooms = self.db.collection::<IncidentRoom>(INCIDENT_ROOMS);
+
+        let mut cursor = rooms.find(doc! {}, None).await?;
+        let mut incidents = vec![];
+        while let Some(room) = cursor.next().await {
+            incidents.push(room?);
+        }
+
+        Ok(incidents)
+    }
+    pub async fn upsert_incident_room(&self, room: &IncidentRoom) -> Result<()> {
+        let rooms = self.db.collection::<IncidentRoom>(INCIDENT_ROOMS);
+
+        rooms
+            .replace_one(doc! { "alert": to_bson(&room.alert)? }, room, {
+                let mut ops = ReplaceOptions::default();
+                ops.upsert = Some(true);
+                ops
+            })
+            .await?;
+
+        Ok(())
+    }
+    pub async fn remove_incident_room(&self, alert: AlertId) -> Result<()> {
+        let rooms = self.db.collection::<IncidentRoom>(INCIDENT_ROOMS);
+
+        rooms
+            .delete_one(doc! { "alert": to_bson(&alert)? }, None)
+            .await?;
+
+        Ok(())
+    }
+    /// Returns the watches of the given user, or of all users.
+    pub async fn get_watches(&self, user: Option<&str>) -> Result<Vec<Watch>> {
+        let watches = self.db.collection::<Watch>(WATCHES);
+
+        let filter = match user {
+            Some(user) => doc! { "user": user },
+            None => doc! {},
+        };
+
+        let mut cursor = watches
+            .find(filter, {
+                let mut ops = FindOptions::default();
+                ops.projection = Some(doc! { "_id": 0 });
+                ops
+            })
+            .await?;
+
+        let mut stored = vec![];
+        while let Some(watch) = cursor.next().await {
+            stored.push(watch?);
+        }
+
+        Ok(stored)
+    }
+    /// Stores the watch, unless the user already watches the same labels.
+    pub async fn upsert_watch(&self, watch: &Watch) -> Result<()> {
+        let watches = self.db.collection::<Watch>(WATCHES);
+
+        watches
+            .replace_one(
+                doc! {
+                    "user": &watch.user,
+                    "labels": to_bson(&watch.labels)?,
+                },
+                watch,
+                {
+                    let mut ops = ReplaceOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Removes the watch of the given labels, or all watches of the user.
+    /// Returns the number of removed watches.
+    pub async fn remove_watches(
+        &self,
+        user: &str,
+        labels: Option<&BTreeMap<String, String>>,
+    ) -> Result<u64> {
+        let watches = self.db.collection::<Watch>(WATCHES);
+
+        let mut filter = doc! { "user": user };
+        if let Some(labels) = labels {
+            filter.insert("labels", to_bson(labels)?);
+        }
+
+        let res = watches.delete_many(filter, None).await?;
+        Ok(res.deleted_count)
+    }
+    pub async fn get_overrides(&self) -> Result<Vec<PolicyOverride>> {
+        let overrides = self.db.collection::<PolicyOverride>(OVERRIDES);
+
+        let mut cursor = overrides
+            .find(doc! {}, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "starts_at": 1 });
+                ops.projection = Some(doc! { "_id": 0 });
+                ops
+            })
+            .await?;
+
+        let mut stored = vec![];
+        while let Some(policy) = cursor.next().await {
+            stored.push(policy?);
+        }
+
+        Ok(stored)
+    }
+    /// Returns the override of the route which is active at the given time.
+    /// The latest one wins if several overlap.
+    pub async fn active_override(&self, route: &str, now: u64) -> Result<Option<PolicyOverride>> {
+        let overrides = self.db.collection::<PolicyOverride>(OVERRIDES);
+
+        let mut cursor = overrides
+            .find(
+                doc! {
+                    "route": route,
+                    "starts_at": { "$lte": to_bson(&now)? },
+                    "ends_at": { "$gt": to_bson(&now)? },
+                },
+                {
+                    let mut ops = FindOptions::default();
+                    ops.sort = Some(doc! { "starts_at": -1 });
+                    ops.projection = Some(doc! { "_id": 0 });
+                    ops.limit = Some(1);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(cursor.next().await.transpose()?)
+    }
+    /// Inserts the override or replaces the stored one of the same name.
+    pub async fn upsert_override(&self, policy: &PolicyOverride) -> Result<()> {
+        let overrides = self.db.collection::<PolicyOverride>(OVERRIDES);
+
+        overrides
+            .replace_one(
+                doc! {
+                    "name": &policy.name,
+                },
+                policy,
+                {
+                    let mut ops = ReplaceOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Removes the stored override. Returns `false` if there was none.
+    pub async fn remove_override(&self, name: &str) -> Result<bool> {
+        let overrides = self.db.collection::<PolicyOverride>(OVERRIDES);
+
+        let res = overrides
+            .delete_one(
+                doc! {
+                    "name": name,
+                },
+                None,
+            )
+            .await?;
+
+        Ok(res.deleted_count > 0)
+    }
+    /// Removes the overrides which have ended, returning their names.
+    pub async fn remove_expired_overrides(&self, now: u64) -> Result<Vec<String>> {
+        let expired: Vec<String> = self
+            .get_overrides()
+            .await?
+            .into_iter()
+            .filter(|policy| policy.ends_at <= now)
+            .map(|policy| policy.name)
+            .collect();
+
+        for name in &expired {
+            self.remove_override(name).await?;
+        }
+
+        Ok(expired)
+    }
+    /// Starts a deploy window, or extends or shortens the active one of the
+    /// same name. Alerts silenced by the active one are kept.
+    pub async fn upsert_deploy_window(&self, window: &ActiveDeployWindow) -> Result<()> {
+        let windows = self.db.collection::<ActiveDeployWindow>(DEPLOY_WINDOWS);
+
+        windows
+            .update_one(
+                doc! {
+                    "name": &window.name,
+                },
+                doc! {
+                    "$set": {
+                        "labels": to_bson(&window.labels)?,
+                        "routes": to_bson(&window.routes)?,
+                        "started_by": &window.started_by,
+                        "ends_at": to_bson(&window.ends_at)?,
+                    }
+                },
+                {
+                    let mut ops = UpdateOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Returns the stored deploy windows, including ended ones which have
+    /// not been removed yet.
+    pub async fn get_deploy_windows(&self) -> Result<Vec<ActiveDeployWindow>> {
+        self.find_deploy_windows(doc! {}).await
+    }
+    /// Returns the deploy windows which are active at the given time.
+    pub async fn active_deploy_windows(&self, now: u64) -> Result<Vec<ActiveDeployWindow>> {
+        self.find_deploy_windows(doc! {
+            "ends_at": { "$gt": to_bson(&now)? },
+        })
+        .await
+    }
+    async fn find_deploy_windows(&self, filter: bson::Document) -> Result<Vec<ActiveDeployWindow>> {
+        let windows = self.db.collection::<ActiveDeployWindow>(DEPLOY_WINDOWS);
+
+        let mut cursor = windows
+            .find(filter, {
+                let mut ops = FindOptions::default();
+                ops.sort = Some(doc! { "name": 1 });
+                ops.projection = Some(doc! { "_id": 0 });
+                ops
+            })
+            .await?;
+
+        let mut stored = vec![];
+        while let Some(window) = cursor.next().await {
+            stored.push(window?);
+        }
+
+        Ok(stored)
+    }
+    /// Adds the alert to the alerts silenced by the deploy window.
+    pub async fn add_silenced(&self, name: &str, alert: &Alert) -> Result<()> {
+        let windows = self.db.collection::<ActiveDeployWindow>(DEPLOY_WINDOWS);
+
+        windows
+            .update_one(
+                doc! {
+                    "name": name,
+                },
+                doc! {
+                    "$push": {
+                        "silenced": to_bson(alert)?,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Removes the deploy window if it has ended, returning it along with the
+    /// silenced alerts. Windows which were extended in the meantime are kept.
+    pub async fn remove_deploy_window(
+        &self,
+        name: &str,
+        now: u64,
+    ) -> Result<Option<ActiveDeployWindow>> {
+        let windows = self.db.collection::<ActiveDeployWindow>(DEPLOY_WINDOWS);
+
+        Ok(windows
+            .find_one_and_delete(
+                doc! {
+                    "name": name,
+                    "ends_at": { "$lte": to_bson(&now)? },
+                },
+                None,
+            )
+            .await?)
+    }
+    /// Records the notification unless it has already been recorded within
+    /// the dedup window. Returns `false` if the notification must not be sent.
+    pub async fn claim_notification(
+        &self,
+        key: &NotificationKey,
+        dedup_window: u64,
+    ) -> Result<bool> {
+        let notifications = self.db.collection::<NotificationRecord>(NOTIFICATIONS);
+        let now = unix_time();
+
+        // Only matches expired records. If a recent record exists, the upsert
+        // fails due to the unique index.
+        let res = notifications
+            .update_one(
+                doc! {
+                    "alert_id": to_bson(&key.alert_id)?,
+                    "channel": &key.channel,
+                    "escalation_idx": to_bson(&key.escalation_idx)?,
+                    "kind": &key.kind,
+                    "sent_at": {
+                        "$lt": now.saturating_sub(dedup_window) as i64,
+                    }
+                },
+                doc! {
+                    "$set": {
+                        "sent_at": now as i64,
+                        "state": to_bson(&DeliveryState::Sending)?,
+                    }
+                },
+                {
+                    let mut ops = UpdateOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(err) if is_duplicate_key(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+    /// Marks a claimed notification as sent, once the alert state reflects it.
+    pub async fn complete_notification(&self, key: &NotificationKey) -> Result<()> {
+        let notifications = self.db.collection::<NotificationRecord>(NOTIFICATIONS);
+
+        notifications
+            .update_one(
+                doc! {
+                    "alert_id": to_bson(&key.alert_id)?,
+                    "channel": &key.channel,
+                    "escalation_idx": to_bson(&key.escalation_idx)?,
+                    "kind": &key.kind,
+                },
+                doc! {
+                    "$set": {
+                        "state": to_bson(&DeliveryState::Sent)?,
+                    }
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+    /// Returns the notifications which are still being sent, i.e. those of a
+    /// previous process which stopped while sending them.
+    pub async fn get_inflight_notifications(&self) -> Result<Vec<NotificationRecord>> {
+        let notifications = self.db.collection::<NotificationRecord>(NOTIFICATIONS);
+
+        let mut cursor = notifications
+            .find(
+                doc! {
+                    "state": to_bson(&DeliveryState::Sending)?,
+                },
+                {
+                    let mut ops = FindOptions::default();
+                    ops.sort = Some(doc! { "sent_at": 1 });
+                    Some(ops)
+                },
+            )
+            .await?;
+
+        let mut records = vec![];
+        while let Some(record) = cursor.next().await {
+            records.push(record?);
+        }
+
+        Ok(records)
+    }
+    /// Records that the report is sent now, unless it has already been sent
+    /// within the period. Returns `false` if the report is not due yet.
+    pub async fn claim_report(&self, name: &str, period: u64, now: u64) -> Result<bool> {
+        let reports = self.db.collection::<SentReport>(REPORTS);
+
+        // Only matches reports sent before the period. If a recent one exists,
+        // the upsert fails due to the unique index.
+        let res = reports
+            .update_one(
+                doc! {
+                    "name": name,
+                    "sent_at": {
+                        "$lte": now.saturating_sub(period) as i64,
+                    }
+                },
+                doc! {
+                    "$set": {
+                        "sent_at": now as i64,
+                    }
+                },
+                {
+                    let mut ops = UpdateOptions::default();
+                    ops.upsert = Some(true);
+                    ops
+                },
+            )
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            Err(err) if is_duplicate_key(&err) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{alert_context, test_database};
+
+    #[test]
+    fn ack_filter_matches_route_and_scope() {
+        let filter = ack_filter("team-a", 1, AckScope::Strict, AlertId::from(5)).unwrap();
+        assert_eq!(
+            filter,
+            doc! {
+                "id": 5_i64,
+                "route": "team-a",
+                "escalation_idx": { "$lte": 1_i64 },
+            }
+        );
+
+        let filter = ack_filter(DEFAULT_ROUTE, 0, AckScope::FirstRoom, AlertId::from(5)).unwrap();
+        assert!(filter.contains_key("$or"));
+        assert!(!filter.contains_key("escalation_idx"));
+    }
+
+    #[test]
+    fn notification_records_of_older_versions_are_sent() {
+        let record: NotificationRecord = bson::from_document(doc! {
+            "alert_id": 1_i64,
+            "channel": "matrix",
+            "escalation_idx": 0_i64,
+            "kind": "escalation",
+            "sent_at": 10_i64,
+        })
+        .unwrap();
+
+        assert_eq!(record.state, DeliveryState::Sent);
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn concurrent_acks_succeed_once() {
+        let db = test_database().await;
+
+        let alert = alert_context(1, DEFAULT_ROUTE);
+        db.insert_alerts(&[alert]).await.unwrap();
+
+        // E.g. an ack in Matrix and one via the HTTP API.
+        let (first, second) = futures::join!(
+            db.acknowledge_alert(
+                DEFAULT_ROUTE,
+                0,
+                AckScope::Strict,
+                AlertId::from(1),
+                String::from("@alice:matrix.org"),
+            ),
+            db.acknowledge_alert(
+                DEFAULT_ROUTE,
+                0,
+                AckScope::Strict,
+                AlertId::from(1),
+                String::from("api-user"),
+            )
+        );
+
+        let confirmations = [first.unwrap(), second.unwrap()];
+        assert_eq!(
+            confirmations
+                .iter()
+                .filter(|c| **c == UserConfirmation::AlertAcknowledged(AlertId::from(1)))
+                .count(),
+            1
+        );
+        assert!(confirmations.contains(&UserConfirmation::AlertNotFound));
+
+        let history = db.export().await.unwrap().history;
+        assert_eq!(history.len(), 1);
+        assert!(db.get_pending(None).await.unwrap().is_empty());
+
+        db.drop_database().await.unwrap();
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn expired_acknowledgements_are_kept_as_history() {
+        let db = test_database().await;
+        let id = AlertId::from(1);
+
+        db.insert_acknowledged(&[AlertAcknowledged {
+            alert: alert_context(1, DEFAULT_ROUTE),
+            acked_by: String::from("@alice:matrix.org"),
+            acked_timestamp: 100,
+            resolved_by: None,
+            resolved_timestamp: None,
+            handed_off_to: None,
+            expired_timestamp: None,
+        }])
+        .await
+        .unwrap();
+
+        let expired = db.expire_acknowledgements(DEFAULT_ROUTE, 60).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(db.get_pending(None).await.unwrap().len(), 1);
+        // Only expires once.
+        assert!(db
+            .expire_acknowledgements(DEFAULT_ROUTE, 60)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let confirmation = db
+            .acknowledge_alert(
+                DEFAULT_ROUTE,
+                0,
+                AckScope::Strict,
+                id,
+                String::from("@bob:matrix.org"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(confirmation, UserConfirmation::AlertAcknowledged(id));
+
+        let confirmation = db
+            .resolve_alert(DEFAULT_ROUTE, id, String::from("@bob:matrix.org"))
+            .await
+            .unwrap();
+        assert_eq!(confirmation, UserConfirmation::AlertResolved(id));
+
+        let history = db.export().await.unwrap().history;
+        assert_eq!(history.len(), 2);
+        let (first, second) = if history[0].acked_timestamp == 100 {
+            (&history[0], &history[1])
+        } else {
+            (&history[1], &history[0])
+        };
+        assert_eq!(first.acked_by, "@alice:matrix.org");
+        assert!(first.expired_timestamp.is_some());
+        assert_eq!(first.resolved_timestamp, None);
+        assert_eq!(second.acked_by, "@bob:matrix.org");
+        assert_eq!(second.expired_timestamp, None);
+        assert!(second.resolved_timestamp.is_some());
+
+        db.drop_database().await.unwrap();
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn handoff_keeps_who_acknowledged() {
+        let db = test_database().await;
+
+        db.insert_alerts(&[alert_context(1, DEFAULT_ROUTE)])
+            .await
+            .unwrap();
+        db.acknowledge_alert(
+            DEFAULT_ROUTE,
+            0,
+            AckScope::Strict,
+            AlertId::from(1),
+            String::from("@alice:matrix.org"),
+        )
+        .await
+        .unwrap();
+
+        let handed_off = db
+            .handoff_alerts(DEFAULT_ROUTE, "@alice:matrix.org", "@bob:matrix.org")
+            .await
+            .unwrap();
+        assert_eq!(handed_off.len(), 1);
+        // Bob is responsible now, so can hand off the alert again.
+        assert!(db
+            .handoff_alerts(DEFAULT_ROUTE, "@alice:matrix.org", "@carol:matrix.org")
+            .await
+            .unwrap()
+            .is_empty());
+        let handed_off = db
+            .handoff_alerts(DEFAULT_ROUTE, "@bob:matrix.org", "@carol:matrix.org")
+            .await
+            .unwrap();
+        assert_eq!(handed_off.len(), 1);
+
+        let history = db.export().await.unwrap().history;
+        assert_eq!(history[0].acked_by, "@alice:matrix.org");
+        assert_eq!(
+            history[0].handed_off_to.as_deref(),
+            Some("@carol:matrix.org")
+        );
+
+        db.drop_database().await.unwrap();
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn deploy_windows_keep_silenced_alerts() {
+        let db = test_database().await;
+
+        let window = ActiveDeployWindow {
+            name: String::from("api v1.4.2"),
+            labels: [(String::from("job"), String::from("api"))].into(),
+            routes: vec![String::from(DEFAULT_ROUTE)],
+            started_by: String::from("ci"),
+            ends_at: 200,
+            silenced: vec![],
+        };
+        db.upsert_deploy_window(&window).await.unwrap();
+        db.add_silenced(&window.name, &alert_context(1, DEFAULT_ROUTE).alert)
+            .await
+            .unwrap();
+
+        // Extending the window keeps the silenced alerts.
+        let extended = ActiveDeployWindow {
+            ends_at: 300,
+            ..window.clone()
+        };
+        db.upsert_deploy_window(&extended).await.unwrap();
+        assert_eq!(db.active_deploy_windows(250).await.unwrap().len(), 1);
+        assert!(db.active_deploy_windows(300).await.unwrap().is_empty());
+
+        // The timer of the original end does not remove the extended window.
+        assert!(db
+            .remove_deploy_window(&window.name, 200)
+            .await
+            .unwrap()
+            .is_none());
+        let removed = db
+            .remove_deploy_window(&window.name, 300)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.silenced.len(), 1);
+        assert!(db.get_deploy_windows().await.unwrap().is_empty());
+
+        db.drop_database().await.unwrap();
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn reports_are_claimed_once_per_period() {
+        let db = test_database().await;
+        let week = 7 * 24 * 60 * 60;
+
+        assert!(db.claim_report("noise", week, 1_000).await.unwrap());
+        // E.g. after a restart.
+        assert!(!db.claim_report("noise", week, 2_000).await.unwrap());
+        assert!(db.claim_report("compliance", week, 2_000).await.unwrap());
+        assert!(db.claim_report("noise", week, 1_000 + week).await.unwrap());
+
+        db.drop_database().await.unwrap();
+    }
 }
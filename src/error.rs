@@ -0,0 +1,113 @@
+use actix::MailboxError;
+use matrix_sdk::{FromHttpResponseError, HttpError, ServerError};
+use reqwest::StatusCode;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub const MATRIX_ADAPTER: &str = "Matrix";
+pub const PROMETHEUS_ADAPTER: &str = "Prometheus";
+pub const SNS_ADAPTER: &str = "SNS";
+pub const ACK_WEBHOOK_ADAPTER: &str = "Ack webhook";
+pub const EXEC_ADAPTER: &str = "Exec hook";
+pub const ARCHIVE_ADAPTER: &str = "Archive";
+pub const TELEGRAM_ADAPTER: &str = "Telegram";
+pub const DISCORD_ADAPTER: &str = "Discord";
+pub const ZULIP_ADAPTER: &str = "Zulip";
+pub const MATTERMOST_ADAPTER: &str = "Mattermost";
+pub const OPSGENIE_ADAPTER: &str = "Opsgenie";
+pub const SNS_PUBLISH_ADAPTER: &str = "SNS topics";
+pub const VICTOROPS_ADAPTER: &str = "Splunk On-Call";
+pub const MQTT_ADAPTER: &str = "MQTT";
+pub const TWILIO_SMS_ADAPTER: &str = "SMS";
+pub const TWILIO_VOICE_ADAPTER: &str = "Voice";
+
+/// Errors of the service, grouped by their origin so callers can react to
+/// them without inspecting messages.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Invalid or unreadable configuration.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// Failure of the database.
+    #[error("Storage error: {0}")]
+    Storage(#[source] BoxError),
+    /// Failure of an external service, e.g. the Matrix client.
+    #[error("{name} error: {source}")]
+    Adapter {
+        name: &'static str,
+        #[source]
+        source: BoxError,
+    },
+    /// An action was refused, e.g. the bot may not post in a room or the
+    /// user may not run a command. Retrying does not help.
+    #[error("Permission denied: {0}")]
+    Permission(String),
+    /// Failure to serve the webhook API.
+    #[error("Webhook error: {0}")]
+    Webhook(#[source] std::io::Error),
+    /// Unexpected state or failure to reach another actor.
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl Error {
+    /// A failure of the external service of the adapter with the given name,
+    /// e.g. `MATRIX_ADAPTER`.
+    pub fn adapter<E: Into<BoxError>>(name: &'static str, err: E) -> Self {
+        Error::Adapter {
+            name,
+            source: err.into(),
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Self {
+        Error::Config(err.to_string())
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Self {
+        Error::Config(err.to_string())
+    }
+}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(err: mongodb::error::Error) -> Self {
+        Error::Storage(err.into())
+    }
+}
+
+impl From<bson::ser::Error> for Error {
+    fn from(err: bson::ser::Error) -> Self {
+        Error::Storage(err.into())
+    }
+}
+
+impl From<matrix_sdk::Error> for Error {
+    fn from(err: matrix_sdk::Error) -> Self {
+        if let matrix_sdk::Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(
+            ServerError::Known(api_err),
+        ))) = &err
+        {
+            if api_err.status_code == StatusCode::FORBIDDEN {
+                return Error::Permission(format!("{}: {}", MATRIX_ADAPTER, api_err));
+            }
+        }
+
+        Error::adapter(MATRIX_ADAPTER, err)
+    }
+}
+
+impl From<ruma::identifiers::Error> for Error {
+    fn from(err: ruma::identifiers::Error) -> Self {
+        Error::adapter(MATRIX_ADAPTER, err)
+    }
+}
+
+impl From<MailboxError> for Error {
+    fn from(err: MailboxError) -> Self {
+        Error::Internal(err.to_string())
+    }
+}
@@ -0,0 +1,315 @@
+use crate::chaos;
+use crate::error::EXEC_ADAPTER;
+use crate::matrix::parse_command;
+use crate::ordering::{KeyedQueue, Turn};
+use crate::processor::{AlertContext, Processor, UserAction};
+use crate::{AlertId, Error, Result, DEFAULT_ROUTE};
+use actix::SystemService;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::Command;
+
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// Runs a local command for every notification and accepts user actions on a
+/// unix socket, e.g. to page via `wall` or custom scripts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecConfig {
+    // Program and its arguments, run without a shell. The notification is
+    // passed as JSON on stdin.
+    command: Vec<String>,
+    // Seconds until the command is killed. Defaults to 30.
+    timeout: Option<u64>,
+    // Path of a unix socket which accepts actions, one JSON object per line,
+    // e.g. `{"user": "pager", "command": "ack 5"}`.
+    socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecEvent {
+    Alert,
+    Escalation,
+    SeverityRaised,
+}
+
+/// Passed to the command on stdin.
+#[derive(Serialize)]
+struct Notification<'a> {
+    event: ExecEvent,
+    alert: &'a AlertContext,
+}
+
+/// A command of a user, received on the socket.
+#[derive(Debug, Deserialize)]
+struct Action {
+    // Defaults to the default route.
+    route: Option<String>,
+    // The escalation level the command is issued from, which limits acks
+    // just like rooms do. Defaults to the first level.
+    #[serde(default)]
+    level: usize,
+    user: String,
+    // Same syntax as in Matrix, e.g. `ack 5`.
+    command: String,
+}
+
+/// Answered for every action, one JSON object per line.
+#[derive(Serialize)]
+struct ActionResponse {
+    ok: bool,
+    message: String,
+}
+
+pub struct ExecHook {
+    config: ExecConfig,
+    // The levels of each route which fan out to the hook. Routes without an
+    // entry fan out on all levels.
+    levels: HashMap<String, Vec<usize>>,
+    // Notifications of the same alert are run in order.
+    queue: KeyedQueue<AlertId>,
+}
+
+impl ExecHook {
+    pub fn new(config: ExecConfig) -> Result<Self> {
+        if config.command.is_empty() {
+            return Err(Error::Config(String::from("Exec hook command is empty")));
+        }
+
+        Ok(ExecHook {
+            config,
+            levels: HashMap::new(),
+            queue: Default::default(),
+        })
+    }
+    /// Limits the hook to the given levels of each route, see
+    /// `RouteConfig::exec_levels`.
+    pub fn with_levels(mut self, levels: HashMap<String, Vec<usize>>) -> Self {
+        self.levels = levels;
+        self
+    }
+    /// Whether the given level of the route fans out to the hook.
+    pub fn covers(&self, route: &str, level: usize) -> bool {
+        self.levels
+            .get(route)
+            .map(|levels| levels.contains(&level))
+            .unwrap_or(true)
+    }
+    /// Seconds until the command is killed, i.e. a run which started earlier
+    /// is no longer in progress.
+    pub fn timeout(&self) -> u64 {
+        self.config.timeout.unwrap_or(DEFAULT_TIMEOUT)
+    }
+    /// Reserves the turn of a notification of the alert, see `KeyedQueue`.
+    pub fn enqueue(&self, id: AlertId) -> Turn {
+        self.queue.enqueue([id])
+    }
+    /// Runs the command with the notification on stdin. Fails if the
+    /// command exits with an error or does not finish in time.
+    pub async fn run(&self, event: ExecEvent, alert: &AlertContext) -> Result<()> {
+        chaos::inject(EXEC_ADAPTER).await?;
+
+        let input = serde_json::to_vec(&Notification { event, alert })
+            .map_err(|err| Error::Internal(err.to_string()))?;
+
+        let mut child = Command::new(&self.config.command[0])
+            .args(&self.config.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| Error::adapter(EXEC_ADAPTER, err))?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let output = async move {
+            // Commands are not required to read the notification.
+            match stdin.write_all(&input).await {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err),
+                _ => drop(stdin),
+            }
+
+            child.wait_with_output().await
+        };
+
+        let timeout = self.timeout();
+        let output = actix::clock::timeout(Duration::from_secs(timeout), output)
+            .await
+            .map_err(|_| {
+                Error::adapter(
+                    EXEC_ADAPTER,
+                    format!("command timed out after {}s", timeout),
+                )
+            })?
+            .map_err(|err| Error::adapter(EXEC_ADAPTER, err))?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::adapter(
+                EXEC_ADAPTER,
+                format!(
+                    "command failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ))
+        }
+    }
+    /// Binds the action socket, if configured. A stale socket file of a
+    /// previous run is replaced.
+    pub fn bind(&self) -> Result<Option<UnixListener>> {
+        let path = match &self.config.socket {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).map_err(|err| Error::adapter(EXEC_ADAPTER, err))?;
+        info!("Accepting exec hook actions on {}", path);
+
+        Ok(Some(listener))
+    }
+}
+
+/// Accepts actions until the listener fails.
+pub async fn serve_actions(listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                actix::spawn(async move {
+                    if let Err(err) = handle_connection(stream).await {
+                        warn!("Failed to handle exec hook actions: {:?}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("Failed to accept exec hook actions: {:?}", err);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .map_err(|err| Error::adapter(EXEC_ADAPTER, err))?
+    {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match parse_action(&line) {
+            Ok(action) => {
+                info!("Received exec hook action: {:?}", action.command);
+                let confirmation = Processor::from_registry().send(action).await?;
+                ActionResponse {
+                    ok: true,
+                    message: confirmation.to_string(),
+                }
+            }
+            Err(message) => ActionResponse { ok: false, message },
+        };
+
+        let mut out =
+            serde_json::to_vec(&response).map_err(|err| Error::Internal(err.to_string()))?;
+        out.push(b'\n');
+        writer
+            .write_all(&out)
+            .await
+            .map_err(|err| Error::adapter(EXEC_ADAPTER, err))?;
+    }
+
+    Ok(())
+}
+
+fn parse_action(line: &str) -> std::result::Result<UserAction, String> {
+    let action: Action =
+        serde_json::from_str(line).map_err(|err| format!("Invalid action: {}", err))?;
+
+    let command = match parse_command(&action.command, &action.user) {
+        Some(Ok(command)) => command,
+        Some(Err(usage)) => return Err(usage),
+        None => return Err(format!("Unknown command: {}", action.command)),
+    };
+
+    Ok(UserAction {
+        route: action.route.unwrap_or_else(|| DEFAULT_ROUTE.to_string()),
+        escalation_idx: action.level,
+        command,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{AckTarget, Command as UserCommand};
+    use crate::testing::alert_context;
+    use crate::AlertId;
+
+    fn hook(command: &[&str]) -> ExecHook {
+        ExecHook::new(ExecConfig {
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            timeout: Some(5),
+            socket: None,
+        })
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn runs_command_with_notification() {
+        let out = std::env::temp_dir().join(format!("matrixbot-exec-{}", std::process::id()));
+        let script = format!("cat > {}", out.display());
+
+        hook(&["sh", "-c", &script])
+            .run(ExecEvent::Escalation, &alert_context(3, "team-a"))
+            .await
+            .unwrap();
+
+        let input: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+        std::fs::remove_file(&out).unwrap();
+
+        assert_eq!(input["event"], "escalation");
+        assert_eq!(input["alert"]["route"], "team-a");
+
+        let err = hook(&["sh", "-c", "echo broken >&2; exit 1"])
+            .run(ExecEvent::Alert, &alert_context(3, "team-a"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("broken"));
+    }
+
+    #[test]
+    fn parses_actions() {
+        let action = parse_action(r#"{"user": "pager", "command": "ack 5", "level": 1}"#).unwrap();
+        assert_eq!(
+            action,
+            UserAction {
+                route: DEFAULT_ROUTE.to_string(),
+                escalation_idx: 1,
+                command: UserCommand::Ack(AckTarget::Id(AlertId::from(5)), String::from("pager")),
+            }
+        );
+
+        assert!(parse_action(r#"{"user": "pager", "command": "reboot"}"#).is_err());
+        assert!(parse_action("ack 5").is_err());
+    }
+
+    #[test]
+    fn covers_configured_levels() {
+        let hook = hook(&["true"]).with_levels(HashMap::from([(String::from("team-a"), vec![1])]));
+
+        assert!(!hook.covers("team-a", 0));
+        assert!(hook.covers("team-a", 1));
+        assert!(hook.covers(DEFAULT_ROUTE, 0));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::webhook::{Alert, Annotations, Labels};
+use crate::DEFAULT_ROUTE;
+
+const DEFAULT_SEVERITY: &str = "critical";
+
+/// Enables `/webhook-healthchecks`, which accepts notifications of
+/// healthchecks.io webhook integrations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthchecksConfig {
+    // Healthchecks.io cannot send custom headers in all plans, so the token is
+    // passed as `?token=...`.
+    token: Option<String>,
+    // Defaults to `critical`.
+    severity: Option<String>,
+    // The first matching route is used, unmatched checks go to the default
+    // route.
+    #[serde(default)]
+    routes: Vec<CheckMatcher>,
+}
+
+/// Assigns checks to a route by name, e.g. `backup-*`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckMatcher {
+    #[serde(rename = "match")]
+    pattern: String,
+    route: String,
+}
+
+impl HealthchecksConfig {
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|matcher| matcher.route.as_str())
+    }
+    /// The route of the given check.
+    pub fn route(&self, check: &str) -> &str {
+        self.routes
+            .iter()
+            .find(|matcher| matches(&matcher.pattern, check))
+            .map(|matcher| matcher.route.as_str())
+            .unwrap_or(DEFAULT_ROUTE)
+    }
+    pub fn alert(&self, ping: HealthcheckPing) -> Alert {
+        Alert {
+            annotations: Annotations {
+                message: Some(format!("Check '{}' is {}", ping.name, ping.status)),
+                description: ping
+                    .tags
+                    .filter(|tags| !tags.is_empty())
+                    .map(|tags| format!("Tags: {}", tags)),
+                ..Default::default()
+            },
+            labels: Labels {
+                severity: self
+                    .severity
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SEVERITY.to_string()),
+                alert_name: ping.name,
+                team: None,
+                ..Default::default()
+            },
+            generator_url: None,
+        }
+    }
+}
+
+/// A notification of a healthchecks.io webhook integration. The body must be
+/// configured as `{"name": "$NAME", "status": "$STATUS", "tags": "$TAGS"}`.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct HealthcheckPing {
+    name: String,
+    // `down` or `up`.
+    status: String,
+    tags: Option<String>,
+}
+
+impl HealthcheckPing {
+    /// Only failing checks are alerts, recoveries are ignored.
+    pub fn is_down(&self) -> bool {
+        self.status.eq_ignore_ascii_case("down")
+    }
+}
+
+/// Matches a check name against a pattern with `*` wildcards.
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // `split` always yields at least one part.
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    for (idx, part) in parts.iter().enumerate() {
+        if idx + 1 == parts.len() {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    // No wildcard at all.
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_check_names() {
+        assert!(matches("backup-*", "backup-db"));
+        assert!(matches("*-db", "backup-db"));
+        assert!(matches("backup-*-daily", "backup-db-daily"));
+        assert!(matches("*", "anything"));
+        assert!(matches("exact", "exact"));
+        assert!(!matches("exact", "exactly"));
+        assert!(!matches("backup-*", "restore-db"));
+        assert!(!matches("backup-*-daily", "backup-db-weekly"));
+
+        let config: HealthchecksConfig =
+            serde_yaml::from_str("routes:\n  - match: backup-*\n    route: team-a\n").unwrap();
+        assert_eq!(config.route("backup-db"), "team-a");
+        assert_eq!(config.route("cron"), DEFAULT_ROUTE);
+    }
+}
@@ -0,0 +1,164 @@
+//! Options shared by all outbound HTTP clients, i.e. Matrix, the adapters,
+//! the ack webhook, the archive and Prometheus.
+use crate::{Error, Result};
+use std::fs;
+
+/// TLS options for on-prem services or TLS-intercepting proxies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    // PEM files of additional root certificates.
+    #[serde(default)]
+    pub ca_certs: Vec<String>,
+    // PEM files of the client certificate and its PKCS #8 private key.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpConfig {
+    // HTTP, HTTPS or SOCKS5 proxy URL.
+    pub proxy: Option<String>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl HttpConfig {
+    /// A client builder with the configured proxy and TLS options applied.
+    pub fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::Client::builder().user_agent("matrixbot-ack");
+
+        if let Some(tls) = &self.tls {
+            builder = apply_tls(builder, tls)?;
+        }
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|err| Error::Config(format!("Invalid proxy {}: {}", proxy, err)))?;
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(builder)
+    }
+    /// A TLS connector with the configured options, for connections which do
+    /// not use `reqwest`, e.g. the Discord gateway.
+    pub fn tls_connector(&self) -> Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(tls) = &self.tls {
+            for (path, pem) in tls.read_ca_certs()? {
+                let cert = native_tls::Certificate::from_pem(&pem).map_err(|err| {
+                    Error::Config(format!("Invalid CA certificate {}: {}", path, err))
+                })?;
+                builder.add_root_certificate(cert);
+            }
+
+            if let Some((cert, key)) = tls.read_identity()? {
+                let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|err| {
+                    Error::Config(format!("Invalid client certificate or key: {}", err))
+                })?;
+                builder.identity(identity);
+            }
+        }
+
+        builder
+            .build()
+            .map_err(|err| Error::Config(format!("Invalid TLS options: {}", err)))
+    }
+}
+
+fn apply_tls(
+    mut builder: reqwest::ClientBuilder,
+    tls: &TlsConfig,
+) -> Result<reqwest::ClientBuilder> {
+    for (path, pem) in tls.read_ca_certs()? {
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .map_err(|err| Error::Config(format!("Invalid CA certificate {}: {}", path, err)))?;
+        builder = builder.add_root_certificate(cert);
+    }
+
+    if let Some((cert, key)) = tls.read_identity()? {
+        let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key)
+            .map_err(|err| Error::Config(format!("Invalid client certificate or key: {}", err)))?;
+        builder = builder.identity(identity);
+    }
+
+    Ok(builder)
+}
+
+impl TlsConfig {
+    /// The paths and contents of the CA certificates.
+    fn read_ca_certs(&self) -> Result<Vec<(&str, Vec<u8>)>> {
+        self.ca_certs
+            .iter()
+            .map(|path| {
+                fs::read(path)
+                    .map(|pem| (path.as_str(), pem))
+                    .map_err(|err| {
+                        Error::Config(format!("Failed to read CA certificate {}: {}", path, err))
+                    })
+            })
+            .collect()
+    }
+    /// The client certificate and its key, if configured.
+    fn read_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let cert = fs::read(cert).map_err(|err| {
+                    Error::Config(format!(
+                        "Failed to read client certificate {}: {}",
+                        cert, err
+                    ))
+                })?;
+                let key = fs::read(key).map_err(|err| {
+                    Error::Config(format!("Failed to read client key {}: {}", key, err))
+                })?;
+                Ok(Some((cert, key)))
+            }
+            (None, None) => Ok(None),
+            _ => Err(Error::Config(String::from(
+                "Client certificate and key must be configured together",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_options() {
+        let missing_ca = HttpConfig {
+            proxy: None,
+            tls: Some(TlsConfig {
+                ca_certs: vec![String::from("/nonexistent/ca.pem")],
+                client_cert: None,
+                client_key: None,
+            }),
+        };
+        assert!(matches!(missing_ca.client_builder(), Err(Error::Config(_))));
+
+        let cert_without_key = HttpConfig {
+            proxy: None,
+            tls: Some(TlsConfig {
+                ca_certs: vec![],
+                client_cert: Some(String::from("/etc/matrixbot/client.pem")),
+                client_key: None,
+            }),
+        };
+        assert!(matches!(
+            cert_without_key.client_builder(),
+            Err(Error::Config(_))
+        ));
+
+        let invalid_proxy = HttpConfig {
+            proxy: Some(String::from("not a proxy")),
+            tls: None,
+        };
+        assert!(matches!(
+            invalid_proxy.client_builder(),
+            Err(Error::Config(_))
+        ));
+
+        assert!(HttpConfig::default().client_builder().is_ok());
+    }
+}
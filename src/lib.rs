@@ -1,30 +1,75 @@
 #[macro_use]
 extern crate log;
 #[macro_use]
-extern crate anyhow;
-#[macro_use]
 extern crate serde;
 #[macro_use]
 extern crate async_trait;
 
 use actix::{prelude::*, SystemRegistry};
+use chrono::NaiveDate;
+use ruma::{RoomId, UserId};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use structopt::StructOpt;
 use tokio::sync::mpsc::unbounded_channel;
 
+mod ack_webhook;
+mod adapter;
+mod archive;
+mod aws;
+mod backup;
+mod calendar;
+mod chaos;
+mod cloud;
 mod database;
+mod error;
+mod exec;
+mod healthchecks;
+mod http;
+mod logging;
 mod matrix;
+mod metrics;
+mod ordering;
 mod processor;
+mod prometheus;
+mod render;
+mod report;
+mod selftest;
+mod sentry;
+mod severity;
+mod sns;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod truncate;
 mod webhook;
 
-pub type Result<T> = std::result::Result<T, anyhow::Error>;
+pub use error::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
 
 const MIN_ESCALATION_WINDOW: u64 = 60; // 60 seconds
+const DEFAULT_ROUTE: &str = "default";
+const DEFAULT_DEDUP_WINDOW: u64 = 30; // 30 seconds
 
-#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    Hash,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Serialize,
+    Deserialize,
+    utoipa::ToSchema,
+)]
 pub struct AlertId(u64);
 
 impl AlertId {
-    fn from_str(str: &str) -> Result<Self> {
+    fn from_str(str: &str) -> std::result::Result<Self, std::num::ParseIntError> {
         Ok(AlertId(str.parse()?))
     }
 }
@@ -35,9 +80,9 @@ impl From<u64> for AlertId {
     }
 }
 
-impl ToString for AlertId {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl std::fmt::Display for AlertId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -51,13 +96,194 @@ fn unix_time() -> u64 {
         .as_secs()
 }
 
+fn unix_time_ms() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to calculate UNIX time")
+        .as_millis() as u64
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Config {
     database: Option<database::DatabaseConfig>,
     matrix: matrix::MatrixConfig,
+    // HTTP, HTTPS or SOCKS5 proxy URL for all outbound HTTP clients.
+    proxy: Option<String>,
+    // TLS options for all outbound HTTP clients, see `http::TlsConfig`.
+    tls: Option<http::TlsConfig>,
     listener: String,
+    #[serde(default)]
+    listeners: Vec<webhook::ListenerConfig>,
     escalation: Option<EscalationConfig>,
+    #[serde(default)]
+    rooms: Vec<String>,
+    // Space, entry levels, observers, ack scope, format, exec levels,
+    // priority and SLO handling of the default route, see `RouteConfig`.
+    space: Option<String>,
+    #[serde(default)]
+    observers: Vec<String>,
+    #[serde(default)]
+    severity_levels: HashMap<String, usize>,
+    #[serde(default)]
+    ack_scope: processor::AckScope,
+    ack_ttl: Option<u64>,
+    business_hours: Option<calendar::BusinessHoursConfig>,
+    #[serde(default)]
+    format: render::Format,
+    exec_levels: Option<Vec<usize>>,
+    #[serde(default)]
+    priority: i32,
+    slo: Option<processor::SloConfig>,
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+    // Whether an alert which is already pending on another route notifies
+    // the route receiving it: `notify_all` (default), `first_match` or
+    // `priority_route`.
+    #[serde(default)]
+    duplicate_policy: processor::DuplicatePolicy,
+    admin: Option<webhook::AdminConfig>,
+    // Accepts issue alerts from Sentry on `/webhook-sentry`.
+    sentry: Option<sentry::SentryConfig>,
+    // Accepts failing checks from healthchecks.io on `/webhook-healthchecks`.
+    healthchecks: Option<healthchecks::HealthchecksConfig>,
+    // Accepts AWS SNS notifications, e.g. of CloudWatch alarms, on
+    // `/webhook-sns`.
+    sns: Option<sns::SnsConfig>,
+    // Accepts alerts of Azure Monitor (common alert schema) on `/webhook-azure`.
+    azure: Option<cloud::CloudConfig>,
+    // Accepts incidents of Google Cloud Monitoring on `/webhook-gcp`.
+    gcp: Option<cloud::CloudConfig>,
+    // Identical webhook payloads are ignored within this window (seconds).
+    replay_window: Option<u64>,
+    // Matrix users which are allowed to run admin commands, e.g. `mute`.
+    #[serde(default)]
+    admins: Vec<String>,
+    severities: Option<severity::SeverityConfig>,
+    // Posts a weekly report of the noisiest alerts to the first room of each
+    // route. Requires a database.
+    #[serde(default)]
+    noise_report: bool,
+    // Posts a weekly CSV report of all alerts, their acks and escalations to
+    // a room. Requires a database.
+    compliance_report: Option<report::ComplianceReportConfig>,
+    // Adds the trend of the alert expression to notifications.
+    prometheus: Option<prometheus::PrometheusConfig>,
+    // Members of each team, mentioned on the first notification of alerts
+    // with the `team` label.
+    #[serde(default)]
+    teams: HashMap<String, matrix::TeamConfig>,
+    // Called whenever an alert is acknowledged or resolved.
+    ack_webhook: Option<ack_webhook::AckWebhookConfig>,
+    // Redaction of secrets and sampling of repetitive warnings in logs.
+    logging: Option<logging::LoggingConfig>,
+    // Runs a local command for every notification.
+    exec: Option<exec::ExecConfig>,
+    // Notifies Telegram chats, one per escalation level, and accepts commands
+    // such as `/ack 1` from them.
+    telegram: Option<adapter::telegram::TelegramConfig>,
+    // Notifies Discord channels, one per escalation level, and accepts text
+    // and slash commands from them.
+    discord: Option<adapter::discord::DiscordConfig>,
+    // Posts to Zulip stream topics, one per escalation level, and accepts
+    // commands sent to them.
+    zulip: Option<adapter::zulip::ZulipConfig>,
+    // Posts to Mattermost channels, one per escalation level, with buttons to
+    // acknowledge alerts, and accepts slash commands.
+    mattermost: Option<adapter::mattermost::MattermostConfig>,
+    // Creates alerts in Opsgenie, with the responders of each escalation
+    // level, and accepts acks from its webhook on `/webhook-opsgenie`.
+    opsgenie: Option<adapter::opsgenie::OpsgenieConfig>,
+    // Publishes alerts, escalations and acks to Amazon SNS topics, one per
+    // escalation level.
+    sns_publish: Option<adapter::sns::SnsPublishConfig>,
+    // Creates incidents in Splunk On-Call (VictorOps), routed by the routing
+    // key of each escalation level, and polls them for acks.
+    victorops: Option<adapter::victorops::VictorOpsConfig>,
+    // Inserts alerts published to an MQTT topic and publishes alerts,
+    // escalations and acks to event topics.
+    mqtt: Option<adapter::mqtt::MqttConfig>,
+    // Pages phone numbers by SMS via Twilio, e.g. on the final escalation
+    // levels, and accepts replies from its webhook on `/webhook-twilio-sms`.
+    twilio_sms: Option<adapter::twilio::TwilioSmsConfig>,
+    // Calls phone numbers via Twilio and reads the alerts, pressing 1
+    // acknowledges them. Keypresses are received on `/webhook-twilio-voice`.
+    twilio_voice: Option<adapter::twilio::voice::TwilioVoiceConfig>,
+    // Fails or delays adapter calls at random. Never enable in production.
+    chaos: Option<chaos::ChaosConfig>,
+    // Maximum lengths of annotations and messages, longer ones are truncated.
+    truncation: Option<truncate::TruncationConfig>,
+    // Moves old acknowledged alerts to S3-compatible object storage. Requires
+    // a database.
+    archive: Option<archive::ArchiveConfig>,
+}
+
+impl Config {
+    fn should_escalate(&self) -> bool {
+        self.escalation.as_ref().map(|c| c.enabled).unwrap_or(false)
+    }
+    fn escalation_window(&self) -> u64 {
+        self.escalation
+            .as_ref()
+            .map(|c| c.escalation_window)
+            .unwrap_or(MIN_ESCALATION_WINDOW)
+            .max(MIN_ESCALATION_WINDOW)
+    }
+    fn check_frequency(&self) -> u64 {
+        self.escalation
+            .as_ref()
+            .map(|c| c.check_frequency)
+            .unwrap_or(20)
+    }
+    fn dedup_window(&self) -> u64 {
+        self.escalation
+            .as_ref()
+            .and_then(|c| c.dedup_window)
+            .unwrap_or(DEFAULT_DEDUP_WINDOW)
+    }
+}
+
+/// An escalation chain. Alerts are assigned to a route by the webhook
+/// listener that received them.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct RouteConfig {
+    name: String,
+    #[serde(default)]
     rooms: Vec<String>,
+    // A Matrix space whose child rooms make up the escalation chain instead
+    // of `rooms`, ordered by the `order` of the children, then by name.
+    // Changes to the space apply within a minute.
+    space: Option<String>,
+    // Read-only rooms which receive all notifications of the route, e.g. for
+    // an audit trail. They are not escalation levels and reject commands.
+    #[serde(default)]
+    observers: Vec<String>,
+    // Alerts of the given severity enter the escalation chain at the given
+    // level (index of `rooms`) instead of the first one.
+    #[serde(default)]
+    severity_levels: HashMap<String, usize>,
+    // Which rooms may acknowledge alerts, defaults to `strict`.
+    #[serde(default)]
+    ack_scope: processor::AckScope,
+    // Acknowledged alerts which are not resolved within this TTL (seconds)
+    // return to pending. Requires escalations.
+    ack_ttl: Option<u64>,
+    // Outside these hours, alerts skip the first levels of the route.
+    business_hours: Option<calendar::BusinessHoursConfig>,
+    // Format of the notifications: `plain` (default), `markdown` or `html`.
+    #[serde(default)]
+    format: render::Format,
+    // Levels (indices of `rooms`) which fan out to the exec hook in addition
+    // to their room. Defaults to all levels.
+    exec_levels: Option<Vec<usize>>,
+    // With the `priority_route` duplicate policy, alerts pending on a route of
+    // the same or a higher priority are suppressed. Defaults to 0.
+    #[serde(default)]
+    priority: i32,
+    // Treats the alerts of the route as SLO burn-rate alerts, with their own
+    // escalation windows and summaries per burn type.
+    slo: Option<processor::SloConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,113 +291,1086 @@ struct EscalationConfig {
     enabled: bool,
     escalation_window: u64,
     check_frequency: u64,
+    // Escalations are never sent twice within this window.
+    dedup_window: Option<u64>,
+    #[serde(default)]
+    catch_up: processor::CatchUpPolicy,
+    adaptive: Option<processor::AdaptiveEscalation>,
+    // Warns the current level once this fraction of the window has passed.
+    warning: Option<f64>,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "matrixbot")]
 struct Cli {
+    /// Path to the config, in YAML, TOML (`.toml`) or JSON (`.json`).
     #[structopt(short, long)]
     config: String,
+    /// Start without processing alerts until promoted via the admin API.
+    #[structopt(long)]
+    standby: bool,
+    #[structopt(subcommand)]
+    cmd: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// Export pending alerts, the history and reminders to a file, gzip
+    /// compressed if it ends with `.gz`.
+    Backup {
+        #[structopt(long)]
+        out: PathBuf,
+    },
+    /// Restore a backup into an empty database.
+    Restore {
+        #[structopt(long)]
+        from: PathBuf,
+    },
+    /// Export the archived alerts of the given days (UTC, `YYYY-MM-DD`,
+    /// inclusive) to a file, as JSON lines.
+    ArchiveQuery {
+        #[structopt(long)]
+        from: NaiveDate,
+        #[structopt(long)]
+        to: NaiveDate,
+        #[structopt(long)]
+        out: PathBuf,
+    },
+    /// Restore the archived alerts of the given days into the history.
+    ArchiveRestore {
+        #[structopt(long)]
+        from: NaiveDate,
+        #[structopt(long)]
+        to: NaiveDate,
+    },
+}
+
+/// Parses the config in the format of its extension, i.e. `.toml`, `.json` or
+/// YAML otherwise.
+fn parse_config(path: &Path, content: &str) -> Result<Config> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => deserialize_config(
+            toml::from_str::<toml::Value>(content).map_err(|err| Error::Config(err.to_string()))?,
+        ),
+        Some("json") => deserialize_config(
+            serde_json::from_str::<serde_json::Value>(content)
+                .map_err(|err| Error::Config(err.to_string()))?,
+        ),
+        _ => deserialize_config(serde_yaml::from_str::<serde_yaml::Value>(content)?),
+    }
+}
+
+/// Unknown fields are rejected, all of them are reported at once with their
+/// path.
+fn deserialize_config<'de, D>(value: D) -> Result<Config>
+where
+    D: serde::Deserializer<'de>,
+    D::Error: std::fmt::Display,
+{
+    let mut problems = vec![];
+    let mut unknown = |path: serde_ignored::Path| {
+        // Optional values show up as `?` segments.
+        let path: Vec<String> = path
+            .to_string()
+            .split('.')
+            .filter(|segment| *segment != "?")
+            .map(String::from)
+            .collect();
+        problems.push(format!("Unknown field '{}'", path.join(".")));
+    };
+    let res =
+        serde_path_to_error::deserialize(serde_ignored::Deserializer::new(value, &mut unknown));
+
+    match res {
+        Ok(config) => check_problems(problems).map(|_| config),
+        Err(err) => {
+            problems.push(format!("{}: {}", err.path(), err.inner()));
+            Err(problems_error(problems))
+        }
+    }
+}
+
+/// Reports all problems at once, if any.
+fn check_problems(problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems_error(problems))
+    }
+}
+
+fn problems_error(mut problems: Vec<String>) -> Error {
+    if problems.len() == 1 {
+        return Error::Config(problems.remove(0));
+    }
+
+    Error::Config(format!(
+        "{} problems found:\n{}",
+        problems.len(),
+        problems
+            .iter()
+            .map(|problem| format!("  - {}", problem))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+fn problem(err: Error) -> String {
+    match err {
+        Error::Config(msg) => msg,
+        err => err.to_string(),
+    }
+}
+
+/// The routes referenced by listeners and integrations, along with what
+/// references them. They may also be stored via the admin API.
+fn route_references(config: &Config) -> Vec<(String, String)> {
+    let mut references = vec![];
+    let mut add = |subject: String, route: &str| references.push((subject, route.to_string()));
+
+    for listener in &config.listeners {
+        add(
+            format!("Listener '{}' references", listener.path()),
+            listener.route(),
+        );
+    }
+    if let Some(sentry) = &config.sentry {
+        add(String::from("Sentry webhook references"), sentry.route());
+    }
+    if let Some(healthchecks) = &config.healthchecks {
+        for route in healthchecks.routes() {
+            add(String::from("Healthchecks webhook references"), route);
+        }
+    }
+    if let Some(sns) = &config.sns {
+        add(String::from("SNS webhook references"), sns.route());
+    }
+    if let Some(telegram) = &config.telegram {
+        for route in telegram.routes() {
+            add(String::from("Telegram chats reference"), route);
+        }
+    }
+    if let Some(discord) = &config.discord {
+        for route in discord.routes() {
+            add(String::from("Discord channels reference"), route);
+        }
+    }
+    if let Some(zulip) = &config.zulip {
+        for route in zulip.routes() {
+            add(String::from("Zulip topics reference"), route);
+        }
+    }
+    if let Some(mattermost) = &config.mattermost {
+        for route in mattermost.routes() {
+            add(String::from("Mattermost channels reference"), route);
+        }
+    }
+    if let Some(opsgenie) = &config.opsgenie {
+        for route in opsgenie.routes() {
+            add(String::from("Opsgenie levels reference"), route);
+        }
+    }
+    if let Some(sns_publish) = &config.sns_publish {
+        for route in sns_publish.routes() {
+            add(String::from("SNS topics reference"), route);
+        }
+    }
+    if let Some(victorops) = &config.victorops {
+        for route in victorops.routes() {
+            add(String::from("Splunk On-Call routing keys reference"), route);
+        }
+    }
+    if let Some(mqtt) = &config.mqtt {
+        add(String::from("MQTT route reference"), mqtt.route());
+    }
+    if let Some(twilio_sms) = &config.twilio_sms {
+        for route in twilio_sms.routes() {
+            add(String::from("SMS numbers reference"), route);
+        }
+    }
+    if let Some(twilio_voice) = &config.twilio_voice {
+        for route in twilio_voice.routes() {
+            add(String::from("Voice numbers reference"), route);
+        }
+    }
+    for (name, cloud) in [("Azure", &config.azure), ("GCP", &config.gcp)] {
+        if let Some(cloud) = cloud {
+            add(format!("{} webhook references", name), cloud.route());
+        }
+    }
+
+    references
+}
+
+/// Checks that all referenced routes exist, see `route_references`.
+fn reference_problems(references: &[(String, String)], routes: &[RouteConfig]) -> Vec<String> {
+    references
+        .iter()
+        .filter(|(_, route)| !routes.iter().any(|r| r.name == *route))
+        .map(|(subject, route)| format!("{} unknown route '{}'", subject, route))
+        .collect()
+}
+
+/// Checks the config as a whole, see `route_problems` for the routes and
+/// `reference_problems` for references to them.
+fn config_problems(
+    config: &Config,
+    routes: &[RouteConfig],
+    severities: &severity::Severities,
+    standby: bool,
+) -> Vec<String> {
+    // The rooms of the default route are checked with the other routes.
+    let mut problems = route_problems(routes, severities, config.should_escalate());
+
+    for admin in &config.admins {
+        if UserId::try_from(admin.as_str()).is_err() {
+            problems.push(format!("Invalid Matrix user ID '{}' in admins", admin));
+        }
+    }
+
+    for (team, members) in &config.teams {
+        for user in members
+            .mentions
+            .iter()
+            .filter(|user| UserId::try_from(user.as_str()).is_err())
+        {
+            problems.push(format!(
+                "Invalid Matrix user ID '{}' in team '{}'",
+                user, team
+            ));
+        }
+    }
+
+    if let Some(incidents) = config.matrix.incident_rooms() {
+        if !severities.is_known(&incidents.min_severity) {
+            problems.push(format!(
+                "Unknown severity '{}' of incident rooms",
+                incidents.min_severity
+            ));
+        }
+
+        for user in incidents
+            .responders
+            .iter()
+            .filter(|user| UserId::try_from(user.as_str()).is_err())
+        {
+            problems.push(format!(
+                "Invalid Matrix user ID '{}' in incident responders",
+                user
+            ));
+        }
+    }
+
+    let escalation_window = config.escalation_window();
+    let dedup_window = config.dedup_window();
+
+    // Pending alerts are only checked once per check frequency.
+    if config.check_frequency() == 0 || config.check_frequency() > escalation_window {
+        problems.push(String::from(
+            "The check frequency must be between one second and the escalation window",
+        ));
+    }
+
+    // The final room is notified once per escalation window, which must not be
+    // suppressed.
+    if dedup_window >= escalation_window {
+        problems.push(String::from(
+            "The dedup window must be shorter than the escalation window",
+        ));
+    }
+
+    if let Some(warning) = config.escalation.as_ref().and_then(|c| c.warning) {
+        if warning <= 0.0 || warning >= 1.0 {
+            problems.push(String::from(
+                "The escalation warning must be a fraction of the window between 0 and 1",
+            ));
+        }
+    }
+
+    if let Some(adaptive) = config.escalation.as_ref().and_then(|c| c.adaptive) {
+        if adaptive.min_window < MIN_ESCALATION_WINDOW
+            || adaptive.min_window <= dedup_window
+            || adaptive.min_window > escalation_window
+        {
+            problems.push(String::from(
+                "The adaptive minimum window must be between the dedup and the escalation window",
+            ));
+        }
+    }
+
+    if standby && config.admin.is_none() {
+        problems.push(String::from(
+            "Standby mode requires an admin configuration, which isn't provided",
+        ));
+    }
+
+    if config.archive.is_some() && config.database.is_none() {
+        problems.push(String::from(
+            "Archives require a database configuration, which isn't provided",
+        ));
+    }
+
+    if let Some(report) = &config.compliance_report {
+        if config.database.is_none() {
+            problems.push(String::from(
+                "Compliance reports require a database configuration, which isn't provided",
+            ));
+        }
+
+        if RoomId::try_from(report.room.as_str()).is_err() {
+            problems.push(format!(
+                "Invalid compliance report room ID '{}'",
+                report.room
+            ));
+        }
+    }
+
+    if config.noise_report && config.database.is_none() {
+        problems.push(String::from(
+            "Noise reports require a database configuration, which isn't provided",
+        ));
+    }
+
+    if config.should_escalate() && config.database.is_none() {
+        problems.push(String::from(
+            "Escalations require a database configuration, which isn't provided",
+        ));
+    }
+
+    problems
+}
+
+/// Checks the routes, including those stored via the admin API, against each
+/// other and the configured severities.
+fn validate_routes(
+    routes: &[RouteConfig],
+    severities: &severity::Severities,
+    should_escalate: bool,
+) -> Result<()> {
+    check_problems(route_problems(routes, severities, should_escalate))
+}
+
+fn route_problems(
+    routes: &[RouteConfig],
+    severities: &severity::Severities,
+    should_escalate: bool,
+) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (idx, route) in routes.iter().enumerate() {
+        match &route.space {
+            None if route.rooms.is_empty() => {
+                problems.push(format!(
+                    "No alert rooms have been configured for route '{}'",
+                    route.name
+                ));
+            }
+            Some(_) if !route.rooms.is_empty() => {
+                problems.push(format!(
+                    "Route '{}' configures both rooms and a space",
+                    route.name
+                ));
+            }
+            Some(space) if RoomId::try_from(space.as_str()).is_err() => {
+                problems.push(format!(
+                    "Invalid space ID '{}' in route '{}'",
+                    space, route.name
+                ));
+            }
+            _ => {}
+        }
+
+        // The rooms of spaces are only known at runtime.
+        let exceeds = |level: usize| route.space.is_none() && level >= route.rooms.len();
+
+        if let Some((severity, level)) = route
+            .severity_levels
+            .iter()
+            .find(|(_, level)| exceeds(**level))
+        {
+            problems.push(format!(
+                "Level {} of severity '{}' exceeds the rooms of route '{}'",
+                level, severity, route.name
+            ));
+        }
+
+        if let Some(hours) = &route.business_hours {
+            match calendar::BusinessHours::new(hours.clone()) {
+                Ok(hours) if exceeds(hours.after_hours_level()) => {
+                    problems.push(format!(
+                        "After hours level {} exceeds the rooms of route '{}'",
+                        hours.after_hours_level(),
+                        route.name
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => problems.push(format!("Route '{}': {}", route.name, problem(err))),
+            }
+        }
+
+        if let Some(level) = route
+            .exec_levels
+            .iter()
+            .flatten()
+            .find(|level| exceeds(**level))
+        {
+            problems.push(format!(
+                "Exec level {} exceeds the rooms of route '{}'",
+                level, route.name
+            ));
+        }
+
+        if let Some(severity) = route
+            .severity_levels
+            .keys()
+            .find(|severity| !severities.is_known(severity))
+        {
+            problems.push(format!(
+                "Route '{}' references unknown severity '{}'",
+                route.name, severity
+            ));
+        }
+
+        if let Some(slo) = &route.slo {
+            if !severities.is_known(slo.fast_severity()) {
+                problems.push(format!(
+                    "SLO of route '{}' references unknown severity '{}'",
+                    route.name,
+                    slo.fast_severity()
+                ));
+            }
+
+            if slo.fast_window == Some(0) || slo.slow_window == Some(0) {
+                problems.push(format!(
+                    "SLO escalation windows of route '{}' must not be zero",
+                    route.name
+                ));
+            }
+        }
+
+        for room in &route.rooms {
+            if RoomId::try_from(room.as_str()).is_err() {
+                problems.push(format!(
+                    "Invalid room ID '{}' in route '{}'",
+                    room, route.name
+                ));
+            }
+
+            if routes[..idx].iter().any(|r| r.rooms.contains(room)) {
+                problems.push(format!("Room {} is assigned to multiple routes", room));
+            }
+        }
+
+        // Observers may be shared between routes, but must not escalate.
+        for room in &route.observers {
+            if RoomId::try_from(room.as_str()).is_err() {
+                problems.push(format!(
+                    "Invalid observer room ID '{}' in route '{}'",
+                    room, route.name
+                ));
+            }
+
+            if routes.iter().any(|r| r.rooms.contains(room)) {
+                problems.push(format!(
+                    "Observer room {} of route '{}' is also an escalation room",
+                    room, route.name
+                ));
+            }
+        }
+
+        if routes[..idx].iter().any(|r| r.name == route.name) {
+            problems.push(format!(
+                "Route '{}' is configured more than once",
+                route.name
+            ));
+        }
+    }
+
+    if !should_escalate && routes.iter().any(|route| route.ack_ttl.is_some()) {
+        problems.push(String::from(
+            "Acknowledgement TTLs require escalations to be enabled",
+        ));
+    }
+
+    problems
+}
+
+/// Layers the routes stored via the admin API onto the configured ones. Stored
+/// routes replace configured routes of the same name.
+/// The top-level rooms or space make up the default route.
+fn default_route(config: &Config) -> RouteConfig {
+    RouteConfig {
+        name: DEFAULT_ROUTE.to_string(),
+        rooms: config.rooms.clone(),
+        space: config.space.clone(),
+        observers: config.observers.clone(),
+        severity_levels: config.severity_levels.clone(),
+        ack_scope: config.ack_scope,
+        ack_ttl: config.ack_ttl,
+        business_hours: config.business_hours.clone(),
+        format: config.format,
+        exec_levels: config.exec_levels.clone(),
+        priority: config.priority,
+        slo: config.slo.clone(),
+    }
+}
+
+fn layer_routes(configured: &[RouteConfig], stored: Vec<RouteConfig>) -> Vec<RouteConfig> {
+    let mut routes: Vec<RouteConfig> = configured
+        .iter()
+        .filter(|route| !stored.iter().any(|r| r.name == route.name))
+        .cloned()
+        .collect();
+
+    routes.extend(stored);
+    routes
 }
 
 pub async fn run() -> Result<()> {
     let cli = Cli::from_args();
 
-    env_logger::builder()
-        .filter_module("system", log::LevelFilter::Debug)
-        .init();
+    logging::init();
 
     info!("Logger initialized");
 
     info!(
         "Opening config at {}",
-        std::fs::canonicalize(&cli.config)?
+        std::fs::canonicalize(&cli.config)
+            .map_err(|err| Error::Config(format!("Failed to open config: {}", err)))?
             .to_str()
-            .ok_or_else(|| anyhow!("Path to config is not valid unicode"))?
+            .ok_or_else(|| Error::Config(String::from("Path to config is not valid unicode")))?
     );
 
-    let content = std::fs::read_to_string(&cli.config)?;
-    let config: Config = serde_yaml::from_str(&content)?;
+    let content = std::fs::read_to_string(&cli.config)
+        .map_err(|err| Error::Config(format!("Failed to read config: {}", err)))?;
+    let config = parse_config(Path::new(&cli.config), &content)?;
 
-    if config.rooms.is_empty() {
-        return Err(anyhow!("No alert rooms have been configured"));
+    let mut problems = vec![];
+    if let Err(err) = logging::configure(
+        &config.logging.clone().unwrap_or_default(),
+        &config.matrix.passwords(),
+    ) {
+        problems.push(problem(err));
     }
 
-    // Retrieve relevant escalation data.
-    let should_escalate = config
-        .escalation
-        .as_ref()
-        .map(|c| c.enabled)
-        .unwrap_or(false);
-
-    let escalation_window = config
-        .escalation
-        .as_ref()
-        .map(|c| c.escalation_window)
-        .unwrap_or(MIN_ESCALATION_WINDOW)
-        .max(MIN_ESCALATION_WINDOW);
-
-    let check_frequency = config
-        .escalation
-        .as_ref()
-        .map(|c| c.check_frequency)
-        .unwrap_or(20);
-
-    if should_escalate && config.database.is_none() {
-        return Err(anyhow!(
-            "Escalations require a database configuration, which isn't provided"
-        ));
+    if let Some(chaos) = config.chaos.clone() {
+        if let Err(err) = chaos::configure(chaos) {
+            problems.push(problem(err));
+        }
+    }
+
+    if let Err(err) = truncate::configure(&config.truncation.clone().unwrap_or_default()) {
+        problems.push(problem(err));
     }
 
+    let severities = match config.severities.clone().map(severity::Severities::new) {
+        Some(Ok(severities)) => severities,
+        Some(Err(err)) => {
+            problems.push(problem(err));
+            Default::default()
+        }
+        None => Default::default(),
+    };
+
+    let mut routes = vec![default_route(&config)];
+    routes.extend(config.routes.clone());
+
+    problems.extend(config_problems(&config, &routes, &severities, cli.standby));
+    check_problems(problems)?;
+    // Checked once stored routes are known.
+    let references = route_references(&config);
+
+    // Retrieve relevant escalation data.
+    let should_escalate = config.should_escalate();
+    let escalation_window = config.escalation_window();
+    let check_frequency = config.check_frequency();
+    let dedup_window = config.dedup_window();
+    let adaptive = config.escalation.as_ref().and_then(|c| c.adaptive);
+
     let opt_db = if let Some(db_conf) = config.database {
         info!("Setting up database {:?}", db_conf);
         let db = database::Database::new(db_conf).await?;
         db.connectivity_check().await?;
 
-        Some(db)
+        Some(Arc::new(db))
     } else {
         warn!("Skipping database setup");
         None
     };
 
+    let http = http::HttpConfig {
+        proxy: config.proxy.clone(),
+        tls: config.tls.clone(),
+    };
+
+    let archiver = match config.archive.clone() {
+        Some(archive) => Some(Arc::new(archive::Archiver::new(archive, &http)?)),
+        None => None,
+    };
+
+    if let Some(cmd) = cli.cmd {
+        let db = opt_db.ok_or_else(|| {
+            Error::Config(String::from(
+                "Backups require a database configuration, which isn't provided",
+            ))
+        })?;
+        let require_archiver = || {
+            archiver.clone().ok_or_else(|| {
+                Error::Config(String::from(
+                    "Archive commands require an archive configuration, which isn't provided",
+                ))
+            })
+        };
+
+        return match cmd {
+            Subcommand::Backup { out } => backup::backup(&db, &out).await,
+            Subcommand::Restore { from } => backup::restore(&db, &from).await,
+            Subcommand::ArchiveQuery { from, to, out } => {
+                let archiver = require_archiver()?;
+                archive::query(&archiver, from, to, &out).await
+            }
+            Subcommand::ArchiveRestore { from, to } => {
+                let archiver = require_archiver()?;
+                archive::restore(&db, &archiver, from, to).await
+            }
+        };
+    }
+
+    // Routes stored via the admin API are layered onto the configured ones,
+    // listeners and integrations may reference either.
+    let configured_routes = routes.clone();
+    if let Some(db) = &opt_db {
+        let stored = db.get_routes().await?;
+        if !stored.is_empty() {
+            info!(
+                "Applying {} route(s) stored via the admin API",
+                stored.len()
+            );
+            routes = layer_routes(&configured_routes, stored);
+            validate_routes(&routes, &severities, should_escalate)?;
+        }
+    }
+
+    check_problems(reference_problems(&references, &routes))?;
+
     // Setup channels for shutdown signals. The Processor and the API server
     // task (below) hold the _sender_. Any message sent to it indicates a full shutdown
     // of the service, which is handled at the end of this function.
     let (tx, mut recv) = unbounded_channel();
 
+    let ack_webhook = match config.ack_webhook.clone() {
+        Some(ack_webhook) => Some(ack_webhook::AckWebhook::new(ack_webhook, &http)?),
+        None => None,
+    };
+
+    let exec = match config.exec.clone() {
+        Some(exec) => Some(Arc::new(
+            exec::ExecHook::new(exec)?.with_levels(
+                routes
+                    .iter()
+                    .filter_map(|route| {
+                        route
+                            .exec_levels
+                            .clone()
+                            .map(|levels| (route.name.clone(), levels))
+                    })
+                    .collect(),
+            ),
+        )),
+        None => None,
+    };
+
+    let telegram = match config.telegram.clone() {
+        Some(telegram) => Some(Arc::new(adapter::telegram::Telegram::new(telegram, &http)?)),
+        None => None,
+    };
+
+    let discord = match config.discord.clone() {
+        Some(discord) => Some(Arc::new(adapter::discord::Discord::new(discord, &http)?)),
+        None => None,
+    };
+
+    let zulip = match config.zulip.clone() {
+        Some(zulip) => Some(Arc::new(adapter::zulip::Zulip::new(zulip, &http)?)),
+        None => None,
+    };
+
+    let mattermost = match config.mattermost.clone() {
+        Some(mattermost) => {
+            let mattermost = adapter::mattermost::Mattermost::new(mattermost, &http)?;
+            if mattermost.token().is_none() {
+                warn!(
+                    "No token configured for Mattermost, buttons and slash commands are disabled"
+                );
+            }
+            Some(Arc::new(mattermost))
+        }
+        None => None,
+    };
+
+    let opsgenie = match config.opsgenie.clone() {
+        Some(opsgenie) => {
+            let opsgenie = adapter::opsgenie::Opsgenie::new(opsgenie, &http)?;
+            if opsgenie.token().is_none() {
+                warn!("No token configured for Opsgenie, acks in Opsgenie are not received");
+            }
+            Some(Arc::new(opsgenie))
+        }
+        None => None,
+    };
+
+    let sns_publish = match config.sns_publish.clone() {
+        Some(sns_publish) => Some(Arc::new(adapter::sns::SnsPublish::new(sns_publish, &http)?)),
+        None => None,
+    };
+
+    let victorops = match config.victorops.clone() {
+        Some(victorops) => {
+            let victorops = adapter::victorops::VictorOps::new(victorops, &http)?;
+            if !victorops.polls_acks() {
+                warn!(
+                    "No API credentials configured for Splunk On-Call, its acks are not received"
+                );
+            }
+            Some(Arc::new(victorops))
+        }
+        None => None,
+    };
+
+    let mqtt = match config.mqtt.clone() {
+        Some(mqtt) => Some(Arc::new(adapter::mqtt::Mqtt::new(mqtt)?)),
+        None => None,
+    };
+
+    let twilio_sms = match config.twilio_sms.clone() {
+        Some(twilio_sms) => Some(Arc::new(adapter::twilio::TwilioSms::new(
+            twilio_sms, &http,
+        )?)),
+        None => None,
+    };
+
+    let twilio_voice = match config.twilio_voice.clone() {
+        Some(twilio_voice) => Some(Arc::new(adapter::twilio::voice::TwilioVoice::new(
+            twilio_voice,
+            &http,
+        )?)),
+        None => None,
+    };
+
+    let mut adapters: Vec<Arc<dyn adapter::Adapter>> = vec![];
+    if let Some(telegram) = &telegram {
+        adapters.push(Arc::clone(telegram) as _);
+    }
+    if let Some(discord) = &discord {
+        adapters.push(Arc::clone(discord) as _);
+    }
+    if let Some(zulip) = &zulip {
+        adapters.push(Arc::clone(zulip) as _);
+    }
+    if let Some(mattermost) = &mattermost {
+        adapters.push(Arc::clone(mattermost) as _);
+    }
+    if let Some(opsgenie) = &opsgenie {
+        adapters.push(Arc::clone(opsgenie) as _);
+    }
+    if let Some(sns_publish) = sns_publish {
+        adapters.push(sns_publish as _);
+    }
+    if let Some(victorops) = &victorops {
+        adapters.push(Arc::clone(victorops) as _);
+    }
+    if let Some(mqtt) = &mqtt {
+        adapters.push(Arc::clone(mqtt) as _);
+    }
+    if let Some(twilio_sms) = &twilio_sms {
+        adapters.push(Arc::clone(twilio_sms) as _);
+    }
+    if let Some(twilio_voice) = &twilio_voice {
+        adapters.push(Arc::clone(twilio_voice) as _);
+    }
+
     info!("Adding message processor to system registry");
     let proc = processor::Processor::new(
-        opt_db,
-        escalation_window,
-        should_escalate,
-        check_frequency,
+        opt_db.clone(),
+        processor::EscalationSettings {
+            enabled: should_escalate,
+            window: escalation_window,
+            check_frequency,
+            dedup_window,
+            catch_up: config
+                .escalation
+                .as_ref()
+                .map(|c| c.catch_up)
+                .unwrap_or_default(),
+            entry_levels: routes
+                .iter()
+                .map(|route| (route.name.clone(), route.severity_levels.clone()))
+                .collect(),
+            duplicates: config.duplicate_policy,
+            priorities: routes
+                .iter()
+                .map(|route| (route.name.clone(), route.priority))
+                .collect(),
+            severities: severities.clone(),
+            ack_scopes: routes
+                .iter()
+                .map(|route| (route.name.clone(), route.ack_scope))
+                .collect(),
+            ack_ttls: routes
+                .iter()
+                .filter_map(|route| route.ack_ttl.map(|ttl| (route.name.clone(), ttl)))
+                .collect(),
+            rooms: routes
+                .iter()
+                .map(|route| (route.name.clone(), route.rooms.clone()))
+                .collect(),
+            business_hours: routes
+                .iter()
+                .filter_map(|route| {
+                    route.business_hours.clone().map(|hours| {
+                        calendar::BusinessHours::new(hours).map(|hours| (route.name.clone(), hours))
+                    })
+                })
+                .collect::<Result<_>>()?,
+            adaptive,
+            warning: config.escalation.as_ref().and_then(|c| c.warning),
+            noise_report: config.noise_report,
+            compliance_room: config
+                .compliance_report
+                .as_ref()
+                .map(|report| report.room.clone()),
+            configured_routes,
+            slos: routes
+                .iter()
+                .filter_map(|route| route.slo.clone().map(|slo| (route.name.clone(), slo)))
+                .collect(),
+        },
+        cli.standby,
+        config.admins.clone(),
         tx.clone(),
-    );
+    )
+    .with_ack_webhook(ack_webhook)
+    .with_exec(exec.clone())
+    .with_archiver(archiver)
+    .with_adapters(adapter::Adapters::new(adapters).with_database(opt_db.clone()))
+    .with_route_references(references.into_iter().map(|(_, route)| route).collect());
     SystemRegistry::set(proc.start());
 
+    if let Some(listener) = exec.map(|exec| exec.bind()).transpose()?.flatten() {
+        actix::spawn(exec::serve_actions(listener));
+    }
+
+    if let Some(telegram) = telegram {
+        actix::spawn(telegram.serve_commands());
+    }
+
+    if let Some(discord) = &discord {
+        if let Err(err) = discord.register_commands().await {
+            error!("Failed to register Discord slash commands: {:?}", err);
+        }
+        actix::spawn(Arc::clone(discord).serve_commands());
+    }
+
+    if let Some(zulip) = zulip {
+        actix::spawn(zulip.serve_commands());
+    }
+
+    if let Some(victorops) = victorops.filter(|victorops| victorops.polls_acks()) {
+        actix::spawn(victorops.poll_acks());
+    }
+
+    if let Some(mqtt) = mqtt {
+        actix::spawn(mqtt.serve());
+    }
+
+    let prometheus = match config.prometheus.clone() {
+        Some(prometheus) => Some(prometheus::Prometheus::new(prometheus, &http)?),
+        None => None,
+    };
+
+    let sns = match config.sns.clone() {
+        Some(sns) => Some(sns::Sns::new(sns, &http)?),
+        None => None,
+    };
+
     info!("Initializing Matrix client");
-    // Only handle user commands if escalations are enabled.
-    let matrix = matrix::MatrixClient::new(&config.matrix, config.rooms, should_escalate).await?;
+    // Only handle user commands if escalations are enabled. A standby
+    // instance starts syncing once it gets promoted.
+    let matrix = matrix::MatrixClient::new(
+        &config.matrix.clone().with_default_http(&http),
+        &routes,
+        opt_db,
+        should_escalate,
+        !cli.standby,
+    )
+    .await?
+    .with_prometheus(prometheus)
+    .with_teams(config.teams.clone())
+    .with_severities(severities);
 
     SystemRegistry::set(matrix.start());
 
     info!("Starting API server");
-    let tx_api = tx.clone();
-    let server = webhook::run_api_server(&config.listener).await?;
+    let servers = webhook::run_api_server(
+        &config.listener,
+        config.listeners,
+        config.admin,
+        webhook::Integrations {
+            sentry: config.sentry,
+            healthchecks: config.healthchecks,
+            sns,
+            azure: config.azure,
+            gcp: config.gcp,
+            discord,
+            mattermost,
+            opsgenie,
+            twilio_sms,
+            twilio_voice,
+        },
+        config.replay_window,
+    )
+    .await?;
 
-    // Run server in seperate task, send a shutdown signal in case of an error.
-    tokio::spawn(async move {
-        if let Err(err) = server.await {
-            error!("Failed to run API server: {:?}", err);
-            tx_api.send(()).unwrap();
-        }
-    });
+    // Run servers in seperate tasks, send a shutdown signal in case of an error.
+    for server in servers {
+        let tx_api = tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Failed to run API server: {:?}", err);
+                tx_api.send(()).unwrap();
+            }
+        });
+    }
 
     // On shutdown signal, shutdown service.
-    while let Some(_) = recv.recv().await {
+    if recv.recv().await.is_some() {
         warn!("Shutting down service...");
-        return Ok(());
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "config.sample.yaml";
+
+    #[test]
+    fn reports_all_config_problems() {
+        let content = include_str!("../config.sample.yaml").replacen(
+            "  # incident_rooms:\n  #   min_severity: critical\n",
+            "  incident_rooms:\n    min_severity: urgent\n    responders: [oncall]\n",
+            1,
+        );
+        let config = parse_config(Path::new(SAMPLE), &content).unwrap();
+        let severities = severity::Severities::new(config.severities.clone().unwrap()).unwrap();
+        let mut routes = vec![config.routes[0].clone(), config.routes[0].clone()];
+        routes[0].rooms = vec![String::from("not-a-room"), String::from("!abc:matrix.org")];
+        routes[1].name = String::from("team-b");
+        routes[1].rooms.clear();
+        routes[1].exec_levels = Some(vec![1]);
+        let mut spaced = routes[1].clone();
+        spaced.name = String::from("team-c");
+        spaced.rooms = vec![String::from("!abc:matrix.org")];
+        spaced.space = Some(String::from("!space:matrix.org"));
+        routes.push(spaced);
+
+        let problems = config_problems(&config, &routes, &severities, false);
+        assert!(problems.contains(&String::from(
+            "Invalid room ID 'not-a-room' in route 'team-a'"
+        )));
+        assert!(problems.iter().any(|p| p.starts_with("No alert rooms")));
+        assert!(problems.contains(&String::from(
+            "Exec level 1 exceeds the rooms of route 'team-b'"
+        )));
+        assert!(problems.contains(&String::from(
+            "Route 'team-c' configures both rooms and a space"
+        )));
+        assert!(problems.contains(&String::from("Unknown severity 'urgent' of incident rooms")));
+        assert!(problems.contains(&String::from(
+            "Invalid Matrix user ID 'oncall' in incident responders"
+        )));
+
+        let content = include_str!("../config.sample.yaml")
+            .replacen("listener:", "listenr:", 1)
+            .replacen("escalation_window:", "escalation_windw:", 1);
+        let err = parse_config(Path::new(SAMPLE), &content)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Unknown field 'listenr'"), "{}", err);
+        assert!(
+            err.contains("Unknown field 'escalation.escalation_windw'"),
+            "{}",
+            err
+        );
+        assert!(err.contains("missing field"), "{}", err);
+    }
+
+    #[test]
+    fn default_route_may_use_a_space() {
+        let mut config =
+            parse_config(Path::new(SAMPLE), include_str!("../config.sample.yaml")).unwrap();
+        let severities = severity::Severities::new(config.severities.clone().unwrap()).unwrap();
+        config.rooms.clear();
+        config.space = Some(String::from("!space:matrix.org"));
+
+        let routes = vec![default_route(&config), config.routes[0].clone()];
+        assert_eq!(
+            config_problems(&config, &routes, &severities, false),
+            Vec::<String>::new()
+        );
+
+        // Neither rooms nor a space.
+        config.space = None;
+        let routes = vec![default_route(&config)];
+        assert_eq!(
+            config_problems(&config, &routes, &severities, false),
+            vec![String::from(
+                "No alert rooms have been configured for route 'default'"
+            )]
+        );
+    }
+
+    #[test]
+    fn references_may_use_stored_routes() {
+        let mut config =
+            parse_config(Path::new(SAMPLE), include_str!("../config.sample.yaml")).unwrap();
+        config.listeners[0] = serde_yaml::from_str(
+            "{endpoint: 127.0.0.1:8001, path: /webhook-ack/team-z, route: team-z}",
+        )
+        .unwrap();
+
+        let references = route_references(&config);
+        let mut routes = config.routes.clone();
+        assert!(
+            reference_problems(&references, &routes).contains(&String::from(
+                "Listener '/webhook-ack/team-z' references unknown route 'team-z'"
+            ))
+        );
+
+        // E.g. stored via the admin API.
+        let mut stored = routes[0].clone();
+        stored.name = String::from("team-z");
+        routes.push(stored);
+        assert!(reference_problems(&references, &routes)
+            .iter()
+            .all(|problem| !problem.contains("team-z")));
+    }
+
+    #[test]
+    fn parses_toml_and_json_configs() {
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(include_str!("../config.sample.yaml")).unwrap();
+
+        let json = serde_json::to_string(&value).unwrap();
+        let config = parse_config(Path::new("config.json"), &json).unwrap();
+        assert_eq!(config.routes[0].name, "team-a");
+
+        let toml = toml::to_string(&value).unwrap();
+        let config = parse_config(Path::new("config.toml"), &toml).unwrap();
+        assert_eq!(config.routes[0].name, "team-a");
+
+        assert!(parse_config(Path::new("config.toml"), "listener = ").is_err());
+    }
+}
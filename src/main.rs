@@ -1,6 +1,11 @@
-use system::{run, Result};
+use system::run;
 
 #[actix_web::main]
-async fn main() -> Result<()> {
-    run().await
+async fn main() {
+    // Errors are displayed rather than debug printed, so that config
+    // problems are readable.
+    if let Err(err) = run().await {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
@@ -1,360 +1,3757 @@
+use crate::chaos::{self, InjectedFault};
+use crate::database::{Database, DirectRoom, IncidentRoom};
+use crate::error::MATRIX_ADAPTER;
+use crate::http::{HttpConfig, TlsConfig};
+use crate::ordering::KeyedQueue;
 use crate::processor::{
-    AlertContextTrimmed, Command, Escalation, NotifyAlert, Processor, UserAction,
+    command_info, format_time, AckExpired, AckTarget, AlertContext, AlertRefired, CatchUpSummary,
+    Command, ComplianceReport, DeployNotice, Escalation, EscalationWarning, GetAlert,
+    IncidentResolved, MuteExpired, NoiseReport, NotifyAlert, Processor, RemindAlert, RemoteAck,
+    SeverityRaised, SpaceRooms, UserAction, UserConfirmation,
 };
-use crate::{AlertId, Result};
+use crate::prometheus::Prometheus;
+use crate::render::{Format, Message, Section};
+use crate::selftest::Check;
+use crate::severity::Severities;
+use crate::truncate;
+use crate::webhook::{Alert, AnnotationChange, Labels};
+use crate::{unix_time, AlertId, Error, Result, RouteConfig};
 use actix::prelude::*;
 use actix::SystemService;
+use futures::FutureExt;
 use matrix_sdk::events::room::message::MessageEventContent;
 use matrix_sdk::events::SyncMessageEvent;
 use matrix_sdk::room::{Joined, Room};
-use matrix_sdk::{Client, ClientConfig, EventHandler, SyncSettings};
+use matrix_sdk::{
+    Client, ClientConfig, EventHandler, FromHttpResponseError, HttpError, LoopCtrl, RequestConfig,
+    ServerError, SyncSettings,
+};
+use ruma::api::client::r0::membership::leave_room;
+use ruma::api::client::r0::room::create_room;
+use ruma::api::client::r0::state::get_state_events;
 use ruma::events::room::message::{MessageType, TextMessageEventContent};
 use ruma::events::AnyMessageEventContent;
-use ruma::RoomId;
+use ruma::{RoomId, UserId};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::sync::Arc;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
 use url::Url;
 
+const DEFAULT_LOGIN_TIMEOUT: u64 = 30;
+// Requests are retried indefinitely by default, which would block messages
+// during homeserver outages instead of queuing them.
+const REQUEST_RETRY_LIMIT: u64 = 5;
+// Seconds between attempts to deliver queued messages.
+const OUTBOX_FLUSH_INTERVAL: u64 = 10;
+// Longer lists of pending alerts are sent as a file, with a summary of the
+// first alerts.
+const PENDING_ATTACHMENT_THRESHOLD: usize = 20;
+const PENDING_SUMMARY_SIZE: usize = 5;
+const PENDING_ATTACHMENT_NAME: &str = "pending-alerts.json";
+// Seconds to wait before restarting a failed sync, doubled on every
+// consecutive failure.
+const SYNC_RESTART_DELAY: u64 = 5;
+const MAX_SYNC_RESTART_DELAY: u64 = 300;
+// A sync that ran at least this many seconds resets the restart delay.
+const SYNC_STABLE_AFTER: u64 = 600;
+// Seconds without a successful sync after which the homeserver counts as
+// unreachable, failing over to the next account.
+const SYNC_STALE_AFTER: u64 = 120;
+// Consecutive transient failures to send messages after which the next
+// account takes over.
+const FAILOVER_THRESHOLD: usize = 3;
+// Seconds between fetches of the children of spaces.
+const SPACE_REFRESH_INTERVAL: u64 = 60;
+const SPACE_CHILD_EVENT: &str = "m.space.child";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MatrixConfig {
-    homeserver: String,
+    // Discovered via `.well-known` from the user ID in `username` if not set.
+    homeserver: Option<String>,
     username: String,
     password: String,
     db_path: String,
     device_name: String,
     device_id: String,
+    // HTTP, HTTPS or SOCKS5 proxy URL, overrides the global proxy.
+    proxy: Option<String>,
+    tls: Option<TlsConfig>,
+    // Seconds to wait for login before failing over to the next account.
+    login_timeout: Option<u64>,
+    // Accounts which are tried in order if this one cannot log in, or once
+    // its homeserver becomes unreachable.
+    #[serde(default)]
+    fallbacks: Vec<MatrixConfig>,
+    // Additionally notifies watchers and team members of new alerts via
+    // direct messages. Rooms are created on first use.
+    #[serde(default)]
+    direct_messages: bool,
+    // Replies to commands which only concern the sender (e.g. `pending` or
+    // `help`) are sent via direct message instead of to the room. Acks and
+    // other changes of alerts are still confirmed in the room.
+    #[serde(default)]
+    private_responses: bool,
+    // Opens a room per major incident, see `IncidentRoomsConfig`.
+    incident_rooms: Option<IncidentRoomsConfig>,
 }
 
-#[derive(Clone)]
-pub struct MatrixClient {
-    rooms: Arc<Vec<RoomId>>,
-    client: Arc<Client>,
+/// Rooms of major incidents, i.e. alerts at or above a severity. The room is
+/// created on the first notification of the alert, receives all further
+/// notifications of it and accepts commands as the room of its current
+/// level. The bot leaves the room once the alert is resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncidentRoomsConfig {
+    // E.g. `critical`.
+    pub min_severity: String,
+    // Matrix user IDs invited to every incident room, in addition to the
+    // members of the alert's team and its watchers.
+    #[serde(default)]
+    pub responders: Vec<String>,
 }
 
-impl MatrixClient {
-    pub async fn new(
-        config: &MatrixConfig,
-        rooms: Vec<String>,
-        handle_user_command: bool,
-    ) -> Result<Self> {
-        info!("Setting up Matrix client");
-        // Setup client
-        let client_config = ClientConfig::new().store_path(&config.db_path);
-
-        let url = Url::parse(&config.homeserver)?;
-        let client = Client::new_with_config(url, client_config)?;
+/// Members of a team, mentioned when an alert with the `team` label is
+/// first notified, regardless of the room it is sent to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamConfig {
+    // Matrix user IDs.
+    pub mentions: Vec<String>,
+}
 
-        info!("Logging in with credentials...");
-        client
-            .login(
-                &config.username,
-                &config.password,
-                Some(&config.device_id),
-                Some(&config.device_name),
-            )
-            .await?;
+impl MatrixConfig {
+    pub fn incident_rooms(&self) -> Option<&IncidentRoomsConfig> {
+        self.incident_rooms.as_ref()
+    }
+    /// Passwords of this account and its fallbacks, which are redacted from
+    /// logs.
+    pub fn passwords(&self) -> Vec<&str> {
+        let mut passwords = vec![self.password.as_str()];
+        for fallback in &self.fallbacks {
+            passwords.extend(fallback.passwords());
+        }
+        passwords
+    }
+    /// Uses the given proxy and TLS options, unless they are configured for
+    /// Matrix.
+    pub fn with_default_http(mut self, http: &HttpConfig) -> Self {
+        self.proxy = self.proxy.or_else(|| http.proxy.clone());
+        self.tls = self.tls.or_else(|| http.tls.clone());
+        self.fallbacks = self
+            .fallbacks
+            .into_iter()
+            .map(|fallback| fallback.with_default_http(http))
+            .collect();
+        self
+    }
+}
 
-        // Sync up, avoid responding to old messages.
-        info!("Syncing client");
-        client.sync_once(SyncSettings::default()).await?;
+/// Sets up a client for the given account and logs in.
+async fn login(config: &MatrixConfig) -> Result<Client> {
+    chaos::inject(MATRIX_ADAPTER).await?;
 
-        debug!("Attempting to parse room ids");
-        let rooms: Vec<RoomId> = rooms
-            .into_iter()
-            .map(|room| RoomId::try_from(room).map_err(|err| err.into()))
-            .collect::<Result<Vec<RoomId>>>()?;
+    let mut client_config = ClientConfig::new()
+        .store_path(&config.db_path)
+        .request_config(RequestConfig::new().retry_limit(REQUEST_RETRY_LIMIT));
+    if config.tls.is_some() {
+        // The proxy must be set on the client too, since it replaces the one
+        // built by the SDK.
+        info!("Using custom TLS options for Matrix requests");
+        let http = HttpConfig {
+            proxy: config.proxy.clone(),
+            tls: config.tls.clone(),
+        };
+        let client = http
+            .client_builder()?
+            .build()
+            .map_err(|err| Error::adapter(MATRIX_ADAPTER, err))?;
+        client_config = client_config.client(Arc::new(client));
+    } else if let Some(proxy) = &config.proxy {
+        info!("Using proxy for Matrix requests");
+        client_config = client_config.proxy(proxy)?;
+    }
 
-        // Add event handler
-        if handle_user_command {
-            client
-                .set_event_handler(Box::new(Listener {
-                    rooms: rooms.clone(),
-                }))
-                .await;
+    let client = match &config.homeserver {
+        Some(homeserver) => Client::new_with_config(Url::parse(homeserver)?, client_config)?,
+        None => {
+            info!("Discovering homeserver of {}", config.username);
+            let user_id = UserId::try_from(config.username.as_str())?;
+            Client::new_from_user_id_with_config(user_id, client_config).await?
         }
+    };
 
-        // Start backend syncing service
-        info!("Executing background sync");
-        let settings = SyncSettings::default().token(
-            client
-                .sync_token()
-                .await
-                .ok_or_else(|| anyhow!("Failed to acquire sync token"))?,
-        );
+    info!("Logging in with credentials...");
+    client
+        .login(
+            &config.username,
+            &config.password,
+            Some(&config.device_id),
+            Some(&config.device_name),
+        )
+        .await?;
 
-        // Sync in background.
-        let t_client = client.clone();
-        actix::spawn(async move {
-            t_client.clone().sync(settings).await;
-        });
+    // Sync up, avoid responding to old messages.
+    info!("Syncing client");
+    client.sync_once(SyncSettings::default()).await?;
 
-        Ok(MatrixClient {
-            rooms: Arc::new(rooms),
-            client: Arc::new(client),
-        })
-    }
+    Ok(client)
 }
 
-/// Convenience trait.
-#[async_trait]
-trait SendMsg {
-    async fn send_msg(&self, room_id: &RoomId, msg: &str) -> Result<()>;
+/// Fails if the future does not complete within the login timeout of the
+/// account. Requests are retried, which would otherwise delay failing over.
+async fn within_login_timeout<T>(
+    account: &MatrixConfig,
+    f: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let timeout = account.login_timeout.unwrap_or(DEFAULT_LOGIN_TIMEOUT);
+    actix::clock::timeout(Duration::from_secs(timeout), f)
+        .await
+        .unwrap_or_else(|_| {
+            Err(Error::adapter(
+                MATRIX_ADAPTER,
+                format!("login timed out after {}s", timeout),
+            ))
+        })
 }
 
-// Implement for matrix client.
-#[async_trait]
-impl SendMsg for Client {
-    async fn send_msg(&self, room_id: &RoomId, msg: &str) -> Result<()> {
-        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(msg));
-
-        self.room_send(room_id, content, None).await?;
-
-        Ok(())
-    }
+/// The configured accounts and the client of the active one. Once the
+/// homeserver of the active account is unreachable, the next account takes
+/// over, wrapping around to the first one.
+struct Homeserver {
+    accounts: Vec<MatrixConfig>,
+    active: AtomicUsize,
+    client: RwLock<Arc<Client>>,
+    // Clients of the accounts which logged in, reused when failing back since
+    // their stores can only be opened once. Held while failing over.
+    clients: futures::lock::Mutex<HashMap<usize, Arc<Client>>>,
+    // Consecutive transient failures of the active client.
+    failures: AtomicUsize,
+    // Handles user commands, set on every active client once syncing.
+    listener: RwLock<Option<Listener>>,
+    // Notified when the active client is replaced.
+    replaced: Notify,
 }
 
-impl Default for MatrixClient {
-    fn default() -> Self {
-        panic!("Matrix client was not initialized in system registry. This is a bug.");
+impl Homeserver {
+    fn new(accounts: Vec<MatrixConfig>, active: usize, client: Client) -> Self {
+        let client = Arc::new(client);
+        Homeserver {
+            accounts,
+            active: AtomicUsize::new(active),
+            client: RwLock::new(Arc::clone(&client)),
+            clients: futures::lock::Mutex::new(std::iter::once((active, client)).collect()),
+            failures: AtomicUsize::new(0),
+            listener: RwLock::new(None),
+            replaced: Notify::new(),
+        }
     }
-}
+    /// The client of the active account.
+    fn client(&self) -> Arc<Client> {
+        Arc::clone(&self.client.read().unwrap())
+    }
+    /// Handles user commands with the active client and any client taking
+    /// over later on.
+    async fn set_listener(&self, listener: Listener) {
+        *self.listener.write().unwrap() = Some(listener.clone());
+        self.client().set_event_handler(Box::new(listener)).await;
+    }
+    /// Replaces the failed client with the one of the next account which is
+    /// reachable. Returns whether the failed client is no longer active.
+    async fn failover(&self, failed: &Arc<Client>) -> bool {
+        let mut clients = self.clients.lock().await;
+        // Another caller failed over in the meantime.
+        if !Arc::ptr_eq(failed, &self.client()) {
+            return true;
+        }
 
-impl Actor for MatrixClient {
-    type Context = Context<Self>;
-}
+        let active = self.active.load(Ordering::SeqCst);
+        for offset in 1..self.accounts.len() {
+            let idx = (active + offset) % self.accounts.len();
+            let account = &self.accounts[idx];
 
-/// Handler for alerts on first entry, when the webhook gets called by the
-/// Watcher. Can be either an escalating or non-escalating alert.
-impl Handler<NotifyAlert> for MatrixClient {
-    type Result = ResponseActFuture<Self, Result<()>>;
+            // Clients which were active before catch up instead.
+            let res = match clients.get(&idx) {
+                Some(client) => {
+                    let client = Arc::clone(client);
+                    within_login_timeout(account, async {
+                        let settings = match client.sync_token().await {
+                            Some(token) => SyncSettings::default().token(token),
+                            None => SyncSettings::default(),
+                        };
+                        client.sync_once(settings).await?;
+                        Ok(())
+                    })
+                    .await
+                    .map(|()| client)
+                }
+                None => within_login_timeout(account, login(account))
+                    .await
+                    .map(Arc::new),
+            };
 
-    fn handle(&mut self, notify: NotifyAlert, _ctx: &mut Self::Context) -> Self::Result {
-        let client = Arc::clone(&self.client);
-        let rooms = Arc::clone(&self.rooms);
+            match res {
+                Ok(client) => {
+                    clients.insert(idx, Arc::clone(&client));
+                    let listener = self.listener.read().unwrap().clone();
+                    if let Some(listener) = listener {
+                        client.set_event_handler(Box::new(listener)).await;
+                    }
 
-        let f = async move {
-            if notify.alerts.is_empty() {
-                return Ok(());
-            }
+                    *self.client.write().unwrap() = client;
+                    self.active.store(idx, Ordering::SeqCst);
+                    self.failures.store(0, Ordering::SeqCst);
+                    self.replaced.notify_waiters();
 
-            let current_room_id = rooms.get(0).unwrap_or_else(|| rooms.last().unwrap());
+                    warn!(
+                        "Failed over from Matrix account {} to {}",
+                        self.accounts[active].username, account.username
+                    );
+                    return true;
+                }
+                Err(err) => warn!(
+                    "Failed to fail over to Matrix account {}: {:?}",
+                    account.username, err
+                ),
+            }
+        }
 
-            let mut msg = String::from("⚠️ Alert occurred!\n\n");
+        // Another round of failures is required before trying again.
+        self.failures.store(0, Ordering::SeqCst);
+        false
+    }
+}
 
-            // Send alerts to room.
-            for alert in notify.alerts {
-                let content = if alert.should_escalate() {
-                    alert.to_string()
-                } else {
-                    // If the alert should not escalate, send trimmed version (no Id).
-                    AlertContextTrimmed::from(alert).to_string()
-                };
+/// Sends via the active client, failing over after consecutive transient
+/// failures. The message is sent again if another account took over.
+#[async_trait]
+impl SendMsg for Homeserver {
+    async fn send_rendered(&self, room_id: &RoomId, msg: &Message) -> Result<()> {
+        let client = self.client();
+        let res = client.send_rendered(room_id, msg).await;
 
-                msg.push_str(&format!("{}\n\n", content));
+        match &res {
+            Err(err) if is_transient(err) => {
+                let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+                if failures >= FAILOVER_THRESHOLD && self.failover(&client).await {
+                    return self.client().send_rendered(room_id, msg).await;
+                }
             }
+            // The homeserver is reachable, even if it rejected the message.
+            _ => self.failures.store(0, Ordering::SeqCst),
+        }
 
-            msg.pop();
-            msg.pop();
+        res
+    }
+}
 
-            client.send_msg(current_room_id, &msg).await
-        };
+/// An escalation chain, the rooms ordered by escalation level. Never empty.
+#[derive(Debug, Clone)]
+struct Levels {
+    rooms: Vec<RoomId>,
+    // Read-only rooms which receive every notification of the route.
+    observers: Vec<RoomId>,
+    format: Format,
+}
 
-        Box::pin(f.into_actor(self))
+impl Levels {
+    fn new(route: &str, rooms: Vec<RoomId>, observers: Vec<RoomId>) -> Result<Self> {
+        if rooms.is_empty() {
+            return Err(Error::Config(format!(
+                "No rooms configured for route '{}'",
+                route
+            )));
+        }
+
+        Ok(Levels {
+            rooms,
+            observers,
+            format: Format::default(),
+        })
+    }
+    fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+    /// Renders a notification in the format of the route.
+    fn render(&self, header: &str, sections: &[Section]) -> Message {
+        self.format.renderer().render(header, sections)
+    }
+    fn last_idx(&self) -> usize {
+        self.rooms.len() - 1
+    }
+    /// Escalation indexes beyond the final room map to the final room.
+    fn clamp(&self, idx: usize) -> usize {
+        idx.min(self.last_idx())
+    }
+    /// Returns the room of the escalation index, see `clamp`.
+    fn room(&self, idx: usize) -> &RoomId {
+        &self.rooms[self.clamp(idx)]
+    }
+    fn contains(&self, room_id: &RoomId) -> bool {
+        self.rooms.contains(room_id)
+    }
+    /// Iterates over the escalation indexes and their rooms.
+    fn iter(&self) -> impl Iterator<Item = (usize, &RoomId)> {
+        self.rooms.iter().enumerate()
+    }
+    fn observers(&self) -> &[RoomId] {
+        &self.observers
     }
 }
 
-/// Handler for escalations triggered by the Processor event loop. *Must* only
-/// process escalating alerts or an error is returned.
-impl Handler<Escalation> for MatrixClient {
-    type Result = ResponseActFuture<Self, Result<bool>>;
-
-    fn handle(&mut self, notify: Escalation, _ctx: &mut Self::Context) -> Self::Result {
-        let client = Arc::clone(&self.client);
-        let rooms = Arc::clone(&self.rooms);
+/// The escalation chains of each route. The chains of routes backed by a
+/// space change with the children of the space.
+#[derive(Debug, Default)]
+struct Routes {
+    levels: RwLock<HashMap<String, Levels>>,
+    // The space of each route backed by one.
+    spaces: HashMap<String, RoomId>,
+}
 
-        let f = async move {
-            if notify.alerts.is_empty() {
-                return Ok(false);
-            }
+impl Routes {
+    fn new(levels: HashMap<String, Levels>, spaces: HashMap<String, RoomId>) -> Self {
+        Routes {
+            levels: RwLock::new(levels),
+            spaces,
+        }
+    }
+    fn rooms(&self, route: &str) -> Result<Levels> {
+        self.levels
+            .read()
+            .unwrap()
+            .get(route)
+            .cloned()
+            .ok_or_else(|| Error::Config(format!("No rooms configured for route '{}'", route)))
+    }
+    /// Returns the route and the escalation index of the given room.
+    fn find_room(&self, room_id: &RoomId) -> Option<(String, usize)> {
+        self.levels
+            .read()
+            .unwrap()
+            .iter()
+            .find_map(|(route, levels)| {
+                levels
+                    .iter()
+                    .find(|(_, id)| *id == room_id)
+                    .map(|(idx, _)| (route.clone(), idx))
+            })
+    }
+    fn is_observer(&self, room_id: &RoomId) -> bool {
+        self.levels
+            .read()
+            .unwrap()
+            .values()
+            .any(|levels| levels.observers().contains(room_id))
+    }
+    /// Replaces the rooms of the route, returns whether they changed.
+    fn set_rooms(&self, route: &str, rooms: Vec<RoomId>) -> Result<bool> {
+        let mut levels = self.levels.write().unwrap();
+        ensure_unassigned(&levels, route, &rooms)?;
 
-            // Determine which rooms to send the alerts to.
-            let current_room_id = rooms
-                .get(notify.escalation_idx.saturating_sub(1))
-                .unwrap_or_else(|| rooms.last().unwrap());
+        let current = levels
+            .get_mut(route)
+            .ok_or_else(|| Error::Config(format!("No rooms configured for route '{}'", route)))?;
+        if current.rooms == rooms {
+            return Ok(false);
+        }
 
-            let next_room_id = rooms
-                .get(notify.escalation_idx)
-                .unwrap_or_else(|| rooms.last().unwrap());
+        *current =
+            Levels::new(route, rooms, current.observers.clone())?.with_format(current.format);
+        Ok(true)
+    }
+}
 
-            let is_last = current_room_id == next_room_id;
+/// A room must map to exactly one escalation level.
+fn ensure_unassigned(
+    levels: &HashMap<String, Levels>,
+    route: &str,
+    rooms: &[RoomId],
+) -> Result<()> {
+    for room in rooms {
+        if levels
+            .iter()
+            .any(|(other, levels)| other != route && levels.contains(room))
+        {
+            return Err(Error::Config(format!(
+                "Room {} is assigned to multiple routes",
+                room
+            )));
+        }
+    }
 
-            // No further rooms to inform if the final room has been reached.
-            if !is_last {
-                // Notify current room that missed to acknowledge the alert.
-                debug!("Notifying current room about escalation");
-                client
-                    .send_msg(
-                        current_room_id,
-                        &format!(
-                            "🚨 ESCALATION OCCURRED! Notifying next room regarding Alerts: {}",
-                            {
-                                let mut list = String::new();
-                                for alert in &notify.alerts {
-                                    list.push_str(&format!("ID: {}, ", alert.id.to_string()));
-                                }
+    Ok(())
+}
 
-                                list.pop();
-                                list.pop();
-                                list
-                            }
-                        ),
-                    )
-                    .await?
-            }
+/// The `m.space.child` state event of a space.
+#[derive(Debug, Deserialize)]
+struct SpaceChildEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    state_key: String,
+    content: SpaceChildContent,
+}
 
-            let mut msg = String::from("🚨 ESCALATION OCCURRED!\n\n");
+#[derive(Debug, Deserialize)]
+struct SpaceChildContent {
+    // Removed children have no servers.
+    #[serde(default)]
+    via: Vec<String>,
+    order: Option<String>,
+}
 
-            if is_last {
-                warn!("Notifying final room about escalation");
-            } else {
-                debug!("Notifying *next* room about escalation");
-            }
+/// A child room of a space and what it is ordered by.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct SpaceChild {
+    room_id: RoomId,
+    order: Option<String>,
+    name: Option<String>,
+}
 
-            // Send alerts to room.
-            for alert in notify.alerts {
-                if !alert.should_escalate() {
-                    return Err(anyhow!(
-                        "Received an alert that shouldn't escalate as an escalation message"
-                    ));
-                }
+/// Orders the children by their `order`, then by their name (e.g. "1 -
+/// Ops", "2 - On-call") and room ID. Invalid orders are ignored, as per the
+/// spec.
+fn order_children(mut children: Vec<SpaceChild>) -> Vec<RoomId> {
+    for child in &mut children {
+        let is_valid =
+            |order: &String| order.len() <= 50 && order.chars().all(|c| (' '..='~').contains(&c));
+        child.order = child.order.take().filter(is_valid);
+    }
 
-                msg.push_str(&format!("{}\n\n", alert.to_string()));
-            }
+    children.sort_by(|a, b| {
+        (
+            a.order.is_none(),
+            &a.order,
+            a.name.is_none(),
+            &a.name,
+            a.room_id.as_str(),
+        )
+            .cmp(&(
+                b.order.is_none(),
+                &b.order,
+                b.name.is_none(),
+                &b.name,
+                b.room_id.as_str(),
+            ))
+    });
 
-            msg.pop();
-            msg.pop();
+    children.into_iter().map(|child| child.room_id).collect()
+}
 
-            client.send_msg(next_room_id, &msg).await?;
+/// Fetches the escalation chain of a route from the children of its space.
+async fn space_rooms(client: &Client, space: &RoomId) -> Result<Vec<RoomId>> {
+    let response = client
+        .send(get_state_events::Request::new(space), None)
+        .await?;
 
-            Ok(is_last)
+    let mut children = vec![];
+    for event in response.room_state {
+        let event = match event.deserialize_as::<SpaceChildEvent>() {
+            Ok(event) if event.kind == SPACE_CHILD_EVENT && !event.content.via.is_empty() => event,
+            _ => continue,
         };
 
-        Box::pin(f.into_actor(self))
-    }
-}
+        let room_id = RoomId::try_from(event.state_key.as_str())?;
+        if client.get_joined_room(&room_id).is_none() {
+            warn!(
+                "Not a member of room {} of space {}, notifications to it will fail",
+                room_id, space
+            );
+        }
 
-impl SystemService for MatrixClient {}
-impl Supervised for MatrixClient {}
+        children.push(SpaceChild {
+            name: client.get_room(&room_id).and_then(|room| room.name()),
+            room_id,
+            order: event.content.order,
+        });
+    }
 
-pub struct Listener {
-    rooms: Vec<RoomId>,
+    Ok(order_children(children))
 }
 
-#[async_trait]
-impl EventHandler for Listener {
-    async fn on_room_message(&self, room: Room, event: &SyncMessageEvent<MessageEventContent>) {
-        if let Room::Joined(room) = room {
-            let res = |room: Joined, event: SyncMessageEvent<MessageEventContent>| async move {
-                // Ignore own messages.
-                if &event.sender == room.own_user_id() {
-                    return Ok(());
-                }
+/// Updates the rooms of the routes backed by a space, for both notifications
+/// and escalations.
+async fn refresh_spaces(client: &Client, routes: &Routes) {
+    for (route, space) in &routes.spaces {
+        let rooms = match space_rooms(client, space).await {
+            Ok(rooms) => rooms,
+            Err(err) => {
+                warn!("Failed to fetch the rooms of space {}: {:?}", space, err);
+                continue;
+            }
+        };
 
-                let msg_body = if let SyncMessageEvent {
-                    content:
-                        MessageEventContent {
-                            msgtype:
-                                MessageType::Text(TextMessageEventContent { body: msg_body, .. }),
-                            ..
-                        },
-                    ..
-                } = event
-                {
-                    msg_body.to_string()
-                } else {
-                    return Err(anyhow!(
-                        "Received unacceptable message type from {}",
-                        event.sender
-                    ));
-                };
+        match routes.set_rooms(route, rooms.clone()) {
+            Ok(true) => info!(
+                "Rooms of route '{}' changed to {} room(s) of space {}",
+                route,
+                rooms.len(),
+                space
+            ),
+            Ok(false) => {}
+            Err(err) => {
+                warn!("Ignoring the rooms of space {}: {:?}", space, err);
+                continue;
+            }
+        }
 
-                debug!("Received message from {}: {}", event.sender, msg_body);
+        Processor::from_registry().do_send(SpaceRooms {
+            route: route.clone(),
+            rooms: rooms.iter().map(|room| room.to_string()).collect(),
+        });
+    }
+}
 
-                // For convenience.
-                let msg_body = msg_body.replace("  ", " ");
-
-                let cmd = match msg_body.trim() {
-                    "pending" => Command::Pending,
-                    "help" => Command::Help,
-                    txt => {
-                        if txt.to_lowercase().starts_with("ack")
-                            || txt.to_lowercase().starts_with("acknowledge")
-                        {
-                            let parts: Vec<&str> = txt.split(' ').collect();
-                            if parts.len() == 2 {
-                                if let Ok(id) = AlertId::from_str(parts[1]) {
-                                    Command::Ack(id, event.sender.to_string())
-                                } else {
-                                    bad_msg(&room).await?
-                                }
-                            } else {
-                                bad_msg(&room).await?
-                            }
-                        } else {
-                            // Ignore casual chatter in rooms.
-                            return Ok(());
-                        }
-                    }
-                };
+#[derive(Clone)]
+pub struct MatrixClient {
+    routes: Arc<Routes>,
+    homeserver: Arc<Homeserver>,
+    outbox: Arc<Outbox>,
+    sync: Arc<SyncHealth>,
+    prometheus: Option<Arc<Prometheus>>,
+    teams: Arc<HashMap<String, TeamConfig>>,
+    direct: Option<Arc<DirectRooms>>,
+    // Set if replies to commands are private.
+    private: Option<Arc<DirectRooms>>,
+    incidents: Option<Arc<IncidentRooms>>,
+    // Decide which alerts are major incidents.
+    severities: Arc<Severities>,
+    // Notifications of the same alert are sent in order.
+    queue: Arc<KeyedQueue<AlertId>>,
+    handle_user_command: bool,
+}
 
-                // Determine the escalation index based on ordering of rooms.
-                let escalation_idx =
-                    if let Some(room_id) = self.rooms.iter().position(|id| id == room.room_id()) {
-                        room_id
-                    } else {
-                        // Silent return.
-                        return Ok(());
-                    };
+impl MatrixClient {
+    pub async fn new(
+        config: &MatrixConfig,
+        routes: &[RouteConfig],
+        db: Option<Arc<Database>>,
+        handle_user_command: bool,
+        sync: bool,
+    ) -> Result<Self> {
+        debug!("Attempting to parse room ids");
+        let mut parsed = HashMap::new();
+        let mut spaces = HashMap::new();
+        let mut space_routes = vec![];
+        for route in routes {
+            let rooms: Vec<RoomId> = route
+                .rooms
+                .iter()
+                .map(|room| RoomId::try_from(room.clone()).map_err(|err| err.into()))
+                .collect::<Result<Vec<RoomId>>>()?;
 
-                // Prepare action type.
-                let action = UserAction {
-                    escalation_idx,
-                    command: cmd,
-                };
+            ensure_unassigned(&parsed, &route.name, &rooms)?;
 
-                // Send action to processor.
-                let confirmation = Processor::from_registry().send(action).await?;
+            let observers: Vec<RoomId> = route
+                .observers
+                .iter()
+                .map(|room| RoomId::try_from(room.clone()).map_err(|err| err.into()))
+                .collect::<Result<Vec<RoomId>>>()?;
+
+            // The rooms of spaces are fetched once logged in.
+            if let Some(space) = &route.space {
+                spaces.insert(route.name.clone(), RoomId::try_from(space.as_str())?);
+                space_routes.push((route, observers));
+                continue;
+            }
 
-                let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
-                    confirmation.to_string(),
-                ));
+            parsed.insert(
+                route.name.clone(),
+                Levels::new(&route.name, rooms, observers)?.with_format(route.format),
+            );
+        }
 
-                // Notify the room.
-                debug!("Notifying room");
-                room.send(content, None).await?;
+        info!("Setting up Matrix client");
+        let accounts: Vec<&MatrixConfig> = std::iter::once(config)
+            .chain(config.fallbacks.iter())
+            .collect();
 
-                Result::<()>::Ok(())
+        // Requests are retried, hence the timeout if there is another account
+        // to fail over to.
+        let mut client = None;
+        for (idx, account) in accounts.iter().enumerate() {
+            let res = if idx + 1 < accounts.len() {
+                within_login_timeout(account, login(account)).await
+            } else {
+                login(account).await
             };
 
-            // Only process whitelisted rooms.
-            if !self.rooms.contains(room.room_id()) {
-                return;
+            match res {
+                Ok(c) => {
+                    client = Some((idx, c));
+                    break;
+                }
+                Err(err) if idx + 1 < accounts.len() => {
+                    warn!(
+                        "Failed to set up Matrix account {}, failing over: {:?}",
+                        account.username, err
+                    );
+                }
+                Err(err) => return Err(err),
             }
+        }
+        // The last account returns early on failure.
+        let (active, client) = client.unwrap();
 
-            match res(room, event.clone()).await {
-                Ok(_) => {}
-                Err(err) => error!("Error when trying to process Matrix message {:?}", err),
-            }
+        for (route, observers) in space_routes {
+            let space = &spaces[&route.name];
+            let rooms = space_rooms(&client, space).await?;
+            info!(
+                "Route '{}' escalates through {} room(s) of space {}",
+                route.name,
+                rooms.len(),
+                space
+            );
+
+            ensure_unassigned(&parsed, &route.name, &rooms)?;
+            parsed.insert(
+                route.name.clone(),
+                Levels::new(&route.name, rooms, observers)?.with_format(route.format),
+            );
         }
-    }
-}
 
-async fn bad_msg(room: &Joined) -> Result<Command> {
-    let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
-        "I don't understand 🤔",
-    ));
+        let homeserver = Arc::new(Homeserver::new(
+            accounts.into_iter().cloned().collect(),
+            active,
+            client,
+        ));
+        let direct_rooms = Arc::new(DirectRooms {
+            homeserver: Arc::clone(&homeserver),
+            db: db.clone(),
+            rooms: Default::default(),
+        });
+        let direct = Some(Arc::clone(&direct_rooms)).filter(|_| config.direct_messages);
+        let private = Some(direct_rooms).filter(|_| config.private_responses);
 
-    room.send(content, None).await?;
+        let outbox = Arc::new(Outbox {
+            homeserver: Arc::clone(&homeserver),
+            db: db.clone(),
+            active: AtomicBool::new(false),
+            flushing: AtomicBool::new(false),
+        });
+        let incidents = match &config.incident_rooms {
+            Some(incidents) => Some(Arc::new(
+                IncidentRooms::new(incidents.clone(), Arc::clone(&outbox), db).await?,
+            )),
+            None => None,
+        };
+
+        let matrix = MatrixClient {
+            routes: Arc::new(Routes::new(parsed, spaces)),
+            homeserver,
+            outbox,
+            sync: Arc::new(SyncHealth::default()),
+            prometheus: None,
+            teams: Default::default(),
+            direct,
+            private,
+            incidents,
+            severities: Default::default(),
+            queue: Default::default(),
+            handle_user_command,
+        };
 
-    Ok(Command::Help)
+        if sync {
+            matrix.start_sync().await?;
+        }
+
+        Ok(matrix)
+    }
+    /// Adds the trend of the alert expression to notifications.
+    pub fn with_prometheus(mut self, prometheus: Option<Prometheus>) -> Self {
+        self.prometheus = prometheus.map(Arc::new);
+        self
+    }
+    /// Mentions the members of the team of new alerts.
+    pub fn with_teams(mut self, teams: HashMap<String, TeamConfig>) -> Self {
+        self.teams = Arc::new(teams);
+        self
+    }
+    /// Ranks the severities of alerts against the one of incident rooms.
+    pub fn with_severities(mut self, severities: Severities) -> Self {
+        self.severities = Arc::new(severities);
+        self
+    }
+    /// Starts handling user commands and syncing in the background.
+    async fn start_sync(&self) -> Result<()> {
+        // Add event handler
+        if self.handle_user_command {
+            self.homeserver
+                .set_listener(Listener {
+                    routes: Arc::clone(&self.routes),
+                    private: self.private.clone(),
+                    incidents: self.incidents.clone(),
+                })
+                .await;
+        }
+
+        // Start backend syncing service
+        info!("Executing background sync");
+        let settings =
+            SyncSettings::default().token(
+                self.homeserver.client().sync_token().await.ok_or_else(|| {
+                    Error::adapter(MATRIX_ADAPTER, "Failed to acquire sync token")
+                })?,
+            );
+
+        // Queued messages are only delivered by the active instance.
+        self.outbox.active.store(true, Ordering::SeqCst);
+
+        // Sync in background.
+        self.sync.started.store(true, Ordering::SeqCst);
+        actix::spawn(supervise_sync(
+            Arc::clone(&self.homeserver),
+            Arc::clone(&self.sync),
+            settings,
+        ));
+
+        Ok(())
+    }
+}
+
+/// Rooms for direct messages, created on first use and reused afterwards.
+struct DirectRooms {
+    homeserver: Arc<Homeserver>,
+    db: Option<Arc<Database>>,
+    // Held while a room is created, so concurrent notifications of the same
+    // user do not create multiple rooms.
+    rooms: futures::lock::Mutex<HashMap<UserId, RoomId>>,
+}
+
+impl DirectRooms {
+    async fn room(&self, user: &UserId) -> Result<RoomId> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room_id) = rooms.get(user) {
+            return Ok(room_id.clone());
+        }
+
+        let stored = match &self.db {
+            Some(db) => db.get_direct_room(user.as_str()).await?,
+            None => None,
+        };
+
+        let room_id = match stored {
+            Some(room_id) => RoomId::try_from(room_id)?,
+            None => {
+                let invite = [user.clone()];
+                let mut request = create_room::Request::new();
+                request.invite = &invite;
+                request.is_direct = true;
+                request.preset = Some(create_room::RoomPreset::TrustedPrivateChat);
+
+                let room_id = self.homeserver.client().create_room(request).await?.room_id;
+                info!("Created direct message room {} for {}", room_id, user);
+
+                if let Some(db) = &self.db {
+                    db.upsert_direct_room(&DirectRoom {
+                        user: user.to_string(),
+                        room_id: room_id.to_string(),
+                    })
+                    .await?;
+                }
+
+                room_id
+            }
+        };
+
+        rooms.insert(user.clone(), room_id.clone());
+        Ok(room_id)
+    }
+}
+
+/// Rooms of open major incidents, see `IncidentRoomsConfig`.
+struct IncidentRooms {
+    config: IncidentRoomsConfig,
+    outbox: Arc<Outbox>,
+    db: Option<Arc<Database>>,
+    // Held while a room is created, so concurrent notifications of the same
+    // alert do not create multiple rooms.
+    rooms: futures::lock::Mutex<HashMap<AlertId, RoomId>>,
+}
+
+impl IncidentRooms {
+    /// Picks up the rooms of incidents which were open before a restart.
+    async fn new(
+        config: IncidentRoomsConfig,
+        outbox: Arc<Outbox>,
+        db: Option<Arc<Database>>,
+    ) -> Result<Self> {
+        let mut rooms = HashMap::new();
+        if let Some(db) = &db {
+            for incident in db.get_incident_rooms().await? {
+                rooms.insert(incident.alert, RoomId::try_from(incident.room_id)?);
+            }
+        }
+
+        Ok(IncidentRooms {
+            config,
+            outbox,
+            db,
+            rooms: futures::lock::Mutex::new(rooms),
+        })
+    }
+    fn is_major(&self, severities: &Severities, alert: &AlertContext) -> bool {
+        severities.at_least(&alert.alert.labels.severity, &self.config.min_severity)
+    }
+    /// Returns the alert of an incident room.
+    async fn find_alert(&self, room_id: &RoomId) -> Option<AlertId> {
+        self.rooms
+            .lock()
+            .await
+            .iter()
+            .find(|(_, id)| *id == room_id)
+            .map(|(alert, _)| *alert)
+    }
+    /// Creates the room of the alert, unless it exists. Invites the
+    /// responders and the given users.
+    async fn open(&self, alert: &AlertContext, users: BTreeSet<String>) -> Result<RoomId> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room_id) = rooms.get(&alert.id) {
+            return Ok(room_id.clone());
+        }
+
+        let invite: Vec<UserId> = self
+            .config
+            .responders
+            .iter()
+            .cloned()
+            .chain(users)
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .filter_map(|user| match UserId::try_from(user.as_str()) {
+                Ok(user) => Some(user),
+                Err(err) => {
+                    warn!("Not inviting {} to incident room: {:?}", user, err);
+                    None
+                }
+            })
+            .collect();
+        let name = format!("Incident {}: {}", alert.id, alert.alert.labels.alert_name);
+
+        let mut request = create_room::Request::new();
+        request.invite = &invite;
+        request.name = Some(&name);
+        request.preset = Some(create_room::RoomPreset::PrivateChat);
+
+        let room_id = self
+            .outbox
+            .homeserver
+            .client()
+            .create_room(request)
+            .await?
+            .room_id;
+        info!("Created incident room {} for {}", room_id, alert.trace());
+
+        if let Some(db) = &self.db {
+            db.upsert_incident_room(&IncidentRoom {
+                alert: alert.id,
+                room_id: room_id.to_string(),
+            })
+            .await?;
+        }
+
+        rooms.insert(alert.id, room_id.clone());
+        Ok(room_id)
+    }
+    /// Opens the room of the alert if it is a major incident, see `open`.
+    /// Failures are logged, the escalation rooms are notified regardless.
+    async fn open_major(
+        &self,
+        severities: &Severities,
+        alert: &AlertContext,
+        users: BTreeSet<String>,
+    ) {
+        if !self.is_major(severities, alert) {
+            return;
+        }
+
+        if let Err(err) = self.open(alert, users).await {
+            warn!(
+                "Failed to create incident room for {}: {:?}",
+                alert.trace(),
+                err
+            );
+        }
+    }
+    /// Relays a message about the alert to its room, if any. Failures are
+    /// logged, like for `open_major`.
+    async fn relay(&self, id: AlertId, msg: &Message) {
+        let room_id = match self.rooms.lock().await.get(&id) {
+            Some(room_id) => room_id.clone(),
+            None => return,
+        };
+
+        if let Err(err) = self.outbox.send_rendered(&room_id, msg).await {
+            warn!(
+                "Failed to relay message to incident room {}: {:?}",
+                room_id, err
+            );
+        }
+    }
+    /// Posts the resolution to the room of the alert and leaves it, which
+    /// keeps its history for the responders.
+    async fn archive(&self, id: AlertId, resolved_by: &str) -> Result<()> {
+        let room_id = match self.rooms.lock().await.get(&id) {
+            Some(room_id) => room_id.clone(),
+            None => return Ok(()),
+        };
+
+        // Bypasses the outbox, a queued message would fail once left.
+        let homeserver = &self.outbox.homeserver;
+        homeserver
+            .send_msg(
+                &room_id,
+                &format!(
+                    "✅ Alert {} has been resolved by {}. This room is archived, further commands are not handled here.",
+                    id, resolved_by
+                ),
+            )
+            .await?;
+        homeserver
+            .client()
+            .send(leave_room::Request::new(&room_id), None)
+            .await?;
+        info!("Archived incident room {} of alert {}", room_id, id);
+
+        self.rooms.lock().await.remove(&id);
+        if let Some(db) = &self.db {
+            db.remove_incident_room(id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// State of the background sync, which delivers user commands such as acks.
+#[derive(Default)]
+struct SyncHealth {
+    started: AtomicBool,
+    running: AtomicBool,
+    restarts: AtomicUsize,
+}
+
+impl SyncHealth {
+    /// A sync that was never started, e.g. in standby, is not a failure.
+    fn is_healthy(&self) -> bool {
+        !self.started.load(Ordering::SeqCst) || self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs the sync loop, restarting it with backoff if it stops or panics.
+/// Otherwise a failed sync silently stops the processing of acks. Fails over
+/// to the next account if syncing fails for too long, and restarts on the
+/// client of the account which took over.
+async fn supervise_sync(
+    homeserver: Arc<Homeserver>,
+    health: Arc<SyncHealth>,
+    settings: SyncSettings<'_>,
+) {
+    let mut settings = Some(settings);
+    let mut delay = SYNC_RESTART_DELAY;
+
+    loop {
+        let client = homeserver.client();
+
+        // Resume from the latest sync token on restarts.
+        let settings = match settings.take() {
+            Some(settings) => settings,
+            None => match client.sync_token().await {
+                Some(token) => SyncSettings::default().token(token),
+                None => SyncSettings::default(),
+            },
+        };
+
+        // Failed syncs are retried by the client, without returning.
+        let last_sync = AtomicU64::new(unix_time());
+        let sync = client.sync_with_callback(settings, |_| {
+            last_sync.store(unix_time(), Ordering::SeqCst);
+            async { LoopCtrl::Continue }
+        });
+        let stale = async {
+            loop {
+                let elapsed = unix_time().saturating_sub(last_sync.load(Ordering::SeqCst));
+                if elapsed >= SYNC_STALE_AFTER {
+                    break;
+                }
+
+                tokio::time::sleep(Duration::from_secs(SYNC_STALE_AFTER - elapsed)).await;
+            }
+        };
+
+        health.running.store(true, Ordering::SeqCst);
+        let started = std::time::Instant::now();
+        let result = tokio::select! {
+            result = AssertUnwindSafe(sync).catch_unwind() => Some(result),
+            _ = homeserver.replaced.notified() => None,
+            _ = stale => {
+                warn!(
+                    "Matrix sync failed for {} seconds, failing over",
+                    SYNC_STALE_AFTER
+                );
+                homeserver.failover(&client).await;
+                None
+            }
+        };
+        health.running.store(false, Ordering::SeqCst);
+
+        // Continue on the client which is active now.
+        let result = match result {
+            Some(result) => result,
+            None => continue,
+        };
+
+        if started.elapsed() >= Duration::from_secs(SYNC_STABLE_AFTER) {
+            delay = SYNC_RESTART_DELAY;
+        }
+
+        let restarts = health.restarts.fetch_add(1, Ordering::SeqCst) + 1;
+        match result {
+            Ok(()) => warn!(
+                "Matrix sync stopped, restarting in {} seconds (restart {})",
+                delay, restarts
+            ),
+            Err(_) => error!(
+                "Matrix sync panicked, restarting in {} seconds (restart {})",
+                delay, restarts
+            ),
+        }
+
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).min(MAX_SYNC_RESTART_DELAY);
+    }
+}
+
+/// Convenience trait.
+#[async_trait]
+trait SendMsg {
+    async fn send_msg(&self, room_id: &RoomId, msg: &str) -> Result<()> {
+        self.send_rendered(
+            room_id,
+            &Message {
+                body: msg.to_string(),
+                html: None,
+            },
+        )
+        .await
+    }
+    /// Sends a rendered notification, including its HTML rendition, if any.
+    async fn send_rendered(&self, room_id: &RoomId, msg: &Message) -> Result<()>;
+}
+
+// Implement for matrix client.
+#[async_trait]
+impl SendMsg for Client {
+    async fn send_rendered(&self, room_id: &RoomId, msg: &Message) -> Result<()> {
+        chaos::inject(MATRIX_ADAPTER).await?;
+
+        let body = truncate::message(&msg.body);
+        let content = match msg.html.as_deref().map(truncate::message) {
+            // Truncated HTML might not be well-formed, the body is sent instead.
+            Some(Cow::Borrowed(html)) if matches!(body, Cow::Borrowed(_)) => {
+                MessageEventContent::text_html(body, html)
+            }
+            _ => MessageEventContent::text_plain(body),
+        };
+
+        self.room_send(room_id, AnyMessageEventContent::RoomMessage(content), None)
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn send_direct(
+    direct: &DirectRooms,
+    client: &Outbox,
+    user: &str,
+    msg: &Message,
+) -> Result<()> {
+    let user = UserId::try_from(user)?;
+    let room_id = direct.room(&user).await?;
+    client.send_rendered(&room_id, msg).await
+}
+
+/// Describes the trend of the alert expression, if available. Failures are
+/// logged, the notification is sent regardless.
+async fn trend(prometheus: Option<&Prometheus>, alert: &Alert) -> Option<String> {
+    let prometheus = prometheus?;
+
+    match prometheus.trend(alert).await {
+        Ok(trend) => trend.map(|trend| trend.to_string()),
+        Err(err) => {
+            warn!(
+                "Failed to query trend of {}: {:?}",
+                alert.labels.alert_name, err
+            );
+            None
+        }
+    }
+}
+
+/// Describes the time until an escalation, in minutes (rounded up).
+fn format_remaining(secs: u64) -> String {
+    match secs.saturating_sub(1) / 60 + 1 {
+        0 | 1 => String::from("1 minute"),
+        minutes => format!("{} minutes", minutes),
+    }
+}
+
+/// Relays each section to the incident room of its alert, if any.
+async fn relay_to_incidents(
+    incidents: Option<&IncidentRooms>,
+    rooms: &Levels,
+    header: &str,
+    sections: impl IntoIterator<Item = Section>,
+) {
+    let incidents = match incidents {
+        Some(incidents) => incidents,
+        None => return,
+    };
+
+    for section in sections {
+        let id = section.alert.id;
+        incidents.relay(id, &rooms.render(header, &[section])).await;
+    }
+}
+
+/// One line per changed annotation, e.g. `Value: 91 → 97`.
+fn refired_msg(alert: &AlertContext, changes: &[AnnotationChange]) -> String {
+    let value = |value: &Option<String>| match value {
+        Some(value) => truncate::annotation(value, Some(alert.id)).into_owned(),
+        None => String::from("(none)"),
+    };
+
+    let mut msg = format!(
+        "🔁 Alert {} ({}) fired again with changes:",
+        alert.id, alert.alert.labels.alert_name
+    );
+    for change in changes {
+        msg.push_str(&format!(
+            "\n  {}: {} → {}",
+            change.label,
+            value(&change.old),
+            value(&change.new)
+        ));
+    }
+
+    msg
+}
+
+/// Adds the trend of the alert expression to the section, if available.
+async fn with_trend(prometheus: Option<&Prometheus>, section: Section) -> Section {
+    match trend(prometheus, &section.alert.alert).await {
+        Some(trend) => section.with_note("Trend", trend),
+        None => section,
+    }
+}
+
+/// Sends a short summary of the pending alerts, followed by all of them as a
+/// JSON file.
+async fn send_pending_attachment(room: &Joined, alerts: &[AlertContext]) -> Result<()> {
+    let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
+        pending_summary(alerts),
+    ));
+    room.send(content, None).await?;
+
+    let json = serde_json::to_vec_pretty(alerts)
+        .map_err(|err| Error::Internal(format!("Failed to serialize alerts: {}", err)))?;
+    room.send_attachment(
+        PENDING_ATTACHMENT_NAME,
+        &mime::APPLICATION_JSON,
+        &mut std::io::Cursor::new(json),
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The number of pending alerts and the most severe ones, which come first.
+fn pending_summary(alerts: &[AlertContext]) -> String {
+    let mut summary = format!(
+        "{} pending alerts, see the attached {}. The most severe ones:\n",
+        alerts.len(),
+        PENDING_ATTACHMENT_NAME
+    );
+
+    for alert in alerts.iter().take(PENDING_SUMMARY_SIZE) {
+        summary.push_str(&format!(
+            "- ID: {}, Name: {}, Severity: {}\n",
+            alert.id, alert.alert.labels.alert_name, alert.alert.labels.severity
+        ));
+    }
+
+    summary
+}
+
+/// Mentions the members of the team of the alert, if configured.
+fn mentions(teams: &HashMap<String, TeamConfig>, section: Section) -> Section {
+    let team = section.alert.alert.labels.team.clone();
+    match team.and_then(|team| teams.get(&team).map(|config| (team, config))) {
+        Some((team, config)) if !config.mentions.is_empty() => {
+            section.with_note(format!("Team {}", team), config.mentions.join(" "))
+        }
+        _ => section,
+    }
+}
+
+/// Whether the error indicates that the homeserver is unreachable or
+/// unavailable, i.e. the request can be retried later.
+fn is_transient(err: &Error) -> bool {
+    let source = match err {
+        Error::Adapter { source, .. } => source,
+        _ => return false,
+    };
+
+    if source.is::<InjectedFault>() {
+        return true;
+    }
+
+    match source.downcast_ref::<matrix_sdk::Error>() {
+        Some(matrix_sdk::Error::Http(err)) => match err {
+            HttpError::Reqwest(_) | HttpError::Server(_) => true,
+            HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(err))) => {
+                err.status_code.is_server_error()
+            }
+            HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Unknown(_))) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Sends messages, queuing them in the database while the homeserver is
+/// unavailable. Queued messages of a room are delivered in order, before any
+/// new ones to that room.
+struct Outbox {
+    homeserver: Arc<Homeserver>,
+    db: Option<Arc<Database>>,
+    active: AtomicBool,
+    flushing: AtomicBool,
+}
+
+impl Outbox {
+    /// Delivers queued messages. A room is skipped after a transient failure,
+    /// messages which fail otherwise are moved to the dead letters, so that
+    /// they don't block the room forever.
+    async fn flush(&self) -> Result<()> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+
+        if !self.active.load(Ordering::SeqCst) || self.flushing.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let res = async {
+            let mut unavailable = HashSet::new();
+            for queued in db.get_queued_messages().await? {
+                if unavailable.contains(&queued.room_id) {
+                    continue;
+                }
+
+                let msg = Message {
+                    body: queued.body.clone(),
+                    html: queued.html.clone(),
+                };
+                let res = match RoomId::try_from(queued.room_id.as_str()) {
+                    Ok(room_id) => self.homeserver.send_rendered(&room_id, &msg).await,
+                    Err(err) => Err(Error::from(err)),
+                };
+
+                match res {
+                    Ok(()) => db.remove_queued_message(&queued).await?,
+                    Err(err) if is_transient(&err) => {
+                        warn!(
+                            "Failed to deliver queued messages to {}, retrying later: {:?}",
+                            queued.room_id, err
+                        );
+                        unavailable.insert(queued.room_id);
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to deliver queued message to {}, moving it to the dead letters: {:?}",
+                            queued.room_id, err
+                        );
+                        db.dead_letter_message(&queued, &err.to_string()).await?;
+                    }
+                }
+            }
+
+            Ok(())
+        }
+        .await;
+
+        self.flushing.store(false, Ordering::SeqCst);
+        res
+    }
+}
+
+#[async_trait]
+impl SendMsg for Outbox {
+    async fn send_rendered(&self, room_id: &RoomId, msg: &Message) -> Result<()> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return self.homeserver.send_rendered(room_id, msg).await,
+        };
+
+        // Preserve the order while older messages to the room are pending.
+        if db.has_queued_messages(room_id.as_str()).await? {
+            return db
+                .queue_message(room_id.as_str(), &msg.body, msg.html.as_deref())
+                .await;
+        }
+
+        match self.homeserver.send_rendered(room_id, msg).await {
+            Err(err) if is_transient(&err) => {
+                warn!(
+                    "Failed to send message to {}, queuing for retry: {:?}",
+                    room_id, err
+                );
+                db.queue_message(room_id.as_str(), &msg.body, msg.html.as_deref())
+                    .await
+            }
+            res => res,
+        }
+    }
+}
+
+impl Default for MatrixClient {
+    fn default() -> Self {
+        panic!("Matrix client was not initialized in system registry. This is a bug.");
+    }
+}
+
+impl Actor for MatrixClient {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_secs(OUTBOX_FLUSH_INTERVAL), |act, _ctx| {
+            let outbox = Arc::clone(&act.outbox);
+            actix::spawn(async move {
+                if let Err(err) = outbox.flush().await {
+                    warn!("Failed to deliver queued messages: {:?}", err);
+                }
+            });
+        });
+
+        // Adding a room to a space adds an escalation level.
+        if !self.routes.spaces.is_empty() {
+            let refresh = |act: &mut Self, _ctx: &mut Self::Context| {
+                let client = act.homeserver.client();
+                let routes = Arc::clone(&act.routes);
+                actix::spawn(async move { refresh_spaces(&client, &routes).await });
+            };
+
+            refresh(self, ctx);
+            ctx.run_interval(Duration::from_secs(SPACE_REFRESH_INTERVAL), refresh);
+        }
+    }
+}
+
+/// Handler for alerts on first entry, when the webhook gets called by the
+/// Watcher. Can be either an escalating or non-escalating alert.
+impl Handler<NotifyAlert> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: NotifyAlert, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|alert| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+        let prometheus = self.prometheus.clone();
+        let teams = Arc::clone(&self.teams);
+        let direct = self.direct.clone();
+        let incidents = self.incidents.clone();
+        let severities = Arc::clone(&self.severities);
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            if notify.alerts.is_empty() {
+                return Ok(());
+            }
+
+            let rooms = routes.rooms(&notify.route)?;
+            let watchers = notify.watchers;
+
+            // Alerts by the users to notify directly.
+            let mut direct_messages: BTreeMap<String, Vec<Section>> = BTreeMap::new();
+
+            // Alerts may enter the escalation chain at a later level, group
+            // them by room.
+            let mut messages: BTreeMap<usize, Vec<Section>> = BTreeMap::new();
+
+            // Alerts which may open an incident room, with the users to invite.
+            let mut incident_alerts = vec![];
+
+            for alert in notify.alerts {
+                let idx = rooms.clamp(alert.escalation_idx);
+
+                debug!("Notifying level {} about {}", idx, alert.trace());
+
+                let alert_watchers = watchers.get(&alert.id).cloned().unwrap_or_default();
+                let team_members = alert
+                    .alert
+                    .labels
+                    .team
+                    .as_ref()
+                    .and_then(|team| teams.get(team))
+                    .map(|team| team.mentions.clone())
+                    .unwrap_or_default();
+
+                let section = with_trend(prometheus.as_deref(), Section::new(alert)).await;
+                let users: BTreeSet<String> =
+                    alert_watchers.iter().cloned().chain(team_members).collect();
+
+                if incidents.is_some() {
+                    incident_alerts.push((section.clone(), users.clone()));
+                }
+
+                if direct.is_some() {
+                    for user in users {
+                        direct_messages
+                            .entry(user)
+                            .or_default()
+                            .push(section.clone());
+                    }
+                }
+
+                let mut section = mentions(&teams, section);
+                if !alert_watchers.is_empty() {
+                    section = section.with_note("Watched by", alert_watchers.join(" "));
+                }
+
+                messages.entry(idx).or_default().push(section);
+            }
+
+            // Send alerts to rooms.
+            for (idx, sections) in messages {
+                let msg = rooms.render("⚠️ Alert occurred!", &sections);
+
+                client.send_rendered(rooms.room(idx), &msg).await?;
+                for observer in rooms.observers() {
+                    client.send_rendered(observer, &msg).await?;
+                }
+            }
+
+            if let Some(incidents) = &incidents {
+                for (section, users) in &incident_alerts {
+                    incidents
+                        .open_major(&severities, &section.alert, users.clone())
+                        .await;
+                }
+            }
+
+            relay_to_incidents(
+                incidents.as_deref(),
+                &rooms,
+                "⚠️ Alert occurred!",
+                incident_alerts.into_iter().map(|(section, _)| section),
+            )
+            .await;
+
+            // Direct messages are best effort, the rooms were notified.
+            if let Some(direct) = direct {
+                for (user, sections) in direct_messages {
+                    let msg = rooms.render("⚠️ Alert occurred!", &sections);
+
+                    if let Err(err) = send_direct(&direct, client.as_ref(), &user, &msg).await {
+                        warn!("Failed to send direct message to {}: {:?}", user, err);
+                    }
+                }
+            }
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for escalations triggered by the Processor event loop. *Must* only
+/// process escalating alerts or an error is returned.
+impl Handler<Escalation> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<usize>>;
+
+    fn handle(&mut self, notify: Escalation, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|alert| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+        let prometheus = self.prometheus.clone();
+        let incidents = self.incidents.clone();
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            if notify.alerts.is_empty() {
+                return Ok(notify.escalation_idx.saturating_sub(1));
+            }
+
+            // Determine which rooms to send the alerts to. Escalations beyond
+            // the final room end up in the final room.
+            let rooms = routes.rooms(&notify.route)?;
+            let next_idx = rooms.clamp(notify.escalation_idx);
+
+            let current_room_id = rooms.room(notify.escalation_idx.saturating_sub(1));
+            let next_room_id = rooms.room(next_idx);
+
+            let is_last = current_room_id == next_room_id;
+
+            // No further rooms to inform if the final room has been reached.
+            if !is_last {
+                // Notify current room that missed to acknowledge the alert.
+                debug!("Notifying current room about escalation");
+                client
+                    .send_msg(
+                        current_room_id,
+                        &format!(
+                            "🚨 ESCALATION OCCURRED! Notifying next room regarding Alerts: {}",
+                            {
+                                let mut list = String::new();
+                                for alert in &notify.alerts {
+                                    list.push_str(&format!("ID: {}, ", alert.id));
+                                }
+
+                                list.pop();
+                                list.pop();
+                                list
+                            }
+                        ),
+                    )
+                    .await?
+            }
+
+            if is_last {
+                warn!("Notifying final room about escalation");
+            } else {
+                debug!("Notifying *next* room about escalation");
+            }
+
+            // Send alerts to room.
+            let mut sections = vec![];
+            for alert in notify.alerts {
+                if !alert.should_escalate() {
+                    return Err(Error::Internal(String::from(
+                        "Received an alert that shouldn't escalate as an escalation message",
+                    )));
+                }
+
+                sections.push(with_trend(prometheus.as_deref(), Section::new(alert)).await);
+            }
+
+            let msg = rooms.render("🚨 ESCALATION OCCURRED!", &sections);
+
+            client.send_rendered(next_room_id, &msg).await?;
+            for observer in rooms.observers() {
+                client.send_rendered(observer, &msg).await?;
+            }
+
+            relay_to_incidents(
+                incidents.as_deref(),
+                &rooms,
+                "🚨 ESCALATION OCCURRED!",
+                sections,
+            )
+            .await;
+
+            Ok(next_idx)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for alerts which missed escalations while the service was down.
+impl Handler<CatchUpSummary> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: CatchUpSummary, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|alert| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            if notify.alerts.is_empty() {
+                return Ok(());
+            }
+
+            let rooms = routes.rooms(&notify.route)?;
+            let room_id = rooms.room(notify.escalation_idx);
+
+            let sections: Vec<Section> = notify.alerts.into_iter().map(Section::new).collect();
+            let msg = rooms.render(
+                "⏰ MISSED ESCALATIONS! The following alerts were not escalated while the service was down:",
+                &sections,
+            );
+
+            client.send_rendered(room_id, &msg).await
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for expired acknowledgements, reminds the rooms of the levels the
+/// alerts have returned to.
+impl Handler<AckExpired> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: AckExpired, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|alert| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+        let incidents = self.incidents.clone();
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.route)?;
+            let header = "⏳ ACKNOWLEDGEMENT EXPIRED! The following alerts have not been resolved in time and are pending again:";
+
+            let mut messages: BTreeMap<usize, Vec<Section>> = BTreeMap::new();
+            let mut relayed = vec![];
+            for alert in notify.alerts {
+                let section = Section::new(alert);
+
+                relayed.push(section.clone());
+                messages
+                    .entry(rooms.clamp(section.alert.escalation_idx))
+                    .or_default()
+                    .push(section);
+            }
+
+            for (idx, sections) in messages {
+                let msg = rooms.render(header, &sections);
+
+                client.send_rendered(rooms.room(idx), &msg).await?;
+            }
+
+            relay_to_incidents(incidents.as_deref(), &rooms, header, relayed).await;
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for alerts whose severity was raised while unacknowledged. The
+/// alerts are already at the entry level of their new severity.
+impl Handler<SeverityRaised> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: SeverityRaised, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|alert| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+        let teams = Arc::clone(&self.teams);
+        let incidents = self.incidents.clone();
+        let severities = Arc::clone(&self.severities);
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.route)?;
+            let header =
+                "⬆️ SEVERITY RAISED! The following alerts have not been acknowledged in time:";
+
+            let mut messages: BTreeMap<usize, Vec<Section>> = BTreeMap::new();
+            let mut relayed = vec![];
+            for alert in notify.alerts {
+                let section = Section::new(alert);
+
+                relayed.push(section.clone());
+                messages
+                    .entry(rooms.clamp(section.alert.escalation_idx))
+                    .or_default()
+                    .push(section);
+            }
+
+            for (idx, sections) in messages {
+                let msg = rooms.render(header, &sections);
+
+                client.send_rendered(rooms.room(idx), &msg).await?;
+                for observer in rooms.observers() {
+                    client.send_rendered(observer, &msg).await?;
+                }
+            }
+
+            // The raised severity may turn the alerts into major incidents.
+            if let Some(incidents) = &incidents {
+                for section in &relayed {
+                    let team_members = section
+                        .alert
+                        .alert
+                        .labels
+                        .team
+                        .as_ref()
+                        .and_then(|team| teams.get(team))
+                        .map(|team| team.mentions.iter().cloned().collect())
+                        .unwrap_or_default();
+                    incidents
+                        .open_major(&severities, &section.alert, team_members)
+                        .await;
+                }
+            }
+
+            relay_to_incidents(incidents.as_deref(), &rooms, header, relayed).await;
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for warnings about imminent escalations, posted to the current
+/// level only.
+impl Handler<EscalationWarning> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: EscalationWarning, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|(alert, _)| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.route)?;
+            let room_id = rooms.room(notify.escalation_idx);
+
+            let soonest = notify
+                .alerts
+                .iter()
+                .map(|(_, remaining)| *remaining)
+                .min()
+                .unwrap_or_default();
+            let sections: Vec<Section> = notify
+                .alerts
+                .into_iter()
+                .map(|(alert, remaining)| {
+                    Section::new(alert).with_note("Escalates in", format_remaining(remaining))
+                })
+                .collect();
+
+            let msg = rooms.render(
+                &format!(
+                    "⏳ {} until escalation to level {}! Acknowledge the following alerts to prevent it:",
+                    format_remaining(soonest),
+                    rooms.clamp(notify.escalation_idx + 1)
+                ),
+                &sections,
+            );
+
+            client.send_rendered(room_id, &msg).await
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for acks outside of Matrix, informs the room of the alert's current
+/// escalation level.
+impl Handler<RemoteAck> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: RemoteAck, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self.queue.enqueue([notify.id]);
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+        let incidents = self.incidents.clone();
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.route)?;
+            let msg = format!(
+                "✅ Alert {} has been acknowledged by {} via {}",
+                notify.id, notify.user, notify.via
+            );
+
+            client
+                .send_msg(rooms.room(rooms.clamp(notify.escalation_idx)), &msg)
+                .await?;
+            for observer in rooms.observers() {
+                client.send_msg(observer, &msg).await?;
+            }
+
+            if let Some(incidents) = &incidents {
+                let msg = Message {
+                    body: msg,
+                    html: None,
+                };
+                incidents.relay(notify.id, &msg).await;
+            }
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for pending alerts which fired again with changed annotations,
+/// posts the changes to the current level.
+impl Handler<AlertRefired> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: AlertRefired, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self.queue.enqueue([notify.alert.id]);
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+        let incidents = self.incidents.clone();
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.alert.route)?;
+            let msg = refired_msg(&notify.alert, &notify.changes);
+
+            client
+                .send_msg(rooms.room(notify.alert.escalation_idx), &msg)
+                .await?;
+            for observer in rooms.observers() {
+                client.send_msg(observer, &msg).await?;
+            }
+
+            if let Some(incidents) = &incidents {
+                let msg = Message {
+                    body: msg,
+                    html: None,
+                };
+                incidents.relay(notify.alert.id, &msg).await;
+            }
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for resolved alerts, archives their incident room.
+impl Handler<IncidentResolved> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: IncidentResolved, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self.queue.enqueue([notify.id]);
+        let incidents = self.incidents.clone();
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            match incidents {
+                Some(incidents) => incidents.archive(notify.id, &notify.resolved_by).await,
+                None => Ok(()),
+            }
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for expired mutes, informs the room which issued the mute.
+impl Handler<MuteExpired> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: MuteExpired, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self
+            .queue
+            .enqueue(notify.alerts.iter().map(|alert| alert.id));
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.route)?;
+            let room_id = rooms.room(notify.escalation_idx);
+
+            let header = format!(
+                "🔔 Mute expired, notifications are active again. {} alert(s) were received while muted{}",
+                notify.alerts.len(),
+                if notify.alerts.is_empty() { "." } else { ":" }
+            );
+
+            let sections: Vec<Section> = notify.alerts.into_iter().map(Section::new).collect();
+            let msg = rooms.render(&header, &sections);
+
+            client.send_rendered(room_id, &msg).await
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for deploy window notices, posted to the first room of each route.
+impl Handler<DeployNotice> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: DeployNotice, _ctx: &mut Self::Context) -> Self::Result {
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            for route in &notify.routes {
+                let rooms = routes.rooms(route)?;
+                client.send_msg(rooms.room(0), &notify.body).await?;
+            }
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for the weekly noise report, posted to the first room of the route.
+impl Handler<NoiseReport> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: NoiseReport, _ctx: &mut Self::Context) -> Self::Result {
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            let rooms = routes.rooms(&notify.route)?;
+            let msg = format!(
+                "📊 Weekly noise report\n\n{}",
+                UserConfirmation::NoisyAlerts(notify.alerts)
+            );
+
+            client.send_msg(rooms.room(0), &msg).await
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for the weekly compliance report, attached as CSV file. Files are
+/// sent directly, not via the outbox.
+impl Handler<ComplianceReport> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: ComplianceReport, _ctx: &mut Self::Context) -> Self::Result {
+        let client = self.homeserver.client();
+
+        let f = async move {
+            chaos::inject(MATRIX_ADAPTER).await?;
+
+            let room_id = RoomId::try_from(notify.room.as_str())?;
+            let room = client.get_joined_room(&room_id).ok_or_else(|| {
+                Error::adapter(
+                    MATRIX_ADAPTER,
+                    format!("Compliance report room {} is not joined", room_id),
+                )
+            })?;
+
+            let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
+                format!(
+                    "📋 Weekly compliance report: {} alert(s) raised since {}, see the attached file.",
+                    notify.alerts,
+                    format_time(notify.since)
+                ),
+            ));
+            room.send(content, None).await?;
+
+            let name = format!(
+                "compliance-report-{}.csv",
+                format_time(notify.since)
+                    .split(' ')
+                    .next()
+                    .unwrap_or_default()
+            );
+            room.send_attachment(
+                &name,
+                &mime::TEXT_CSV,
+                &mut std::io::Cursor::new(notify.csv.into_bytes()),
+                None,
+            )
+            .await?;
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Handler for reminders, posted to the room which requested them.
+impl Handler<RemindAlert> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, notify: RemindAlert, _ctx: &mut Self::Context) -> Self::Result {
+        let turn = self.queue.enqueue([notify.alert.id]);
+        let client = Arc::clone(&self.outbox);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            let _turn = turn.wait().await;
+
+            let rooms = routes.rooms(&notify.route)?;
+            let room_id = rooms.room(notify.escalation_idx);
+
+            let mut msg = format!(
+                "⏰ REMINDER for {}:\n\n{}",
+                notify.requested_by, notify.alert
+            );
+            msg.pop();
+
+            client.send_msg(room_id, &msg).await
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Starts syncing on a promoted standby instance.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct StartSync;
+
+impl Handler<StartSync> for MatrixClient {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, _msg: StartSync, _ctx: &mut Self::Context) -> Self::Result {
+        let matrix = self.clone();
+
+        let f = async move {
+            // Catch up first, avoid responding to messages sent during standby.
+            let client = matrix.homeserver.client();
+            let settings =
+                SyncSettings::default().token(client.sync_token().await.ok_or_else(|| {
+                    Error::adapter(MATRIX_ADAPTER, "Failed to acquire sync token")
+                })?);
+            client.sync_once(settings).await?;
+
+            matrix.start_sync().await
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Sends a test message to each room and observer of the route, returns the
+/// outcome of each.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Vec<Check>")]
+pub struct TestRooms {
+    pub route: String,
+    pub body: String,
+}
+
+impl Handler<TestRooms> for MatrixClient {
+    type Result = ResponseActFuture<Self, Vec<Check>>;
+
+    fn handle(&mut self, msg: TestRooms, _ctx: &mut Self::Context) -> Self::Result {
+        let homeserver = Arc::clone(&self.homeserver);
+        let routes = Arc::clone(&self.routes);
+
+        let f = async move {
+            let rooms = match routes.rooms(&msg.route) {
+                Ok(rooms) => rooms,
+                Err(err) => {
+                    return vec![Check::new(String::from("Matrix"), Duration::ZERO, Err(err))]
+                }
+            };
+
+            let observers = rooms
+                .observers()
+                .iter()
+                .map(|room_id| (format!("Matrix, observer {}", room_id), room_id));
+            let targets: Vec<(String, &RoomId)> = rooms
+                .iter()
+                .map(|(idx, room_id)| (format!("Matrix, level {}", idx), room_id))
+                .chain(observers)
+                .collect();
+
+            // Bypasses the outbox, a message queued for retry would hide the
+            // failure.
+            let mut checks = vec![];
+            for (name, room_id) in targets {
+                checks.push(Check::run(name, homeserver.send_msg(room_id, &msg.body)).await);
+            }
+
+            checks
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+/// Whether the background sync is running, if it was started.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsSyncHealthy;
+
+impl Handler<IsSyncHealthy> for MatrixClient {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: IsSyncHealthy, _ctx: &mut Self::Context) -> Self::Result {
+        self.sync.is_healthy()
+    }
+}
+
+impl SystemService for MatrixClient {}
+impl Supervised for MatrixClient {}
+
+#[derive(Clone)]
+pub struct Listener {
+    routes: Arc<Routes>,
+    private: Option<Arc<DirectRooms>>,
+    incidents: Option<Arc<IncidentRooms>>,
+}
+
+impl Listener {
+    /// Returns the alert of an incident room.
+    async fn incident(&self, room_id: &RoomId) -> Option<AlertId> {
+        match &self.incidents {
+            Some(incidents) => incidents.find_alert(room_id).await,
+            None => None,
+        }
+    }
+    /// Replies to a command in the room, or directly to the sender if replies
+    /// are private and do not concern the room.
+    async fn reply(
+        &self,
+        room: &Joined,
+        sender: &UserId,
+        confirmation: &UserConfirmation,
+    ) -> Result<()> {
+        let body = truncate::message(&confirmation.to_string()).into_owned();
+
+        if let Some(direct) = self.private.as_ref().filter(|_| !confirmation.is_public()) {
+            match direct.room(sender).await {
+                Ok(room_id) => return direct.homeserver.send_msg(&room_id, &body).await,
+                Err(err) => warn!(
+                    "Failed to reply to {} directly, replying in the room: {:?}",
+                    sender, err
+                ),
+            }
+        }
+
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(body));
+        room.send(content, None).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventHandler for Listener {
+    async fn on_room_message(&self, room: Room, event: &SyncMessageEvent<MessageEventContent>) {
+        if let Room::Joined(room) = room {
+            let res = |room: Joined, event: SyncMessageEvent<MessageEventContent>| async move {
+                // Ignore own messages.
+                if &event.sender == room.own_user_id() {
+                    return Ok(());
+                }
+
+                let msg_body = if let SyncMessageEvent {
+                    content:
+                        MessageEventContent {
+                            msgtype:
+                                MessageType::Text(TextMessageEventContent { body: msg_body, .. }),
+                            ..
+                        },
+                    ..
+                } = event
+                {
+                    msg_body.to_string()
+                } else {
+                    return Err(Error::adapter(
+                        MATRIX_ADAPTER,
+                        format!("Received unacceptable message type from {}", event.sender),
+                    ));
+                };
+
+                debug!("Received message from {}: {}", event.sender, msg_body);
+
+                let cmd = match parse_command(msg_body.trim(), event.sender.as_str()) {
+                    Some(Ok(cmd)) => cmd,
+                    Some(Err(usage)) => {
+                        return self
+                            .reply(&room, &event.sender, &UserConfirmation::Usage(usage))
+                            .await;
+                    }
+                    // Ignore casual chatter in rooms.
+                    None => return Ok(()),
+                };
+
+                // Determine the escalation index based on ordering of rooms.
+                // Incident rooms act as the room of their alert's level.
+                let incident = self.incident(room.room_id()).await;
+                let (route, escalation_idx) =
+                    if let Some(found) = self.routes.find_room(room.room_id()) {
+                        found
+                    } else if let Some(id) = incident {
+                        match Processor::from_registry().send(GetAlert(id)).await?? {
+                            Some(alert) => (alert.route, alert.escalation_idx),
+                            None => {
+                                return self
+                                    .reply(&room, &event.sender, &UserConfirmation::AlertNotFound)
+                                    .await
+                            }
+                        }
+                    } else {
+                        // Observers only receive notifications.
+                        return self
+                            .reply(&room, &event.sender, &UserConfirmation::ReadOnly)
+                            .await;
+                    };
+
+                // Prepare action type.
+                let action = UserAction {
+                    route: route.to_string(),
+                    escalation_idx,
+                    command: cmd,
+                };
+
+                // Send action to processor.
+                let confirmation = Processor::from_registry().send(action).await?;
+
+                // Inform the adapters, e.g. Telegram, about the ack.
+                if let UserConfirmation::AlertAcknowledged(id) = confirmation {
+                    Processor::from_registry().do_send(RemoteAck {
+                        route: route.clone(),
+                        escalation_idx,
+                        id,
+                        user: event.sender.to_string(),
+                        via: String::from(MATRIX_ADAPTER),
+                    });
+
+                    // Acks in incident rooms are announced in the room of the
+                    // level, and vice versa.
+                    if let Some(incidents) = &self.incidents {
+                        let via = match incident {
+                            Some(_) => "its incident room",
+                            None => MATRIX_ADAPTER,
+                        };
+                        let msg = Message {
+                            body: format!(
+                                "✅ Alert {} has been acknowledged by {} via {}",
+                                id, event.sender, via
+                            ),
+                            html: None,
+                        };
+
+                        match incident {
+                            Some(_) => {
+                                let rooms = self.routes.rooms(&route)?;
+                                incidents
+                                    .outbox
+                                    .send_rendered(rooms.room(escalation_idx), &msg)
+                                    .await?
+                            }
+                            None => incidents.relay(id, &msg).await,
+                        }
+                    }
+                }
+
+                // The incident room is archived instead, see `IncidentResolved`.
+                if let (Some(_), UserConfirmation::AlertResolved(_)) = (incident, &confirmation) {
+                    return Ok(());
+                }
+
+                // Long lists of pending alerts are attached as a file, unless
+                // sent privately.
+                if let UserConfirmation::PendingAlerts(alerts) = &confirmation {
+                    if alerts.len() > PENDING_ATTACHMENT_THRESHOLD && self.private.is_none() {
+                        debug!("Attaching {} pending alerts", alerts.len());
+                        return send_pending_attachment(&room, alerts).await;
+                    }
+                }
+
+                debug!("Replying to {}", event.sender);
+                self.reply(&room, &event.sender, &confirmation).await
+            };
+
+            // Only process whitelisted rooms.
+            if self.routes.find_room(room.room_id()).is_none()
+                && !self.routes.is_observer(room.room_id())
+                && self.incident(room.room_id()).await.is_none()
+            {
+                return;
+            }
+
+            match res(room, event.clone()).await {
+                Ok(_) => {}
+                Err(err) => error!("Error when trying to process Matrix message {:?}", err),
+            }
+        }
+    }
+}
+
+/// Returns the argument of a command with exactly one argument.
+/// Splits a message into arguments. Double quotes group words, e.g.
+/// `simulate critical "Node down"`.
+fn split_args(txt: &str) -> Option<Vec<String>> {
+    let mut args = vec![];
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+
+    for c in txt.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                // Keeps empty quotes as an argument.
+                current.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    args.extend(current);
+    Some(args)
+}
+
+/// Parses a user command. Returns `None` if the message is not a command, or
+/// the usage of the command if its arguments are invalid.
+pub fn parse_command(txt: &str, sender: &str) -> Option<std::result::Result<Command, String>> {
+    let name = txt.split_whitespace().next()?.to_lowercase();
+    let info = command_info(&name)?;
+    let usage = info.usage_hint();
+
+    let args = match split_args(txt) {
+        Some(args) => args,
+        None => return Some(Err(usage)),
+    };
+    let args: Vec<&str> = args.iter().skip(1).map(String::as_str).collect();
+    let sender = sender.to_string();
+
+    let cmd = match (info.name, args.as_slice()) {
+        ("ack", [target]) => {
+            let target = match AlertId::from_str(target) {
+                Ok(id) => AckTarget::Id(id),
+                Err(_) if target.eq_ignore_ascii_case("last") => AckTarget::Last,
+                Err(_) => AckTarget::Name(target.to_string()),
+            };
+
+            Some(Command::Ack(target, sender))
+        }
+        ("resolve", [id]) => AlertId::from_str(id)
+            .ok()
+            .map(|id| Command::Resolve(id, sender)),
+        ("remind", [id, delay]) => AlertId::from_str(id)
+            .ok()
+            .zip(parse_duration(delay))
+            .map(|(id, delay)| Command::Remind(id, delay, sender)),
+        ("details", [id]) => AlertId::from_str(id).ok().map(Command::Details),
+        ("handoff", [to]) => UserId::try_from(*to)
+            .ok()
+            .map(|to| Command::Handoff(sender, to.to_string())),
+        ("mute", [duration]) => {
+            parse_duration(duration).map(|duration| Command::Mute(duration, sender))
+        }
+        ("override", ["end", name]) => Some(Command::EndOverride(name.to_string(), sender)),
+        ("override", [name, duration, options @ ..]) => {
+            parse_override_options(options).and_then(|(target_route, entry_level)| {
+                parse_duration(duration).map(|duration| {
+                    Command::Override(
+                        name.to_string(),
+                        duration,
+                        target_route,
+                        entry_level,
+                        sender,
+                    )
+                })
+            })
+        }
+        ("overrides", []) => Some(Command::Overrides),
+        ("simulate", [severity, alert_name]) => Some(Command::Simulate(
+            severity.to_string(),
+            alert_name.to_string(),
+            sender,
+        )),
+        ("selftest", []) => Some(Command::SelfTest(sender)),
+        ("disable", [kind, name]) if kind.eq_ignore_ascii_case("adapter") => {
+            Some(Command::SetAdapter(name.to_string(), false, sender))
+        }
+        ("enable", [kind, name]) if kind.eq_ignore_ascii_case("adapter") => {
+            Some(Command::SetAdapter(name.to_string(), true, sender))
+        }
+        ("pending", []) => Some(Command::Pending),
+        ("noisy", []) => Some(Command::Noisy),
+        ("failures", []) => Some(Command::Failures),
+        ("stats", [kind]) if kind.eq_ignore_ascii_case("users") => Some(Command::UserStats(None)),
+        ("stats", [kind, period]) if kind.eq_ignore_ascii_case("users") => {
+            parse_duration(period).map(|period| Command::UserStats(Some(period)))
+        }
+        ("watch", labels) => parse_labels(labels).map(|labels| Command::Watch(labels, sender)),
+        ("unwatch", []) => Some(Command::Unwatch(None, sender)),
+        ("unwatch", labels) => {
+            parse_labels(labels).map(|labels| Command::Unwatch(Some(labels), sender))
+        }
+        ("watches", []) => Some(Command::Watches(sender)),
+        ("help", []) => Some(Command::Help(None, sender)),
+        ("help", [topic]) => Some(Command::Help(Some(topic.to_string()), sender)),
+        _ => None,
+    };
+
+    Some(cmd.ok_or(usage))
+}
+
+/// Parses labels such as `severity=critical`, see `Labels::NAMES`.
+fn parse_labels(args: &[&str]) -> Option<BTreeMap<String, String>> {
+    if args.is_empty() {
+        return None;
+    }
+
+    args.iter()
+        .map(|arg| {
+            let (name, value) = arg.split_once('=')?;
+            let name = name.to_lowercase();
+            if !Labels::NAMES.contains(&name.as_str()) || value.is_empty() {
+                return None;
+            }
+
+            Some((name, value.to_string()))
+        })
+        .collect()
+}
+
+/// Parses the target route and the entry level of an override, such as
+/// `route=payments level=1`. At least one of them must be given.
+fn parse_override_options(args: &[&str]) -> Option<(Option<String>, Option<usize>)> {
+    let mut target_route = None;
+    let mut entry_level = None;
+
+    for arg in args {
+        match arg.split_once('=')? {
+            ("route", route) if !route.is_empty() => target_route = Some(route.to_string()),
+            ("level", level) => entry_level = Some(level.parse().ok()?),
+            _ => return None,
+        }
+    }
+
+    if target_route.is_none() && entry_level.is_none() {
+        return None;
+    }
+
+    Some((target_route, entry_level))
+}
+
+/// Parses durations such as `30m`, `2h` or `1d` into seconds.
+fn parse_duration(txt: &str) -> Option<u64> {
+    let unit = match txt.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    txt[..txt.len() - 1]
+        .parse::<u64>()
+        .ok()
+        .filter(|val| *val > 0)
+        .and_then(|val| val.checked_mul(unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{EscalationSettings, InsertAlerts};
+    use crate::testing::{
+        alert_context, escalation_settings, test_database, MockHomeserver, SentMessage, BOT_USER,
+        DIRECT_ROOM, OTHER_USER,
+    };
+    use actix::SystemRegistry;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const FIRST_ROOM: &str = "!first:localhost";
+    const SECOND_ROOM: &str = "!second:localhost";
+    const OTHER_ROOM: &str = "!other:localhost";
+    const OBSERVER_ROOM: &str = "!observer:localhost";
+
+    #[test]
+    fn summarizes_pending_alerts() {
+        let alerts: Vec<AlertContext> = (1..=30).map(|id| alert_context(id, "other")).collect();
+        let summary = pending_summary(&alerts);
+
+        assert!(summary.starts_with("30 pending alerts, see the attached pending-alerts.json."));
+        assert!(summary.contains("- ID: 5, Name: Alert5, Severity: critical"));
+        assert!(!summary.contains("ID: 6,"));
+    }
+
+    #[test]
+    fn sync_is_healthy_unless_stopped() {
+        let health = SyncHealth::default();
+        assert!(health.is_healthy());
+
+        health.started.store(true, Ordering::SeqCst);
+        assert!(!health.is_healthy());
+
+        health.running.store(true, Ordering::SeqCst);
+        assert!(health.is_healthy());
+    }
+
+    fn routes() -> Vec<RouteConfig> {
+        vec![
+            RouteConfig {
+                name: crate::DEFAULT_ROUTE.to_string(),
+                rooms: vec![FIRST_ROOM.to_string(), SECOND_ROOM.to_string()],
+                space: None,
+                observers: vec![],
+                severity_levels: Default::default(),
+                ack_scope: Default::default(),
+                ack_ttl: None,
+                business_hours: None,
+                format: Default::default(),
+                exec_levels: None,
+                priority: 0,
+                slo: None,
+            },
+            RouteConfig {
+                name: String::from("other"),
+                rooms: vec![OTHER_ROOM.to_string()],
+                space: None,
+                observers: vec![],
+                severity_levels: Default::default(),
+                ack_scope: Default::default(),
+                ack_ttl: None,
+                business_hours: None,
+                format: Default::default(),
+                exec_levels: None,
+                priority: 0,
+                slo: None,
+            },
+        ]
+    }
+
+    fn message(room_id: &str, body: &str) -> SentMessage {
+        SentMessage {
+            room_id: room_id.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_room_in_multiple_routes() {
+        let homeserver = MockHomeserver::start().await;
+
+        let mut routes = routes();
+        routes[1].rooms.push(FIRST_ROOM.to_string());
+
+        assert!(
+            MatrixClient::new(&homeserver.config(), &routes, None, false, true)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn orders_space_children() {
+        let child = |room_id: &str, order: Option<&str>, name: Option<&str>| SpaceChild {
+            room_id: RoomId::try_from(room_id).unwrap(),
+            order: order.map(String::from),
+            name: name.map(String::from),
+        };
+
+        let rooms = order_children(vec![
+            child("!d:localhost", None, None),
+            child("!c:localhost", None, Some("1 - Ops")),
+            child("!b:localhost", Some("b"), None),
+            child("!a:localhost", Some("a"), Some("2 - On-call")),
+            // Invalid orders are ignored.
+            child("!e:localhost", Some("\u{1f600}"), Some("0 - First")),
+        ]);
+
+        assert_eq!(
+            rooms.iter().map(|room| room.as_str()).collect::<Vec<_>>(),
+            vec![
+                "!a:localhost",
+                "!b:localhost",
+                "!e:localhost",
+                "!c:localhost",
+                "!d:localhost"
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn resolves_rooms_of_spaces() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver
+            .space_children(&[
+                ("!later:localhost", Some("2"), false),
+                (OTHER_ROOM, Some("1"), false),
+                ("!removed:localhost", Some("0"), true),
+            ])
+            .await;
+
+        let mut routes = routes();
+        routes[1].rooms.clear();
+        routes[1].space = Some(String::from("!space:localhost"));
+
+        let client = MatrixClient::new(&homeserver.config(), &routes, None, false, false)
+            .await
+            .unwrap();
+        let rooms = vec![
+            RoomId::try_from(OTHER_ROOM).unwrap(),
+            RoomId::try_from("!later:localhost").unwrap(),
+        ];
+        assert_eq!(client.routes.rooms("other").unwrap().rooms, rooms);
+        assert!(!client.routes.set_rooms("other", rooms).unwrap());
+
+        // Rooms of other routes are rejected.
+        assert!(client
+            .routes
+            .set_rooms("other", vec![RoomId::try_from(FIRST_ROOM).unwrap()])
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn notify_alert_sends_to_first_room_of_route() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        client
+            .send(NotifyAlert {
+                route: String::from("other"),
+                alerts: vec![alert_context(1, "other")],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, OTHER_ROOM);
+        assert!(sent[0].body.starts_with("⚠️ Alert occurred!"));
+        assert!(sent[0].body.contains("ID: 1"));
+    }
+
+    #[actix_web::test]
+    async fn notify_alert_copies_to_observers() {
+        let homeserver = MockHomeserver::start().await;
+        let mut routes = routes();
+        routes[1].observers.push(OBSERVER_ROOM.to_string());
+
+        let client = MatrixClient::new(&homeserver.config(), &routes, None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        client
+            .send(NotifyAlert {
+                route: String::from("other"),
+                alerts: vec![alert_context(1, "other")],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(2).await;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].room_id, OTHER_ROOM);
+        assert_eq!(sent[1].room_id, OBSERVER_ROOM);
+        assert_eq!(sent[0].body, sent[1].body);
+    }
+
+    #[actix_web::test]
+    async fn notify_alert_mentions_team() {
+        let homeserver = MockHomeserver::start().await;
+        let mut teams = HashMap::new();
+        teams.insert(
+            String::from("payments"),
+            TeamConfig {
+                mentions: vec![OTHER_USER.to_string()],
+            },
+        );
+
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .with_teams(teams)
+            .start();
+
+        let mut alert = alert_context(1, "other");
+        alert.alert.labels.team = Some(String::from("payments"));
+
+        client
+            .send(NotifyAlert {
+                route: String::from("other"),
+                alerts: vec![alert, alert_context(2, "other")],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]
+            .body
+            .contains(&format!("Team payments: {}", OTHER_USER)));
+        assert_eq!(sent[0].body.matches(OTHER_USER).count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn notify_alert_sends_direct_messages() {
+        let homeserver = MockHomeserver::start().await;
+        let mut config = homeserver.config();
+        config.direct_messages = true;
+
+        let client = MatrixClient::new(&config, &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let mut watchers = HashMap::new();
+        watchers.insert(AlertId::from(1), vec![OTHER_USER.to_string()]);
+        watchers.insert(AlertId::from(3), vec![OTHER_USER.to_string()]);
+
+        for id in [1, 3] {
+            client
+                .send(NotifyAlert {
+                    route: String::from("other"),
+                    alerts: vec![alert_context(id, "other"), alert_context(id + 1, "other")],
+                    watchers: watchers.clone(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let sent = homeserver.wait_for_messages(4).await;
+        let direct: Vec<&SentMessage> = sent
+            .iter()
+            .filter(|msg| msg.room_id == DIRECT_ROOM)
+            .collect();
+
+        assert_eq!(direct.len(), 2);
+        assert!(direct[0].body.contains("Alert1"));
+        assert!(!direct[0].body.contains("Alert2"));
+        assert!(direct[1].body.contains("Alert3"));
+        // The room is reused.
+        assert_eq!(homeserver.created_rooms().await, 1);
+    }
+
+    #[actix_web::test]
+    async fn incident_rooms_relay_notifications_until_resolved() {
+        let homeserver = MockHomeserver::start().await;
+        let mut config = homeserver.config();
+        config.incident_rooms = Some(IncidentRoomsConfig {
+            min_severity: String::from("critical"),
+            responders: vec![OTHER_USER.to_string()],
+        });
+
+        let client = MatrixClient::new(&config, &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        // Only the critical alert is a major incident.
+        let mut minor = alert_context(2, crate::DEFAULT_ROUTE);
+        minor.alert.labels.severity = String::from("warning");
+        client
+            .send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert_context(1, crate::DEFAULT_ROUTE), minor],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let rooms = homeserver.room_requests().await;
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0]["name"], "Incident 1: Alert1");
+        assert_eq!(rooms[0]["invite"], serde_json::json!([OTHER_USER]));
+
+        let mut escalated = alert_context(1, crate::DEFAULT_ROUTE);
+        escalated.escalation_idx = 1;
+        client
+            .send(Escalation {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                escalation_idx: 1,
+                alerts: vec![escalated],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        client
+            .send(IncidentResolved {
+                id: AlertId::from(1),
+                resolved_by: OTHER_USER.to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(6).await;
+        let incident: Vec<&SentMessage> = sent
+            .iter()
+            .filter(|msg| msg.room_id == DIRECT_ROOM)
+            .collect();
+
+        assert_eq!(incident.len(), 3);
+        assert!(incident[0].body.contains("Alert1"));
+        assert!(!incident[0].body.contains("Alert2"));
+        assert!(incident[1].body.starts_with("🚨 ESCALATION OCCURRED!"));
+        assert!(incident[2]
+            .body
+            .starts_with(&format!("✅ Alert 1 has been resolved by {}", OTHER_USER)));
+        assert_eq!(homeserver.left_rooms().await, vec![DIRECT_ROOM]);
+
+        // Nothing is relayed once archived.
+        client
+            .send(RemoteAck {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                escalation_idx: 1,
+                id: AlertId::from(1),
+                user: OTHER_USER.to_string(),
+                via: String::from("the API"),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(homeserver
+            .sent_messages()
+            .await
+            .iter()
+            .skip(6)
+            .all(|msg| msg.room_id != DIRECT_ROOM));
+    }
+
+    #[actix_web::test]
+    async fn notify_alert_sends_to_room_of_entry_level() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let mut critical = alert_context(2, crate::DEFAULT_ROUTE);
+        critical.escalation_idx = 1;
+
+        client
+            .send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert_context(1, crate::DEFAULT_ROUTE), critical],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(2).await;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].room_id, FIRST_ROOM);
+        assert!(sent[0].body.contains("ID: 1"));
+        assert!(!sent[0].body.contains("ID: 2"));
+        assert_eq!(sent[1].room_id, SECOND_ROOM);
+        assert!(sent[1].body.contains("ID: 2"));
+    }
+
+    #[actix_web::test]
+    async fn escalation_notifies_current_and_next_room() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let reached = client
+            .send(Escalation {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                escalation_idx: 1,
+                alerts: vec![alert_context(2, crate::DEFAULT_ROUTE)],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(reached, 1);
+
+        let sent = homeserver.wait_for_messages(2).await;
+        assert_eq!(
+            sent[0],
+            message(
+                FIRST_ROOM,
+                "🚨 ESCALATION OCCURRED! Notifying next room regarding Alerts: ID: 2"
+            )
+        );
+        assert_eq!(sent[1].room_id, SECOND_ROOM);
+        assert!(sent[1]
+            .body
+            .starts_with("🚨 ESCALATION OCCURRED!\n\n- ID: 2"));
+    }
+
+    #[actix_web::test]
+    async fn escalation_on_last_room_only_notifies_last_room() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let reached = client
+            .send(Escalation {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                escalation_idx: 2,
+                alerts: vec![alert_context(3, crate::DEFAULT_ROUTE)],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(reached, 1);
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, SECOND_ROOM);
+    }
+
+    #[actix_web::test]
+    async fn escalation_beyond_last_room_is_capped() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let reached = client
+            .send(Escalation {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                escalation_idx: 5,
+                alerts: vec![alert_context(4, crate::DEFAULT_ROUTE)],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(reached, 1);
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, SECOND_ROOM);
+    }
+
+    #[actix_web::test]
+    async fn catch_up_summary_is_sent_to_current_room() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        client
+            .send(CatchUpSummary {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                escalation_idx: 0,
+                alerts: vec![
+                    alert_context(5, crate::DEFAULT_ROUTE),
+                    alert_context(6, crate::DEFAULT_ROUTE),
+                ],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, FIRST_ROOM);
+        assert!(sent[0].body.starts_with("⏰ MISSED ESCALATIONS!"));
+        assert!(sent[0].body.contains("ID: 5"));
+        assert!(sent[0].body.contains("ID: 6"));
+    }
+
+    #[actix_web::test]
+    async fn listener_answers_malformed_ack() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver
+            .receive_message(SECOND_ROOM, OTHER_USER, "ack 1 2")
+            .await;
+
+        let (tx, _recv) = unbounded_channel();
+        let settings = EscalationSettings {
+            enabled: false,
+            ..escalation_settings()
+        };
+        SystemRegistry::set(Processor::new(None, settings, false, vec![], tx).start());
+
+        let _client = MatrixClient::new(&homeserver.config(), &routes(), None, true, true)
+            .await
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(
+            sent[0],
+            message(
+                SECOND_ROOM,
+                "Usage: ack <ID|last|NAME>, e.g. `ack 5` or `ack last`"
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn listener_replies_privately() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver
+            .receive_message(SECOND_ROOM, OTHER_USER, "ack 1 2")
+            .await;
+
+        let mut config = homeserver.config();
+        config.private_responses = true;
+        let _client = MatrixClient::new(&config, &routes(), None, true, true)
+            .await
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent[0].room_id, DIRECT_ROOM);
+        assert!(sent[0].body.starts_with("Usage: ack"));
+    }
+
+    #[actix_web::test]
+    async fn listener_ignores_own_messages() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver
+            .receive_message(FIRST_ROOM, BOT_USER, "ack abc")
+            .await;
+
+        let _client = MatrixClient::new(&homeserver.config(), &routes(), None, true, true)
+            .await
+            .unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(homeserver.sent_messages().await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn standby_client_skips_messages_received_before_promotion() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver
+            .receive_message(FIRST_ROOM, OTHER_USER, "ack abc")
+            .await;
+
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, true, false)
+            .await
+            .unwrap()
+            .start();
+
+        client.send(StartSync).await.unwrap().unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(homeserver.sent_messages().await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn ack_expiry_reminds_room_of_current_level() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let mut alert = alert_context(7, crate::DEFAULT_ROUTE);
+        alert.escalation_idx = 1;
+
+        client
+            .send(AckExpired {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, SECOND_ROOM);
+        assert!(sent[0].body.starts_with("⏳ ACKNOWLEDGEMENT EXPIRED!"));
+        assert!(sent[0].body.contains("ID: 7"));
+    }
+
+    #[actix_web::test]
+    async fn refire_posts_changes_to_current_level() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        let mut alert = alert_context(7, crate::DEFAULT_ROUTE);
+        alert.escalation_idx = 1;
+
+        client
+            .send(AlertRefired {
+                alert,
+                changes: vec![
+                    AnnotationChange {
+                        label: "Value",
+                        old: Some(String::from("91")),
+                        new: Some(String::from("97")),
+                    },
+                    AnnotationChange {
+                        label: "Runbook",
+                        old: None,
+                        new: Some(String::from("https://runbooks.example.com/disk")),
+                    },
+                ],
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, SECOND_ROOM);
+        assert_eq!(
+            sent[0].body,
+            "🔁 Alert 7 (Alert7) fired again with changes:\n  \
+             Value: 91 → 97\n  \
+             Runbook: (none) → https://runbooks.example.com/disk"
+        );
+    }
+
+    #[actix_web::test]
+    async fn requests_are_sent_through_proxy() {
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.proxied_config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        client
+            .send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert_context(1, crate::DEFAULT_ROUTE)],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent[0].room_id, FIRST_ROOM);
+    }
+
+    #[actix_web::test]
+    async fn notification_includes_trend() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let prometheus = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/query_range"))
+            .and(query_param("query", "up == 0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "status": "success",
+                "data": {
+                    "resultType": "matrix",
+                    "result": [{ "metric": {}, "values": [[1, "1"], [2, "2"]] }],
+                },
+            })))
+            .mount(&prometheus)
+            .await;
+
+        let homeserver = MockHomeserver::start().await;
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap()
+            .with_prometheus(Some(
+                Prometheus::new(
+                    serde_json::from_value(serde_json::json!({
+                        "allowed_hosts": [prometheus.address().to_string()],
+                    }))
+                    .unwrap(),
+                    &HttpConfig::default(),
+                )
+                .unwrap(),
+            ))
+            .start();
+
+        let mut alert = alert_context(1, crate::DEFAULT_ROUTE);
+        alert.alert.generator_url = Some(format!(
+            "{}/graph?g0.expr=up+%3D%3D+0&g0.tab=1",
+            prometheus.uri()
+        ));
+
+        client
+            .send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert!(sent[0].body.contains("  Trend: ▁█ (current: 2)"));
+    }
+
+    #[actix_web::test]
+    async fn fails_over_to_fallback_account() {
+        let homeserver = MockHomeserver::start().await;
+        let config: MatrixConfig = serde_json::from_value(serde_json::json!({
+            "homeserver": "http://127.0.0.1:9",
+            "username": "bot",
+            "password": "password",
+            "db_path": std::env::temp_dir()
+                .join(format!("matrixbot-test-unreachable-{}", std::process::id())),
+            "device_name": "matrixbot-test",
+            "device_id": "DEVICEID",
+            "login_timeout": 1,
+            "fallbacks": [homeserver.config()],
+        }))
+        .unwrap();
+
+        let client = MatrixClient::new(&config, &routes(), None, false, true)
+            .await
+            .unwrap()
+            .start();
+
+        client
+            .send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert_context(1, crate::DEFAULT_ROUTE)],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert_eq!(sent[0].room_id, FIRST_ROOM);
+    }
+
+    #[actix_web::test]
+    async fn fails_over_once_homeserver_goes_down() {
+        let primary = MockHomeserver::start().await;
+        let fallback = MockHomeserver::start().await;
+        let mut config = primary.config();
+        config.fallbacks = vec![fallback.config()];
+
+        let client = MatrixClient::new(&config, &routes(), None, false, false)
+            .await
+            .unwrap()
+            .start();
+        primary.go_down().await;
+
+        // The last failure within the threshold fails over and is sent again.
+        let notifications = (1..=FAILOVER_THRESHOLD as u64).map(|id| {
+            client.send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert_context(id, crate::DEFAULT_ROUTE)],
+                watchers: Default::default(),
+            })
+        });
+        futures::future::join_all(notifications).await;
+        fallback.wait_for_messages(1).await;
+
+        client
+            .send(NotifyAlert {
+                route: crate::DEFAULT_ROUTE.to_string(),
+                alerts: vec![alert_context(10, crate::DEFAULT_ROUTE)],
+                watchers: Default::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = fallback.wait_for_messages(2).await;
+        assert!(sent.iter().all(|msg| msg.room_id == FIRST_ROOM));
+        assert!(sent.iter().any(|msg| msg.body.contains("Alert10")));
+    }
+
+    #[test]
+    fn levels_clamp_to_final_room() {
+        assert!(Levels::new("empty", vec![], vec![]).is_err());
+
+        let rooms: Vec<RoomId> = [FIRST_ROOM, SECOND_ROOM]
+            .iter()
+            .map(|room| RoomId::try_from(*room).unwrap())
+            .collect();
+        let levels = Levels::new("default", rooms.clone(), vec![]).unwrap();
+
+        assert_eq!(levels.clamp(0), 0);
+        assert_eq!(levels.clamp(1), 1);
+        assert_eq!(levels.clamp(8), 1);
+        assert_eq!(levels.room(8), &rooms[1]);
+        assert_eq!(
+            levels.iter().collect::<Vec<_>>(),
+            vec![(0, &rooms[0]), (1, &rooms[1])]
+        );
+    }
+
+    #[test]
+    fn server_errors_are_transient() {
+        let unavailable =
+            matrix_sdk::Error::Http(HttpError::Server(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient(&unavailable.into()));
+
+        let unauthenticated = matrix_sdk::Error::AuthenticationRequired;
+        assert!(!is_transient(&unauthenticated.into()));
+
+        let forbidden = matrix_sdk::Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(
+            ServerError::Known(ruma::api::client::error::Error {
+                kind: ruma::api::client::error::ErrorKind::Forbidden,
+                message: String::from("You are not in this room"),
+                status_code: reqwest::StatusCode::FORBIDDEN,
+            }),
+        )));
+        let forbidden = Error::from(forbidden);
+        assert!(matches!(forbidden, Error::Permission(_)));
+        assert!(!is_transient(&forbidden));
+        assert!(!is_transient(&Error::Internal(String::from(
+            "Some other error"
+        ))));
+    }
+
+    #[test]
+    fn splits_quoted_arguments() {
+        assert_eq!(
+            split_args("simulate  critical \"Node down\"").unwrap(),
+            vec!["simulate", "critical", "Node down"]
+        );
+        assert_eq!(split_args("ack \"\"").unwrap(), vec!["ack", ""]);
+        assert!(split_args("simulate critical \"Node down").is_none());
+    }
+
+    #[test]
+    fn parses_commands() {
+        let sender = OTHER_USER;
+
+        assert_eq!(
+            parse_command("ACK 5", sender),
+            Some(Ok(Command::Ack(
+                AckTarget::Id(AlertId::from(5)),
+                sender.to_string()
+            )))
+        );
+        assert_eq!(
+            parse_command("ack last", sender),
+            Some(Ok(Command::Ack(AckTarget::Last, sender.to_string())))
+        );
+        assert_eq!(
+            parse_command("ack \"Node down\"", sender),
+            Some(Ok(Command::Ack(
+                AckTarget::Name(String::from("Node down")),
+                sender.to_string()
+            )))
+        );
+        assert_eq!(
+            parse_command("simulate critical \"Node down\"", sender),
+            Some(Ok(Command::Simulate(
+                String::from("critical"),
+                String::from("Node down"),
+                sender.to_string()
+            )))
+        );
+        assert_eq!(
+            parse_command("remind 5 45m", sender),
+            Some(Ok(Command::Remind(
+                AlertId::from(5),
+                45 * 60,
+                sender.to_string()
+            )))
+        );
+        assert_eq!(
+            parse_command("selftest", sender),
+            Some(Ok(Command::SelfTest(sender.to_string())))
+        );
+        assert_eq!(
+            parse_command("disable adapter \"SNS topics\"", sender),
+            Some(Ok(Command::SetAdapter(
+                String::from("SNS topics"),
+                false,
+                sender.to_string()
+            )))
+        );
+        assert_eq!(
+            parse_command("enable Adapter telegram", sender),
+            Some(Ok(Command::SetAdapter(
+                String::from("telegram"),
+                true,
+                sender.to_string()
+            )))
+        );
+        assert!(matches!(
+            parse_command("disable telegram", sender),
+            Some(Err(_))
+        ));
+        assert!(matches!(parse_command("remind 5", sender), Some(Err(_))));
+        assert_eq!(
+            parse_command("resolve 5 6", sender),
+            Some(Err(String::from("resolve <ID>")))
+        );
+        assert!(matches!(
+            parse_command("mute two hours", sender),
+            Some(Err(_))
+        ));
+        assert!(matches!(parse_command("handoff bob", sender), Some(Err(_))));
+        assert_eq!(parse_command("acked, thanks!", sender), None);
+        assert_eq!(
+            parse_command("watch SEVERITY=critical team=infra", sender),
+            Some(Ok(Command::Watch(
+                vec![
+                    (String::from("severity"), String::from("critical")),
+                    (String::from("team"), String::from("infra")),
+                ]
+                .into_iter()
+                .collect(),
+                sender.to_string()
+            )))
+        );
+        assert!(matches!(
+            parse_command("watch color=red", sender),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            parse_command("stats users 30d", sender),
+            Some(Ok(Command::UserStats(Some(30 * 24 * 60 * 60))))
+        );
+        assert_eq!(
+            parse_command("stats users", sender),
+            Some(Ok(Command::UserStats(None)))
+        );
+        assert!(matches!(
+            parse_command("stats alerts", sender),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            parse_command("unwatch", sender),
+            Some(Ok(Command::Unwatch(None, sender.to_string())))
+        );
+        assert_eq!(
+            parse_command("override freeze 2d route=payments level=1", sender),
+            Some(Ok(Command::Override(
+                String::from("freeze"),
+                2 * 24 * 60 * 60,
+                Some(String::from("payments")),
+                Some(1),
+                sender.to_string()
+            )))
+        );
+        assert_eq!(
+            parse_command("override end freeze", sender),
+            Some(Ok(Command::EndOverride(
+                String::from("freeze"),
+                sender.to_string()
+            )))
+        );
+        // Without effect.
+        assert!(matches!(
+            parse_command("override freeze 2d", sender),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            parse_command("help ack", sender),
+            Some(Ok(Command::Help(
+                Some(String::from("ack")),
+                sender.to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("45s"), Some(45));
+        assert_eq!(parse_duration("30m"), Some(30 * 60));
+        assert_eq!(parse_duration("2h"), Some(2 * 60 * 60));
+        assert_eq!(parse_duration("1d"), Some(24 * 60 * 60));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("2"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("two hours"), None);
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn outbox_dead_letters_undeliverable_messages() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver.forbid_room(OTHER_ROOM).await;
+
+        let db = Arc::new(test_database().await);
+        db.queue_message(OTHER_ROOM, "Lost", None).await.unwrap();
+        db.queue_message(FIRST_ROOM, "Delivered", None)
+            .await
+            .unwrap();
+
+        let outbox = Outbox {
+            homeserver: Arc::new(Homeserver::new(
+                vec![homeserver.config()],
+                0,
+                login(&homeserver.config()).await.unwrap(),
+            )),
+            db: Some(Arc::clone(&db)),
+            active: AtomicBool::new(true),
+            flushing: AtomicBool::new(false),
+        };
+
+        // The forbidden room neither blocks other rooms nor is retried.
+        outbox.flush().await.unwrap();
+        outbox.flush().await.unwrap();
+        assert_eq!(
+            homeserver.sent_messages().await,
+            vec![
+                message(OTHER_ROOM, "Lost"),
+                message(FIRST_ROOM, "Delivered")
+            ]
+        );
+        assert!(db.get_queued_messages().await.unwrap().is_empty());
+        assert!(!db.has_queued_messages(OTHER_ROOM).await.unwrap());
+
+        db.drop_database().await.unwrap();
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn mute_holds_back_alerts_until_it_expires() {
+        let homeserver = MockHomeserver::start().await;
+        homeserver
+            .receive_message(FIRST_ROOM, OTHER_USER, "mute 2s")
+            .await;
+
+        let db = Arc::new(test_database().await);
+        let (tx, _recv) = unbounded_channel();
+        let settings = EscalationSettings {
+            enabled: false,
+            ..escalation_settings()
+        };
+        SystemRegistry::set(
+            Processor::new(
+                Some(Arc::clone(&db)),
+                settings,
+                false,
+                vec![OTHER_USER.to_string()],
+                tx,
+            )
+            .start(),
+        );
+
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, true, true)
+            .await
+            .unwrap();
+        SystemRegistry::set(client.start());
+
+        let sent = homeserver.wait_for_messages(1).await;
+        assert!(sent[0]
+            .body
+            .starts_with("All notifications are muted until"));
+
+        let mut alerts: InsertAlerts = serde_json::from_value(serde_json::json!({
+            "alerts": [alert_context(1, crate::DEFAULT_ROUTE).alert],
+        }))
+        .unwrap();
+        alerts.route = crate::DEFAULT_ROUTE.to_string();
+        Processor::from_registry()
+            .send(alerts)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.get_mute().await.unwrap().unwrap().alerts.len(), 1);
+
+        // The held back alert is notified on its entry level once the mute
+        // expires, followed by the reminder.
+        let sent = homeserver.wait_for_messages(3).await;
+        assert_eq!(sent[1].room_id, FIRST_ROOM);
+        assert!(sent[1].body.contains("Alert1"));
+        assert!(sent[2].body.starts_with(
+            "🔔 Mute expired, notifications are active again. 1 alert(s) were received while muted:"
+        ));
+        assert!(db.get_mute().await.unwrap().is_none());
+
+        db.drop_database().await.unwrap();
+    }
+
+    // Requires a MongoDB instance, see `test_database`.
+    #[actix_web::test]
+    #[ignore]
+    async fn refired_alerts_post_changed_annotations() {
+        let homeserver = MockHomeserver::start().await;
+        let db = Arc::new(test_database().await);
+        let (tx, _recv) = unbounded_channel();
+        SystemRegistry::set(
+            Processor::new(
+                Some(Arc::clone(&db)),
+                escalation_settings(),
+                false,
+                vec![OTHER_USER.to_string()],
+                tx,
+            )
+            .start(),
+        );
+
+        let client = MatrixClient::new(&homeserver.config(), &routes(), None, false, true)
+            .await
+            .unwrap();
+        SystemRegistry::set(client.start());
+
+        let insert = |message: &str| {
+            let mut alert = alert_context(1, crate::DEFAULT_ROUTE).alert;
+            alert.annotations.message = Some(message.to_string());
+            let mut alerts: InsertAlerts = serde_json::from_value(serde_json::json!({
+                "alerts": [alert],
+            }))
+            .unwrap();
+            alerts.route = crate::DEFAULT_ROUTE.to_string();
+            Processor::from_registry().send(alerts)
+        };
+
+        let first = insert("Disk is 91% full").await.unwrap().unwrap();
+        assert!(!first[0].duplicate);
+
+        // Unchanged re-fires stay silent.
+        for message in ["Disk is 91% full", "Disk is 97% full"] {
+            let inserted = insert(message).await.unwrap().unwrap();
+            assert!(inserted[0].duplicate);
+            assert_eq!(inserted[0].id, first[0].id);
+        }
+
+        let sent = homeserver.wait_for_messages(2).await;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].room_id, FIRST_ROOM);
+        assert_eq!(
+            sent[1].body,
+            format!(
+                "🔁 Alert {} (Alert1) fired again with changes:\n  \
+                 Message: Disk is 91% full → Disk is 97% full",
+                first[0].id
+            )
+        );
+
+        let pending = db.get_alert(first[0].id).await.unwrap().unwrap();
+        assert_eq!(
+            pending.alert.annotations.message.as_deref(),
+            Some("Disk is 97% full")
+        );
+
+        db.drop_database().await.unwrap();
+    }
 }
@@ -0,0 +1,219 @@
+//! Latency histograms of the paging pipeline, exported on `/metrics` in the
+//! Prometheus text format.
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+// Upper bounds of the buckets, in seconds.
+const NOTIFICATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+const ACK_BUCKETS: &[f64] = &[
+    60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 14400.0, 43200.0, 86400.0,
+];
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Records the time from webhook receipt to the first successful
+/// notification via the channel, e.g. `matrix`.
+pub fn observe_notification(channel: &str, latency_ms: u64) {
+    METRICS.observe_notification(channel, latency_ms);
+}
+
+/// Records the time from the first notification to the acknowledgement.
+pub fn observe_ack(latency_ms: u64) {
+    METRICS.observe_ack(latency_ms);
+}
+
+/// Sets the number of acks which have not been propagated to the adapter,
+/// see `Adapters::retry`.
+pub fn set_unsent_acks(adapter: &str, count: u64) {
+    METRICS.set_unsent_acks(adapter, count);
+}
+
+pub fn render() -> String {
+    METRICS.render()
+}
+
+struct Histogram {
+    buckets: &'static [f64],
+    // Cumulative, i.e. the observations up to the bound of the bucket.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+    fn render(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            let bound = bound.to_string();
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                format_labels(labels, Some(&bound)),
+                count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            format_labels(labels, Some("+Inf")),
+            self.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            format_labels(labels, None),
+            self.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{} {}",
+            name,
+            format_labels(labels, None),
+            self.count
+        );
+    }
+}
+
+/// Formats labels, e.g. `{channel="matrix",le="0.5"}`.
+fn format_labels(labels: &[(&str, &str)], le: Option<&str>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .copied()
+        .chain(le.map(|le| ("le", le)))
+        .map(|(name, value)| format!("{}=\"{}\"", name, value))
+        .collect();
+
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+struct Metrics {
+    // By channel.
+    notification: Mutex<BTreeMap<String, Histogram>>,
+    ack: Mutex<Histogram>,
+    // By adapter.
+    unsent_acks: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            notification: Mutex::new(BTreeMap::new()),
+            ack: Mutex::new(Histogram::new(ACK_BUCKETS)),
+            unsent_acks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Metrics {
+    fn observe_notification(&self, channel: &str, latency_ms: u64) {
+        self.notification
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_insert_with(|| Histogram::new(NOTIFICATION_BUCKETS))
+            .observe(latency_ms as f64 / 1000.0);
+    }
+    fn observe_ack(&self, latency_ms: u64) {
+        self.ack.lock().unwrap().observe(latency_ms as f64 / 1000.0);
+    }
+    fn set_unsent_acks(&self, adapter: &str, count: u64) {
+        self.unsent_acks
+            .lock()
+            .unwrap()
+            .insert(adapter.to_string(), count);
+    }
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        let name = "matrixbot_notification_latency_seconds";
+        let _ = writeln!(out, "# HELP {} Time from webhook receipt to the first successful notification, per channel.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (channel, histogram) in self.notification.lock().unwrap().iter() {
+            histogram.render(&mut out, name, &[("channel", channel)]);
+        }
+
+        let name = "matrixbot_ack_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time from the first notification to the acknowledgement.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.ack.lock().unwrap().render(&mut out, name, &[]);
+
+        let name = "matrixbot_unsent_ack_propagations";
+        let _ = writeln!(
+            out,
+            "# HELP {} Acks which have not been propagated to the adapter yet.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (adapter, count) in self.unsent_acks.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                name,
+                format_labels(&[("adapter", adapter)], None),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_histograms() {
+        let metrics = Metrics::default();
+        metrics.observe_notification("matrix", 200);
+        metrics.observe_notification("matrix", 3000);
+        metrics.observe_ack(120_000);
+        metrics.set_unsent_acks("Telegram", 2);
+
+        let out = metrics.render();
+        assert!(out.contains(
+            "matrixbot_notification_latency_seconds_bucket{channel=\"matrix\",le=\"0.25\"} 1\n"
+        ));
+        assert!(out.contains(
+            "matrixbot_notification_latency_seconds_bucket{channel=\"matrix\",le=\"5\"} 2\n"
+        ));
+        assert!(
+            out.contains("matrixbot_notification_latency_seconds_sum{channel=\"matrix\"} 3.2\n")
+        );
+        assert!(out.contains("matrixbot_ack_latency_seconds_bucket{le=\"60\"} 0\n"));
+        assert!(out.contains("matrixbot_ack_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(out.contains("matrixbot_ack_latency_seconds_count 1\n"));
+        assert!(out.contains("matrixbot_unsent_ack_propagations{adapter=\"Telegram\"} 2\n"));
+    }
+}
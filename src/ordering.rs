@@ -0,0 +1,113 @@
+//! Ordering of notifications. Adapters send notifications concurrently, but
+//! those of the same alert must arrive in the order they were issued, e.g. an
+//! escalation must not arrive after the acknowledgement of the alert.
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+type Done = Shared<oneshot::Receiver<()>>;
+
+/// Runs tasks with a common key one after another, in the order they were
+/// enqueued. Tasks without common keys run concurrently.
+pub struct KeyedQueue<K> {
+    // The last task enqueued for each key.
+    tails: Mutex<HashMap<K, Done>>,
+}
+
+impl<K> Default for KeyedQueue<K> {
+    fn default() -> Self {
+        KeyedQueue {
+            tails: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash + Eq> KeyedQueue<K> {
+    /// Enqueues a task for the given keys. Must be called synchronously when
+    /// the task is issued (e.g. in the handler of a message, not in the
+    /// future it returns), as that determines the order.
+    pub fn enqueue<I>(&self, keys: I) -> Turn
+    where
+        I: IntoIterator<Item = K>,
+    {
+        let (tx, rx) = oneshot::channel();
+        let done = rx.shared();
+
+        let mut tails = self.tails.lock().unwrap();
+        // Finished tasks are no longer waited for.
+        tails.retain(|_, tail| tail.clone().now_or_never().is_none());
+
+        let predecessors = keys
+            .into_iter()
+            .filter_map(|key| tails.insert(key, done.clone()))
+            .collect();
+
+        Turn {
+            predecessors,
+            _done: tx,
+        }
+    }
+}
+
+/// The position of a task in a `KeyedQueue`. The next tasks of the same keys
+/// proceed once it is dropped.
+pub struct Turn {
+    predecessors: Vec<Done>,
+    // Dropping the sender completes the receivers of the successors.
+    _done: oneshot::Sender<()>,
+}
+
+impl Turn {
+    /// Waits until all previous tasks of the same keys have finished. The
+    /// returned turn must be held until the task has finished.
+    pub async fn wait(mut self) -> Self {
+        for predecessor in self.predecessors.drain(..) {
+            // Cancelled, i.e. the predecessor finished or was dropped.
+            let _ = predecessor.await;
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn runs_tasks_of_the_same_key_in_order() {
+        let queue = KeyedQueue::default();
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let task = |turn: Turn, name: &'static str, delay: u64| {
+            let log = Arc::clone(&log);
+            actix::spawn(async move {
+                let _turn = turn.wait().await;
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                log.lock().unwrap().push(name);
+            })
+        };
+
+        // The escalation of alert 1 takes longer than its ack, alert 2 does
+        // not wait for alert 1.
+        let escalation = task(queue.enqueue([1]), "escalation 1", 100);
+        let ack = task(queue.enqueue([1]), "ack 1", 0);
+        let other = task(queue.enqueue([2]), "escalation 2", 10);
+
+        for handle in [escalation, ack, other] {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["escalation 2", "escalation 1", "ack 1"]
+        );
+        assert_eq!(queue.tails.lock().unwrap().len(), 2);
+        queue.enqueue([3]);
+        assert_eq!(queue.tails.lock().unwrap().len(), 1);
+    }
+}
@@ -1,35 +1,290 @@
-use crate::database::Database;
-use crate::matrix::MatrixClient;
-use crate::webhook::Alert;
-use crate::{unix_time, AlertId, Result};
+use crate::ack_webhook::{AckEvent, AckWebhook};
+use crate::adapter::{AdapterState, Adapters, Notification, UnsentAck};
+use crate::archive::Archiver;
+use crate::calendar::BusinessHours;
+use crate::database::{
+    AlertAcknowledged, ApiKey, ApiKeyInfo, Database, NotificationKey, Reminder, Watch,
+};
+use crate::exec::{ExecEvent, ExecHook};
+use crate::matrix::{MatrixClient, StartSync};
+use crate::metrics;
+use crate::report::{self, ReportRow};
+use crate::selftest::{self, SelfTestReport};
+use crate::severity::Severities;
+use crate::truncate;
+use crate::webhook::{Alert, AnnotationChange, Labels};
+use crate::{
+    layer_routes, unix_time, unix_time_ms, validate_routes, AlertId, Error, Result, RouteConfig,
+    DEFAULT_ROUTE,
+};
 use actix::prelude::*;
+use chrono::NaiveDateTime;
+use ruma::UserId;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct AlertContext {
     pub id: AlertId,
     pub alert: Alert,
+    // Alerts stored before routes were introduced belong to the default route.
+    #[serde(default = "default_route")]
+    pub route: String,
     pub escalation_idx: usize,
     pub last_notified: u64,
     pub should_escalate: bool,
+    #[serde(default)]
+    pub timeline: Vec<TimelineEvent>,
+    // The webhook request which inserted the alert, see `X-Request-Id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    // Only set for alerts received by a webhook.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latency>,
+    // Only set for alerts of routes with SLO handling.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slo: Option<SloBurn>,
+    // Of the labels as received, which stay the same if the severity is
+    // raised. Unset for alerts stored before, see `AlertContext::fingerprint`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+/// The burn type of an SLO burn-rate alert and its summary, see `SloConfig`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SloBurn {
+    pub kind: BurnKind,
+    pub summary: String,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BurnKind {
+    Fast,
+    Slow,
+}
+
+/// Handling of the SLO burn-rate alerts of a route. Alerts of at least
+/// `fast_severity` are fast burns, all others slow burns. Each burn type has
+/// its own escalation window and summary, which shows the remaining error
+/// budget.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SloConfig {
+    // Defaults to `critical`.
+    pub fast_severity: Option<String>,
+    // Escalation windows (seconds) of each burn type, default to the
+    // escalation window.
+    pub fast_window: Option<u64>,
+    pub slow_window: Option<u64>,
+    // Summaries of each burn type. `{alertname}`, `{budget_remaining}` and
+    // `{burn_rate}` are replaced by the label and annotations of the alert.
+    pub fast_template: Option<String>,
+    pub slow_template: Option<String>,
+}
+
+impl SloConfig {
+    pub fn fast_severity(&self) -> &str {
+        self.fast_severity
+            .as_deref()
+            .unwrap_or(DEFAULT_FAST_SEVERITY)
+    }
+    fn kind(&self, severity: &str, severities: &Severities) -> BurnKind {
+        if severities.at_least(severity, self.fast_severity()) {
+            BurnKind::Fast
+        } else {
+            BurnKind::Slow
+        }
+    }
+    fn window(&self, kind: BurnKind) -> Option<u64> {
+        match kind {
+            BurnKind::Fast => self.fast_window,
+            BurnKind::Slow => self.slow_window,
+        }
+    }
+    fn burn(&self, alert: &Alert, severities: &Severities) -> SloBurn {
+        let kind = self.kind(&alert.labels.severity, severities);
+        let template = match kind {
+            BurnKind::Fast => self.fast_template.as_deref().unwrap_or(FAST_BURN_TEMPLATE),
+            BurnKind::Slow => self.slow_template.as_deref().unwrap_or(SLOW_BURN_TEMPLATE),
+        };
+        let annotation = |value: &Option<String>| value.as_deref().unwrap_or("unknown").to_string();
+
+        SloBurn {
+            kind,
+            summary: template
+                .replace("{alertname}", &alert.labels.alert_name)
+                .replace(
+                    "{budget_remaining}",
+                    &annotation(&alert.annotations.budget_remaining),
+                )
+                .replace("{burn_rate}", &annotation(&alert.annotations.burn_rate)),
+        }
+    }
+}
+
+/// Timestamps of the paging pipeline (UNIX time in milliseconds), see
+/// `/metrics`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Latency {
+    pub received_at: u64,
+    // The first successful notification, per channel.
+    #[serde(default)]
+    pub notified_at: BTreeMap<String, u64>,
+    pub acked_at: Option<u64>,
+}
+
+impl Latency {
+    pub fn new(received_at: u64) -> Self {
+        Latency {
+            received_at,
+            notified_at: BTreeMap::new(),
+            acked_at: None,
+        }
+    }
+}
+
+fn default_route() -> String {
+    DEFAULT_ROUTE.to_string()
 }
 
 impl AlertContext {
-    pub fn new(alert: Alert, id: AlertId, should_escalate: bool) -> Self {
+    pub fn new(alert: Alert, id: AlertId, route: String, should_escalate: bool) -> Self {
         AlertContext {
             id,
+            fingerprint: Some(alert.fingerprint()),
             alert,
+            route,
             escalation_idx: 0,
             last_notified: unix_time(),
             should_escalate,
+            timeline: vec![],
+            request_id: None,
+            latency: None,
+            slo: None,
         }
     }
     pub fn should_escalate(&self) -> bool {
         self.should_escalate
     }
+    /// Identifies the alert as received, see `Alert::fingerprint`.
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint
+            .clone()
+            .unwrap_or_else(|| self.alert.fingerprint())
+    }
+    /// Identifies the alert and the request which inserted it in logs.
+    pub fn trace(&self) -> String {
+        match &self.request_id {
+            Some(request_id) => format!("alert {} (request {})", self.id, request_id),
+            None => format!("alert {}", self.id),
+        }
+    }
+    /// Since when the alert is unacknowledged at its current severity, i.e.
+    /// the last time it was notified or its severity was raised.
+    fn unacked_since(&self) -> Option<u64> {
+        self.timeline
+            .iter()
+            .rev()
+            .find(|event| {
+                matches!(
+                    event.kind,
+                    TimelineKind::Notified | TimelineKind::SeverityRaised
+                )
+            })
+            .map(|event| event.timestamp)
+    }
+    /// Records the first successful notification via the channel.
+    pub fn record_notified(&mut self, channel: &str) {
+        if let Some(latency) = &mut self.latency {
+            if !latency.notified_at.contains_key(channel) {
+                let now = unix_time_ms();
+                latency.notified_at.insert(channel.to_string(), now);
+                metrics::observe_notification(channel, now.saturating_sub(latency.received_at));
+            }
+        }
+    }
+    /// Adds an event to the timeline of the alert.
+    pub fn record(&mut self, kind: TimelineKind, escalation_idx: usize) {
+        if kind == TimelineKind::Acknowledged {
+            if let Some(latency) = &mut self.latency {
+                let first = latency.notified_at.values().min().copied();
+                if let (Some(first), None) = (first, latency.acked_at) {
+                    let now = unix_time_ms();
+                    latency.acked_at = Some(now);
+                    metrics::observe_ack(now.saturating_sub(first));
+                }
+            }
+        }
+
+        self.timeline.push(TimelineEvent {
+            kind,
+            escalation_idx,
+            channel: MATRIX_CHANNEL.to_string(),
+            timestamp: unix_time(),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineKind {
+    Notified,
+    Escalated,
+    CatchUp,
+    Acknowledged,
+    AckExpired,
+    Resolved,
+    HandedOff,
+    SeverityRaised,
+    Refired,
+}
+
+/// An entry of the alert timeline, i.e. when which level was notified.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct TimelineEvent {
+    pub kind: TimelineKind,
+    pub escalation_idx: usize,
+    pub channel: String,
+    pub timestamp: u64,
+}
+
+impl fmt::Display for TimelineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            TimelineKind::Notified => "Notified",
+            TimelineKind::Escalated => "Escalated",
+            TimelineKind::CatchUp => "Catch-up summary",
+            TimelineKind::Acknowledged => "Acknowledged",
+            TimelineKind::AckExpired => "Acknowledgement expired",
+            TimelineKind::Resolved => "Resolved",
+            TimelineKind::HandedOff => "Handed off",
+            TimelineKind::SeverityRaised => "Severity raised",
+            TimelineKind::Refired => "Fired again with changes",
+        };
+
+        write!(
+            f,
+            "{}: {} (level {}, {})",
+            format_time(self.timestamp),
+            kind,
+            self.escalation_idx,
+            self.channel
+        )
+    }
+}
+
+/// Formats a UNIX timestamp for room messages.
+pub fn format_time(timestamp: u64) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
 }
 
 /// A trimmed version of `AlertContext`. Used when an alert should not escalate
@@ -43,9 +298,10 @@ impl From<AlertContext> for AlertContextTrimmed {
     }
 }
 
-impl ToString for AlertContextTrimmed {
-    fn to_string(&self) -> String {
-        format!(
+impl fmt::Display for AlertContextTrimmed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "\
             - Name: {}\n  \
               Severity: {}\n  \
@@ -54,14 +310,55 @@ impl ToString for AlertContextTrimmed {
         ",
             self.0.labels.alert_name,
             self.0.labels.severity,
-            self.0.annotations.message.as_deref().unwrap_or("N/A"),
-            self.0.annotations.description.as_deref().unwrap_or("N/A")
-        )
+            truncate::annotation(self.0.annotations.message.as_deref().unwrap_or("N/A"), None),
+            truncate::annotation(
+                self.0.annotations.description.as_deref().unwrap_or("N/A"),
+                None
+            )
+        )?;
+
+        let annotation = |text: &str| truncate::annotation(text, None).into_owned();
+        write!(f, "{}", conventions(&self.0, annotation))
     }
 }
 
-impl ToString for AlertContext {
-    fn to_string(&self) -> String {
+/// The lines of the conventional annotations and labels which are set, e.g.
+/// the runbook, following the other fields. Links are never truncated.
+fn conventions(alert: &Alert, annotation: impl Fn(&str) -> String) -> String {
+    alert
+        .conventions()
+        .into_iter()
+        .map(|convention| {
+            let value = if convention.is_link {
+                convention.value.to_string()
+            } else {
+                annotation(convention.value)
+            };
+
+            format!("  {}: {}\n", convention.label, value)
+        })
+        .collect()
+}
+
+impl AlertContext {
+    /// Renders the alert for room messages. Long annotations are truncated
+    /// unless `full`, i.e. for `details`.
+    fn render(&self, full: bool) -> String {
+        let annotation = |text: Option<&str>| {
+            let text = text.unwrap_or("N/A");
+            if full {
+                text.to_string()
+            } else {
+                truncate::annotation(text, Some(self.id)).into_owned()
+            }
+        };
+
+        let slo = self
+            .slo
+            .as_ref()
+            .map(|slo| format!("  SLO: {}\n", slo.summary))
+            .unwrap_or_default();
+
         format!(
             "\
             - ID: {}\n  \
@@ -69,271 +366,4732 @@ impl ToString for AlertContext {
               Severity: {}\n  \
               Message: {}\n  \
               Description: {}\n\
+              {}{}\
         ",
-            self.id.to_string(),
+            self.id,
             self.alert.labels.alert_name,
             self.alert.labels.severity,
-            self.alert.annotations.message.as_deref().unwrap_or("N/A"),
-            self.alert
-                .annotations
-                .description
-                .as_deref()
-                .unwrap_or("N/A")
+            annotation(self.alert.annotations.message.as_deref()),
+            annotation(self.alert.annotations.description.as_deref()),
+            slo,
+            conventions(&self.alert, |text| annotation(Some(text)))
         )
     }
 }
 
-pub struct Processor {
-    db: Option<Arc<Database>>,
-    escalation_window: u64,
-    should_escalate: bool,
-    // Ensures that only one escalation task is running at the time.
-    escalation_lock: Arc<Mutex<()>>,
-    check_frequency: u64,
-    shutdown_indicator: UnboundedSender<()>,
-}
-
-impl Processor {
-    pub fn new(
-        db: Option<Database>,
-        escalation_window: u64,
-        should_escalate: bool,
-        check_frequency: u64,
-        shutdown_indicator: UnboundedSender<()>,
-    ) -> Self {
-        Processor {
-            db: db.map(Arc::new),
-            escalation_window,
-            should_escalate,
-            escalation_lock: Default::default(),
-            check_frequency,
-            shutdown_indicator,
-        }
-    }
-    fn db(&self) -> Arc<Database> {
-        Arc::clone(self.db.as_ref().expect("Database has not been configured"))
-    }
-}
-
-impl Default for Processor {
-    fn default() -> Self {
-        panic!("Processor was not initialized in system registry. This is a bug.");
+impl fmt::Display for AlertContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(false))
     }
 }
 
-impl Actor for Processor {
-    type Context = Context<Self>;
+const MATRIX_CHANNEL: &str = "matrix";
+const EXEC_CHANNEL: &str = "exec";
+// Period of the weekly reports and the `noisy` command.
+const NOISE_PERIOD: u64 = 7 * 24 * 60 * 60; // one week
+const NOISY_LIMIT: usize = 10;
+// Names of the weekly reports, see `send_weekly_report`.
+const NOISE_REPORT: &str = "noise";
+const COMPLIANCE_REPORT: &str = "compliance";
+// Adaptive windows require this many acknowledged alerts of the same name.
+const ADAPTIVE_MIN_SAMPLES: u64 = 3;
+// Channel of timeline events of imported alerts.
+const IMPORT_CHANNEL: &str = "import";
+// Notifications of adapters about new alerts, on their entry level.
+pub const ALERT_KIND: &str = "alert";
+pub const ESCALATION_KIND: &str = "escalation";
+// Of acks propagated to adapters, see `UnsentAck`.
+pub const ACK_KIND: &str = "ack";
+const CATCH_UP_KIND: &str = "catch_up";
+const WARNING_KIND: &str = "escalation_warning";
+const SEVERITY_KIND: &str = "severity_raised";
+// Random bytes of API keys.
+const API_KEY_SIZE: usize = 32;
+// Deploy windows are meant to be short, longer silences should be overrides.
+const MAX_DEPLOY_WINDOW: u64 = 2 * 60 * 60;
+const DEFAULT_FAST_SEVERITY: &str = "critical";
+const FAST_BURN_TEMPLATE: &str =
+    "🔥 Fast burn of {alertname}: {budget_remaining} of the error budget left, burning at {burn_rate}x";
+const SLOW_BURN_TEMPLATE: &str =
+    "🐢 Slow burn of {alertname}: {budget_remaining} of the error budget left, burning at {burn_rate}x";
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        if self.should_escalate {
-            let db = self.db();
-            let escalation_window = self.escalation_window;
+/// How to handle alerts which missed multiple escalation windows, i.e. while
+/// the service was down.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUpPolicy {
+    /// Escalate one level at a time on the normal schedule.
+    #[default]
+    Schedule,
+    /// Jump straight to the level the alert would have reached.
+    Jump,
+    /// Send a single summary to the current room, then continue on the normal
+    /// schedule.
+    Summary,
+}
 
-            let local = |db: Arc<Database>, escalation_window: u64| async move {
-                let mut pending = db.get_pending(Some(escalation_window)).await?;
+/// How to handle an alert which is already pending on another route, e.g.
+/// because teams share label spaces.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Notify every route which receives the alert.
+    #[default]
+    NotifyAll,
+    /// Only the route which received the alert first is notified.
+    FirstMatch,
+    /// Only notify the route unless a route of the same or a higher
+    /// `priority` has already been notified.
+    PriorityRoute,
+}
 
-                for alert in &mut pending {
-                    debug!("Alert escalated: {:?}", alert);
+/// Shortens the escalation window of alerts whose earlier levels repeatedly
+/// failed to acknowledge in time, based on the history of the alert name.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AdaptiveEscalation {
+    // Lower bound of the shortened windows, in seconds.
+    pub min_window: u64,
+    // How far back acknowledged alerts are considered, in seconds.
+    #[serde(default = "default_lookback")]
+    pub lookback: u64,
+}
 
-                    // Send alert to the matrix client, increment escalation index.
-                    let is_last = MatrixClient::from_registry()
-                        .send(Escalation {
-                            escalation_idx: alert.escalation_idx + 1,
-                            alerts: vec![alert.clone()],
-                        })
-                        .await??;
+fn default_lookback() -> u64 {
+    30 * 24 * 60 * 60 // 30 days
+}
 
-                    // Update alert info.
-                    if !is_last {
-                        alert.escalation_idx += 1;
-                    }
-                    alert.last_notified = unix_time();
-                }
+/// How often acknowledged alerts of one name had to be escalated, see
+/// `Database::escalation_stats`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct EscalationStats {
+    pub acknowledged: u64,
+    pub escalated: u64,
+}
 
-                // Update all alert states.
-                db.insert_alerts(&pending).await?;
+/// How often alerts of one name fired on a route, see
+/// `Database::noise_stats`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct NoiseStats {
+    pub alert_name: String,
+    pub acknowledged: u64,
+    // Of the acknowledged alerts, the ones which have been resolved since.
+    pub resolved: u64,
+    // Alerts which have not been acknowledged (yet).
+    pub pending: u64,
+}
 
-                Result::<()>::Ok(())
-            };
+impl NoiseStats {
+    /// Every alert counts, alerts nobody acknowledged count twice.
+    pub fn score(&self) -> u64 {
+        self.acknowledged + 2 * self.pending
+    }
+}
 
-            let lock = Arc::clone(&self.escalation_lock);
-            let shutdown_indicator = self.shutdown_indicator.clone();
+/// How many alerts a user acknowledged and how fast, see
+/// `Database::user_stats`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct UserStats {
+    pub user: String,
+    pub acknowledged: u64,
+    // Seconds from the first notification to the acknowledgement. Alerts
+    // stored before timelines were introduced are not included.
+    pub median_time_to_ack: Option<u64>,
+}
 
-            ctx.run_interval(
-                Duration::from_secs(self.check_frequency),
-                move |_proc, _ctx| {
-                    // Acquire new handles for async task.
-                    let db = Arc::clone(&db);
-                    let lock = Arc::clone(&lock);
-                    let shutdown_indicator = shutdown_indicator.clone();
+impl UserStats {
+    pub fn new(user: String, acknowledged: u64, mut times_to_ack: Vec<u64>) -> Self {
+        times_to_ack.sort_unstable();
 
-                    actix::spawn(async move {
-                        // Immediately exit if the lock cannot be acquired.
-                        if let Ok(locked) = lock.try_lock() {
-                            // Lock acquired and will remain locked until
-                            // `_l` goes out of scope.
-                            let _l = locked;
+        let len = times_to_ack.len();
+        let median_time_to_ack = match (len, len % 2) {
+            (0, _) => None,
+            (_, 0) => Some((times_to_ack[len / 2 - 1] + times_to_ack[len / 2]) / 2),
+            _ => Some(times_to_ack[len / 2]),
+        };
 
-                            match local(db, escalation_window).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    error!("{:?}", err);
-                                    // Shutdown entire service.
-                                    shutdown_indicator.send(()).unwrap();
-                                }
-                            }
-                        }
-                    });
-                },
-            );
+        UserStats {
+            user,
+            acknowledged,
+            median_time_to_ack,
         }
     }
 }
 
-impl SystemService for Processor {}
-impl Supervised for Processor {}
+/// Ranks the users by the number of acknowledged alerts.
+fn leaderboard(mut stats: Vec<UserStats>) -> Vec<UserStats> {
+    stats.sort_by(|a, b| {
+        b.acknowledged
+            .cmp(&a.acknowledged)
+            .then_with(|| a.user.cmp(&b.user))
+    });
+    stats
+}
 
-#[derive(Clone, Debug, Eq, PartialEq, Message)]
-#[rtype(result = "UserConfirmation")]
-pub struct UserAction {
-    pub escalation_idx: usize,
-    pub command: Command,
+/// Formats a duration for room messages, e.g. `1h 5m` or `4m 10s`.
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, secs) => format!("{}s", secs),
+        (0, minutes, secs) => format!("{}m {}s", minutes, secs),
+        (hours, minutes, _) => format!("{}h {}m", hours, minutes),
+    }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Command {
-    Ack(AlertId, String),
-    Pending,
-    Help,
+/// Returns the alert names with the highest noise score.
+fn noisiest(mut stats: Vec<NoiseStats>) -> Vec<NoiseStats> {
+    stats.sort_by(|a, b| {
+        b.score()
+            .cmp(&a.score())
+            .then_with(|| a.alert_name.cmp(&b.alert_name))
+    });
+    stats.truncate(NOISY_LIMIT);
+    stats
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Message)]
-#[rtype(result = "Result<()>")]
-pub struct NotifyAlert {
-    pub alerts: Vec<AlertContext>,
+/// Which rooms of a route may acknowledge an alert.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AckScope {
+    /// Only the room of the current escalation level and above.
+    #[default]
+    Strict,
+    /// Any room of the route.
+    Lenient,
+    /// Like `Strict`, but the first room can always acknowledge.
+    FirstRoom,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Message)]
-#[rtype(result = "Result<bool>")]
-pub struct Escalation {
-    pub escalation_idx: usize,
-    pub alerts: Vec<AlertContext>,
+impl AckScope {
+    #[cfg(test)]
+    pub fn allows(&self, alert_idx: usize, room_idx: usize) -> bool {
+        self.max_alert_idx(room_idx)
+            .map(|max_idx| alert_idx <= max_idx)
+            .unwrap_or(true)
+    }
+    /// The highest escalation level of alerts the room may acknowledge, `None`
+    /// if any.
+    pub fn max_alert_idx(&self, room_idx: usize) -> Option<usize> {
+        match self {
+            AckScope::Strict => Some(room_idx),
+            AckScope::Lenient => None,
+            AckScope::FirstRoom if room_idx == 0 => None,
+            AckScope::FirstRoom => Some(room_idx),
+        }
+    }
+    fn describe(&self) -> &'static str {
+        match self {
+            AckScope::Strict => "the room of the current escalation level and above",
+            AckScope::Lenient => "any room of the route",
+            AckScope::FirstRoom => {
+                "the first room and the room of the current escalation level and above"
+            }
+        }
+    }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Message)]
-#[rtype(result = "Result<()>")]
-pub struct InsertAlerts {
-    alerts: Vec<Alert>,
+#[derive(Debug, Clone)]
+pub struct EscalationSettings {
+    pub enabled: bool,
+    pub window: u64,
+    pub check_frequency: u64,
+    pub dedup_window: u64,
+    pub catch_up: CatchUpPolicy,
+    // The escalation level at which alerts enter the chain, per route and
+    // severity. Defaults to the first level.
+    pub entry_levels: HashMap<String, HashMap<String, usize>>,
+    // Alerts pending on another route are suppressed according to the
+    // policy and the priorities of the routes.
+    pub duplicates: DuplicatePolicy,
+    pub priorities: HashMap<String, i32>,
+    pub severities: Severities,
+    pub ack_scopes: HashMap<String, AckScope>,
+    // Acknowledged alerts which are not resolved within the TTL (seconds)
+    // return to pending, per route.
+    pub ack_ttls: HashMap<String, u64>,
+    // The rooms of each route, ordered by escalation level.
+    pub rooms: HashMap<String, Vec<String>>,
+    pub business_hours: HashMap<String, BusinessHours>,
+    pub adaptive: Option<AdaptiveEscalation>,
+    // Warns the current level once this fraction of the escalation window
+    // has passed.
+    pub warning: Option<f64>,
+    // Posts a weekly report of the noisiest alerts to the first room of each
+    // route.
+    pub noise_report: bool,
+    // Posts a weekly compliance report of all alerts to the given room.
+    pub compliance_room: Option<String>,
+    // The routes of the config file, which routes stored via the admin API
+    // are layered onto.
+    pub configured_routes: Vec<RouteConfig>,
+    // Handling of SLO burn-rate alerts, per route.
+    pub slos: HashMap<String, SloConfig>,
 }
 
-impl Handler<UserAction> for Processor {
-    type Result = ResponseActFuture<Self, UserConfirmation>;
+impl EscalationSettings {
+    /// Whether to warn the current level of the alert that it is about to
+    /// escalate. Alerts on the last level do not escalate further.
+    fn should_warn(&self, alert: &AlertContext, elapsed: u64, window: u64) -> bool {
+        let warning = match self.warning {
+            Some(warning) => warning,
+            None => return false,
+        };
 
-    fn handle(&mut self, msg: UserAction, _ctx: &mut Self::Context) -> Self::Result {
-        let db = self.db();
+        let has_next = self
+            .rooms
+            .get(&alert.route)
+            .map(|rooms| alert.escalation_idx + 1 < rooms.len())
+            .unwrap_or(false);
 
-        let f = async move {
-            async fn local(db: Arc<Database>, msg: UserAction) -> Result<UserConfirmation> {
-                match msg.command {
-                    Command::Ack(id, acked_by) => {
-                        info!("Acknowledging alert Id: {}", id.to_string());
-                        db.acknowledge_alert(msg.escalation_idx, id, acked_by).await
-                    }
-                    Command::Pending => db
-                        .get_pending(None)
-                        .await
-                        .map(UserConfirmation::PendingAlerts),
-                    Command::Help => Ok(UserConfirmation::Help),
-                }
+        has_next && elapsed as f64 >= window as f64 * warning
+    }
+    /// The escalation window of SLO burn-rate alerts, if their burn type has
+    /// its own.
+    fn slo_window(&self, alert: &AlertContext) -> Option<u64> {
+        let kind = alert.slo.as_ref()?.kind;
+        self.slos.get(&alert.route)?.window(kind)
+    }
+    /// The shortest escalation window of any alert.
+    fn min_window(&self) -> u64 {
+        self.slos
+            .values()
+            .flat_map(|slo| [slo.fast_window, slo.slow_window])
+            .flatten()
+            .chain([self
+                .adaptive
+                .map(|adaptive| adaptive.min_window)
+                .unwrap_or(self.window)])
+            .min()
+            .unwrap_or(self.window)
+    }
+    /// The escalation window of alerts with the given history. The more of
+    /// them had to be escalated, the closer the window gets to the minimum.
+    fn adaptive_window(&self, stats: EscalationStats) -> u64 {
+        match self.adaptive {
+            Some(adaptive) if stats.acknowledged >= ADAPTIVE_MIN_SAMPLES => {
+                let reducible = self.window.saturating_sub(adaptive.min_window);
+                self.window
+                    - reducible * stats.escalated.min(stats.acknowledged) / stats.acknowledged
             }
+            _ => self.window,
+        }
+    }
+    /// Whether an alert received by the route is suppressed, as it is
+    /// already pending on the other route.
+    fn is_duplicate(&self, route: &str, pending_route: &str) -> bool {
+        if route == pending_route {
+            return false;
+        }
 
-            local(db, msg)
-                .await
-                .map_err(|err| {
-                    error!("Error when trying to process user command: {:?}", err);
-                    UserConfirmation::InternalError
+        match self.duplicates {
+            DuplicatePolicy::NotifyAll => false,
+            DuplicatePolicy::FirstMatch => true,
+            DuplicatePolicy::PriorityRoute => {
+                let priority = |route| self.priorities.get(route).copied().unwrap_or_default();
+                priority(pending_route) >= priority(route)
+            }
+        }
+    }
+    /// Matches a received alert with the pending alerts. Re-fires on the same
+    /// route update the annotations of the pending alert, which are posted if
+    /// changed. Duplicates on other routes are suppressed by policy.
+    fn match_pending(
+        &self,
+        pending: &mut [AlertContext],
+        route: &str,
+        index: usize,
+        alert: &Alert,
+    ) -> Option<(InsertedAlert, Option<AlertRefired>)> {
+        let fingerprint = alert.fingerprint();
+
+        if let Some(previous) = pending
+            .iter_mut()
+            .find(|previous| previous.fingerprint() == fingerprint && previous.route == route)
+        {
+            let changes = previous.alert.annotations.changes(&alert.annotations);
+            let refired = if changes.is_empty() {
+                None
+            } else {
+                info!(
+                    "{} fired again with {} changed annotation(s)",
+                    previous.trace(),
+                    changes.len()
+                );
+                previous.alert.annotations = alert.annotations.clone();
+                previous.record(TimelineKind::Refired, previous.escalation_idx);
+                Some(AlertRefired {
+                    alert: previous.clone(),
+                    changes,
                 })
-                .unwrap()
+            };
+
+            let inserted = InsertedAlert {
+                index,
+                fingerprint,
+                id: previous.id,
+                route: route.to_string(),
+                duplicate: true,
+            };
+            return Some((inserted, refired));
+        }
+
+        let duplicate = pending.iter().find(|duplicate| {
+            duplicate.fingerprint() == fingerprint && self.is_duplicate(route, &duplicate.route)
+        })?;
+        info!(
+            "Suppressing '{}' on route '{}', already pending on route '{}'",
+            alert.labels.alert_name, route, duplicate.route
+        );
+
+        let inserted = InsertedAlert {
+            index,
+            fingerprint,
+            id: duplicate.id,
+            route: duplicate.route.clone(),
+            duplicate: true,
         };
+        Some((inserted, None))
+    }
+    /// Alerts enter at the highest level whose severity threshold they meet.
+    /// Outside business hours, they skip the levels staffed during them.
+    fn entry_level(&self, route: &str, severity: &str) -> usize {
+        let level = self
+            .entry_levels
+            .get(route)
+            .and_then(|levels| {
+                levels
+                    .iter()
+                    .filter(|(threshold, _)| self.severities.at_least(severity, threshold))
+                    .map(|(_, level)| *level)
+                    .max()
+            })
+            .unwrap_or(0);
 
-        Box::pin(f.into_actor(self))
+        match self.business_hours.get(route) {
+            Some(hours) if !hours.is_open(unix_time()) => level.max(hours.after_hours_level()),
+            _ => level,
+        }
+    }
+    /// Determines the rooms an alert would be sent to, without notifying
+    /// anyone. Returns `None` if the route is unknown.
+    fn simulate(
+        &self,
+        route: &str,
+        severity: &str,
+        alert_name: &str,
+        muted: bool,
+        stats: Option<EscalationStats>,
+        policy: Option<&PolicyOverride>,
+    ) -> Option<Simulation> {
+        let route = policy.map(PolicyOverride::target).unwrap_or(route);
+        let rooms = self.rooms.get(route)?;
+        let severity = self.severities.normalize(severity);
+
+        // Entry levels beyond the final room end up in the final room.
+        let entry_level = policy
+            .and_then(|policy| policy.entry_level)
+            .unwrap_or_else(|| self.entry_level(route, &severity))
+            .min(rooms.len().saturating_sub(1));
+        let final_level = if self.enabled {
+            rooms.len()
+        } else {
+            (entry_level + 1).min(rooms.len())
+        };
+        let slo_window = self
+            .slos
+            .get(route)
+            .and_then(|slo| slo.window(slo.kind(&severity, &self.severities)));
+        let (window, adaptive) = match (slo_window, stats) {
+            (Some(window), _) => (window, false),
+            (None, Some(stats)) => {
+                let window = self.adaptive_window(stats);
+                (window, window != self.window)
+            }
+            (None, None) => (self.window, false),
+        };
+
+        let steps = rooms[entry_level..final_level]
+            .iter()
+            .enumerate()
+            .map(|(step, room)| SimulationStep {
+                escalation_idx: entry_level + step,
+                room: room.clone(),
+                channel: MATRIX_CHANNEL.to_string(),
+                after: step as u64 * window,
+            })
+            .collect();
+
+        Some(Simulation {
+            route: route.to_string(),
+            severity,
+            alert_name: alert_name.to_string(),
+            muted,
+            window,
+            adaptive,
+            policy: policy.map(|policy| policy.name.clone()),
+            steps,
+        })
     }
 }
 
-impl Handler<InsertAlerts> for Processor {
-    type Result = ResponseActFuture<Self, Result<()>>;
+/// A notification of a simulated alert.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SimulationStep {
+    pub escalation_idx: usize,
+    pub room: String,
+    pub channel: String,
+    // Seconds after the alert has been received. Escalations happen within
+    // the check frequency after that.
+    pub after: u64,
+}
 
-    fn handle(&mut self, msg: InsertAlerts, _ctx: &mut Self::Context) -> Self::Result {
-        let db = self.db();
-        let should_escalate = self.should_escalate;
+/// The escalation path of a simulated alert, see `Simulate`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct Simulation {
+    pub route: String,
+    pub severity: String,
+    pub alert_name: String,
+    // Notifications are held back until the mute expires.
+    pub muted: bool,
+    // Seconds between the steps.
+    pub window: u64,
+    // Whether the window was shortened by adaptive escalation, based on the
+    // history of the alert name.
+    pub adaptive: bool,
+    // The name of the active override of the route, see `PolicyOverride`.
+    pub policy: Option<String>,
+    pub steps: Vec<SimulationStep>,
+}
 
-        let f = async move {
-            // Convert webhook alerts into alert contexts.
-            // (avoid an iterator so `async` can be used conveniently)
-            let mut alerts = vec![];
-            for alert in msg.alerts {
-                let next_id = db.get_next_id().await?;
-                alerts.push(AlertContext::new(alert, next_id, should_escalate));
-            }
+impl fmt::Display for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Simulated alert '{}' ({}) on route '{}':",
+            self.alert_name, self.severity, self.route
+        )?;
 
-            // Only store alerts that should escalate.
-            if should_escalate {
-                db.insert_alerts(&alerts).await?;
-            }
+        if let Some(policy) = &self.policy {
+            writeln!(f, "Override '{}' applies.", policy)?;
+        }
 
-            // Notify rooms about all alerts.
-            debug!("Notifying rooms about new alerts");
-            MatrixClient::from_registry()
-                .send(NotifyAlert { alerts })
-                .await??;
+        for step in &self.steps {
+            writeln!(
+                f,
+                "  - after {}s: level {}, {} ({})",
+                step.after, step.escalation_idx, step.room, step.channel
+            )?;
+        }
 
-            Ok(())
-        };
+        if self.adaptive {
+            writeln!(
+                f,
+                "The escalation window is shortened to {}s, earlier alerts of this name had to be escalated.",
+                self.window
+            )?;
+        }
 
-        Box::pin(f.into_actor(self))
+        if self.muted {
+            writeln!(
+                f,
+                "Notifications are muted, the alert would be held back until the mute expires."
+            )?;
+        }
+
+        Ok(())
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum UserConfirmation {
-    PendingAlerts(Vec<AlertContext>),
-    AlertOutOfScope,
-    AlertAcknowledged(AlertId),
-    AlertNotFound,
-    Help,
-    InternalError,
+/// A temporary change of the escalation policy of a route, e.g. during a
+/// freeze period. Stored via the admin API and reverted once it ends.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct PolicyOverride {
+    pub name: String,
+    pub route: String,
+    // Alerts of `route` are inserted into this route instead.
+    pub target_route: Option<String>,
+    // Alerts enter the escalation chain at this level (index of the rooms of
+    // the target route), regardless of their severity.
+    pub entry_level: Option<usize>,
+    // Unix timestamps (seconds), the end is exclusive.
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub reason: Option<String>,
 }
 
-impl ToString for UserConfirmation {
-    fn to_string(&self) -> String {
-        match self {
-            UserConfirmation::PendingAlerts(alerts) => {
-                if alerts.is_empty() {
-                    return String::from("No pending alerts!");
-                }
+impl PolicyOverride {
+    /// The route which alerts of the overridden route are inserted into.
+    fn target(&self) -> &str {
+        self.target_route.as_deref().unwrap_or(&self.route)
+    }
+    /// Checks the override against the rooms of each route.
+    fn validate(&self, rooms: &HashMap<String, Vec<String>>, now: u64) -> Result<()> {
+        let mut problems = vec![];
 
-                let mut content = String::from("Pending alerts:\n");
-                for alert in alerts {
-                    content.push_str(&alert.to_string());
-                }
+        if !rooms.contains_key(&self.route) {
+            problems.push(format!("Unknown route '{}'", self.route));
+        }
 
-                content
-            }
-            UserConfirmation::AlertOutOfScope => {
-                String::from("The alert has already reached the next escalation level. It cannot be acknowledged!")
+        match rooms.get(self.target()) {
+            Some(target) => {
+                if let Some(level) = self.entry_level.filter(|level| *level >= target.len()) {
+                    problems.push(format!(
+                        "Entry level {} exceeds the rooms of route '{}'",
+                        level,
+                        self.target()
+                    ));
+                }
             }
-            UserConfirmation::AlertAcknowledged(id) => {
-                format!("Alert {} has been acknowledged.", id.to_string())
+            None if self.target_route.is_some() => {
+                problems.push(format!("Unknown target route '{}'", self.target()));
             }
-            UserConfirmation::AlertNotFound => {
-                String::from("The alert Id has not been found!")
+            None => {}
+        }
+
+        if self.ends_at <= self.starts_at {
+            problems.push(String::from("The override must end after it starts"));
+        } else if self.ends_at <= now {
+            problems.push(String::from("The override has already ended"));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(format!(
+                "Override '{}': {}",
+                self.name,
+                problems.join(", ")
+            )))
+        }
+    }
+}
+
+impl fmt::Display for PolicyOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' of route '{}' from {} until {}",
+            self.name,
+            self.route,
+            format_time(self.starts_at),
+            format_time(self.ends_at)
+        )?;
+
+        if let Some(target) = &self.target_route {
+            write!(f, ", alerts go to route '{}'", target)?;
+        }
+        if let Some(level) = self.entry_level {
+            write!(f, ", alerts enter at level {}", level)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A deployment during which alerts with matching labels are silenced, e.g.
+/// announced by a CD pipeline. The first rooms of the routes are informed
+/// when it starts and ends.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Message, ToSchema)]
+#[rtype(result = "Result<u64>")]
+pub struct DeployWindow {
+    // Identifies the deployment in notices, e.g. `api v1.4.2`. Starting a
+    // window with the same name again extends or shortens it.
+    pub name: String,
+    // Names (see `Labels::NAMES`) and values of labels, all of which must
+    // match, ignoring case.
+    pub labels: BTreeMap<String, String>,
+    // Defaults to all routes.
+    #[serde(default)]
+    pub routes: Vec<String>,
+    // Seconds, at most two hours.
+    pub duration: u64,
+    // Set to the user of the API key.
+    #[serde(skip)]
+    pub started_by: String,
+}
+
+impl DeployWindow {
+    fn validate(&self, rooms: &HashMap<String, Vec<String>>) -> Result<()> {
+        let mut problems = vec![];
+
+        if self.labels.is_empty() {
+            problems.push(String::from("At least one label is required"));
+        }
+        for name in self
+            .labels
+            .keys()
+            .filter(|name| !Labels::NAMES.contains(&name.as_str()))
+        {
+            problems.push(format!("Unknown label '{}'", name));
+        }
+        for route in self
+            .routes
+            .iter()
+            .filter(|route| !rooms.contains_key(*route))
+        {
+            problems.push(format!("Unknown route '{}'", route));
+        }
+        if self.duration == 0 || self.duration > MAX_DEPLOY_WINDOW {
+            problems.push(format!(
+                "The duration must be between 1 and {} seconds",
+                MAX_DEPLOY_WINDOW
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(format!(
+                "Deploy window '{}': {}",
+                self.name,
+                problems.join(", ")
+            )))
+        }
+    }
+    fn describe_labels(&self) -> String {
+        self.labels
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+/// An active deploy window. Stored so that it survives restarts, which are
+/// likely during deployments.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActiveDeployWindow {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub routes: Vec<String>,
+    pub started_by: String,
+    pub ends_at: u64,
+    // Alerts which were silenced, not inserted.
+    #[serde(default)]
+    pub silenced: Vec<Alert>,
+}
+
+impl ActiveDeployWindow {
+    fn new(window: DeployWindow, now: u64) -> Self {
+        ActiveDeployWindow {
+            ends_at: now + window.duration,
+            name: window.name,
+            labels: window.labels,
+            routes: window.routes,
+            started_by: window.started_by,
+            silenced: vec![],
+        }
+    }
+    fn silences(&self, route: &str, alert: &Alert) -> bool {
+        self.routes.iter().any(|other| other == route) && alert.labels.matches(&self.labels)
+    }
+}
+
+pub struct Processor {
+    db: Option<Arc<Database>>,
+    escalation: EscalationSettings,
+    // Ensures that only one escalation task is running at the time.
+    escalation_lock: Arc<Mutex<()>>,
+    // A standby instance does not process alerts until it gets promoted.
+    standby: bool,
+    // Set while a promotion waits for the Matrix sync to start.
+    promoting: bool,
+    // Matrix users which are allowed to run admin commands.
+    admins: Vec<String>,
+    // Expires the active mute, see `MuteState`.
+    mute: Option<SpawnHandle>,
+    // Ends the stored deploy windows, by name.
+    deploy_windows: HashMap<String, SpawnHandle>,
+    // Informs upstream systems about acknowledged and resolved alerts.
+    ack_webhook: Option<Arc<AckWebhook>>,
+    // Runs a local command for every notification.
+    exec: Option<Arc<ExecHook>>,
+    // Moves old acknowledged alerts to object storage.
+    archiver: Option<Arc<Archiver>>,
+    // Chat services notified in addition to the rooms, e.g. Telegram.
+    adapters: Adapters,
+    // Routes referenced by listeners and integrations, which must not be
+    // removed.
+    route_references: Vec<String>,
+    shutdown_indicator: UnboundedSender<()>,
+}
+
+impl Processor {
+    pub fn new(
+        db: Option<Arc<Database>>,
+        escalation: EscalationSettings,
+        standby: bool,
+        admins: Vec<String>,
+        shutdown_indicator: UnboundedSender<()>,
+    ) -> Self {
+        Processor {
+            db,
+            escalation,
+            escalation_lock: Default::default(),
+            standby,
+            promoting: false,
+            admins,
+            mute: None,
+            deploy_windows: HashMap::new(),
+            ack_webhook: None,
+            exec: None,
+            archiver: None,
+            adapters: Adapters::default(),
+            route_references: vec![],
+            shutdown_indicator,
+        }
+    }
+    /// Calls the webhook whenever an alert is acknowledged or resolved.
+    pub fn with_ack_webhook(mut self, ack_webhook: Option<AckWebhook>) -> Self {
+        self.ack_webhook = ack_webhook.map(Arc::new);
+        self
+    }
+    /// Runs the command of the exec hook for new and escalated alerts.
+    pub fn with_exec(mut self, exec: Option<Arc<ExecHook>>) -> Self {
+        self.exec = exec;
+        self
+    }
+    /// Archives old acknowledged alerts periodically.
+    pub fn with_archiver(mut self, archiver: Option<Arc<Archiver>>) -> Self {
+        self.archiver = archiver;
+        self
+    }
+    /// Notifies the adapters of new and escalated alerts.
+    pub fn with_adapters(mut self, adapters: Adapters) -> Self {
+        self.adapters = adapters;
+        self
+    }
+    /// Stored routes referenced by the config cannot be removed.
+    pub fn with_route_references(mut self, route_references: Vec<String>) -> Self {
+        self.route_references = route_references;
+        self
+    }
+    fn db(&self) -> Arc<Database> {
+        Arc::clone(self.db.as_ref().expect("Database has not been configured"))
+    }
+    fn is_admin(&self, sender: &str) -> bool {
+        self.admins.iter().any(|admin| admin == sender)
+    }
+    /// Simulates an alert, with the active override of the route and the
+    /// adaptive window of its name if enabled.
+    fn simulation(
+        &self,
+        route: String,
+        severity: String,
+        alert_name: String,
+    ) -> impl std::future::Future<Output = Option<Simulation>> {
+        let settings = self.escalation.clone();
+        let db = self.db.clone();
+        let muted = self.mute.is_some();
+
+        async move {
+            let policy = match &db {
+                Some(db) => db
+                    .active_override(&route, unix_time())
+                    .await
+                    .map_err(|err| {
+                        warn!(
+                            "Failed to retrieve the active override of route '{}': {:?}",
+                            route, err
+                        )
+                    })
+                    .ok()
+                    .flatten(),
+                None => None,
+            };
+
+            let stats = match (settings.adaptive, db) {
+                (Some(adaptive), Some(db)) => {
+                    let since = unix_time().saturating_sub(adaptive.lookback);
+                    match db.escalation_stats(&alert_name, since).await {
+                        Ok(stats) => Some(stats),
+                        Err(err) => {
+                            warn!(
+                                "Failed to retrieve the escalation stats of '{}': {:?}",
+                                alert_name, err
+                            );
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            settings.simulate(
+                &route,
+                &severity,
+                &alert_name,
+                muted,
+                stats,
+                policy.as_ref(),
+            )
+        }
+    }
+    /// Expires the mute at the given time, replacing the timer of the
+    /// previous one, if any.
+    fn schedule_unmute(&mut self, ctx: &mut Context<Self>, until: u64) {
+        if let Some(handle) = self.mute.take() {
+            ctx.cancel_future(handle);
+        }
+
+        let remaining = until.saturating_sub(unix_time());
+        self.mute = Some(ctx.run_later(Duration::from_secs(remaining), |proc, _ctx| proc.unmute()));
+    }
+    /// Continues the mute of a previous process, if any.
+    fn restore_mute(&mut self, ctx: &mut Context<Self>) {
+        let db = self.db();
+
+        ctx.spawn(async move { db.get_mute().await }.into_actor(self).map(
+            |res, proc, ctx| match res {
+                Ok(Some(mute)) => {
+                    warn!("Notifications are muted until {}", format_time(mute.until));
+                    proc.schedule_unmute(ctx, mute.until);
+                }
+                Ok(None) => {}
+                Err(err) => error!("Failed to restore mute: {:?}", err),
+            },
+        ));
+    }
+    /// Notifies about the alerts which were held back on their entry level,
+    /// then reminds the room which issued the mute.
+    fn unmute(&mut self) {
+        if self.mute.take().is_none() {
+            return;
+        }
+
+        info!("Mute expired");
+        let db = self.db();
+        let exec = self.exec.clone();
+        let adapters = self.adapters.clone();
+        let should_escalate = self.escalation.enabled;
+
+        actix::spawn(async move {
+            let res = async {
+                let mute = match db.remove_mute().await? {
+                    Some(mute) => mute,
+                    None => return Ok(()),
+                };
+
+                // Alerts which were acknowledged or resolved in the meantime
+                // are no longer notified.
+                let held: Vec<AlertContext> = if should_escalate {
+                    let ids: HashSet<AlertId> = mute.alerts.iter().map(|alert| alert.id).collect();
+                    db.get_pending(None)
+                        .await?
+                        .into_iter()
+                        .filter(|alert| ids.contains(&alert.id))
+                        .collect()
+                } else {
+                    mute.alerts.clone()
+                };
+
+                let mut by_route: BTreeMap<String, Vec<AlertContext>> = BTreeMap::new();
+                for mut alert in held {
+                    alert.record(TimelineKind::Notified, alert.escalation_idx);
+                    by_route.entry(alert.route.clone()).or_default().push(alert);
+                }
+                for (route, mut alerts) in by_route {
+                    notify_new_alerts(
+                        &db,
+                        exec.as_ref(),
+                        &adapters,
+                        route,
+                        &mut alerts,
+                        should_escalate,
+                    )
+                    .await?;
+                }
+
+                MatrixClient::from_registry()
+                    .send(MuteExpired {
+                        route: mute.route,
+                        escalation_idx: mute.escalation_idx,
+                        alerts: mute.alerts,
+                    })
+                    .await?
+            };
+
+            if let Err(err) = res.await {
+                error!("Failed to end mute: {:?}", err);
+            }
+        });
+    }
+    /// Ends the deploy window at the given time, replacing the timer of the
+    /// window of the same name, if any.
+    fn schedule_deploy_window_end(&mut self, ctx: &mut Context<Self>, name: String, ends_at: u64) {
+        if let Some(handle) = self.deploy_windows.remove(&name) {
+            ctx.cancel_future(handle);
+        }
+
+        let remaining = ends_at.saturating_sub(unix_time());
+        let handle = ctx.run_later(Duration::from_secs(remaining), {
+            let name = name.clone();
+            move |proc, _ctx| proc.end_deploy_window(name)
+        });
+        self.deploy_windows.insert(name, handle);
+    }
+    /// Continues the deploy windows of a previous process, if any.
+    fn restore_deploy_windows(&mut self, ctx: &mut Context<Self>) {
+        let db = self.db();
+
+        ctx.spawn(
+            async move { db.get_deploy_windows().await }
+                .into_actor(self)
+                .map(|res, proc, ctx| match res {
+                    Ok(windows) => {
+                        for window in windows {
+                            info!(
+                                "Deploy window '{}' is active until {}",
+                                window.name,
+                                format_time(window.ends_at)
+                            );
+                            proc.schedule_deploy_window_end(ctx, window.name, window.ends_at);
+                        }
+                    }
+                    Err(err) => error!("Failed to restore deploy windows: {:?}", err),
+                }),
+        );
+    }
+    fn end_deploy_window(&mut self, name: String) {
+        self.deploy_windows.remove(&name);
+        let db = self.db();
+
+        actix::spawn(async move {
+            let window = match db.remove_deploy_window(&name, unix_time()).await {
+                Ok(Some(window)) => window,
+                Ok(None) => return,
+                Err(err) => {
+                    error!("Failed to end deploy window '{}': {:?}", name, err);
+                    return;
+                }
+            };
+
+            info!("Deploy window '{}' ended", name);
+
+            let mut body = format!(
+                "🚧 Deploy window '{}' has ended, {} alert(s) were silenced{}",
+                name,
+                window.silenced.len(),
+                if window.silenced.is_empty() { "." } else { ":" }
+            );
+            for alert in &window.silenced {
+                body.push_str(&format!(
+                    "\n- {} ({})",
+                    alert.labels.alert_name, alert.labels.severity
+                ));
+            }
+
+            send_deploy_notice(window.routes, body);
+        });
+    }
+    /// Starts the escalation sweep, if escalations are enabled.
+    fn start_escalations(&mut self, ctx: &mut Context<Self>) {
+        if self.escalation.enabled {
+            let db = self.db();
+            let exec = self.exec.clone();
+            let adapters = self.adapters.clone();
+            let local = |db: Arc<Database>,
+                         settings: EscalationSettings,
+                         exec: Option<Arc<ExecHook>>,
+                         adapters: Adapters,
+                         recover: bool| async move {
+                // Notifications interrupted by a restart are completed first.
+                if recover {
+                    recover_notifications(&db, exec.as_ref(), &adapters).await?;
+                }
+                retry_exec(&db, exec.as_ref()).await?;
+                adapters.retry().await?;
+
+                let min_window = settings.min_window();
+
+                if settings.severities.has_bumps() {
+                    raise_severities(&db, &settings, exec.as_ref()).await?;
+                }
+
+                // Alerts to warn about are not due yet.
+                let threshold = settings
+                    .warning
+                    .map(|warning| (min_window as f64 * warning) as u64)
+                    .unwrap_or(min_window);
+                let pending = db.get_pending(Some(threshold)).await?;
+                let now = unix_time();
+
+                for name in db.remove_expired_overrides(now).await? {
+                    info!("Policy override '{}' has ended and was removed", name);
+                }
+
+                let mut summaries: BTreeMap<(String, usize), Vec<AlertContext>> = BTreeMap::new();
+                let mut warnings: BTreeMap<(String, usize), Vec<(AlertContext, u64)>> =
+                    BTreeMap::new();
+                let mut windows: HashMap<String, u64> = HashMap::new();
+
+                for alert in pending {
+                    let window = match (settings.slo_window(&alert), settings.adaptive) {
+                        (Some(window), _) => window,
+                        (None, Some(adaptive)) => {
+                            let alert_name = &alert.alert.labels.alert_name;
+                            match windows.get(alert_name) {
+                                Some(window) => *window,
+                                None => {
+                                    let stats = db
+                                        .escalation_stats(
+                                            alert_name,
+                                            now.saturating_sub(adaptive.lookback),
+                                        )
+                                        .await?;
+                                    let window = settings.adaptive_window(stats);
+                                    debug!(
+                                        "Escalation window of '{}' is {}s ({:?})",
+                                        alert_name, window, stats
+                                    );
+
+                                    windows.insert(alert_name.clone(), window);
+                                    window
+                                }
+                            }
+                        }
+                        (None, None) => settings.window,
+                    };
+
+                    let elapsed = now.saturating_sub(alert.last_notified);
+                    if elapsed <= window {
+                        if settings.should_warn(&alert, elapsed, window) {
+                            // Once per level, unless it returns to pending.
+                            let key = NotificationKey {
+                                alert_id: alert.id,
+                                channel: MATRIX_CHANNEL.to_string(),
+                                escalation_idx: alert.escalation_idx,
+                                kind: WARNING_KIND.to_string(),
+                            };
+
+                            if db.claim_notification(&key, window).await? {
+                                warnings
+                                    .entry((alert.route.clone(), alert.escalation_idx))
+                                    .or_default()
+                                    .push((alert, window - elapsed));
+                            }
+                        }
+
+                        continue;
+                    }
+
+                    // More than one missed window means that the service was
+                    // not running.
+                    let missed = (now.saturating_sub(alert.last_notified) / window).max(1);
+                    let overdue = missed > 1;
+
+                    let (escalation_idx, kind) = match settings.catch_up {
+                        CatchUpPolicy::Summary if overdue => (alert.escalation_idx, CATCH_UP_KIND),
+                        CatchUpPolicy::Jump if overdue => {
+                            (alert.escalation_idx + missed as usize, ESCALATION_KIND)
+                        }
+                        _ => (alert.escalation_idx + 1, ESCALATION_KIND),
+                    };
+
+                    // Skip notifications which have already been sent recently,
+                    // e.g. by an overlapping sweep or before a restart.
+                    let key = NotificationKey {
+                        alert_id: alert.id,
+                        channel: MATRIX_CHANNEL.to_string(),
+                        escalation_idx,
+                        kind: kind.to_string(),
+                    };
+
+                    if !db.claim_notification(&key, settings.dedup_window).await? {
+                        debug!("Skipping duplicate notification: {:?}", key);
+                        continue;
+                    }
+
+                    if kind == CATCH_UP_KIND {
+                        summaries
+                            .entry((alert.route.clone(), alert.escalation_idx))
+                            .or_default()
+                            .push(alert);
+
+                        continue;
+                    }
+
+                    info!("Escalating {}", alert.trace());
+                    debug!("Alert escalated: {:?}", alert);
+
+                    escalate(&db, exec.as_ref(), &adapters, alert, escalation_idx).await?;
+                    db.complete_notification(&key).await?;
+                }
+
+                // Send one summary per room, the alerts stay on their current
+                // escalation level.
+                for ((route, escalation_idx), alerts) in summaries {
+                    debug!("Sending catch-up summary for {} alert(s)", alerts.len());
+
+                    let keys: Vec<NotificationKey> = alerts
+                        .iter()
+                        .map(|alert| NotificationKey {
+                            alert_id: alert.id,
+                            channel: MATRIX_CHANNEL.to_string(),
+                            escalation_idx: alert.escalation_idx,
+                            kind: CATCH_UP_KIND.to_string(),
+                        })
+                        .collect();
+
+                    send_catch_up(&db, route, escalation_idx, alerts).await?;
+                    for key in &keys {
+                        db.complete_notification(key).await?;
+                    }
+                }
+
+                // Give the current levels a chance to acknowledge before the
+                // next ones are paged.
+                for ((route, escalation_idx), alerts) in warnings {
+                    let keys: Vec<NotificationKey> = alerts
+                        .iter()
+                        .map(|(alert, _)| NotificationKey {
+                            alert_id: alert.id,
+                            channel: MATRIX_CHANNEL.to_string(),
+                            escalation_idx,
+                            kind: WARNING_KIND.to_string(),
+                        })
+                        .collect();
+
+                    MatrixClient::from_registry()
+                        .send(EscalationWarning {
+                            route,
+                            escalation_idx,
+                            alerts,
+                        })
+                        .await??;
+                    for key in &keys {
+                        db.complete_notification(key).await?;
+                    }
+                }
+
+                // Remind rooms about acknowledged alerts which have not been
+                // resolved in time.
+                for (route, ack_ttl) in &settings.ack_ttls {
+                    let expired = db.expire_acknowledgements(route, *ack_ttl).await?;
+                    if expired.is_empty() {
+                        continue;
+                    }
+
+                    warn!(
+                        "{} acknowledgement(s) expired on route '{}'",
+                        expired.len(),
+                        route
+                    );
+
+                    MatrixClient::from_registry()
+                        .send(AckExpired {
+                            route: route.clone(),
+                            alerts: expired,
+                        })
+                        .await??;
+                }
+
+                for reminder in db.get_due_reminders().await? {
+                    if let Some(alert) = db.get_alert(reminder.alert_id).await? {
+                        MatrixClient::from_registry()
+                            .send(RemindAlert {
+                                route: reminder.route.clone(),
+                                escalation_idx: reminder.escalation_idx,
+                                requested_by: reminder.requested_by.clone(),
+                                alert,
+                            })
+                            .await??;
+                    }
+
+                    db.remove_reminder(&reminder).await?;
+                }
+
+                if settings.noise_report {
+                    send_weekly_report(&db, NOISE_REPORT, send_noise_reports(&db, &settings))
+                        .await?;
+                }
+
+                if let Some(room) = &settings.compliance_room {
+                    send_weekly_report(&db, COMPLIANCE_REPORT, send_compliance_report(&db, room))
+                        .await?;
+                }
+
+                Result::<()>::Ok(())
+            };
+
+            let lock = Arc::clone(&self.escalation_lock);
+            let shutdown_indicator = self.shutdown_indicator.clone();
+            // Whether the notifications of a previous process were recovered.
+            let recovered = Arc::new(AtomicBool::new(false));
+
+            ctx.run_interval(
+                Duration::from_secs(self.escalation.check_frequency),
+                move |proc, _ctx| {
+                    // Escalations are paused while muted.
+                    if proc.mute.is_some() {
+                        return;
+                    }
+
+                    // Acquire new handles for async task. The settings are
+                    // read on every sweep, as the rooms of spaces may change.
+                    let db = Arc::clone(&db);
+                    let settings = proc.escalation.clone();
+                    let lock = Arc::clone(&lock);
+                    let exec = exec.clone();
+                    let adapters = adapters.clone();
+                    let shutdown_indicator = shutdown_indicator.clone();
+                    let recovered = Arc::clone(&recovered);
+
+                    actix::spawn(async move {
+                        // Immediately exit if the lock cannot be acquired.
+                        if let Ok(locked) = lock.try_lock() {
+                            // Lock acquired and will remain locked until
+                            // `_l` goes out of scope.
+                            let _l = locked;
+
+                            let recover = !recovered.load(Ordering::SeqCst);
+                            match local(db, settings, exec, adapters, recover).await {
+                                Ok(_) => recovered.store(true, Ordering::SeqCst),
+                                Err(err) => {
+                                    error!("{:?}", err);
+                                    // Shutdown entire service.
+                                    shutdown_indicator.send(()).unwrap();
+                                }
+                            }
+                        }
+                    });
+                },
+            );
+        }
+    }
+}
+
+impl Processor {
+    fn start_archiving(&mut self, ctx: &mut Context<Self>) {
+        let archiver = match &self.archiver {
+            Some(archiver) => Arc::clone(archiver),
+            None => return,
+        };
+
+        let db = self.db();
+        ctx.run_interval(archiver.interval(), move |_proc, _ctx| {
+            let db = Arc::clone(&db);
+            let archiver = Arc::clone(&archiver);
+
+            actix::spawn(async move {
+                match archiver.archive(&db).await {
+                    Ok(0) => {}
+                    Ok(archived) => info!("Archived {} acknowledged alert(s)", archived),
+                    Err(err) => error!("Failed to archive acknowledged alerts: {:?}", err),
+                }
+            });
+        });
+    }
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        panic!("Processor was not initialized in system registry. This is a bug.");
+    }
+}
+
+impl Actor for Processor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.standby {
+            warn!("Running in standby mode, escalations are paused until promotion");
+        } else {
+            self.start_escalations(ctx);
+            self.start_archiving(ctx);
+            self.restore_mute(ctx);
+            self.restore_deploy_windows(ctx);
+        }
+    }
+}
+
+impl SystemService for Processor {}
+impl Supervised for Processor {}
+
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "UserConfirmation")]
+pub struct UserAction {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Ack(AckTarget, String),
+    Details(AlertId),
+    Resolve(AlertId, String),
+    // From, to.
+    Handoff(String, String),
+    // Duration in seconds, sender.
+    Mute(u64, String),
+    // Name, duration in seconds, target route, entry level, sender. Applies
+    // to the route of the room, see `PolicyOverride`.
+    Override(String, u64, Option<String>, Option<usize>, String),
+    // Name, sender.
+    EndOverride(String, String),
+    Overrides,
+    // Severity, alert name, sender.
+    Simulate(String, String, String),
+    // Sender.
+    SelfTest(String),
+    // Name of the adapter, whether to enable it, sender.
+    SetAdapter(String, bool, String),
+    // Delay in seconds, sender.
+    Remind(AlertId, u64, String),
+    Pending,
+    Noisy,
+    Failures,
+    // Period in seconds, defaults to a week.
+    UserStats(Option<u64>),
+    // Labels, sender.
+    Watch(BTreeMap<String, String>, String),
+    // Labels of the watch to remove (all if `None`), sender.
+    Unwatch(Option<BTreeMap<String, String>>, String),
+    // Sender.
+    Watches(String),
+    // Command to explain, sender.
+    Help(Option<String>, String),
+}
+
+/// The alert to acknowledge, either by ID or by a shortcut.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AckTarget {
+    Id(AlertId),
+    // The most recent alert announced in the room.
+    Last,
+    // The only pending alert of the route whose name contains the given text.
+    Name(String),
+}
+
+impl AckTarget {
+    /// Resolves the target against the pending alerts of the route, given
+    /// the escalation index of the room and of the final room.
+    fn select(
+        &self,
+        pending: Vec<AlertContext>,
+        route: &str,
+        escalation_idx: usize,
+        last_idx: usize,
+    ) -> std::result::Result<AlertId, UserConfirmation> {
+        let pending = pending.into_iter().filter(|alert| alert.route == route);
+
+        match self {
+            AckTarget::Id(id) => Ok(*id),
+            AckTarget::Last => pending
+                // Alerts beyond the final room are announced in the final room.
+                .filter(|alert| {
+                    alert.escalation_idx == escalation_idx
+                        || (escalation_idx == last_idx && alert.escalation_idx > last_idx)
+                })
+                .max_by_key(|alert| (alert.last_notified, alert.id))
+                .map(|alert| alert.id)
+                .ok_or(UserConfirmation::AlertNotFound),
+            AckTarget::Name(name) => {
+                let name = name.to_lowercase();
+                let mut matches: Vec<AlertContext> = pending
+                    .filter(|alert| alert.alert.labels.alert_name.to_lowercase().contains(&name))
+                    .collect();
+
+                match matches.len() {
+                    0 => Err(UserConfirmation::AlertNotFound),
+                    1 => Ok(matches.remove(0).id),
+                    _ => {
+                        matches.sort_by_key(|alert| alert.id);
+                        Err(UserConfirmation::AmbiguousAlerts(matches))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Metadata of a user command, used for usage hints and `help`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub examples: &'static [&'static str],
+    // Additional rules, shown by `help <COMMAND>`.
+    pub notes: &'static str,
+    pub admin_only: bool,
+}
+
+impl CommandInfo {
+    /// The syntax and examples of the command, shown on invalid arguments.
+    pub fn usage_hint(&self) -> String {
+        if self.examples.is_empty() {
+            return self.usage.to_string();
+        }
+
+        let examples: Vec<String> = self
+            .examples
+            .iter()
+            .map(|example| format!("`{}`", example))
+            .collect();
+
+        format!("{}, e.g. {}", self.usage, examples.join(" or "))
+    }
+}
+
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "ack",
+        aliases: &["acknowledge"],
+        usage: "ack <ID|last|NAME>",
+        summary: "Acknowledge an alert by id, the last one of this room or by name",
+        examples: &["ack 5", "ack last"],
+        notes: "Acknowledged alerts no longer escalate. `ack last` acknowledges the most \
+                recent alert announced in this room, `ack <NAME>` the only pending alert \
+                whose name contains NAME.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "resolve",
+        aliases: &[],
+        usage: "resolve <ID>",
+        summary: "Resolve an acknowledged alert by id",
+        examples: &[],
+        notes: "The acknowledgement of resolved alerts no longer expires.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "handoff",
+        aliases: &[],
+        usage: "handoff <USER>",
+        summary: "Hand off the alerts you are responsible for to another user",
+        examples: &["handoff @bob:matrix.org"],
+        notes: "Only the unresolved alerts of this route which you acknowledged or which \
+                were handed off to you are handed off. Who acknowledged them is kept.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "remind",
+        aliases: &[],
+        usage: "remind <ID> <DURATION>",
+        summary: "Get reminded about an alert",
+        examples: &["remind 5 45m"],
+        notes: "Durations are given in seconds (s), minutes (m), hours (h) or days (d). \
+                The reminder is sent to this room.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "details",
+        aliases: &[],
+        usage: "details <ID>",
+        summary: "Show an alert and its timeline",
+        examples: &[],
+        notes: "",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "pending",
+        aliases: &[],
+        usage: "pending",
+        summary: "Show pending alerts",
+        examples: &[],
+        notes: "The most severe alerts are shown first.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "noisy",
+        aliases: &[],
+        usage: "noisy",
+        summary: "Show the noisiest alerts of the last week",
+        examples: &[],
+        notes: "",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "failures",
+        aliases: &[],
+        usage: "failures",
+        summary: "Show acks which have not reached all adapters yet",
+        examples: &[],
+        notes: "Acks whose propagation to an adapter failed are retried on every escalation \
+                sweep until they are delivered.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "stats",
+        aliases: &[],
+        usage: "stats users [PERIOD]",
+        summary: "Show who acknowledged the alerts of this route and how fast",
+        examples: &["stats users", "stats users 30d"],
+        notes: "The period defaults to the last week. Users are ranked by the number of \
+                acknowledged alerts, the response time is the median time from the first \
+                notification to the acknowledgement.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "watch",
+        aliases: &[],
+        usage: "watch <LABEL=VALUE>...",
+        summary: "Get mentioned about new alerts with the given labels",
+        examples: &["watch severity=critical team=infra"],
+        notes: "Labels are `severity`, `alertname`, `team`, `instance`, `job` and \
+                `namespace`. All given labels must match.",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "unwatch",
+        aliases: &[],
+        usage: "unwatch [LABEL=VALUE]...",
+        summary: "Remove a watch, or all of your watches",
+        examples: &["unwatch severity=critical team=infra"],
+        notes: "",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "watches",
+        aliases: &[],
+        usage: "watches",
+        summary: "Show your watches",
+        examples: &[],
+        notes: "",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "mute",
+        aliases: &[],
+        usage: "mute <DURATION>",
+        summary: "Mute all notifications",
+        examples: &["mute 30m", "mute 2h"],
+        notes: "Alerts are still recorded and notified once the mute expires, which also survives restarts.",
+        admin_only: true,
+    },
+    CommandInfo {
+        name: "override",
+        aliases: &[],
+        usage: "override <NAME> <DURATION> [route=ROUTE] [level=LEVEL] | override end <NAME>",
+        summary: "Temporarily change the escalation policy of this route",
+        examples: &["override freeze 2d route=payments", "override end freeze"],
+        notes: "From now on, new alerts of this route go to the given route and/or enter \
+                its escalation chain at the given level. The override is reverted once it \
+                expires or ends.",
+        admin_only: true,
+    },
+    CommandInfo {
+        name: "overrides",
+        aliases: &[],
+        usage: "overrides",
+        summary: "Show the overrides of this route",
+        examples: &[],
+        notes: "",
+        admin_only: false,
+    },
+    CommandInfo {
+        name: "simulate",
+        aliases: &[],
+        usage: "simulate <SEVERITY> <ALERTNAME>",
+        summary: "Show how an alert would escalate",
+        examples: &["simulate critical \"Node down\""],
+        notes: "Nobody is notified.",
+        admin_only: true,
+    },
+    CommandInfo {
+        name: "selftest",
+        aliases: &[],
+        usage: "selftest",
+        summary: "Verify the rooms, adapters and database of this route",
+        examples: &["selftest"],
+        notes: "Sends a test message to every room and adapter destination of the route and \
+                measures the latency of the database, then reports which checks failed. No \
+                alert is created. Opsgenie and Voice are not paged, their credentials are \
+                verified instead.",
+        admin_only: true,
+    },
+    CommandInfo {
+        name: "disable",
+        aliases: &[],
+        usage: "disable adapter <NAME>",
+        summary: "Stop notifying an adapter, e.g. during an outage of the service",
+        examples: &["disable adapter Telegram"],
+        notes: "Applies to all routes until the adapter is enabled again or the bot restarts. \
+                Alerts keep escalating as usual, notifications which failed are retried once \
+                the adapter is enabled. Names with spaces are quoted, e.g. \
+                `disable adapter \"SNS topics\"`.",
+        admin_only: true,
+    },
+    CommandInfo {
+        name: "enable",
+        aliases: &[],
+        usage: "enable adapter <NAME>",
+        summary: "Notify a disabled adapter again",
+        examples: &["enable adapter Telegram"],
+        notes: "Notifications sent while the adapter was disabled are not repeated.",
+        admin_only: true,
+    },
+    CommandInfo {
+        name: "help",
+        aliases: &[],
+        usage: "help [COMMAND]",
+        summary: "Show this help message or the details of a command",
+        examples: &["help ack"],
+        notes: "",
+        admin_only: false,
+    },
+];
+
+/// Looks up a command by its name or an alias.
+pub fn command_info(name: &str) -> Option<&'static CommandInfo> {
+    let name = name.to_lowercase();
+    COMMANDS
+        .iter()
+        .find(|info| info.name == name || info.aliases.contains(&name.as_str()))
+}
+
+/// The response to `help`, generated from `COMMANDS`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Help {
+    // Lists the commands the user may run.
+    Commands {
+        is_admin: bool,
+    },
+    Command {
+        info: &'static CommandInfo,
+        ack_scope: AckScope,
+    },
+    UnknownCommand(String),
+}
+
+impl fmt::Display for Help {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Help::Commands { is_admin } => {
+                let lines: Vec<String> = COMMANDS
+                    .iter()
+                    .filter(|info| *is_admin || !info.admin_only)
+                    .map(|info| {
+                        let admin = if info.admin_only {
+                            " (admins only)"
+                        } else {
+                            ""
+                        };
+                        format!("{} - {}{}", info.usage, info.summary, admin)
+                    })
+                    .collect();
+
+                write!(f, "{}", lines.join("\n"))
+            }
+            Help::Command { info, ack_scope } => {
+                writeln!(f, "{} - {}", info.usage, info.summary)?;
+                if !info.aliases.is_empty() {
+                    writeln!(f, "Aliases: {}", info.aliases.join(", "))?;
+                }
+                if !info.examples.is_empty() {
+                    let examples: Vec<String> = info
+                        .examples
+                        .iter()
+                        .map(|example| format!("`{}`", example))
+                        .collect();
+                    writeln!(f, "Examples: {}", examples.join(", "))?;
+                }
+                if !info.notes.is_empty() {
+                    writeln!(f, "{}", info.notes)?;
+                }
+                if info.name == "ack" {
+                    writeln!(
+                        f,
+                        "Alerts of this route can be acknowledged by {}.",
+                        ack_scope.describe()
+                    )?;
+                }
+                if info.admin_only {
+                    writeln!(f, "Only admins may run this command.")?;
+                }
+
+                Ok(())
+            }
+            Help::UnknownCommand(name) => {
+                write!(f, "Unknown command '{}', see `help`.", name)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct NotifyAlert {
+    pub route: String,
+    pub alerts: Vec<AlertContext>,
+    // Users watching the alerts, see `watch`.
+    pub watchers: HashMap<AlertId, Vec<String>>,
+}
+
+/// Escalates alerts to the given escalation index. Results in the index of
+/// the notified room, which is capped at the final room.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<usize>")]
+pub struct Escalation {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub alerts: Vec<AlertContext>,
+}
+
+/// Warns the room of the given escalation index that the alerts are about to
+/// escalate, with the seconds until they do.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct EscalationWarning {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub alerts: Vec<(AlertContext, u64)>,
+}
+
+/// Summarizes alerts which missed escalations, sent to the room of the given
+/// escalation index.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct CatchUpSummary {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub alerts: Vec<AlertContext>,
+}
+
+/// Reminds the room which issued a mute that it has expired, including the
+/// alerts which were received in the meantime.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct MuteExpired {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub alerts: Vec<AlertContext>,
+}
+
+/// Informs the first room of each route about a deploy window.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct DeployNotice {
+    pub routes: Vec<String>,
+    pub body: String,
+}
+
+/// The weekly report of the noisiest alerts of a route.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct NoiseReport {
+    pub route: String,
+    pub alerts: Vec<NoiseStats>,
+}
+
+/// The weekly compliance report of all alerts raised since the given time, as
+/// CSV.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct ComplianceReport {
+    pub room: String,
+    pub since: u64,
+    pub alerts: usize,
+    pub csv: String,
+}
+
+/// Reminds a room about an alert, as requested by a user.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct RemindAlert {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub requested_by: String,
+    pub alert: AlertContext,
+}
+
+/// Reminds the rooms about acknowledged alerts which have not been resolved
+/// in time.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct AckExpired {
+    pub route: String,
+    pub alerts: Vec<AlertContext>,
+}
+
+/// Informs the room of an alert that it was acknowledged outside of Matrix,
+/// e.g. via the HTTP API. Sent to the processor, it informs the adapters.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct RemoteAck {
+    pub route: String,
+    pub escalation_idx: usize,
+    pub id: AlertId,
+    pub user: String,
+    // Where the alert was acknowledged, e.g. `the API`.
+    pub via: String,
+}
+
+/// Archives the incident room of a resolved alert, if any.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct IncidentResolved {
+    pub id: AlertId,
+    pub resolved_by: String,
+}
+
+/// Informs the rooms about alerts whose severity was raised, see
+/// `SeverityBump`.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct SeverityRaised {
+    pub route: String,
+    pub alerts: Vec<AlertContext>,
+}
+
+/// Informs the current level about a pending alert which fired again with
+/// changed annotations.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct AlertRefired {
+    pub alert: AlertContext,
+    pub changes: Vec<AnnotationChange>,
+}
+
+/// Runs a synthetic alert through routing and escalation without notifying
+/// anyone.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Option<Simulation>")]
+pub struct Simulate {
+    pub route: String,
+    pub severity: String,
+    pub alert_name: String,
+}
+
+/// Retrieves a pending or acknowledged alert.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Option<AlertContext>>")]
+pub struct GetAlert(pub AlertId);
+
+/// Retrieves a page of pending alerts, see `Database::get_pending_page`.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Vec<AlertContext>>")]
+pub struct ListPending {
+    pub after: Option<AlertId>,
+    pub limit: i64,
+}
+
+/// Retrieves the acknowledgements per user since the given time, of all
+/// routes if none is given.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Vec<UserStats>>")]
+pub struct ListUserStats {
+    pub route: Option<String>,
+    pub since: u64,
+}
+
+/// Retrieves a page of acknowledged alerts, see
+/// `Database::get_history_page`.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Vec<AlertAcknowledged>>")]
+pub struct ListHistory {
+    pub after: Option<AlertId>,
+    pub limit: i64,
+}
+
+/// Lists the routes, i.e. the configured ones with those stored via the admin
+/// API layered onto them.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Vec<RouteConfig>>")]
+pub struct ListRoutes;
+
+/// Stores a route, which takes effect once the service is restarted. Invalid
+/// routes are rejected with `Error::Config`.
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "Result<()>")]
+pub struct PutRoute(pub RouteConfig);
+
+/// Removes a stored route, restoring the configured one of the same name (if
+/// any). Returns `false` if there was no stored route.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<bool>")]
+pub struct DeleteRoute(pub String);
+
+/// Lists the configured adapters and whether they are enabled.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Vec<AdapterState>")]
+pub struct ListAdapters;
+
+/// Takes an adapter out of the rotation, or back in, see
+/// `Adapters::set_enabled`. Returns `false` if there is no such adapter.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "bool")]
+pub struct SetAdapterEnabled {
+    pub name: String,
+    pub enabled: bool,
+}
+
+/// Lists the stored policy overrides, including upcoming ones.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Vec<PolicyOverride>>")]
+pub struct ListOverrides;
+
+/// Stores an override, which applies to alerts received between its start
+/// and end. Invalid overrides are rejected with `Error::Config`.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct PutOverride(pub PolicyOverride);
+
+/// Removes an override before it ends. Returns `false` if there was none.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<bool>")]
+pub struct DeleteOverride(pub String);
+
+/// Lists the users with API keys, see `CreateApiKey`.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Vec<ApiKeyInfo>>")]
+pub struct ListApiKeys;
+
+/// Creates an API key for the given Matrix user and returns it. The key is
+/// not stored, only its hash.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<String>")]
+pub struct CreateApiKey(pub String);
+
+/// Revokes all API keys of the user. Returns `false` if there were none.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<bool>")]
+pub struct DeleteApiKeys(pub String);
+
+/// Returns the user of the API key, if it is valid.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<Option<String>>")]
+pub struct ResolveApiKey(pub String);
+
+/// Promotes a standby instance to an active one.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<()>")]
+pub struct Promote;
+
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "bool")]
+pub struct IsStandby;
+
+/// The rooms of a route backed by a Matrix space have changed.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "()")]
+pub struct SpaceRooms {
+    pub route: String,
+    pub rooms: Vec<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Message, ToSchema)]
+#[rtype(result = "Result<Vec<InsertedAlert>>")]
+pub struct InsertAlerts {
+    alerts: Vec<Alert>,
+    // Set by the webhook listener which received the alerts.
+    #[serde(skip)]
+    pub route: String,
+    // The ID of the webhook request, attached to the inserted alerts.
+    #[serde(skip)]
+    pub request_id: Option<String>,
+}
+
+impl InsertAlerts {
+    pub fn new(alerts: Vec<Alert>) -> Self {
+        InsertAlerts {
+            alerts,
+            route: DEFAULT_ROUTE.to_string(),
+            request_id: None,
+        }
+    }
+}
+
+/// The ID and route assigned to a submitted alert.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct InsertedAlert {
+    // Position of the alert in the submitted payload.
+    pub index: usize,
+    pub fingerprint: String,
+    pub id: AlertId,
+    pub route: String,
+    // Whether the alert was suppressed as a duplicate or re-fire, in which
+    // case `id` and `route` are those of the alert already pending.
+    pub duplicate: bool,
+}
+
+/// A historical alert, e.g. exported from a previous system.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ImportedAlert {
+    pub alert: Alert,
+    // Defaults to the default route.
+    pub route: Option<String>,
+    // Imported as acknowledged if set, otherwise as pending.
+    pub acked_by: Option<String>,
+    // Defaults to the time of the import.
+    pub acked_timestamp: Option<u64>,
+    pub resolved_by: Option<String>,
+    pub resolved_timestamp: Option<u64>,
+}
+
+/// Stores imported alerts without notifying anyone.
+#[derive(Clone, Debug, Eq, PartialEq, Message)]
+#[rtype(result = "Result<ImportSummary>")]
+pub struct ImportAlerts(pub Vec<ImportedAlert>);
+
+/// The number of imported alerts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, ToSchema)]
+pub struct ImportSummary {
+    pub pending: usize,
+    pub history: usize,
+}
+
+/// Acknowledges pending alerts at once, selected either by their IDs or by
+/// their labels. If storing an acknowledgement fails, the alerts acknowledged
+/// so far are returned to pending. This is not atomic, transactions would
+/// require MongoDB to run as a replica set, so others may briefly see some of
+/// the alerts acknowledged.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Message, ToSchema)]
+#[rtype(result = "Result<Vec<BatchAckResult>>")]
+pub struct AckAlerts {
+    #[serde(default)]
+    pub ids: Vec<AlertId>,
+    // Names (see `Labels::NAMES`) and values of labels, all of which must
+    // match, ignoring case.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    // Defaults to the user of the API key.
+    pub user: Option<String>,
+}
+
+impl AckAlerts {
+    /// The pending alerts to acknowledge, and the requested IDs which are not
+    /// pending.
+    fn select(&self, pending: Vec<AlertContext>) -> Result<(Vec<AlertContext>, Vec<AlertId>)> {
+        match (self.ids.is_empty(), self.labels.is_empty()) {
+            (false, true) => {
+                let mut pending: HashMap<AlertId, AlertContext> =
+                    pending.into_iter().map(|alert| (alert.id, alert)).collect();
+
+                let mut selected = vec![];
+                let mut missing = vec![];
+                for id in &self.ids {
+                    match pending.remove(id) {
+                        Some(alert) => selected.push(alert),
+                        None => missing.push(*id),
+                    }
+                }
+
+                Ok((selected, missing))
+            }
+            (true, false) => {
+                if let Some(name) = self
+                    .labels
+                    .keys()
+                    .find(|name| !Labels::NAMES.contains(&name.as_str()))
+                {
+                    return Err(Error::Config(format!("Unknown label '{}'", name)));
+                }
+
+                let mut selected: Vec<AlertContext> = pending
+                    .into_iter()
+                    .filter(|alert| alert.alert.labels.matches(&self.labels))
+                    .collect();
+                selected.sort_by_key(|alert| alert.id);
+
+                Ok((selected, vec![]))
+            }
+            _ => Err(Error::Config(String::from(
+                "Either ids or labels must be given",
+            ))),
+        }
+    }
+}
+
+/// The outcome of a batch ack for a single alert.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct BatchAckResult {
+    pub id: AlertId,
+    pub status: BatchAckStatus,
+    // Unset for alerts which are not pending.
+    pub route: Option<String>,
+    pub escalation_idx: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchAckStatus {
+    Acknowledged,
+    // Not pending, e.g. already acknowledged.
+    NotFound,
+}
+
+/// Posts a notice about a deploy window to the first room of each route.
+fn send_deploy_notice(routes: Vec<String>, body: String) {
+    actix::spawn(async move {
+        let res = MatrixClient::from_registry()
+            .send(DeployNotice { routes, body })
+            .await;
+
+        if let Err(err) = res.map_err(|err| err.into()).and_then(|res| res) {
+            error!("Failed to send deploy window notice: {:?}", err);
+        }
+    });
+}
+
+/// The users watching each of the alerts, if any.
+fn watchers(watches: &[Watch], alerts: &[AlertContext]) -> HashMap<AlertId, Vec<String>> {
+    alerts
+        .iter()
+        .filter_map(|alert| {
+            let users: BTreeSet<&String> = watches
+                .iter()
+                .filter(|watch| watch.matches(&alert.alert))
+                .map(|watch| &watch.user)
+                .collect();
+
+            if users.is_empty() {
+                None
+            } else {
+                Some((alert.id, users.into_iter().cloned().collect()))
+            }
+        })
+        .collect()
+}
+
+/// Raises the severity of alerts which stayed unacknowledged for too long.
+/// Alerts move to the entry level of their new severity, if higher.
+async fn raise_severities(
+    db: &Database,
+    settings: &EscalationSettings,
+    exec: Option<&Arc<ExecHook>>,
+) -> Result<()> {
+    let now = unix_time();
+    let mut raised: BTreeMap<String, Vec<(NotificationKey, AlertContext)>> = BTreeMap::new();
+
+    for mut alert in db.get_pending(None).await? {
+        let since = match alert.unacked_since() {
+            Some(since) if alert.should_escalate() => since,
+            _ => continue,
+        };
+
+        let severity = match settings
+            .severities
+            .bump(&alert.alert.labels.severity, now.saturating_sub(since))
+        {
+            Some(severity) => severity,
+            None => continue,
+        };
+
+        let key = NotificationKey {
+            alert_id: alert.id,
+            channel: MATRIX_CHANNEL.to_string(),
+            escalation_idx: alert.escalation_idx,
+            kind: format!("{}:{}", SEVERITY_KIND, severity),
+        };
+
+        if !db.claim_notification(&key, settings.dedup_window).await? {
+            debug!("Skipping duplicate notification: {:?}", key);
+            continue;
+        }
+
+        info!(
+            "Raising severity of {} from '{}' to '{}'",
+            alert.trace(),
+            alert.alert.labels.severity,
+            severity
+        );
+
+        let last_idx = settings
+            .rooms
+            .get(&alert.route)
+            .map(|rooms| rooms.len().saturating_sub(1))
+            .unwrap_or_default();
+        let entry_level = settings.entry_level(&alert.route, &severity).min(last_idx);
+
+        alert.alert.labels.severity = severity;
+        alert.escalation_idx = alert.escalation_idx.max(entry_level);
+        alert.last_notified = now;
+        alert.record(TimelineKind::SeverityRaised, alert.escalation_idx);
+
+        raised
+            .entry(alert.route.clone())
+            .or_default()
+            .push((key, alert));
+    }
+
+    for (route, raised) in raised {
+        let (keys, alerts): (Vec<_>, Vec<_>) = raised.into_iter().unzip();
+
+        db.insert_alerts(&alerts).await?;
+        forward_exec(exec, ExecEvent::SeverityRaised, &alerts);
+
+        MatrixClient::from_registry()
+            .send(SeverityRaised { route, alerts })
+            .await??;
+
+        for key in &keys {
+            db.complete_notification(key).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Escalates the alert to the given index and stores its new state.
+async fn escalate(
+    db: &Arc<Database>,
+    exec: Option<&Arc<ExecHook>>,
+    adapters: &Adapters,
+    mut alert: AlertContext,
+    escalation_idx: usize,
+) -> Result<()> {
+    // Send alert to the matrix client, update escalation index.
+    alert.escalation_idx = MatrixClient::from_registry()
+        .send(Escalation {
+            route: alert.route.clone(),
+            escalation_idx,
+            alerts: vec![alert.clone()],
+        })
+        .await??;
+
+    alert.last_notified = unix_time();
+    alert.record(TimelineKind::Escalated, alert.escalation_idx);
+    forward_escalation(Arc::clone(db), exec, &alert);
+    adapters.forward(
+        &alert.route,
+        alert.escalation_idx,
+        Notification::Escalation(vec![alert.clone()]),
+        None,
+    );
+
+    db.insert_alerts(&[alert]).await
+}
+
+/// Sends a catch-up summary of the alerts to the room of the given index and
+/// stores their new state.
+async fn send_catch_up(
+    db: &Database,
+    route: String,
+    escalation_idx: usize,
+    mut alerts: Vec<AlertContext>,
+) -> Result<()> {
+    MatrixClient::from_registry()
+        .send(CatchUpSummary {
+            route,
+            escalation_idx,
+            alerts: alerts.clone(),
+        })
+        .await??;
+
+    for alert in &mut alerts {
+        alert.last_notified = unix_time();
+        alert.record(TimelineKind::CatchUp, alert.escalation_idx);
+    }
+
+    db.insert_alerts(&alerts).await
+}
+
+/// Reconciles the notifications which a previous process claimed but did not
+/// complete, e.g. because it crashed during an escalation sweep. Notifications
+/// whose alert state was not updated are sent again, so no level is skipped.
+async fn recover_notifications(
+    db: &Arc<Database>,
+    exec: Option<&Arc<ExecHook>>,
+    adapters: &Adapters,
+) -> Result<()> {
+    // Runs of the exec hook are retried by `retry_exec`.
+    let inflight: Vec<_> = db
+        .get_inflight_notifications()
+        .await?
+        .into_iter()
+        .filter(|record| record.key.channel == MATRIX_CHANNEL)
+        .collect();
+    if inflight.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "Recovering {} notification(s) which were being sent when the service stopped",
+        inflight.len()
+    );
+
+    let pending: HashMap<AlertId, AlertContext> = db
+        .get_pending(None)
+        .await?
+        .into_iter()
+        .map(|alert| (alert.id, alert))
+        .collect();
+
+    for record in inflight {
+        let key = &record.key;
+        // Acknowledged alerts need no further notifications.
+        let alert = match pending.get(&key.alert_id) {
+            Some(alert) => alert.clone(),
+            None => {
+                db.complete_notification(key).await?;
+                continue;
+            }
+        };
+
+        // The state is stored after sending, except for raised severities.
+        let is_stale = alert.last_notified < record.sent_at;
+
+        match key.kind.as_str() {
+            ESCALATION_KIND if is_stale => {
+                info!("Resending escalation of {}", alert.trace());
+                escalate(db, exec, adapters, alert, key.escalation_idx).await?;
+            }
+            CATCH_UP_KIND if is_stale => {
+                info!("Resending catch-up summary of {}", alert.trace());
+                send_catch_up(db, alert.route.clone(), alert.escalation_idx, vec![alert]).await?;
+            }
+            kind if kind
+                .strip_prefix(SEVERITY_KIND)
+                .and_then(|k| k.strip_prefix(':'))
+                == Some(alert.alert.labels.severity.as_str()) =>
+            {
+                info!("Resending raised severity of {}", alert.trace());
+                MatrixClient::from_registry()
+                    .send(SeverityRaised {
+                        route: alert.route.clone(),
+                        alerts: vec![alert],
+                    })
+                    .await??;
+            }
+            _ => {}
+        }
+
+        db.complete_notification(key).await?;
+    }
+
+    Ok(())
+}
+
+/// Runs the exec hook for each alert in the background, failures do not
+/// affect other notifications.
+/// Notifies the rooms, watchers, exec hook and adapters about new alerts of
+/// the route, on their entry level.
+async fn notify_new_alerts(
+    db: &Database,
+    exec: Option<&Arc<ExecHook>>,
+    adapters: &Adapters,
+    route: String,
+    alerts: &mut [AlertContext],
+    should_escalate: bool,
+) -> Result<()> {
+    let watches = db.get_watches(None).await?;
+    let watchers = watchers(&watches, alerts);
+
+    forward_exec(exec, ExecEvent::Alert, alerts);
+    adapters.forward_alerts(alerts);
+
+    // Notify rooms about all alerts.
+    debug!("Notifying rooms about new alerts");
+    MatrixClient::from_registry()
+        .send(NotifyAlert {
+            route,
+            alerts: alerts.to_vec(),
+            watchers,
+        })
+        .await??;
+
+    for alert in alerts.iter_mut() {
+        alert.record_notified(MATRIX_CHANNEL);
+    }
+
+    if should_escalate {
+        db.insert_alerts(alerts).await?;
+    }
+
+    Ok(())
+}
+
+fn forward_exec(exec: Option<&Arc<ExecHook>>, event: ExecEvent, alerts: &[AlertContext]) {
+    let exec = match exec {
+        Some(exec) => exec,
+        None => return,
+    };
+
+    for alert in alerts
+        .iter()
+        .filter(|alert| exec.covers(&alert.route, alert.escalation_idx))
+    {
+        let exec = Arc::clone(exec);
+        let alert = alert.clone();
+        let turn = exec.enqueue(alert.id);
+
+        actix::spawn(async move {
+            let _turn = turn.wait().await;
+
+            match exec.run(event, &alert).await {
+                // Only new alerts count towards the notification latency.
+                Ok(()) if event == ExecEvent::Alert => {
+                    if let Some(latency) = &alert.latency {
+                        metrics::observe_notification(
+                            EXEC_CHANNEL,
+                            unix_time_ms().saturating_sub(latency.received_at),
+                        );
+                    }
+                }
+                Ok(()) => {}
+                Err(err) => error!("Failed to run exec hook for {}: {:?}", alert.trace(), err),
+            }
+        });
+    }
+}
+
+/// Runs the exec hook for the escalation of the alert in the background. Its
+/// delivery is tracked separately from the room of the level, a failed run is
+/// retried by `retry_exec` without notifying the room again.
+fn forward_escalation(db: Arc<Database>, exec: Option<&Arc<ExecHook>>, alert: &AlertContext) {
+    let exec = match exec {
+        Some(exec) if exec.covers(&alert.route, alert.escalation_idx) => Arc::clone(exec),
+        _ => return,
+    };
+
+    let key = NotificationKey {
+        alert_id: alert.id,
+        channel: EXEC_CHANNEL.to_string(),
+        escalation_idx: alert.escalation_idx,
+        kind: ESCALATION_KIND.to_string(),
+    };
+    let alert = alert.clone();
+    let turn = exec.enqueue(alert.id);
+
+    actix::spawn(async move {
+        let _turn = turn.wait().await;
+
+        let res = async {
+            // A run which started within the timeout is still in progress.
+            if !db.claim_notification(&key, exec.timeout()).await? {
+                debug!("Skipping duplicate notification: {:?}", key);
+                return Ok(());
+            }
+
+            exec.run(ExecEvent::Escalation, &alert).await?;
+            db.complete_notification(&key).await
+        };
+
+        if let Err(err) = res.await {
+            error!("Failed to run exec hook for {}: {:?}", alert.trace(), err);
+        }
+    });
+}
+
+/// Sends a weekly report from the escalation sweep, once a week has passed
+/// since it was last sent. That time is stored, so that restarts do not delay
+/// the report.
+async fn send_weekly_report(
+    db: &Database,
+    name: &str,
+    report: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    if db.claim_report(name, NOISE_PERIOD, unix_time()).await? {
+        info!("Sending weekly {} report", name);
+        report.await;
+    }
+
+    Ok(())
+}
+
+/// Posts the noisiest alerts of the past week to the first room of every
+/// route.
+async fn send_noise_reports(db: &Database, settings: &EscalationSettings) {
+    for route in settings.rooms.keys() {
+        let res = async {
+            let stats = db
+                .noise_stats(route, unix_time().saturating_sub(NOISE_PERIOD))
+                .await?;
+
+            MatrixClient::from_registry()
+                .send(NoiseReport {
+                    route: route.clone(),
+                    alerts: noisiest(stats),
+                })
+                .await?
+        };
+
+        if let Err(err) = res.await {
+            error!(
+                "Failed to send noise report of route '{}': {:?}",
+                route, err
+            );
+        }
+    }
+}
+
+/// Posts all alerts raised in the past week as CSV file to the room.
+async fn send_compliance_report(db: &Database, room: &str) {
+    let res = async {
+        let since = unix_time().saturating_sub(NOISE_PERIOD);
+        let (pending, acked) = db.get_raised_since(since).await?;
+
+        let rows: Vec<ReportRow> = pending
+            .into_iter()
+            .map(ReportRow::from)
+            .chain(acked.into_iter().map(ReportRow::from))
+            .collect();
+
+        MatrixClient::from_registry()
+            .send(ComplianceReport {
+                room: room.to_string(),
+                since,
+                alerts: rows.len(),
+                csv: report::to_csv(rows),
+            })
+            .await?
+    };
+
+    if let Err(err) = res.await {
+        error!("Failed to send compliance report: {:?}", err);
+    }
+}
+
+/// Retries the exec hook for escalations whose run failed or was interrupted,
+/// as long as the alert is pending on the same level.
+async fn retry_exec(db: &Arc<Database>, exec: Option<&Arc<ExecHook>>) -> Result<()> {
+    let exec = match exec {
+        Some(exec) => exec,
+        None => return Ok(()),
+    };
+
+    let now = unix_time();
+    let failed: Vec<_> = db
+        .get_inflight_notifications()
+        .await?
+        .into_iter()
+        .filter(|record| {
+            record.key.channel == EXEC_CHANNEL && record.sent_at + exec.timeout() < now
+        })
+        .collect();
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let pending: HashMap<AlertId, AlertContext> = db
+        .get_pending(None)
+        .await?
+        .into_iter()
+        .map(|alert| (alert.id, alert))
+        .collect();
+
+    for record in failed {
+        match pending.get(&record.key.alert_id) {
+            Some(alert) if alert.escalation_idx == record.key.escalation_idx => {
+                info!("Retrying exec hook for {}", alert.trace());
+                forward_escalation(Arc::clone(db), Some(exec), alert);
+            }
+            // Acknowledged or escalated further in the meantime.
+            _ => db.complete_notification(&record.key).await?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Informs the ack webhook in the background, the confirmation of the user
+/// does not wait for upstream systems.
+fn forward_ack(
+    db: Arc<Database>,
+    webhook: Arc<AckWebhook>,
+    event: AckEvent,
+    id: AlertId,
+    user: String,
+) {
+    let turn = webhook.enqueue(id);
+
+    actix::spawn(async move {
+        let _turn = turn.wait().await;
+
+        let res = match db.get_alert(id).await {
+            Ok(Some(alert)) => webhook.forward(event, &alert, &user).await,
+            Ok(None) => Err(Error::Internal(format!("Alert {} disappeared", id))),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = res {
+            error!("Failed to forward ack state of alert {}: {:?}", id, err);
+        }
+    });
+}
+
+/// Archives the incident room of a resolved alert in the background, so that
+/// the confirmation is not held up.
+fn archive_incident(id: AlertId, resolved_by: String) {
+    actix::spawn(async move {
+        let res = MatrixClient::from_registry()
+            .send(IncidentResolved { id, resolved_by })
+            .await;
+
+        if let Err(err) = res.map_err(Error::from).and_then(|res| res) {
+            error!(
+                "Failed to archive the incident room of alert {}: {:?}",
+                id, err
+            );
+        }
+    });
+}
+
+impl Handler<UserAction> for Processor {
+    type Result = ResponseActFuture<Self, UserConfirmation>;
+
+    fn handle(&mut self, msg: UserAction, _ctx: &mut Self::Context) -> Self::Result {
+        // Does not require a database.
+        if let Command::Help(topic, sender) = &msg.command {
+            let help = match topic {
+                None => Help::Commands {
+                    is_admin: self.is_admin(sender),
+                },
+                Some(topic) => match command_info(topic) {
+                    Some(info) => Help::Command {
+                        info,
+                        ack_scope: self
+                            .escalation
+                            .ack_scopes
+                            .get(&msg.route)
+                            .copied()
+                            .unwrap_or_default(),
+                    },
+                    None => Help::UnknownCommand(topic.clone()),
+                },
+            };
+
+            return Box::pin(async { UserConfirmation::Help(help) }.into_actor(self));
+        }
+
+        if let Command::Simulate(severity, alert_name, sender) = &msg.command {
+            if !self.is_admin(sender) {
+                return Box::pin(async { UserConfirmation::NotAuthorized }.into_actor(self));
+            }
+
+            let f = self.simulation(msg.route.clone(), severity.clone(), alert_name.clone());
+            return Box::pin(
+                async move {
+                    f.await
+                        .map(|simulation| UserConfirmation::Simulation(Box::new(simulation)))
+                        .unwrap_or(UserConfirmation::InternalError)
+                }
+                .into_actor(self),
+            );
+        }
+
+        if let Command::Override(name, duration, target_route, entry_level, sender) = &msg.command {
+            if !self.is_admin(sender) {
+                return Box::pin(async { UserConfirmation::NotAuthorized }.into_actor(self));
+            }
+
+            let now = unix_time();
+            let policy = PolicyOverride {
+                name: name.clone(),
+                route: msg.route.clone(),
+                target_route: target_route.clone(),
+                entry_level: *entry_level,
+                starts_at: now,
+                ends_at: now + duration,
+                reason: Some(format!("Set by {}", sender)),
+            };
+            let validated = policy.validate(&self.escalation.rooms, now);
+            let db = self.require_db();
+
+            let f = async move {
+                let res = async {
+                    validated?;
+                    db?.upsert_override(&policy).await
+                }
+                .await;
+
+                match res {
+                    Ok(()) => {
+                        info!("Stored override {}", policy);
+                        UserConfirmation::OverrideStored(Box::new(policy))
+                    }
+                    Err(Error::Config(problems)) => UserConfirmation::OverrideRejected(problems),
+                    Err(err) => {
+                        error!("Failed to store override '{}': {:?}", policy.name, err);
+                        UserConfirmation::InternalError
+                    }
+                }
+            };
+
+            return Box::pin(f.into_actor(self));
+        }
+
+        if let Command::SelfTest(sender) = &msg.command {
+            if !self.is_admin(sender) {
+                return Box::pin(async { UserConfirmation::NotAuthorized }.into_actor(self));
+            }
+
+            info!("Running self-test of route '{}' for {}", msg.route, sender);
+            let levels = self
+                .escalation
+                .rooms
+                .get(&msg.route)
+                .map(Vec::len)
+                .unwrap_or_default();
+            let f = selftest::run(
+                self.require_db(),
+                self.adapters.clone(),
+                msg.route.clone(),
+                levels,
+                sender.clone(),
+            );
+
+            return Box::pin(
+                async move { UserConfirmation::SelfTest(Box::new(f.await)) }.into_actor(self),
+            );
+        }
+
+        if let Command::Failures = &msg.command {
+            let adapters = self.adapters.clone();
+            return Box::pin(
+                async move {
+                    adapters
+                        .unsent_acks()
+                        .await
+                        .map(UserConfirmation::UnsentAcks)
+                        .unwrap_or(UserConfirmation::InternalError)
+                }
+                .into_actor(self),
+            );
+        }
+
+        if let Command::SetAdapter(name, enabled, sender) = &msg.command {
+            if !self.is_admin(sender) {
+                return Box::pin(async { UserConfirmation::NotAuthorized }.into_actor(self));
+            }
+
+            let confirmation = match self.adapters.set_enabled(name, *enabled) {
+                Some(name) => {
+                    info!(
+                        "{} adapter {} for {}",
+                        if *enabled { "Enabled" } else { "Disabled" },
+                        name,
+                        sender
+                    );
+                    UserConfirmation::AdapterToggled(name.to_string(), *enabled)
+                }
+                None => UserConfirmation::AdapterNotFound(
+                    self.adapters
+                        .states()
+                        .into_iter()
+                        .map(|state| state.name)
+                        .collect(),
+                ),
+            };
+
+            return Box::pin(async { confirmation }.into_actor(self));
+        }
+
+        let db = self.db();
+        let ack_webhook = self.ack_webhook.clone();
+        let last_idx = self
+            .escalation
+            .rooms
+            .get(&msg.route)
+            .map(|rooms| rooms.len().saturating_sub(1))
+            .unwrap_or_default();
+        let severities = self.escalation.severities.clone();
+        let scope = self
+            .escalation
+            .ack_scopes
+            .get(&msg.route)
+            .copied()
+            .unwrap_or_default();
+        let admins = self.admins.clone();
+
+        let f = async move {
+            async fn local(
+                db: Arc<Database>,
+                ack_webhook: Option<Arc<AckWebhook>>,
+                last_idx: usize,
+                severities: Severities,
+                scope: AckScope,
+                admins: Vec<String>,
+                msg: UserAction,
+            ) -> Result<UserConfirmation> {
+                match msg.command {
+                    Command::Ack(target, acked_by) => {
+                        let id = match target {
+                            AckTarget::Id(id) => id,
+                            target => {
+                                let pending = db.get_pending(None).await?;
+                                match target.select(
+                                    pending,
+                                    &msg.route,
+                                    msg.escalation_idx,
+                                    last_idx,
+                                ) {
+                                    Ok(id) => id,
+                                    Err(confirmation) => return Ok(confirmation),
+                                }
+                            }
+                        };
+
+                        info!("Acknowledging alert Id: {}", id.to_string());
+                        let confirmation = db
+                            .acknowledge_alert(
+                                &msg.route,
+                                msg.escalation_idx,
+                                scope,
+                                id,
+                                acked_by.clone(),
+                            )
+                            .await?;
+
+                        if let (UserConfirmation::AlertAcknowledged(_), Some(webhook)) =
+                            (&confirmation, ack_webhook)
+                        {
+                            forward_ack(db, webhook, AckEvent::Acknowledged, id, acked_by);
+                        }
+
+                        Ok(confirmation)
+                    }
+                    Command::Resolve(id, resolved_by) => {
+                        info!("Resolving alert Id: {}", id);
+                        let confirmation = db
+                            .resolve_alert(&msg.route, id, resolved_by.clone())
+                            .await?;
+
+                        if let UserConfirmation::AlertResolved(_) = confirmation {
+                            archive_incident(id, resolved_by.clone());
+                        }
+
+                        if let (UserConfirmation::AlertResolved(_), Some(webhook)) =
+                            (&confirmation, ack_webhook)
+                        {
+                            forward_ack(db, webhook, AckEvent::Resolved, id, resolved_by);
+                        }
+
+                        Ok(confirmation)
+                    }
+                    Command::Handoff(from, to) => {
+                        info!("Handing off alerts from {} to {}", from, to);
+                        db.handoff_alerts(&msg.route, &from, &to)
+                            .await
+                            .map(|alerts| UserConfirmation::HandedOff(to, alerts))
+                    }
+                    Command::Remind(id, delay, ref requested_by) => {
+                        if db
+                            .get_alert(id)
+                            .await?
+                            .filter(|alert| alert.route == msg.route)
+                            .is_none()
+                        {
+                            return Ok(UserConfirmation::AlertNotFound);
+                        }
+
+                        let due = unix_time() + delay;
+                        db.insert_reminder(&Reminder::new(
+                            id,
+                            msg.route.clone(),
+                            msg.escalation_idx,
+                            requested_by.clone(),
+                            due,
+                        ))
+                        .await?;
+
+                        Ok(UserConfirmation::ReminderScheduled(id, due))
+                    }
+                    Command::Details(id) => Ok(db
+                        .get_alert(id)
+                        .await?
+                        .filter(|alert| alert.route == msg.route)
+                        .map(|alert| UserConfirmation::AlertDetails(Box::new(alert)))
+                        .unwrap_or(UserConfirmation::AlertNotFound)),
+                    Command::Pending => db.get_pending(None).await.map(|pending| {
+                        let mut pending: Vec<AlertContext> = pending
+                            .into_iter()
+                            .filter(|alert| alert.route == msg.route)
+                            .collect();
+
+                        // Most severe alerts first.
+                        pending.sort_by_key(|alert| {
+                            (severities.rank(&alert.alert.labels.severity), alert.id)
+                        });
+
+                        UserConfirmation::PendingAlerts(pending)
+                    }),
+                    Command::Noisy => db
+                        .noise_stats(&msg.route, unix_time().saturating_sub(NOISE_PERIOD))
+                        .await
+                        .map(|stats| UserConfirmation::NoisyAlerts(noisiest(stats))),
+                    Command::UserStats(period) => {
+                        let since = unix_time().saturating_sub(period.unwrap_or(NOISE_PERIOD));
+                        db.user_stats(Some(&msg.route), since)
+                            .await
+                            .map(|stats| UserConfirmation::UserStats(since, leaderboard(stats)))
+                    }
+                    Command::Watch(labels, user) => {
+                        let watch = Watch { user, labels };
+                        db.upsert_watch(&watch).await?;
+                        info!("{} watches {}", watch.user, watch);
+
+                        Ok(UserConfirmation::Watching(watch))
+                    }
+                    Command::Unwatch(labels, user) => db
+                        .remove_watches(&user, labels.as_ref())
+                        .await
+                        .map(UserConfirmation::Unwatched),
+                    Command::Watches(user) => db
+                        .get_watches(Some(&user))
+                        .await
+                        .map(UserConfirmation::Watches),
+                    Command::Mute(duration, sender) => {
+                        if !admins.contains(&sender) {
+                            return Ok(UserConfirmation::NotAuthorized);
+                        }
+
+                        // Extends or shortens the active mute, if any.
+                        warn!(
+                            "{} muted all notifications for {} seconds",
+                            sender, duration
+                        );
+                        let until = unix_time() + duration;
+                        db.upsert_mute(&msg.route, msg.escalation_idx, until)
+                            .await?;
+
+                        Ok(UserConfirmation::Muted(until))
+                    }
+                    Command::EndOverride(ref name, ref sender) => {
+                        if !admins.contains(sender) {
+                            return Ok(UserConfirmation::NotAuthorized);
+                        }
+
+                        let exists = db
+                            .get_overrides()
+                            .await?
+                            .iter()
+                            .any(|policy| policy.name == *name && policy.route == msg.route);
+                        if !exists || !db.remove_override(name).await? {
+                            return Ok(UserConfirmation::OverrideNotFound);
+                        }
+
+                        info!("{} ended override '{}'", sender, name);
+                        Ok(UserConfirmation::OverrideEnded(name.clone()))
+                    }
+                    Command::Overrides => db.get_overrides().await.map(|overrides| {
+                        UserConfirmation::Overrides(
+                            overrides
+                                .into_iter()
+                                .filter(|policy| policy.route == msg.route)
+                                .collect(),
+                        )
+                    }),
+                    Command::Help(..)
+                    | Command::Simulate(..)
+                    | Command::SelfTest(..)
+                    | Command::SetAdapter(..)
+                    | Command::Failures
+                    | Command::Override(..) => {
+                        Ok(UserConfirmation::Help(Help::Commands { is_admin: false }))
+                    }
+                }
+            }
+
+            local(db, ack_webhook, last_idx, severities, scope, admins, msg)
+                .await
+                .map_err(|err| {
+                    error!("Error when trying to process user command: {:?}", err);
+                    UserConfirmation::InternalError
+                })
+                .unwrap()
+        };
+
+        Box::pin(f.into_actor(self).map(|confirmation, proc, ctx| {
+            if let UserConfirmation::Muted(until) = confirmation {
+                proc.schedule_unmute(ctx, until);
+            }
+            confirmation
+        }))
+    }
+}
+
+impl Handler<InsertAlerts> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<InsertedAlert>>>;
+
+    fn handle(&mut self, msg: InsertAlerts, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db();
+        let settings = self.escalation.clone();
+        let should_escalate = settings.enabled;
+        let muted = self.mute.is_some();
+        let exec = self.exec.clone();
+        let adapters = self.adapters.clone();
+        let received_at = unix_time_ms();
+
+        let f = async move {
+            let windows = db.active_deploy_windows(unix_time()).await?;
+
+            // Overrides may redirect the alerts and change their entry level.
+            let policy = db.active_override(&msg.route, unix_time()).await?;
+            let route = match &policy {
+                Some(policy) => {
+                    info!(
+                        "Applying override '{}' to alerts of route '{}'",
+                        policy.name, msg.route
+                    );
+                    policy.target().to_string()
+                }
+                None => msg.route.clone(),
+            };
+
+            // To detect re-fires and duplicates on other routes.
+            let mut pending = db.get_pending(None).await?;
+
+            // Convert webhook alerts into alert contexts.
+            // (avoid an iterator so `async` can be used conveniently)
+            let mut alerts = vec![];
+            let mut inserted = vec![];
+            let mut refired = vec![];
+            for (index, mut alert) in msg.alerts.into_iter().enumerate() {
+                if let Some(window) = windows
+                    .iter()
+                    .find(|window| window.silences(&route, &alert))
+                {
+                    info!(
+                        "Silencing '{}' on route '{}' during deploy window '{}'",
+                        alert.labels.alert_name, route, window.name
+                    );
+                    db.add_silenced(&window.name, &alert).await?;
+                    continue;
+                }
+
+                // Pending alerts are stored with the normalized severity.
+                alert.labels.severity = settings.severities.normalize(&alert.labels.severity);
+
+                if let Some((matched, changed)) =
+                    settings.match_pending(&mut pending, &route, index, &alert)
+                {
+                    inserted.push(matched);
+                    refired.extend(changed);
+                    continue;
+                }
+
+                let next_id = db.get_next_id().await?;
+                let entry_level = policy
+                    .as_ref()
+                    .and_then(|policy| policy.entry_level)
+                    .unwrap_or_else(|| settings.entry_level(&route, &alert.labels.severity));
+
+                let mut alert = AlertContext::new(alert, next_id, route.clone(), should_escalate);
+                alert.escalation_idx = entry_level;
+                alert.slo = settings
+                    .slos
+                    .get(&route)
+                    .map(|slo| slo.burn(&alert.alert, &settings.severities));
+                alert.request_id = msg.request_id.clone();
+                alert.latency = Some(Latency::new(received_at));
+                if !muted {
+                    alert.record(TimelineKind::Notified, entry_level);
+                }
+
+                info!("Inserting {} into route '{}'", alert.trace(), route);
+
+                inserted.push(InsertedAlert {
+                    index,
+                    fingerprint: alert.fingerprint(),
+                    id: alert.id,
+                    route: route.clone(),
+                    duplicate: false,
+                });
+                alerts.push(alert);
+            }
+
+            // Only store alerts that should escalate.
+            if should_escalate {
+                db.insert_alerts(&alerts).await?;
+            }
+
+            if !refired.is_empty() {
+                let changed: Vec<AlertContext> =
+                    refired.iter().map(|msg| msg.alert.clone()).collect();
+                db.insert_alerts(&changed).await?;
+
+                // While muted, the changes are only stored with the alerts.
+                if !muted {
+                    for msg in refired {
+                        MatrixClient::from_registry().send(msg).await??;
+                    }
+                }
+            }
+
+            // Hold back notifications until the mute expires.
+            if muted {
+                if db.hold_muted_alerts(&alerts).await? {
+                    debug!("Muted, holding back notifications about new alerts");
+                    return Ok(inserted);
+                }
+
+                // The mute expired in the meantime.
+                for alert in &mut alerts {
+                    alert.record(TimelineKind::Notified, alert.escalation_idx);
+                }
+            }
+
+            notify_new_alerts(
+                &db,
+                exec.as_ref(),
+                &adapters,
+                route,
+                &mut alerts,
+                should_escalate,
+            )
+            .await?;
+
+            Ok(inserted)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<Simulate> for Processor {
+    type Result = ResponseActFuture<Self, Option<Simulation>>;
+
+    fn handle(&mut self, msg: Simulate, _ctx: &mut Self::Context) -> Self::Result {
+        let f = self.simulation(msg.route, msg.severity, msg.alert_name);
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<GetAlert> for Processor {
+    type Result = ResponseActFuture<Self, Result<Option<AlertContext>>>;
+
+    fn handle(&mut self, msg: GetAlert, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+
+        let f = async move {
+            match db {
+                Some(db) => db.get_alert(msg.0).await,
+                None => Err(Error::Config(String::from(
+                    "Database has not been configured",
+                ))),
+            }
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<DeployWindow> for Processor {
+    type Result = ResponseActFuture<Self, Result<u64>>;
+
+    fn handle(&mut self, mut msg: DeployWindow, _ctx: &mut Self::Context) -> Self::Result {
+        if let Err(err) = msg.validate(&self.escalation.rooms) {
+            return Box::pin(async { Err(err) }.into_actor(self));
+        }
+
+        if msg.routes.is_empty() {
+            msg.routes = self.escalation.rooms.keys().cloned().collect();
+            msg.routes.sort();
+        }
+
+        let db = self.db();
+        let notice = format!(
+            "🚧 Deploy window '{}' started by {}, alerts with labels {} are silenced for {}.",
+            msg.name,
+            msg.started_by,
+            msg.describe_labels(),
+            format_duration(msg.duration)
+        );
+        // Extends or shortens the window of the same name, if any.
+        let window = ActiveDeployWindow::new(msg, unix_time());
+
+        let f = async move {
+            db.upsert_deploy_window(&window).await?;
+            Ok(window)
+        };
+
+        Box::pin(
+            f.into_actor(self)
+                .map(move |res: Result<ActiveDeployWindow>, proc, ctx| {
+                    let window = res?;
+
+                    info!(
+                        "Deploy window '{}' started by {} until {}",
+                        window.name,
+                        window.started_by,
+                        format_time(window.ends_at)
+                    );
+                    send_deploy_notice(window.routes, notice);
+                    proc.schedule_deploy_window_end(ctx, window.name, window.ends_at);
+
+                    Ok(window.ends_at)
+                }),
+        )
+    }
+}
+
+impl Handler<AckAlerts> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<BatchAckResult>>>;
+
+    fn handle(&mut self, msg: AckAlerts, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let ack_webhook = self.ack_webhook.clone();
+
+        let f = async move {
+            let db =
+                db.ok_or_else(|| Error::Config(String::from("Database has not been configured")))?;
+            let acked_by = msg
+                .user
+                .clone()
+                .ok_or_else(|| Error::Config(String::from("No user given")))?;
+
+            let (selected, missing) = msg.select(db.get_pending(None).await?)?;
+
+            let mut results: Vec<BatchAckResult> = missing
+                .into_iter()
+                .map(|id| BatchAckResult {
+                    id,
+                    status: BatchAckStatus::NotFound,
+                    route: None,
+                    escalation_idx: None,
+                })
+                .collect();
+
+            let mut acked: Vec<AlertContext> = vec![];
+            for alert in selected {
+                // The alert may have escalated in the meantime, so the scope
+                // must not depend on its level.
+                let res = db
+                    .acknowledge_alert(
+                        &alert.route,
+                        alert.escalation_idx,
+                        AckScope::Lenient,
+                        alert.id,
+                        acked_by.clone(),
+                    )
+                    .await;
+
+                let status = match res {
+                    Ok(UserConfirmation::AlertAcknowledged(_)) => BatchAckStatus::Acknowledged,
+                    // Acknowledged concurrently, e.g. in Matrix.
+                    Ok(_) => BatchAckStatus::NotFound,
+                    Err(err) => {
+                        // Return the alerts acknowledged so far to pending.
+                        let ids: Vec<AlertId> = acked.iter().map(|alert| alert.id).collect();
+                        if let Err(rollback) = async {
+                            db.remove_acknowledgements(&ids).await?;
+                            db.insert_alerts(&acked).await
+                        }
+                        .await
+                        {
+                            error!(
+                                "Failed to return acknowledged alerts {:?} to pending: {:?}",
+                                ids, rollback
+                            );
+                        }
+                        return Err(err);
+                    }
+                };
+
+                results.push(BatchAckResult {
+                    id: alert.id,
+                    status,
+                    route: Some(alert.route.clone()),
+                    escalation_idx: Some(alert.escalation_idx),
+                });
+
+                if status == BatchAckStatus::Acknowledged {
+                    acked.push(alert);
+                }
+            }
+            results.sort_by_key(|result| result.id);
+
+            info!(
+                "Acknowledged {} alerts on behalf of {}",
+                acked.len(),
+                acked_by
+            );
+
+            if let Some(webhook) = ack_webhook {
+                for alert in &acked {
+                    forward_ack(
+                        Arc::clone(&db),
+                        Arc::clone(&webhook),
+                        AckEvent::Acknowledged,
+                        alert.id,
+                        acked_by.clone(),
+                    );
+                }
+            }
+
+            Ok(results)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ImportAlerts> for Processor {
+    type Result = ResponseActFuture<Self, Result<ImportSummary>>;
+
+    fn handle(&mut self, msg: ImportAlerts, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let settings = self.escalation.clone();
+
+        let f = async move {
+            let db =
+                db.ok_or_else(|| Error::Config(String::from("Database has not been configured")))?;
+
+            if let Some(route) = msg
+                .0
+                .iter()
+                .filter_map(|imported| imported.route.as_ref())
+                .find(|route| !settings.rooms.contains_key(*route))
+            {
+                return Err(Error::Config(format!("Unknown route '{}'", route)));
+            }
+
+            let mut pending = vec![];
+            let mut history = vec![];
+            for imported in msg.0 {
+                let mut alert = imported.alert;
+                alert.labels.severity = settings.severities.normalize(&alert.labels.severity);
+
+                let route = imported.route.unwrap_or_else(|| DEFAULT_ROUTE.to_string());
+                let id = db.get_next_id().await?;
+                let entry_level = settings.entry_level(&route, &alert.labels.severity);
+
+                let mut alert = AlertContext::new(alert, id, route, settings.enabled);
+                alert.escalation_idx = entry_level;
+
+                match imported.acked_by {
+                    Some(acked_by) => {
+                        let acked_timestamp = imported.acked_timestamp.unwrap_or_else(unix_time);
+                        alert.timeline.push(TimelineEvent {
+                            kind: TimelineKind::Acknowledged,
+                            escalation_idx: entry_level,
+                            channel: IMPORT_CHANNEL.to_string(),
+                            timestamp: acked_timestamp,
+                        });
+
+                        let resolved_timestamp = imported.resolved_timestamp;
+                        let resolved_timestamp = imported
+                            .resolved_by
+                            .as_ref()
+                            .map(|_| resolved_timestamp.unwrap_or(acked_timestamp));
+
+                        if let Some(timestamp) = resolved_timestamp {
+                            alert.timeline.push(TimelineEvent {
+                                kind: TimelineKind::Resolved,
+                                escalation_idx: entry_level,
+                                channel: IMPORT_CHANNEL.to_string(),
+                                timestamp,
+                            });
+                        }
+
+                        history.push(AlertAcknowledged {
+                            alert,
+                            acked_by,
+                            acked_timestamp,
+                            resolved_timestamp,
+                            resolved_by: imported.resolved_by,
+                            handed_off_to: None,
+                            expired_timestamp: None,
+                        });
+                    }
+                    // Escalates from now on, like a new alert which has just
+                    // been notified.
+                    None => pending.push(alert),
+                }
+            }
+
+            db.insert_alerts(&pending).await?;
+            db.insert_acknowledged(&history).await?;
+
+            Ok(ImportSummary {
+                pending: pending.len(),
+                history: history.len(),
+            })
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ListPending> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<AlertContext>>>;
+
+    fn handle(&mut self, msg: ListPending, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+
+        let f = async move {
+            match db {
+                Some(db) => db.get_pending_page(msg.after, msg.limit).await,
+                None => Err(Error::Config(String::from(
+                    "Database has not been configured",
+                ))),
+            }
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ListHistory> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<AlertAcknowledged>>>;
+
+    fn handle(&mut self, msg: ListHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+
+        let f = async move {
+            match db {
+                Some(db) => db.get_history_page(msg.after, msg.limit).await,
+                None => Err(Error::Config(String::from(
+                    "Database has not been configured",
+                ))),
+            }
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ListUserStats> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<UserStats>>>;
+
+    fn handle(&mut self, msg: ListUserStats, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+
+        let f = async move {
+            match db {
+                Some(db) => db
+                    .user_stats(msg.route.as_deref(), msg.since)
+                    .await
+                    .map(leaderboard),
+                None => Err(Error::Config(String::from(
+                    "Database has not been configured",
+                ))),
+            }
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Processor {
+    fn require_db(&self) -> Result<Arc<Database>> {
+        self.db
+            .clone()
+            .ok_or_else(|| Error::Config(String::from("Database has not been configured")))
+    }
+}
+
+impl Handler<ListRoutes> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<RouteConfig>>>;
+
+    fn handle(&mut self, _msg: ListRoutes, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+        let configured = self.escalation.configured_routes.clone();
+
+        let f = async move {
+            let stored = db?.get_routes().await?;
+            Ok(layer_routes(&configured, stored))
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<PutRoute> for Processor {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, msg: PutRoute, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+        let configured = self.escalation.configured_routes.clone();
+        let severities = self.escalation.severities.clone();
+        let should_escalate = self.escalation.enabled;
+
+        let f = async move {
+            let db = db?;
+            let route = msg.0;
+
+            // Validate the routes as they would be applied on the next start.
+            let mut stored = db.get_routes().await?;
+            stored.retain(|r| r.name != route.name);
+            stored.push(route.clone());
+            validate_routes(
+                &layer_routes(&configured, stored),
+                &severities,
+                should_escalate,
+            )?;
+
+            db.upsert_route(&route).await?;
+            info!("Stored route '{}' via the admin API", route.name);
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<DeleteRoute> for Processor {
+    type Result = ResponseActFuture<Self, Result<bool>>;
+
+    fn handle(&mut self, msg: DeleteRoute, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+        let configured = self.escalation.configured_routes.clone();
+        let severities = self.escalation.severities.clone();
+        let should_escalate = self.escalation.enabled;
+        let references = self.route_references.clone();
+
+        let f = async move {
+            let db = db?;
+
+            // The configured route of the same name may conflict with other
+            // stored routes.
+            let mut stored = db.get_routes().await?;
+            stored.retain(|r| r.name != msg.0);
+            let routes = layer_routes(&configured, stored);
+            validate_routes(&routes, &severities, should_escalate)?;
+
+            if references.contains(&msg.0) && !routes.iter().any(|r| r.name == msg.0) {
+                return Err(Error::Config(format!(
+                    "Route '{}' is referenced by the config",
+                    msg.0
+                )));
+            }
+
+            let removed = db.remove_route(&msg.0).await?;
+            if removed {
+                info!("Removed stored route '{}' via the admin API", msg.0);
+            }
+
+            Ok(removed)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+fn hash_api_key(key: &str) -> String {
+    openssl::sha::sha256(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Handler<ListApiKeys> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<ApiKeyInfo>>>;
+
+    fn handle(&mut self, _msg: ListApiKeys, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+
+        let f = async move { db?.get_api_keys().await };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<CreateApiKey> for Processor {
+    type Result = ResponseActFuture<Self, Result<String>>;
+
+    fn handle(&mut self, msg: CreateApiKey, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+
+        let f = async move {
+            let db = db?;
+            let user = msg.0;
+            if UserId::try_from(user.as_str()).is_err() {
+                return Err(Error::Config(format!("Invalid Matrix user ID: {}", user)));
+            }
+
+            let mut bytes = [0; API_KEY_SIZE];
+            openssl::rand::rand_bytes(&mut bytes)
+                .map_err(|err| Error::Internal(err.to_string()))?;
+            let key: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+            db.insert_api_key(&ApiKey {
+                user: user.clone(),
+                key_hash: hash_api_key(&key),
+                created_at: unix_time(),
+            })
+            .await?;
+            info!("Created API key for {} via the admin API", user);
+
+            Ok(key)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<DeleteApiKeys> for Processor {
+    type Result = ResponseActFuture<Self, Result<bool>>;
+
+    fn handle(&mut self, msg: DeleteApiKeys, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+
+        let f = async move {
+            let removed = db?.remove_api_keys(&msg.0).await?;
+            if removed {
+                info!("Revoked API keys of {} via the admin API", msg.0);
+            }
+
+            Ok(removed)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ResolveApiKey> for Processor {
+    type Result = ResponseActFuture<Self, Result<Option<String>>>;
+
+    fn handle(&mut self, msg: ResolveApiKey, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+
+        let f = async move { db?.get_api_key_user(&hash_api_key(&msg.0)).await };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ListOverrides> for Processor {
+    type Result = ResponseActFuture<Self, Result<Vec<PolicyOverride>>>;
+
+    fn handle(&mut self, _msg: ListOverrides, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+
+        let f = async move { db?.get_overrides().await };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<PutOverride> for Processor {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, msg: PutOverride, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+        let validated = msg.0.validate(&self.escalation.rooms, unix_time());
+
+        let f = async move {
+            let db = db?;
+            validated?;
+
+            let policy = msg.0;
+            db.upsert_override(&policy).await?;
+            info!(
+                "Stored override '{}' of route '{}' via the admin API",
+                policy.name, policy.route
+            );
+
+            Ok(())
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<ListAdapters> for Processor {
+    type Result = MessageResult<ListAdapters>;
+
+    fn handle(&mut self, _msg: ListAdapters, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.adapters.states())
+    }
+}
+
+impl Handler<SetAdapterEnabled> for Processor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: SetAdapterEnabled, _ctx: &mut Self::Context) -> Self::Result {
+        match self.adapters.set_enabled(&msg.name, msg.enabled) {
+            Some(name) => {
+                info!(
+                    "{} adapter {} via the admin API",
+                    if msg.enabled { "Enabled" } else { "Disabled" },
+                    name
+                );
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Handler<DeleteOverride> for Processor {
+    type Result = ResponseActFuture<Self, Result<bool>>;
+
+    fn handle(&mut self, msg: DeleteOverride, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.require_db();
+
+        let f = async move {
+            let removed = db?.remove_override(&msg.0).await?;
+            if removed {
+                info!("Removed override '{}' via the admin API", msg.0);
+            }
+
+            Ok(removed)
+        };
+
+        Box::pin(f.into_actor(self))
+    }
+}
+
+impl Handler<Promote> for Processor {
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    fn handle(&mut self, _msg: Promote, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.standby || self.promoting {
+            return Box::pin(async { Ok(()) }.into_actor(self));
+        }
+
+        warn!("Promoting standby instance");
+        self.promoting = true;
+
+        let f = async move { MatrixClient::from_registry().send(StartSync).await? };
+
+        // Only leave standby once Matrix is synced, the instance would
+        // otherwise escalate without being able to receive acks.
+        Box::pin(f.into_actor(self).map(|res, proc, ctx| {
+            proc.promoting = false;
+
+            match res {
+                Ok(()) => {
+                    proc.standby = false;
+                    proc.start_escalations(ctx);
+                    proc.start_archiving(ctx);
+                    proc.restore_mute(ctx);
+                    proc.restore_deploy_windows(ctx);
+                    Ok(())
+                }
+                Err(err) => {
+                    error!("Failed to start syncing, staying in standby: {:?}", err);
+                    Err(err)
+                }
+            }
+        }))
+    }
+}
+
+impl Handler<SpaceRooms> for Processor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SpaceRooms, _ctx: &mut Self::Context) -> Self::Result {
+        self.escalation.rooms.insert(msg.route, msg.rooms);
+    }
+}
+
+/// Handler for acks in Matrix or an adapter, informs the other adapters.
+impl Handler<RemoteAck> for Processor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: RemoteAck, _ctx: &mut Self::Context) -> Self::Result {
+        self.adapters.forward(
+            &msg.route,
+            msg.escalation_idx,
+            Notification::Acknowledged {
+                id: msg.id,
+                user: msg.user,
+                via: msg.via.clone(),
+            },
+            Some(&msg.via),
+        );
+
+        Ok(())
+    }
+}
+
+impl Handler<IsStandby> for Processor {
+    type Result = bool;
+
+    fn handle(&mut self, _msg: IsStandby, _ctx: &mut Self::Context) -> Self::Result {
+        self.standby
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UserConfirmation {
+    PendingAlerts(Vec<AlertContext>),
+    NoisyAlerts(Vec<NoiseStats>),
+    // Since, stats.
+    UserStats(u64, Vec<UserStats>),
+    AlertDetails(Box<AlertContext>),
+    Simulation(Box<Simulation>),
+    SelfTest(Box<SelfTestReport>),
+    // Usage of a command with invalid arguments.
+    Usage(String),
+    // Timestamp of when the reminder is due.
+    ReminderScheduled(AlertId, u64),
+    // Timestamp of when the mute expires.
+    Muted(u64),
+    OverrideStored(Box<PolicyOverride>),
+    // Why the override is invalid.
+    OverrideRejected(String),
+    // The name of the ended override.
+    OverrideEnded(String),
+    OverrideNotFound,
+    Overrides(Vec<PolicyOverride>),
+    // Name of the adapter, whether it is enabled now.
+    AdapterToggled(String, bool),
+    // Names of the configured adapters.
+    AdapterNotFound(Vec<String>),
+    NotAuthorized,
+    // Commands sent to an observer room.
+    ReadOnly,
+    AlertOutOfScope,
+    AlertAcknowledged(AlertId),
+    AlertResolved(AlertId),
+    // The new owner and the handed off alerts.
+    HandedOff(String, Vec<AlertContext>),
+    AlertNotFound,
+    // Pending alerts matching an ack by name.
+    AmbiguousAlerts(Vec<AlertContext>),
+    Watching(Watch),
+    // The number of removed watches.
+    Unwatched(u64),
+    Watches(Vec<Watch>),
+    UnsentAcks(Vec<UnsentAck>),
+    Help(Help),
+    InternalError,
+}
+
+impl UserConfirmation {
+    /// Whether the confirmation concerns everyone in the room, e.g. as it
+    /// changed an alert, as opposed to only the user who issued the command.
+    pub fn is_public(&self) -> bool {
+        matches!(
+            self,
+            UserConfirmation::AlertAcknowledged(_)
+                | UserConfirmation::AlertResolved(_)
+                | UserConfirmation::HandedOff(..)
+                | UserConfirmation::Muted(_)
+                | UserConfirmation::OverrideStored(_)
+                | UserConfirmation::OverrideEnded(_)
+                | UserConfirmation::AdapterToggled(..)
+        )
+    }
+}
+
+impl fmt::Display for UserConfirmation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let content = match self {
+            UserConfirmation::PendingAlerts(alerts) => {
+                if alerts.is_empty() {
+                    return write!(f, "No pending alerts!");
+                }
+
+                let mut content = String::from("Pending alerts:\n");
+                for alert in alerts {
+                    content.push_str(&alert.to_string());
+                }
+
+                content
+            }
+            UserConfirmation::NoisyAlerts(alerts) => {
+                if alerts.is_empty() {
+                    return write!(f, "No alerts in the last week!");
+                }
+
+                let mut content = String::from(
+                    "Noisiest alerts of the last week (unacknowledged ones count twice):\n",
+                );
+                for stats in alerts {
+                    content.push_str(&format!(
+                        "- {}: {} ({} acknowledged, {} of them resolved, {} pending)\n",
+                        stats.alert_name,
+                        stats.score(),
+                        stats.acknowledged,
+                        stats.resolved,
+                        stats.pending
+                    ));
+                }
+
+                content.pop();
+                content
+            }
+            UserConfirmation::UserStats(since, stats) => {
+                if stats.is_empty() {
+                    return write!(
+                        f,
+                        "No alerts have been acknowledged since {}.",
+                        format_time(*since)
+                    );
+                }
+
+                let mut content = format!("Acknowledgements since {}:\n", format_time(*since));
+                for stats in stats {
+                    let median = stats
+                        .median_time_to_ack
+                        .map(|secs| format!(", median response time {}", format_duration(secs)))
+                        .unwrap_or_default();
+
+                    content.push_str(&format!(
+                        "- {}: {} acknowledged{}\n",
+                        stats.user, stats.acknowledged, median
+                    ));
+                }
+
+                content.pop();
+                content
+            }
+            UserConfirmation::AlertDetails(alert) => {
+                let mut content = alert.render(true);
+                content.push_str("  Timeline:\n");
+                for event in &alert.timeline {
+                    content.push_str(&format!("  - {}\n", event));
+                }
+
+                content
+            }
+            UserConfirmation::Simulation(simulation) => simulation.to_string(),
+            UserConfirmation::SelfTest(report) => report.to_string(),
+            UserConfirmation::Usage(usage) => format!("Usage: {}", usage),
+            UserConfirmation::ReminderScheduled(id, due) => {
+                format!("You will be reminded about alert {} at {}.", id, format_time(*due))
+            }
+            UserConfirmation::Muted(until) => {
+                format!("All notifications are muted until {}.", format_time(*until))
+            }
+            UserConfirmation::OverrideStored(policy) => format!("Stored override {}.", policy),
+            UserConfirmation::OverrideRejected(problems) => problems.clone(),
+            UserConfirmation::OverrideEnded(name) => {
+                format!("Override '{}' has ended.", name)
+            }
+            UserConfirmation::OverrideNotFound => {
+                String::from("No override of this route has been found!")
+            }
+            UserConfirmation::Overrides(overrides) => {
+                if overrides.is_empty() {
+                    return write!(f, "No overrides of this route!");
+                }
+
+                let mut content = String::from("Overrides:\n");
+                for policy in overrides {
+                    content.push_str(&format!("- {}\n", policy));
+                }
+
+                content
+            }
+            UserConfirmation::AdapterToggled(name, true) => {
+                format!("Adapter {} has been enabled.", name)
+            }
+            UserConfirmation::AdapterToggled(name, false) => format!(
+                "Adapter {} has been disabled until it is enabled again.",
+                name
+            ),
+            UserConfirmation::AdapterNotFound(names) if names.is_empty() => {
+                String::from("No adapters are configured!")
+            }
+            UserConfirmation::AdapterNotFound(names) => format!(
+                "No adapter of that name is configured, adapters are: {}",
+                names.join(", ")
+            ),
+            UserConfirmation::NotAuthorized => {
+                String::from("You are not authorized to run this command!")
+            }
+            UserConfirmation::ReadOnly => String::from(
+                "This room only observes alerts, please run commands in the escalation rooms.",
+            ),
+            UserConfirmation::AlertOutOfScope => {
+                String::from("The alert has already reached the next escalation level. It cannot be acknowledged!")
+            }
+            UserConfirmation::AlertAcknowledged(id) => {
+                format!("Alert {} has been acknowledged.", id)
+            }
+            UserConfirmation::AlertResolved(id) => {
+                format!("Alert {} has been resolved.", id)
+            }
+            UserConfirmation::HandedOff(to, alerts) => {
+                if alerts.is_empty() {
+                    return write!(f, "No acknowledged alerts to hand off!");
+                }
+
+                let mut content = format!("Handed off {} alert(s) to {}:\n", alerts.len(), to);
+                for alert in alerts {
+                    content.push_str(&alert.to_string());
+                }
+
+                content
+            }
+            UserConfirmation::AlertNotFound => {
+                String::from("The alert Id has not been found!")
+            }
+            UserConfirmation::AmbiguousAlerts(alerts) => {
+                let mut content =
+                    String::from("Multiple pending alerts match, please acknowledge by ID:\n");
+                for alert in alerts {
+                    content.push_str(&format!(
+                        "- ID: {}, Name: {}\n",
+                        alert.id, alert.alert.labels.alert_name
+                    ));
+                }
+
+                content
+            }
+            UserConfirmation::Watching(watch) => {
+                format!("You will be mentioned about new alerts with {}", watch)
+            }
+            UserConfirmation::Unwatched(0) => String::from("No matching watches found!"),
+            UserConfirmation::Unwatched(count) => format!("Removed {} watch(es)", count),
+            UserConfirmation::Watches(watches) => {
+                if watches.is_empty() {
+                    return write!(f, "You are not watching any alerts!");
+                }
+
+                let mut content = String::from("Your watches:\n");
+                for watch in watches {
+                    content.push_str(&format!("- {}\n", watch));
+                }
+
+                content
             }
-            UserConfirmation::Help => {
-                String::from("ack <ID> - Acknowledge an alert by id\npending - Show pending alerts\nhelp - Show this help message")
+            UserConfirmation::UnsentAcks(acks) => {
+                if acks.is_empty() {
+                    return write!(f, "All acks have been propagated!");
+                }
+
+                let mut content =
+                    String::from("Acks which have not been propagated yet, retried until sent:\n");
+                for ack in acks {
+                    content.push_str(&format!(
+                        "- Alert {} to {} (level {}), acknowledged by {} via {}, last attempt {}\n",
+                        ack.id,
+                        ack.adapter,
+                        ack.level,
+                        ack.user,
+                        ack.via,
+                        format_time(ack.attempted_at)
+                    ));
+                }
+
+                content
             }
+            UserConfirmation::Help(help) => help.to_string(),
             UserConfirmation::InternalError => {
                 String::from("There was an internal error. Please contact the admin.")
             }
+        };
+
+        write!(f, "{}", content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{alert_context, escalation_settings};
+
+    #[test]
+    fn finds_watchers() {
+        let watch = |user: &str, labels: &[(&str, &str)]| Watch {
+            user: user.to_string(),
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+
+        let mut infra = alert_context(1, "default");
+        infra.alert.labels.team = Some(String::from("infra"));
+        let other = alert_context(2, "default");
+
+        let watches = vec![
+            watch(
+                "@a:localhost",
+                &[("severity", "Critical"), ("team", "infra")],
+            ),
+            watch("@a:localhost", &[("alertname", "Alert1")]),
+            watch("@b:localhost", &[("team", "payments")]),
+        ];
+
+        let watchers = watchers(&watches, &[infra, other]);
+        assert_eq!(
+            watchers.get(&AlertId::from(1)),
+            Some(&vec![String::from("@a:localhost")])
+        );
+        assert!(!watchers.contains_key(&AlertId::from(2)));
+    }
+
+    #[test]
+    fn records_latency() {
+        let mut alert = alert_context(1, "default");
+        alert.record(TimelineKind::Acknowledged, 0);
+        assert_eq!(alert.latency, None);
+
+        alert.latency = Some(Latency::new(unix_time_ms() - 1000));
+        // Acks before any notification are not measured.
+        alert.record(TimelineKind::Acknowledged, 0);
+        assert_eq!(alert.latency.as_ref().unwrap().acked_at, None);
+
+        alert.record_notified(MATRIX_CHANNEL);
+        let notified_at = alert.latency.as_ref().unwrap().notified_at[MATRIX_CHANNEL];
+        assert!(notified_at >= alert.latency.as_ref().unwrap().received_at + 1000);
+
+        alert.record_notified(MATRIX_CHANNEL);
+        alert.record(TimelineKind::Acknowledged, 0);
+
+        let latency = alert.latency.unwrap();
+        assert_eq!(latency.notified_at[MATRIX_CHANNEL], notified_at);
+        assert!(latency.acked_at.unwrap() >= notified_at);
+    }
+
+    #[test]
+    fn hashes_api_keys() {
+        assert_eq!(
+            hash_api_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn selects_ack_targets() {
+        let mut first = alert_context(1, "default");
+        first.alert.labels.alert_name = String::from("NodeDown");
+        first.last_notified = 10;
+
+        let mut second = alert_context(2, "default");
+        second.alert.labels.alert_name = String::from("DiskFull");
+        second.last_notified = 20;
+
+        let mut escalated = alert_context(3, "default");
+        escalated.alert.labels.alert_name = String::from("DiskSlow");
+        escalated.escalation_idx = 2;
+        escalated.last_notified = 30;
+
+        let other = alert_context(4, "other");
+        let pending = vec![first, second, escalated, other];
+
+        let select = |target: AckTarget, idx| target.select(pending.clone(), "default", idx, 1);
+
+        assert_eq!(select(AckTarget::Last, 0), Ok(AlertId::from(2)));
+        // Alerts beyond the final room are announced in the final room.
+        assert_eq!(select(AckTarget::Last, 1), Ok(AlertId::from(3)));
+        assert_eq!(
+            select(AckTarget::Name(String::from("nodedown")), 0),
+            Ok(AlertId::from(1))
+        );
+        assert_eq!(
+            select(AckTarget::Name(String::from("alert4")), 0),
+            Err(UserConfirmation::AlertNotFound)
+        );
+        assert!(matches!(
+            select(AckTarget::Name(String::from("Disk")), 0),
+            Err(UserConfirmation::AmbiguousAlerts(alerts)) if alerts.len() == 2
+        ));
+    }
+
+    #[test]
+    fn generates_help() {
+        let help = Help::Commands { is_admin: false }.to_string();
+        assert!(help.contains("ack <ID|last|NAME> - Acknowledge an alert by id"));
+        assert!(!help.contains("mute"));
+
+        let help = Help::Commands { is_admin: true }.to_string();
+        assert!(help.contains("mute <DURATION> - Mute all notifications (admins only)"));
+
+        let help = Help::Command {
+            info: command_info("ACKNOWLEDGE").unwrap(),
+            ack_scope: AckScope::Lenient,
         }
+        .to_string();
+        assert!(help.contains("Examples: `ack 5`, `ack last`"));
+        assert!(help.contains("acknowledged by any room of the route"));
+
+        assert!(command_info("unknown").is_none());
+    }
+
+    #[test]
+    fn validates_overrides() {
+        let rooms: HashMap<String, Vec<String>> = vec![
+            (String::from("payments"), vec![String::from("!a:localhost")]),
+            (
+                String::from("oncall"),
+                vec![String::from("!b:localhost"), String::from("!c:localhost")],
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let policy = PolicyOverride {
+            name: String::from("freeze"),
+            route: String::from("payments"),
+            target_route: Some(String::from("oncall")),
+            entry_level: Some(1),
+            starts_at: 100,
+            ends_at: 200,
+            reason: None,
+        };
+        assert!(policy.validate(&rooms, 150).is_ok());
+        assert_eq!(policy.target(), "oncall");
+
+        // Levels refer to the target route.
+        let too_deep = PolicyOverride {
+            target_route: None,
+            ..policy.clone()
+        };
+        assert!(too_deep.validate(&rooms, 150).is_err());
+
+        let unknown = PolicyOverride {
+            target_route: Some(String::from("unknown")),
+            ..policy.clone()
+        };
+        assert!(unknown.validate(&rooms, 150).is_err());
+
+        assert!(policy.validate(&rooms, 200).is_err());
+
+        let inverted = PolicyOverride {
+            ends_at: 100,
+            ..policy
+        };
+        assert!(inverted.validate(&rooms, 50).is_err());
+    }
+
+    #[test]
+    fn validates_deploy_windows() {
+        let rooms: HashMap<String, Vec<String>> =
+            [(String::from("team-a"), vec![String::from("!a:localhost")])].into();
+
+        let window = DeployWindow {
+            name: String::from("api v1.4.2"),
+            labels: [(String::from("job"), String::from("API"))].into(),
+            routes: vec![String::from("team-a")],
+            duration: 600,
+            started_by: String::from("ci"),
+        };
+        assert!(window.validate(&rooms).is_ok());
+        assert_eq!(window.describe_labels(), "job=API");
+
+        let active = ActiveDeployWindow::new(window.clone(), 100);
+        assert_eq!(active.ends_at, 700);
+
+        let mut alert = alert_context(1, "team-a").alert;
+        alert.labels.job = Some(String::from("api"));
+        assert!(active.silences("team-a", &alert));
+        assert!(!active.silences("default", &alert));
+        alert.labels.job = Some(String::from("db"));
+        assert!(!active.silences("team-a", &alert));
+
+        let invalid = DeployWindow {
+            labels: [(String::from("region"), String::from("eu"))].into(),
+            routes: vec![String::from("default")],
+            duration: MAX_DEPLOY_WINDOW + 1,
+            ..window.clone()
+        };
+        assert_eq!(
+            invalid.validate(&rooms).unwrap_err().to_string(),
+            Error::Config(String::from(
+                "Deploy window 'api v1.4.2': Unknown label 'region', Unknown route 'default', \
+                 The duration must be between 1 and 7200 seconds"
+            ))
+            .to_string()
+        );
+
+        let unlabeled = DeployWindow {
+            labels: BTreeMap::new(),
+            ..window
+        };
+        assert!(unlabeled.validate(&rooms).is_err());
+    }
+
+    #[test]
+    fn ack_scopes() {
+        assert!(AckScope::Strict.allows(1, 1));
+        assert!(AckScope::Strict.allows(1, 2));
+        assert!(!AckScope::Strict.allows(1, 0));
+
+        assert!(AckScope::Lenient.allows(2, 0));
+
+        assert!(AckScope::FirstRoom.allows(2, 0));
+        assert!(AckScope::FirstRoom.allows(1, 2));
+        assert!(!AckScope::FirstRoom.allows(2, 1));
+    }
+
+    #[test]
+    fn selects_alerts_of_batch_acks() {
+        let mut pending = vec![alert_context(3, "team-a"), alert_context(1, "default")];
+        pending[1].alert.labels.team = Some(String::from("Infra"));
+
+        let batch = |ids: Vec<u64>, labels: &[(&str, &str)]| AckAlerts {
+            ids: ids.into_iter().map(AlertId::from).collect(),
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            user: None,
+        };
+        let ids = |alerts: Vec<AlertContext>| -> Vec<AlertId> {
+            alerts.iter().map(|alert| alert.id).collect()
+        };
+
+        let (selected, missing) = batch(vec![3, 2], &[]).select(pending.clone()).unwrap();
+        assert_eq!(ids(selected), vec![AlertId::from(3)]);
+        assert_eq!(missing, vec![AlertId::from(2)]);
+
+        let (selected, _) = batch(vec![], &[("severity", "CRITICAL")])
+            .select(pending.clone())
+            .unwrap();
+        assert_eq!(ids(selected), vec![AlertId::from(1), AlertId::from(3)]);
+
+        let (selected, _) = batch(vec![], &[("severity", "critical"), ("team", "infra")])
+            .select(pending.clone())
+            .unwrap();
+        assert_eq!(ids(selected), vec![AlertId::from(1)]);
+
+        // Neither, both and unknown labels are rejected.
+        assert!(batch(vec![], &[]).select(pending.clone()).is_err());
+        assert!(batch(vec![1], &[("team", "infra")])
+            .select(pending.clone())
+            .is_err());
+        assert!(batch(vec![], &[("region", "eu")]).select(pending).is_err());
+    }
+
+    #[test]
+    fn ranks_noisy_alerts() {
+        let stats = |alert_name: &str, acknowledged, resolved, pending| NoiseStats {
+            alert_name: alert_name.to_string(),
+            acknowledged,
+            resolved,
+            pending,
+        };
+
+        let ranked = noisiest(vec![
+            stats("DiskFull", 4, 3, 0),
+            stats("NodeDown", 1, 1, 2),
+            stats("HighLatency", 0, 0, 3),
+        ]);
+
+        assert_eq!(
+            ranked
+                .iter()
+                .map(|stats| stats.alert_name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["HighLatency", "NodeDown", "DiskFull"]
+        );
+        assert_eq!(
+            UserConfirmation::NoisyAlerts(ranked[..1].to_vec()).to_string(),
+            "Noisiest alerts of the last week (unacknowledged ones count twice):\n- HighLatency: 6 (0 acknowledged, 0 of them resolved, 3 pending)"
+        );
+        assert_eq!(
+            UserConfirmation::NoisyAlerts(ranked[1..2].to_vec()).to_string(),
+            "Noisiest alerts of the last week (unacknowledged ones count twice):\n- NodeDown: 5 (1 acknowledged, 1 of them resolved, 2 pending)"
+        );
+    }
+
+    #[test]
+    fn ranks_users_by_acknowledgements() {
+        let ranked = leaderboard(vec![
+            UserStats::new(String::from("@bob:matrix.org"), 2, vec![90, 30]),
+            UserStats::new(String::from("@alice:matrix.org"), 3, vec![4000, 60, 300]),
+            UserStats::new(String::from("@carol:matrix.org"), 2, vec![]),
+        ]);
+
+        assert_eq!(
+            ranked
+                .iter()
+                .map(|stats| (stats.user.as_str(), stats.median_time_to_ack))
+                .collect::<Vec<_>>(),
+            vec![
+                ("@alice:matrix.org", Some(300)),
+                ("@bob:matrix.org", Some(60)),
+                ("@carol:matrix.org", None),
+            ]
+        );
+        assert_eq!(
+            UserConfirmation::UserStats(0, ranked).to_string(),
+            "Acknowledgements since 1970-01-01 00:00:00 UTC:\n\
+             - @alice:matrix.org: 3 acknowledged, median response time 5m 0s\n\
+             - @bob:matrix.org: 2 acknowledged, median response time 1m 0s\n\
+             - @carol:matrix.org: 2 acknowledged"
+        );
+        assert_eq!(format_duration(3900), "1h 5m");
+        assert_eq!(format_duration(45), "45s");
+    }
+
+    #[test]
+    fn slo_burns_escalate_by_type() {
+        let slo = SloConfig {
+            fast_window: Some(60),
+            slow_template: Some(String::from("{alertname} has {budget_remaining} left")),
+            ..Default::default()
+        };
+
+        let mut alert = alert_context(1, "slo");
+        alert.alert.annotations.budget_remaining = Some(String::from("42%"));
+        alert.alert.annotations.burn_rate = Some(String::from("14.4"));
+        alert.slo = Some(slo.burn(&alert.alert, &Default::default()));
+        assert_eq!(
+            alert.slo,
+            Some(SloBurn {
+                kind: BurnKind::Fast,
+                summary: String::from(
+                    "🔥 Fast burn of Alert1: 42% of the error budget left, burning at 14.4x"
+                ),
+            })
+        );
+        assert!(alert.to_string().contains(
+            "  SLO: 🔥 Fast burn of Alert1: 42% of the error budget left, burning at 14.4x\n"
+        ));
+
+        let mut slow = alert_context(2, "slo");
+        slow.alert.labels.severity = String::from("warning");
+        slow.slo = Some(slo.burn(&slow.alert, &Default::default()));
+        assert_eq!(
+            slow.slo.as_ref().map(|slo| slo.summary.as_str()),
+            Some("Alert2 has unknown left")
+        );
+
+        let settings = EscalationSettings {
+            rooms: [(
+                String::from("slo"),
+                vec![String::from("!a:localhost"), String::from("!b:localhost")],
+            )]
+            .into(),
+            slos: [(String::from("slo"), slo)].into(),
+            ..escalation_settings()
+        };
+
+        // Slow burns keep the escalation window.
+        assert_eq!(settings.slo_window(&alert), Some(60));
+        assert_eq!(settings.slo_window(&slow), None);
+        assert_eq!(settings.min_window(), 60);
+        assert_eq!(
+            settings
+                .simulate("slo", "critical", "Alert1", false, None, None)
+                .map(|simulation| simulation.steps[1].after),
+            Some(60)
+        );
+    }
+
+    #[test]
+    fn adaptive_window_shrinks_with_escalations() {
+        let settings = EscalationSettings {
+            adaptive: Some(AdaptiveEscalation {
+                min_window: 120,
+                lookback: default_lookback(),
+            }),
+            ..escalation_settings()
+        };
+
+        let stats = |acknowledged, escalated| EscalationStats {
+            acknowledged,
+            escalated,
+        };
+
+        assert_eq!(settings.adaptive_window(stats(4, 0)), 600);
+        assert_eq!(settings.adaptive_window(stats(4, 2)), 360);
+        assert_eq!(settings.adaptive_window(stats(4, 4)), 120);
+        // Not enough history.
+        assert_eq!(settings.adaptive_window(stats(2, 2)), 600);
+
+        let settings = EscalationSettings {
+            adaptive: None,
+            ..settings
+        };
+        assert_eq!(settings.adaptive_window(stats(4, 4)), 600);
+    }
+
+    #[test]
+    fn suppresses_duplicates_by_policy() {
+        let mut settings = escalation_settings();
+        assert!(!settings.is_duplicate("team-a", "team-b"));
+
+        settings.duplicates = DuplicatePolicy::FirstMatch;
+        assert!(settings.is_duplicate("team-a", "team-b"));
+        assert!(!settings.is_duplicate("team-a", "team-a"));
+
+        settings.duplicates = DuplicatePolicy::PriorityRoute;
+        settings.priorities = vec![(String::from("team-a"), 10)].into_iter().collect();
+        assert!(settings.is_duplicate("team-b", "team-a"));
+        assert!(!settings.is_duplicate("team-a", "team-b"));
+        // Routes without priority default to zero.
+        assert!(settings.is_duplicate("team-b", "team-c"));
+    }
+
+    #[test]
+    fn matches_refires_after_raising_the_severity() {
+        let settings = escalation_settings();
+        let received = alert_context(1, DEFAULT_ROUTE).alert;
+
+        // As done by `raise_severities`.
+        let mut pending = vec![alert_context(1, DEFAULT_ROUTE)];
+        pending[0].alert.labels.severity = String::from("emergency");
+
+        let (inserted, refired) = settings
+            .match_pending(&mut pending, DEFAULT_ROUTE, 0, &received)
+            .unwrap();
+        assert!(inserted.duplicate);
+        assert_eq!(inserted.id, AlertId::from(1));
+        assert_eq!(inserted.fingerprint, received.fingerprint());
+        assert!(refired.is_none());
+
+        let mut changed = received.clone();
+        changed.annotations.value = Some(String::from("97"));
+        let (_, refired) = settings
+            .match_pending(&mut pending, DEFAULT_ROUTE, 0, &changed)
+            .unwrap();
+        assert_eq!(refired.unwrap().changes.len(), 1);
+        assert_eq!(pending[0].alert.annotations, changed.annotations);
+        assert_eq!(pending[0].alert.labels.severity, "emergency");
+
+        // Other routes only match as duplicates, see `duplicate_policy`.
+        assert!(settings
+            .match_pending(&mut pending, "team-a", 0, &received)
+            .is_none());
+    }
+
+    #[test]
+    fn simulation_follows_entry_level_and_window() {
+        let mut settings = EscalationSettings {
+            entry_levels: vec![(
+                DEFAULT_ROUTE.to_string(),
+                vec![(String::from("critical"), 1)].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            rooms: vec![(
+                DEFAULT_ROUTE.to_string(),
+                vec![
+                    String::from("!first:localhost"),
+                    String::from("!second:localhost"),
+                    String::from("!third:localhost"),
+                ],
+            )]
+            .into_iter()
+            .collect(),
+            ..escalation_settings()
+        };
+
+        let simulation = settings
+            .simulate(DEFAULT_ROUTE, "critical", "NodeDown", false, None, None)
+            .unwrap();
+        assert_eq!(
+            simulation
+                .steps
+                .iter()
+                .map(|step| (step.escalation_idx, step.after))
+                .collect::<Vec<_>>(),
+            vec![(1, 0), (2, 600)]
+        );
+        assert_eq!(
+            simulation.to_string(),
+            "Simulated alert 'NodeDown' (critical) on route 'default':\n  - after 0s: level 1, !second:localhost (matrix)\n  - after 600s: level 2, !third:localhost (matrix)\n"
+        );
+
+        assert_eq!(
+            settings
+                .simulate(DEFAULT_ROUTE, "warning", "NodeDown", false, None, None)
+                .unwrap()
+                .steps
+                .len(),
+            3
+        );
+        assert!(settings
+            .simulate("unknown", "warning", "NodeDown", false, None, None)
+            .is_none());
+
+        // Alerts of this name had to be escalated before.
+        settings.adaptive = Some(AdaptiveEscalation {
+            min_window: 120,
+            lookback: default_lookback(),
+        });
+        let stats = EscalationStats {
+            acknowledged: 4,
+            escalated: 4,
+        };
+        let simulation = settings
+            .simulate(
+                DEFAULT_ROUTE,
+                "critical",
+                "NodeDown",
+                false,
+                Some(stats),
+                None,
+            )
+            .unwrap();
+        assert!(simulation.adaptive);
+        assert_eq!(simulation.steps[1].after, 120);
+        assert!(simulation.to_string().contains("shortened to 120s"));
+        settings.adaptive = None;
+
+        // The active override of the route applies.
+        let policy = PolicyOverride {
+            name: String::from("freeze"),
+            route: DEFAULT_ROUTE.to_string(),
+            target_route: None,
+            entry_level: Some(2),
+            starts_at: 0,
+            ends_at: u64::MAX,
+            reason: None,
+        };
+        let simulation = settings
+            .simulate(
+                DEFAULT_ROUTE,
+                "warning",
+                "NodeDown",
+                false,
+                None,
+                Some(&policy),
+            )
+            .unwrap();
+        assert_eq!(simulation.steps.len(), 1);
+        assert_eq!(simulation.steps[0].escalation_idx, 2);
+        assert!(simulation
+            .to_string()
+            .contains("Override 'freeze' applies."));
+
+        settings.enabled = false;
+        let simulation = settings
+            .simulate(DEFAULT_ROUTE, "critical", "NodeDown", true, None, None)
+            .unwrap();
+        assert_eq!(simulation.steps.len(), 1);
+        assert!(simulation.to_string().contains("muted"));
+
+        // Warnings at 80% of the window, except on the last level.
+        let mut alert = alert_context(1, DEFAULT_ROUTE);
+        assert!(!settings.should_warn(&alert, 500, 600));
+        settings.warning = Some(0.8);
+        assert!(!settings.should_warn(&alert, 479, 600));
+        assert!(settings.should_warn(&alert, 480, 600));
+        alert.escalation_idx = 2;
+        assert!(!settings.should_warn(&alert, 500, 600));
+    }
+
+    #[test]
+    fn handoff_lists_alerts() {
+        let alert = alert_context(1, DEFAULT_ROUTE);
+
+        assert_eq!(
+            UserConfirmation::HandedOff(String::from("@bob:localhost"), vec![alert.clone()])
+                .to_string(),
+            format!("Handed off 1 alert(s) to @bob:localhost:\n{}", alert)
+        );
+        assert_eq!(
+            UserConfirmation::HandedOff(String::from("@bob:localhost"), vec![]).to_string(),
+            "No acknowledged alerts to hand off!"
+        );
+    }
+
+    #[test]
+    fn alert_details_render_timeline() {
+        let mut alert = alert_context(1, DEFAULT_ROUTE);
+        alert.timeline = vec![
+            TimelineEvent {
+                kind: TimelineKind::Notified,
+                escalation_idx: 0,
+                channel: MATRIX_CHANNEL.to_string(),
+                timestamp: 0,
+            },
+            TimelineEvent {
+                kind: TimelineKind::Escalated,
+                escalation_idx: 1,
+                channel: MATRIX_CHANNEL.to_string(),
+                timestamp: 3600,
+            },
+        ];
+
+        let content = UserConfirmation::AlertDetails(Box::new(alert.clone())).to_string();
+
+        assert!(content.starts_with(&alert.to_string()));
+        assert!(content.ends_with(
+            "  Timeline:\n  \
+             - 1970-01-01 00:00:00 UTC: Notified (level 0, matrix)\n  \
+             - 1970-01-01 01:00:00 UTC: Escalated (level 1, matrix)\n"
+        ));
+    }
+
+    #[test]
+    fn long_annotations_are_truncated_except_in_details() {
+        let mut alert = alert_context(1, DEFAULT_ROUTE);
+        let description = "x".repeat(10_000);
+        alert.alert.annotations.description = Some(description.clone());
+
+        let notification = alert.to_string();
+        assert!(!notification.contains(&description));
+        assert!(notification.contains("(truncated, see `details 1`)"));
+
+        let content = UserConfirmation::AlertDetails(Box::new(alert)).to_string();
+        assert!(content.contains(&description));
     }
 }
@@ -0,0 +1,276 @@
+use crate::chaos;
+use crate::error::PROMETHEUS_ADAPTER;
+use crate::http::HttpConfig;
+use crate::webhook::Alert;
+use crate::{unix_time, Error, Result};
+use std::fmt;
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_RANGE: u64 = 60 * 60; // one hour
+                                    // Number of data points of the trend.
+const TREND_POINTS: u64 = 20;
+// Notifications must not be delayed by a slow Prometheus.
+const QUERY_TIMEOUT: u64 = 5;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrometheusConfig {
+    // Falls back to the Prometheus which generated the alert, if its host is
+    // allowed.
+    url: Option<String>,
+    // Hosts of generator URLs which may be queried, e.g. `prometheus` or
+    // `prometheus:9090`. Anyone posting alerts controls these URLs, so none
+    // are queried by default.
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+    // Time range of the trend, in seconds.
+    range: Option<u64>,
+}
+
+/// The recent values of the expression of an alert.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trend {
+    pub current: String,
+    pub sparkline: String,
+}
+
+impl fmt::Display for Trend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (current: {})", self.sparkline, self.current)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryResponse {
+    data: QueryData,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryData {
+    result: Vec<Series>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Series {
+    // Pairs of timestamp and value.
+    values: Vec<(f64, String)>,
+}
+
+/// Queries Prometheus for the current values of alert expressions.
+pub struct Prometheus {
+    client: reqwest::Client,
+    url: Option<Url>,
+    allowed_hosts: Vec<String>,
+    range: u64,
+}
+
+impl Prometheus {
+    pub fn new(config: PrometheusConfig, http: &HttpConfig) -> Result<Self> {
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(QUERY_TIMEOUT));
+
+        Ok(Prometheus {
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(PROMETHEUS_ADAPTER, err))?,
+            url: config
+                .url
+                .as_deref()
+                .map(|url| {
+                    // Otherwise the last path segment is replaced when joining.
+                    let mut url = Url::parse(url)?;
+                    if !url.path().ends_with('/') {
+                        url.set_path(&format!("{}/", url.path()));
+                    }
+
+                    Ok::<_, Error>(url)
+                })
+                .transpose()?,
+            allowed_hosts: config.allowed_hosts,
+            range: config.range.unwrap_or(DEFAULT_RANGE),
+        })
+    }
+    /// Whether the host of the generator URL is allowed, with any port or the
+    /// given one.
+    fn allows(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return false,
+        };
+        let with_port = url
+            .port_or_known_default()
+            .map(|port| format!("{}:{}", host, port));
+
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| allowed == host || Some(allowed) == with_port.as_ref())
+    }
+    /// Returns the trend of the alert expression, if the alert carries a
+    /// generator URL.
+    pub async fn trend(&self, alert: &Alert) -> Result<Option<Trend>> {
+        chaos::inject(PROMETHEUS_ADAPTER).await?;
+
+        let (base, expr) = match alert.generator_url.as_deref().and_then(parse_generator_url) {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+
+        let base = match &self.url {
+            Some(url) => url.clone(),
+            None if self.allows(&base) => base,
+            None => {
+                debug!("Not querying the trend of {}, host is not allowed", base);
+                return Ok(None);
+            }
+        };
+        let query_url = base
+            .join("api/v1/query_range")
+            .map_err(|err| Error::adapter(PROMETHEUS_ADAPTER, err))?;
+
+        let end = unix_time();
+        let start = end.saturating_sub(self.range);
+        let step = (self.range / TREND_POINTS).max(1);
+
+        let resp: QueryResponse = self
+            .client
+            .get(query_url)
+            .query(&[
+                ("query", expr),
+                ("start", start.to_string()),
+                ("end", end.to_string()),
+                ("step", step.to_string()),
+            ])
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(PROMETHEUS_ADAPTER, err))?
+            .bytes()
+            .await
+            .map_err(|err| Error::adapter(PROMETHEUS_ADAPTER, err))
+            .and_then(|body| {
+                serde_json::from_slice(&body).map_err(|err| Error::adapter(PROMETHEUS_ADAPTER, err))
+            })?;
+
+        Ok(resp
+            .data
+            .result
+            .into_iter()
+            .next()
+            .and_then(|series| trend(&series.values)))
+    }
+}
+
+/// Extracts the base URL of Prometheus and the expression from the generator
+/// URL of an alert, e.g. `http://prometheus:9090/graph?g0.expr=up+%3D%3D+0`.
+fn parse_generator_url(url: &str) -> Option<(Url, String)> {
+    let mut url = Url::parse(url).ok()?;
+    let expr = url
+        .query_pairs()
+        .find(|(key, _)| key == "g0.expr")
+        .map(|(_, expr)| expr.into_owned())?;
+
+    // Keep path prefixes, e.g. `/prometheus/graph`.
+    let path = url.path().trim_end_matches("graph").to_string();
+    url.set_path(&path);
+    url.set_query(None);
+    url.set_fragment(None);
+
+    Some((url, expr))
+}
+
+fn trend(values: &[(f64, String)]) -> Option<Trend> {
+    let current = values.last()?.1.clone();
+    let parsed: Vec<f64> = values
+        .iter()
+        .filter_map(|(_, value)| value.parse().ok())
+        .filter(|value: &f64| value.is_finite())
+        .collect();
+
+    let min = parsed.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = parsed.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+    let sparkline = parsed
+        .iter()
+        .map(|value| {
+            if max > min {
+                let idx = ((value - min) / (max - min) * (SPARKS.len() - 1) as f64).round();
+                SPARKS[idx as usize]
+            } else {
+                SPARKS[0]
+            }
+        })
+        .collect();
+
+    Some(Trend { current, sparkline })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_generator_url() {
+        let (base, expr) = parse_generator_url(
+            "http://prometheus:9090/prometheus/graph?g0.expr=up+%3D%3D+0&g0.tab=1",
+        )
+        .unwrap();
+
+        assert_eq!(base.as_str(), "http://prometheus:9090/prometheus/");
+        assert_eq!(expr, "up == 0");
+        assert!(parse_generator_url("http://prometheus:9090/graph").is_none());
+    }
+
+    #[test]
+    fn only_queries_allowed_hosts() {
+        let prometheus = |config: serde_json::Value| {
+            Prometheus::new(
+                serde_json::from_value(config).unwrap(),
+                &HttpConfig::default(),
+            )
+            .unwrap()
+        };
+        let url = Url::parse("http://prometheus:9090/").unwrap();
+
+        assert!(!prometheus(serde_json::json!({})).allows(&url));
+        assert!(prometheus(serde_json::json!({ "allowed_hosts": ["prometheus"] })).allows(&url));
+        assert!(
+            prometheus(serde_json::json!({ "allowed_hosts": ["prometheus:9090"] })).allows(&url)
+        );
+        assert!(
+            !prometheus(serde_json::json!({ "allowed_hosts": ["prometheus:9091"] })).allows(&url)
+        );
+        assert!(
+            !prometheus(serde_json::json!({ "allowed_hosts": ["169.254.169.254"] })).allows(&url)
+        );
+    }
+
+    #[actix_web::test]
+    async fn skips_trends_of_unknown_hosts() {
+        let prometheus = Prometheus::new(
+            serde_json::from_value(serde_json::json!({})).unwrap(),
+            &HttpConfig::default(),
+        )
+        .unwrap();
+        let mut alert = crate::testing::alert_context(1, "team-a").alert;
+        alert.generator_url = Some(String::from(
+            "http://169.254.169.254/graph?g0.expr=up+%3D%3D+0",
+        ));
+
+        assert_eq!(prometheus.trend(&alert).await.unwrap(), None);
+    }
+
+    #[test]
+    fn renders_trend() {
+        let values: Vec<(f64, String)> = ["1", "2", "4", "8"]
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| (idx as f64, value.to_string()))
+            .collect();
+
+        assert_eq!(trend(&values).unwrap().to_string(), "▁▂▄█ (current: 8)");
+        assert_eq!(trend(&values[..1]).unwrap().sparkline, "▁");
+        assert!(trend(&[]).is_none());
+    }
+}
@@ -0,0 +1,344 @@
+//! Formatting of notifications, independent of the adapter which sends them.
+use crate::processor::{AlertContext, AlertContextTrimmed};
+use crate::truncate;
+
+/// The format of the notifications of a route.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Plain,
+    // Markdown body, with an HTML rendition for clients which support it.
+    Markdown,
+    // Plain body, with an HTML rendition for clients which support it.
+    Html,
+}
+
+impl Format {
+    pub fn renderer(&self) -> &'static dyn NotificationRenderer {
+        match self {
+            Format::Plain => &PlainRenderer,
+            Format::Markdown => &MarkdownRenderer,
+            Format::Html => &HtmlRenderer,
+        }
+    }
+}
+
+/// A rendered notification. Adapters without HTML support only send the body.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Message {
+    pub body: String,
+    pub html: Option<String>,
+}
+
+/// An alert of a notification and additional lines, e.g. its trend.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub alert: AlertContext,
+    pub notes: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn new(alert: AlertContext) -> Self {
+        Section {
+            alert,
+            notes: vec![],
+        }
+    }
+    pub fn with_note(mut self, label: impl Into<String>, value: impl Into<String>) -> Self {
+        self.notes.push((label.into(), value.into()));
+        self
+    }
+}
+
+/// Renders a notification, a header followed by the alerts.
+pub trait NotificationRenderer: Send + Sync {
+    fn render(&self, header: &str, sections: &[Section]) -> Message;
+}
+
+/// A line of a rendered alert.
+struct Field {
+    label: String,
+    value: String,
+    // Rendered as a link, if it is a web URL.
+    is_link: bool,
+}
+
+impl Field {
+    fn new(label: impl Into<String>, value: impl Into<String>) -> Self {
+        Field {
+            label: label.into(),
+            value: value.into(),
+            is_link: false,
+        }
+    }
+    /// The URL of the link, unless it could be anything but a web page.
+    fn url(&self) -> Option<&str> {
+        Some(self.value.as_str()).filter(|url| {
+            self.is_link
+                && (url.starts_with("https://") || url.starts_with("http://"))
+                && !url.contains(|c: char| c.is_whitespace() || c == '<' || c == '>')
+        })
+    }
+}
+
+/// Fields of an alert as shown in notifications, followed by its notes.
+/// Alerts which do not escalate cannot be acknowledged, hence without ID.
+fn fields(section: &Section) -> Vec<Field> {
+    let alert = &section.alert;
+    let annotation = |text: Option<&str>| {
+        let id = Some(alert.id).filter(|_| alert.should_escalate());
+        truncate::annotation(text.unwrap_or("N/A"), id).into_owned()
+    };
+
+    let mut fields = vec![];
+    if alert.should_escalate() {
+        fields.push(Field::new("ID", alert.id.to_string()));
+    }
+
+    fields.extend([
+        Field::new("Name", alert.alert.labels.alert_name.clone()),
+        Field::new("Severity", alert.alert.labels.severity.clone()),
+        Field::new(
+            "Message",
+            annotation(alert.alert.annotations.message.as_deref()),
+        ),
+        Field::new(
+            "Description",
+            annotation(alert.alert.annotations.description.as_deref()),
+        ),
+    ]);
+
+    if let Some(slo) = &alert.slo {
+        fields.push(Field::new("SLO", slo.summary.clone()));
+    }
+
+    for convention in alert.alert.conventions() {
+        fields.push(Field {
+            label: convention.label.to_string(),
+            value: if convention.is_link {
+                convention.value.to_string()
+            } else {
+                annotation(Some(convention.value))
+            },
+            is_link: convention.is_link,
+        });
+    }
+
+    fields.extend(
+        section
+            .notes
+            .iter()
+            .map(|(label, value)| Field::new(label.clone(), value.clone())),
+    );
+
+    fields
+}
+
+/// The format of room messages so far, kept as the default.
+pub struct PlainRenderer;
+
+impl NotificationRenderer for PlainRenderer {
+    fn render(&self, header: &str, sections: &[Section]) -> Message {
+        let mut body = format!("{}\n\n", header);
+        for section in sections {
+            if section.alert.should_escalate() {
+                body.push_str(&section.alert.to_string());
+            } else {
+                body.push_str(&AlertContextTrimmed::from(section.alert.clone()).to_string());
+            }
+
+            for (label, value) in &section.notes {
+                body.push_str(&format!("  {}: {}\n", label, value));
+            }
+
+            body.push_str("\n\n");
+        }
+
+        body.pop();
+        body.pop();
+
+        Message { body, html: None }
+    }
+}
+
+pub struct MarkdownRenderer;
+
+impl NotificationRenderer for MarkdownRenderer {
+    fn render(&self, header: &str, sections: &[Section]) -> Message {
+        let mut body = format!("**{}**\n\n", escape_markdown(header));
+        for section in sections {
+            for (idx, field) in fields(section).iter().enumerate() {
+                let bullet = if idx == 0 { "- " } else { "  " };
+                let value = match field.url() {
+                    Some(url) => format!("<{}>", url),
+                    None => escape_markdown(&field.value),
+                };
+
+                body.push_str(&format!(
+                    "{}**{}:** {}\n",
+                    bullet,
+                    escape_markdown(&field.label),
+                    value
+                ));
+            }
+
+            body.push('\n');
+        }
+
+        Message {
+            body: body.trim_end().to_string(),
+            html: Some(html(header, sections)),
+        }
+    }
+}
+
+pub struct HtmlRenderer;
+
+impl NotificationRenderer for HtmlRenderer {
+    fn render(&self, header: &str, sections: &[Section]) -> Message {
+        Message {
+            body: PlainRenderer.render(header, sections).body,
+            html: Some(html(header, sections)),
+        }
+    }
+}
+
+fn html(header: &str, sections: &[Section]) -> String {
+    let mut html = format!("<p><strong>{}</strong></p>", escape_html(header));
+    if sections.is_empty() {
+        return html;
+    }
+
+    html.push_str("<ul>");
+    for section in sections {
+        let lines: Vec<String> = fields(section)
+            .iter()
+            .map(|field| {
+                let value = match field.url() {
+                    Some(url) => format!("<a href=\"{0}\">{0}</a>", escape_html(url)),
+                    None => escape_html(&field.value).replace('\n', "<br>"),
+                };
+
+                format!("<strong>{}:</strong> {}", escape_html(&field.label), value)
+            })
+            .collect();
+
+        html.push_str(&format!("<li>{}</li>", lines.join("<br>")));
+    }
+    html.push_str("</ul>");
+
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::alert_context;
+
+    #[test]
+    fn renders_formats() {
+        let mut alert = alert_context(1, "default");
+        alert.alert.labels.alert_name = String::from("<b>Node_down</b>");
+        let sections = [Section::new(alert.clone()).with_note("Trend", "▁█ (current: 2)")];
+
+        let plain = Format::Plain
+            .renderer()
+            .render("⚠️ Alert occurred!", &sections);
+        assert_eq!(
+            plain.body,
+            format!("⚠️ Alert occurred!\n\n{}  Trend: ▁█ (current: 2)\n", alert)
+        );
+        assert_eq!(plain.html, None);
+
+        let html = Format::Html
+            .renderer()
+            .render("⚠️ Alert occurred!", &sections);
+        assert_eq!(html.body, plain.body);
+        assert_eq!(
+            html.html.unwrap(),
+            "<p><strong>⚠️ Alert occurred!</strong></p><ul><li>\
+             <strong>ID:</strong> 1<br>\
+             <strong>Name:</strong> &lt;b&gt;Node_down&lt;/b&gt;<br>\
+             <strong>Severity:</strong> critical<br>\
+             <strong>Message:</strong> Message of alert 1<br>\
+             <strong>Description:</strong> N/A<br>\
+             <strong>Trend:</strong> ▁█ (current: 2)</li></ul>"
+        );
+
+        let markdown = Format::Markdown.renderer().render("Header", &sections);
+        assert!(markdown
+            .body
+            .starts_with("**Header**\n\n- **ID:** 1\n  **Name:** \\<b\\>Node\\_down\\</b\\>\n"));
+        assert!(markdown.body.ends_with("  **Trend:** ▁█ (current: 2)"));
+
+        // Without alerts, only the header is rendered.
+        assert_eq!(
+            Format::Plain.renderer().render("Mute expired.", &[]).body,
+            "Mute expired."
+        );
+    }
+
+    #[test]
+    fn renders_conventions() {
+        let mut alert = alert_context(1, "default");
+        alert.alert.annotations.summary = Some(String::from("Node is down"));
+        alert.alert.annotations.runbook_url = Some(String::from("https://runbooks.io/node_down"));
+        alert.alert.annotations.dashboard = Some(String::from("javascript:alert(1)"));
+        alert.alert.labels.instance = Some(String::from("node-1:9100"));
+        let sections = [Section::new(alert)];
+
+        let plain = Format::Plain.renderer().render("Header", &sections);
+        assert!(plain.body.ends_with(
+            "  Description: N/A\n  \
+               Summary: Node is down\n  \
+               Instance: node-1:9100\n  \
+               Runbook: https://runbooks.io/node_down\n  \
+               Dashboard: javascript:alert(1)\n"
+        ));
+
+        // Only web URLs are links.
+        let html = Format::Html.renderer().render("Header", &sections);
+        assert!(html.html.unwrap().ends_with(
+            "<strong>Summary:</strong> Node is down<br>\
+             <strong>Instance:</strong> node-1:9100<br>\
+             <strong>Runbook:</strong> <a href=\"https://runbooks.io/node_down\">\
+             https://runbooks.io/node_down</a><br>\
+             <strong>Dashboard:</strong> javascript:alert(1)</li></ul>"
+        ));
+
+        let markdown = Format::Markdown.renderer().render("Header", &sections);
+        assert!(markdown
+            .body
+            .contains("  **Runbook:** <https://runbooks.io/node_down>\n"));
+        assert!(markdown.body.contains("  **Summary:** Node is down\n"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Weekly compliance report of all alerts, as evidence of how they were
+//! handled: who acknowledged them, how fast and how far they escalated.
+use crate::database::AlertAcknowledged;
+use crate::processor::{AlertContext, TimelineKind};
+use chrono::NaiveDateTime;
+
+const COLUMNS: [&str; 12] = [
+    "id",
+    "route",
+    "alert_name",
+    "severity",
+    "status",
+    "raised_at",
+    "acked_at",
+    "acked_by",
+    "time_to_ack",
+    "resolved_at",
+    "resolved_by",
+    "max_escalation_idx",
+];
+
+/// Posts the report of the past week, as a CSV file, to the given room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComplianceReportConfig {
+    pub room: String,
+}
+
+/// An alert of the report, either pending or acknowledged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportRow {
+    alert: AlertContext,
+    acked: Option<(String, u64)>,
+    resolved: Option<(String, u64)>,
+}
+
+impl From<AlertContext> for ReportRow {
+    fn from(alert: AlertContext) -> Self {
+        ReportRow {
+            alert,
+            acked: None,
+            resolved: None,
+        }
+    }
+}
+
+impl From<AlertAcknowledged> for ReportRow {
+    fn from(acked: AlertAcknowledged) -> Self {
+        ReportRow {
+            alert: acked.alert,
+            acked: Some((acked.acked_by, acked.acked_timestamp)),
+            resolved: acked.resolved_by.zip(acked.resolved_timestamp),
+        }
+    }
+}
+
+impl ReportRow {
+    /// When the alert was first notified. Alerts stored before timelines were
+    /// introduced have no such time.
+    fn raised_at(&self) -> Option<u64> {
+        self.alert
+            .timeline
+            .iter()
+            .find(|event| event.kind == TimelineKind::Notified)
+            .map(|event| event.timestamp)
+    }
+    fn status(&self) -> &'static str {
+        match (&self.acked, &self.resolved) {
+            (_, Some(_)) => "resolved",
+            (Some(_), None) => "acknowledged",
+            (None, None) => "pending",
+        }
+    }
+    fn max_escalation_idx(&self) -> usize {
+        self.alert
+            .timeline
+            .iter()
+            .map(|event| event.escalation_idx)
+            .chain([self.alert.escalation_idx])
+            .max()
+            .unwrap_or_default()
+    }
+    fn fields(&self) -> Vec<String> {
+        let time_to_ack = match (self.raised_at(), &self.acked) {
+            (Some(raised_at), Some((_, acked_at))) => {
+                acked_at.saturating_sub(raised_at).to_string()
+            }
+            _ => String::new(),
+        };
+
+        vec![
+            self.alert.id.to_string(),
+            self.alert.route.clone(),
+            self.alert.alert.labels.alert_name.clone(),
+            self.alert.alert.labels.severity.clone(),
+            self.status().to_string(),
+            format_time(self.raised_at()),
+            format_time(self.acked.as_ref().map(|(_, at)| *at)),
+            self.acked
+                .as_ref()
+                .map(|(by, _)| by.clone())
+                .unwrap_or_default(),
+            time_to_ack,
+            format_time(self.resolved.as_ref().map(|(_, at)| *at)),
+            self.resolved
+                .as_ref()
+                .map(|(by, _)| by.clone())
+                .unwrap_or_default(),
+            self.max_escalation_idx().to_string(),
+        ]
+    }
+}
+
+/// Renders the rows as CSV (RFC 4180), ordered by alert ID. `time_to_ack` is
+/// in seconds, times are in UTC.
+pub fn to_csv(mut rows: Vec<ReportRow>) -> String {
+    rows.sort_by_key(|row| row.alert.id);
+
+    let mut csv = COLUMNS.join(",");
+    csv.push_str("\r\n");
+
+    for row in rows {
+        let fields: Vec<String> = row.fields().iter().map(|field| escape(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+
+    csv
+}
+
+fn format_time(timestamp: Option<u64>) -> String {
+    timestamp
+        .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp as i64, 0))
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::TimelineEvent;
+    use crate::testing::alert_context;
+
+    #[test]
+    fn renders_csv() {
+        let event = |kind, escalation_idx, timestamp| TimelineEvent {
+            kind,
+            escalation_idx,
+            channel: String::from("matrix"),
+            timestamp,
+        };
+
+        let mut pending = alert_context(2, "default");
+        pending.alert.labels.alert_name = String::from("Disk \"full\", again");
+        pending.timeline = vec![event(TimelineKind::Notified, 0, 1704067200)];
+
+        let mut acked = alert_context(1, "team-a");
+        acked.escalation_idx = 0;
+        acked.timeline = vec![
+            event(TimelineKind::Notified, 0, 1704067200),
+            event(TimelineKind::Escalated, 2, 1704067800),
+            event(TimelineKind::Acknowledged, 2, 1704068100),
+        ];
+
+        let csv = to_csv(vec![
+            ReportRow::from(pending),
+            ReportRow::from(AlertAcknowledged {
+                alert: acked,
+                acked_by: String::from("@ops:matrix.org"),
+                acked_timestamp: 1704068100,
+                resolved_by: None,
+                resolved_timestamp: None,
+                handed_off_to: None,
+                expired_timestamp: None,
+            }),
+        ]);
+
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(lines[0], COLUMNS.join(","));
+        assert_eq!(
+            lines[1],
+            "1,team-a,Alert1,critical,acknowledged,2024-01-01 00:00:00,\
+             2024-01-01 00:15:00,@ops:matrix.org,900,,,2"
+        );
+        assert!(lines[2].starts_with("2,default,\"Disk \"\"full\"\", again\",critical,pending,"));
+        assert_eq!(lines[3], "");
+    }
+}
@@ -0,0 +1,187 @@
+//! The `selftest` command, verifies a deployment in one go: the rooms and
+//! adapter destinations of a route receive a test message, the database is
+//! written to and read from. No alert is created, so nothing escalates.
+use crate::adapter::{Adapters, Notification};
+use crate::database::Database;
+use crate::matrix::{MatrixClient, TestRooms};
+use crate::render::Format;
+use crate::Result;
+use actix::SystemService;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The outcome of a single check.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub elapsed: Duration,
+    // The error, if the check failed.
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn new(name: String, elapsed: Duration, res: Result<()>) -> Self {
+        Check {
+            name,
+            elapsed,
+            error: res.err().map(|err| err.to_string()),
+        }
+    }
+    /// Times the check.
+    pub async fn run(name: String, check: impl Future<Output = Result<()>>) -> Self {
+        let started = Instant::now();
+        let res = check.await;
+        Check::new(name, started.elapsed(), res)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SelfTestReport {
+    pub route: String,
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.error.is_none())
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self
+            .checks
+            .iter()
+            .filter(|check| check.error.is_some())
+            .count();
+
+        if failed == 0 {
+            writeln!(
+                f,
+                "✅ Self-test of route '{}' passed, all {} checks succeeded:",
+                self.route,
+                self.checks.len()
+            )?;
+        } else {
+            writeln!(
+                f,
+                "❌ Self-test of route '{}' failed, {} of {} checks failed:",
+                self.route,
+                failed,
+                self.checks.len()
+            )?;
+        }
+
+        for check in &self.checks {
+            let elapsed = check.elapsed.as_millis();
+            match &check.error {
+                None => writeln!(f, "- PASS {} ({} ms)", check.name, elapsed)?,
+                Some(err) => writeln!(f, "- FAIL {} ({} ms): {}", check.name, elapsed, err)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs all checks of the route, which escalates through the given number of
+/// levels. A missing database is reported as a failed check.
+pub async fn run(
+    db: Result<Arc<Database>>,
+    adapters: Adapters,
+    route: String,
+    levels: usize,
+    user: String,
+) -> SelfTestReport {
+    let mut checks = vec![];
+
+    let latency = match db {
+        Ok(db) => db.latency_check().await,
+        Err(err) => Err(err),
+    };
+    match latency {
+        Ok((write, read)) => {
+            checks.push(Check::new(String::from("Database write"), write, Ok(())));
+            checks.push(Check::new(String::from("Database read"), read, Ok(())));
+        }
+        Err(err) => checks.push(Check::new(
+            String::from("Database"),
+            Duration::default(),
+            Err(err),
+        )),
+    }
+
+    let notification = Notification::SelfTest { user };
+
+    let started = Instant::now();
+    match MatrixClient::from_registry()
+        .send(TestRooms {
+            route: route.clone(),
+            body: notification.render(Format::Plain.renderer()).body,
+        })
+        .await
+    {
+        Ok(rooms) => checks.extend(rooms),
+        Err(err) => checks.push(Check::new(
+            String::from("Matrix"),
+            started.elapsed(),
+            Err(err.into()),
+        )),
+    }
+
+    checks.extend(adapters.test(&route, levels, &notification).await);
+
+    let report = SelfTestReport { route, checks };
+    if !report.passed() {
+        warn!("Self-test of route '{}' failed:\n{}", report.route, report);
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn summarizes_checks() {
+        let mut report = SelfTestReport {
+            route: String::from("team-a"),
+            checks: vec![
+                Check::new(
+                    String::from("Database write"),
+                    Duration::from_millis(3),
+                    Ok(()),
+                ),
+                Check::new(
+                    String::from("Matrix, level 0"),
+                    Duration::from_millis(120),
+                    Ok(()),
+                ),
+            ],
+        };
+        assert!(report.passed());
+        assert_eq!(
+            report.to_string(),
+            "✅ Self-test of route 'team-a' passed, all 2 checks succeeded:\n\
+             - PASS Database write (3 ms)\n\
+             - PASS Matrix, level 0 (120 ms)\n"
+        );
+
+        report.checks.push(Check::new(
+            String::from("Telegram, level 1"),
+            Duration::from_millis(40),
+            Err(Error::Internal(String::from("chat not found"))),
+        ));
+        assert!(!report.passed());
+        assert!(report
+            .to_string()
+            .starts_with("❌ Self-test of route 'team-a' failed, 1 of 3 checks failed:\n"));
+        assert!(report
+            .to_string()
+            .ends_with("- FAIL Telegram, level 1 (40 ms): Internal error: chat not found\n"));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::webhook::{Alert, Annotations, Labels};
+use crate::DEFAULT_ROUTE;
+
+/// Enables `/webhook-sentry`, which accepts issue alerts of the Sentry
+/// webhook integration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentryConfig {
+    route: Option<String>,
+    // Sentry cannot send headers, so the token is passed as `?token=...`.
+    token: Option<String>,
+}
+
+impl SentryConfig {
+    pub fn route(&self) -> &str {
+        self.route.as_deref().unwrap_or(DEFAULT_ROUTE)
+    }
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+}
+
+/// An issue alert, as sent by the Sentry webhook integration.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SentryIssue {
+    project: Option<String>,
+    project_name: Option<String>,
+    level: Option<String>,
+    message: Option<String>,
+    culprit: Option<String>,
+    url: Option<String>,
+    event: Option<SentryEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SentryEvent {
+    title: Option<String>,
+    level: Option<String>,
+}
+
+impl From<SentryIssue> for Alert {
+    fn from(issue: SentryIssue) -> Self {
+        let SentryIssue {
+            project,
+            project_name,
+            level,
+            message,
+            culprit,
+            url,
+            event,
+        } = issue;
+
+        let project = project_name
+            .or(project)
+            .unwrap_or_else(|| String::from("sentry"));
+
+        let title = event
+            .as_ref()
+            .and_then(|event| event.title.clone())
+            .or_else(|| message.clone())
+            .unwrap_or_else(|| String::from("Sentry issue"));
+
+        // Sentry levels (e.g. `fatal` or `error`) are normalized like any
+        // other severity, see `Severities`.
+        let level = event
+            .and_then(|event| event.level)
+            .or(level)
+            .unwrap_or_else(|| String::from("error"));
+
+        Alert {
+            annotations: Annotations {
+                message: culprit.or(message),
+                description: url,
+                ..Default::default()
+            },
+            labels: Labels {
+                severity: level,
+                alert_name: format!("{}: {}", project, title),
+                team: None,
+                ..Default::default()
+            },
+            generator_url: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_issue_to_alert() {
+        let issue: SentryIssue = serde_json::from_str(
+            r#"{
+                "project": "api",
+                "project_name": "API",
+                "culprit": "handlers.users in get_user",
+                "level": "error",
+                "message": "",
+                "url": "https://sentry.io/organizations/acme/issues/42/",
+                "event": {
+                    "title": "KeyError: 'id'",
+                    "level": "fatal"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let alert = Alert::from(issue);
+        assert_eq!(alert.labels.alert_name, "API: KeyError: 'id'");
+        assert_eq!(alert.labels.severity, "fatal");
+        assert_eq!(
+            alert.annotations.message.as_deref(),
+            Some("handlers.users in get_user")
+        );
+        assert_eq!(
+            alert.annotations.description.as_deref(),
+            Some("https://sentry.io/organizations/acme/issues/42/")
+        );
+    }
+}
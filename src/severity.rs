@@ -0,0 +1,203 @@
+use crate::{Error, Result};
+
+/// The recognized severities, ordered from the most to the least severe.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeverityConfig {
+    levels: Vec<String>,
+    // Assigned to alerts with an unrecognized severity.
+    default: Option<String>,
+    // Raise the severity of alerts which stay unacknowledged.
+    #[serde(default)]
+    bumps: Vec<SeverityBump>,
+}
+
+/// Raises the severity of alerts which have not been acknowledged within
+/// `after` seconds, e.g. from `warning` to `critical`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeverityBump {
+    from: String,
+    to: String,
+    after: u64,
+}
+
+/// Recognized severities. Without any configured levels, severities are
+/// treated as opaque strings.
+#[derive(Debug, Clone, Default)]
+pub struct Severities {
+    levels: Vec<String>,
+    default: Option<String>,
+    bumps: Vec<SeverityBump>,
+}
+
+impl Severities {
+    pub fn new(config: SeverityConfig) -> Result<Self> {
+        for (idx, level) in config.levels.iter().enumerate() {
+            if config.levels[..idx]
+                .iter()
+                .any(|other| other.eq_ignore_ascii_case(level))
+            {
+                return Err(Error::Config(format!(
+                    "Severity '{}' is configured more than once",
+                    level
+                )));
+            }
+        }
+
+        let severities = Severities {
+            levels: config.levels,
+            default: config.default,
+            bumps: config.bumps,
+        };
+
+        // Bumps must raise the severity, which also rules out cycles.
+        for bump in &severities.bumps {
+            match (
+                severities.position(&bump.from),
+                severities.position(&bump.to),
+            ) {
+                (Some(from), Some(to)) if to < from => {}
+                (Some(_), Some(_)) => {
+                    return Err(Error::Config(format!(
+                        "Severity bump from '{}' to '{}' does not raise the severity",
+                        bump.from, bump.to
+                    )))
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "Severity bump from '{}' to '{}' uses an unconfigured severity",
+                        bump.from, bump.to
+                    )))
+                }
+            }
+        }
+
+        if let Some(default) = &severities.default {
+            if severities.position(default).is_none() {
+                return Err(Error::Config(format!(
+                    "Default severity '{}' is not configured",
+                    default
+                )));
+            }
+        }
+
+        Ok(severities)
+    }
+    fn position(&self, severity: &str) -> Option<usize> {
+        self.levels
+            .iter()
+            .position(|level| level.eq_ignore_ascii_case(severity))
+    }
+    pub fn is_known(&self, severity: &str) -> bool {
+        self.levels.is_empty() || self.position(severity).is_some()
+    }
+    /// Returns the configured name of the severity. Unrecognized severities
+    /// are replaced by the default, if configured.
+    pub fn normalize(&self, severity: &str) -> String {
+        match self.position(severity) {
+            Some(idx) => self.levels[idx].clone(),
+            None => self.default.clone().unwrap_or_else(|| severity.to_string()),
+        }
+    }
+    /// Lower ranks are more severe. Unrecognized severities rank last.
+    pub fn rank(&self, severity: &str) -> usize {
+        self.position(severity).unwrap_or(self.levels.len())
+    }
+    pub fn has_bumps(&self) -> bool {
+        !self.bumps.is_empty()
+    }
+    /// The raised severity of an alert which has not been acknowledged for
+    /// `unacked` seconds, if any bump applies.
+    pub fn bump(&self, severity: &str, unacked: u64) -> Option<String> {
+        self.bumps
+            .iter()
+            .find(|bump| bump.from.eq_ignore_ascii_case(severity) && unacked >= bump.after)
+            .map(|bump| self.normalize(&bump.to))
+    }
+    /// Whether `severity` is at least as severe as `threshold`. Without
+    /// configured levels, the severities must match exactly.
+    pub fn at_least(&self, severity: &str, threshold: &str) -> bool {
+        match (self.position(severity), self.position(threshold)) {
+            (Some(severity), Some(threshold)) => severity <= threshold,
+            _ => severity == threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn severities() -> Severities {
+        Severities::new(SeverityConfig {
+            levels: vec![
+                String::from("critical"),
+                String::from("warning"),
+                String::from("info"),
+            ],
+            default: Some(String::from("warning")),
+            bumps: vec![SeverityBump {
+                from: String::from("warning"),
+                to: String::from("critical"),
+                after: 7200,
+            }],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_unknown_default() {
+        assert!(Severities::new(SeverityConfig {
+            levels: vec![String::from("critical")],
+            default: Some(String::from("warning")),
+            bumps: vec![],
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn bumps_unacknowledged_alerts() {
+        let severities = severities();
+
+        assert_eq!(severities.bump("Warning", 7199), None);
+        assert_eq!(
+            severities.bump("Warning", 7200).as_deref(),
+            Some("critical")
+        );
+        assert_eq!(severities.bump("info", 7200), None);
+
+        let lowering = Severities::new(SeverityConfig {
+            levels: vec![String::from("critical"), String::from("warning")],
+            default: None,
+            bumps: vec![SeverityBump {
+                from: String::from("critical"),
+                to: String::from("warning"),
+                after: 60,
+            }],
+        });
+        assert!(lowering.is_err());
+    }
+
+    #[test]
+    fn normalizes_to_configured_names() {
+        let severities = severities();
+
+        assert_eq!(severities.normalize("CRITICAL"), "critical");
+        assert_eq!(severities.normalize("unknown"), "warning");
+        assert_eq!(Severities::default().normalize("unknown"), "unknown");
+    }
+
+    #[test]
+    fn ranks_by_configured_order() {
+        let severities = severities();
+
+        assert!(severities.rank("critical") < severities.rank("info"));
+        assert_eq!(severities.rank("unknown"), 3);
+
+        assert!(severities.at_least("critical", "warning"));
+        assert!(severities.at_least("warning", "warning"));
+        assert!(!severities.at_least("info", "warning"));
+
+        assert!(Severities::default().at_least("critical", "critical"));
+        assert!(!Severities::default().at_least("critical", "warning"));
+    }
+}
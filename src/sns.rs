@@ -0,0 +1,385 @@
+use crate::error::SNS_ADAPTER;
+use crate::http::HttpConfig;
+use crate::webhook::{Alert, Annotations, Labels};
+use crate::{Error, Result, DEFAULT_ROUTE};
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_SEVERITY: &str = "critical";
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// Enables `/webhook-sns`, which accepts AWS SNS notifications, e.g. of
+/// CloudWatch alarms.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnsConfig {
+    route: Option<String>,
+    // ARNs of the topics to accept (and confirm subscriptions of). Required,
+    // since anyone could subscribe the listener to their topic otherwise.
+    topics: Vec<String>,
+    // Defaults to `critical`.
+    severity: Option<String>,
+}
+
+impl SnsConfig {
+    pub fn route(&self) -> &str {
+        self.route.as_deref().unwrap_or(DEFAULT_ROUTE)
+    }
+}
+
+/// A message delivered by SNS over HTTP(S).
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnsMessage {
+    #[serde(rename = "Type")]
+    kind: String,
+    message_id: String,
+    topic_arn: String,
+    subject: Option<String>,
+    message: String,
+    timestamp: String,
+    // Only set on subscription confirmations.
+    token: Option<String>,
+    #[serde(rename = "SubscribeURL")]
+    subscribe_url: Option<String>,
+    signature_version: String,
+    signature: String,
+    #[serde(rename = "SigningCertURL")]
+    signing_cert_url: String,
+}
+
+impl SnsMessage {
+    pub fn is_subscription_confirmation(&self) -> bool {
+        self.kind == "SubscriptionConfirmation"
+    }
+    pub fn is_notification(&self) -> bool {
+        self.kind == "Notification"
+    }
+    pub fn topic_arn(&self) -> &str {
+        &self.topic_arn
+    }
+    /// The canonical string SNS signs, see
+    /// https://docs.aws.amazon.com/sns/latest/dg/sns-verify-signature-of-message.html
+    fn string_to_sign(&self) -> String {
+        let mut fields = vec![("Message", Some(&self.message))];
+        fields.push(("MessageId", Some(&self.message_id)));
+
+        if self.is_notification() {
+            fields.push(("Subject", self.subject.as_ref()));
+        } else {
+            fields.push(("SubscribeURL", self.subscribe_url.as_ref()));
+        }
+
+        fields.push(("Timestamp", Some(&self.timestamp)));
+        if !self.is_notification() {
+            fields.push(("Token", self.token.as_ref()));
+        }
+        fields.push(("TopicArn", Some(&self.topic_arn)));
+        fields.push(("Type", Some(&self.kind)));
+
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| format!("{}\n{}\n", key, value)))
+            .collect()
+    }
+}
+
+/// A CloudWatch alarm, the `Message` of its SNS notifications.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CloudWatchAlarm {
+    alarm_name: String,
+    alarm_description: Option<String>,
+    new_state_value: String,
+    new_state_reason: Option<String>,
+    region: Option<String>,
+}
+
+/// Verifies SNS messages and confirms subscriptions.
+pub struct Sns {
+    config: SnsConfig,
+    client: reqwest::Client,
+    // Signing certificates by URL.
+    certs: Mutex<HashMap<String, PKey<Public>>>,
+}
+
+impl Sns {
+    pub fn new(config: SnsConfig, http: &HttpConfig) -> Result<Self> {
+        if config.topics.is_empty() {
+            return Err(Error::Config(String::from(
+                "SNS topics must not be empty, subscriptions are confirmed automatically",
+            )));
+        }
+
+        let builder = http
+            .client_builder()?
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT));
+
+        Ok(Sns {
+            config,
+            client: builder
+                .build()
+                .map_err(|err| Error::adapter(SNS_ADAPTER, err))?,
+            certs: Mutex::new(HashMap::new()),
+        })
+    }
+    pub fn route(&self) -> &str {
+        self.config.route()
+    }
+    pub fn accepts_topic(&self, topic_arn: &str) -> bool {
+        self.config.topics.iter().any(|topic| topic == topic_arn)
+    }
+    /// Verifies the signature of the message with the certificate of SNS.
+    pub async fn verify(&self, msg: &SnsMessage) -> Result<()> {
+        let url = aws_url(&msg.signing_cert_url).ok_or_else(|| {
+            Error::adapter(
+                SNS_ADAPTER,
+                format!(
+                    "Untrusted signing certificate URL: {}",
+                    msg.signing_cert_url
+                ),
+            )
+        })?;
+
+        let cached = self.certs.lock().unwrap().get(url.as_str()).cloned();
+        let key = match cached {
+            Some(key) => key,
+            None => {
+                let pem = self
+                    .client
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                    .map_err(|err| Error::adapter(SNS_ADAPTER, err))?
+                    .bytes()
+                    .await
+                    .map_err(|err| Error::adapter(SNS_ADAPTER, err))?;
+
+                let key = X509::from_pem(&pem)
+                    .and_then(|cert| cert.public_key())
+                    .map_err(|err| Error::adapter(SNS_ADAPTER, err))?;
+
+                self.certs
+                    .lock()
+                    .unwrap()
+                    .insert(url.to_string(), key.clone());
+
+                key
+            }
+        };
+
+        if verify_signature(&key, msg)? {
+            Ok(())
+        } else {
+            Err(Error::adapter(SNS_ADAPTER, "Invalid message signature"))
+        }
+    }
+    /// Confirms the subscription of the topic, i.e. visits the subscribe URL.
+    pub async fn confirm(&self, msg: &SnsMessage) -> Result<()> {
+        let url = msg
+            .subscribe_url
+            .as_deref()
+            .and_then(aws_url)
+            .ok_or_else(|| Error::adapter(SNS_ADAPTER, "Missing or untrusted subscribe URL"))?;
+
+        self.client
+            .get(url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::adapter(SNS_ADAPTER, err))?;
+
+        info!("Confirmed SNS subscription of topic {}", msg.topic_arn);
+
+        Ok(())
+    }
+    /// Converts a notification into an alert. CloudWatch alarms are only
+    /// alerts while in the `ALARM` state, other messages always are.
+    pub fn alert(&self, msg: &SnsMessage) -> Option<Alert> {
+        let severity = self
+            .config
+            .severity
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SEVERITY.to_string());
+
+        match serde_json::from_str::<CloudWatchAlarm>(&msg.message) {
+            Ok(alarm) if alarm.new_state_value != "ALARM" => None,
+            Ok(alarm) => Some(Alert {
+                annotations: Annotations {
+                    message: alarm.new_state_reason,
+                    description: match (alarm.alarm_description, alarm.region) {
+                        (Some(description), Some(region)) => {
+                            Some(format!("{} ({})", description, region))
+                        }
+                        (description, region) => description.or(region),
+                    },
+                    ..Default::default()
+                },
+                labels: Labels {
+                    severity,
+                    alert_name: alarm.alarm_name,
+                    team: None,
+                    ..Default::default()
+                },
+                generator_url: None,
+            }),
+            Err(_) => Some(Alert {
+                annotations: Annotations {
+                    message: Some(msg.message.clone()),
+                    description: Some(msg.topic_arn.clone()),
+                    ..Default::default()
+                },
+                labels: Labels {
+                    severity,
+                    alert_name: msg
+                        .subject
+                        .clone()
+                        .unwrap_or_else(|| String::from("SNS notification")),
+                    team: None,
+                    ..Default::default()
+                },
+                generator_url: None,
+            }),
+        }
+    }
+}
+
+fn verify_signature(key: &PKey<Public>, msg: &SnsMessage) -> Result<bool> {
+    let digest = match msg.signature_version.as_str() {
+        "1" => MessageDigest::sha1(),
+        "2" => MessageDigest::sha256(),
+        version => {
+            return Err(Error::adapter(
+                SNS_ADAPTER,
+                format!("Unsupported signature version {}", version),
+            ))
+        }
+    };
+
+    let signature =
+        base64::decode_block(&msg.signature).map_err(|err| Error::adapter(SNS_ADAPTER, err))?;
+
+    let mut verifier =
+        Verifier::new(digest, key).map_err(|err| Error::adapter(SNS_ADAPTER, err))?;
+    verifier
+        .update(msg.string_to_sign().as_bytes())
+        .map_err(|err| Error::adapter(SNS_ADAPTER, err))?;
+
+    verifier
+        .verify(&signature)
+        .map_err(|err| Error::adapter(SNS_ADAPTER, err))
+}
+
+/// Only HTTPS URLs of SNS itself are trusted, e.g.
+/// `https://sns.eu-central-1.amazonaws.com/...`.
+fn aws_url(url: &str) -> Option<Url> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+
+    let trusted = url.scheme() == "https"
+        && host.starts_with("sns.")
+        && (host.ends_with(".amazonaws.com") || host.ends_with(".amazonaws.com.cn"));
+
+    if trusted {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+
+    fn notification(message: &str) -> SnsMessage {
+        SnsMessage {
+            kind: String::from("Notification"),
+            message_id: String::from("22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324"),
+            topic_arn: String::from("arn:aws:sns:eu-central-1:123456789012:alarms"),
+            subject: Some(String::from("ALARM: \"HighCPU\"")),
+            message: message.to_string(),
+            timestamp: String::from("2024-01-01T00:00:00.000Z"),
+            token: None,
+            subscribe_url: None,
+            signature_version: String::from("2"),
+            signature: String::new(),
+            signing_cert_url: String::from(
+                "https://sns.eu-central-1.amazonaws.com/SimpleNotificationService.pem",
+            ),
+        }
+    }
+
+    fn sns() -> Sns {
+        let config: SnsConfig =
+            serde_yaml::from_str("topics: [\"arn:aws:sns:eu-central-1:123456789012:alarms\"]")
+                .unwrap();
+        Sns::new(config, &HttpConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn requires_topics() {
+        let config: SnsConfig = serde_yaml::from_str("topics: []").unwrap();
+        assert!(matches!(
+            Sns::new(config, &HttpConfig::default()),
+            Err(Error::Config(_))
+        ));
+        assert!(serde_yaml::from_str::<SnsConfig>("route: team-a").is_err());
+
+        let sns = sns();
+        assert!(sns.accepts_topic("arn:aws:sns:eu-central-1:123456789012:alarms"));
+        assert!(!sns.accepts_topic("arn:aws:sns:eu-central-1:999999999999:alarms"));
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let private = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem().unwrap()).unwrap();
+
+        let mut msg = notification("Hello");
+        assert_eq!(
+            msg.string_to_sign(),
+            "Message\nHello\nMessageId\n22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324\nSubject\nALARM: \"HighCPU\"\nTimestamp\n2024-01-01T00:00:00.000Z\nTopicArn\narn:aws:sns:eu-central-1:123456789012:alarms\nType\nNotification\n"
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &private).unwrap();
+        signer.update(msg.string_to_sign().as_bytes()).unwrap();
+        msg.signature = base64::encode_block(&signer.sign_to_vec().unwrap());
+        assert!(verify_signature(&public, &msg).unwrap());
+
+        msg.message = String::from("Tampered");
+        assert!(!verify_signature(&public, &msg).unwrap());
+
+        assert!(aws_url("https://sns.us-east-1.amazonaws.com/cert.pem").is_some());
+        assert!(aws_url("http://sns.us-east-1.amazonaws.com/cert.pem").is_none());
+        assert!(aws_url("https://sns.us-east-1.amazonaws.com.evil.org/cert.pem").is_none());
+    }
+
+    #[test]
+    fn converts_cloudwatch_alarms() {
+        let sns = sns();
+
+        let alarm = r#"{"AlarmName":"HighCPU","AlarmDescription":"CPU above 90%","NewStateValue":"ALARM","NewStateReason":"Threshold crossed","Region":"EU (Frankfurt)"}"#;
+        let alert = sns.alert(&notification(alarm)).unwrap();
+        assert_eq!(alert.labels.alert_name, "HighCPU");
+        assert_eq!(alert.labels.severity, "critical");
+        assert_eq!(
+            alert.annotations.description.as_deref(),
+            Some("CPU above 90% (EU (Frankfurt))")
+        );
+
+        let ok = alarm.replace("\"ALARM\"", "\"OK\"");
+        assert!(sns.alert(&notification(&ok)).is_none());
+
+        let alert = sns.alert(&notification("Something happened")).unwrap();
+        assert_eq!(alert.labels.alert_name, "ALARM: \"HighCPU\"");
+    }
+}
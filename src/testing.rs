@@ -0,0 +1,384 @@
+//! Test utilities which spin up a fake Matrix homeserver, so the Matrix
+//! client can be exercised without real credentials.
+use crate::database::Database;
+use crate::matrix::MatrixConfig;
+use crate::processor::{AlertContext, EscalationSettings};
+use crate::webhook::{Alert, Annotations, Labels};
+use crate::AlertId;
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use wiremock::matchers::{any, method, path, path_regex, query_param};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+pub const BOT_USER: &str = "@bot:localhost";
+pub const OTHER_USER: &str = "@alice:localhost";
+/// The room created for direct messages, or any other room.
+pub const DIRECT_ROOM: &str = "!direct:localhost";
+
+const LOGIN_PATH: &str = "/_matrix/client/r0/login";
+const SYNC_PATH: &str = "/_matrix/client/r0/sync";
+const KEYS_UPLOAD_PATH: &str = "/_matrix/client/r0/keys/upload";
+const CREATE_ROOM_PATH: &str = "/_matrix/client/r0/createRoom";
+// Path segments are percent-encoded by the client.
+const SEND_PATH: &str = r"^/_matrix/client/r0/rooms/[^/]+/send/[^/]+/[^/]+$";
+const STATE_PATH: &str = r"^/_matrix/client/r0/rooms/[^/]+/state$";
+const LEAVE_PATH: &str = r"^/_matrix/client/r0/rooms/[^/]+/leave$";
+// The `next_batch` token returned by every sync. The client ignores responses
+// carrying its current token, hence injected events use a different one.
+const SYNC_TOKEN: &str = "s1";
+const EVENT_SYNC_TOKEN: &str = "s2";
+
+static STORE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A message sent by the bot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SentMessage {
+    pub room_id: String,
+    pub body: String,
+}
+
+/// Fake homeserver which accepts logins, answers syncs and records all
+/// messages sent by the client.
+pub struct MockHomeserver {
+    server: MockServer,
+    store_path: PathBuf,
+}
+
+impl MockHomeserver {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path(LOGIN_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": BOT_USER,
+                "access_token": "access-token",
+                "device_id": "DEVICEID",
+            })))
+            .mount(&server)
+            .await;
+
+        // Syncs are delayed in order to avoid a busy loop in the background
+        // sync of the client.
+        Mock::given(method("GET"))
+            .and(path(SYNC_PATH))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "next_batch": SYNC_TOKEN }))
+                    .set_delay(Duration::from_millis(100)),
+            )
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path(KEYS_UPLOAD_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "one_time_key_counts": { "signed_curve25519": 50 },
+            })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path(CREATE_ROOM_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "room_id": DIRECT_ROOM,
+            })))
+            .mount(&server)
+            .await;
+
+        Mock::given(method("PUT"))
+            .and(path_regex(SEND_PATH))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$event:localhost" })),
+            )
+            .mount(&server)
+            .await;
+
+        Mock::given(method("POST"))
+            .and(path_regex(LEAVE_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .mount(&server)
+            .await;
+
+        let store_path = std::env::temp_dir().join(format!(
+            "matrixbot-test-{}-{}",
+            std::process::id(),
+            STORE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        MockHomeserver { server, store_path }
+    }
+    /// Client configuration pointing to this homeserver.
+    pub fn config(&self) -> MatrixConfig {
+        serde_json::from_value(json!({
+            "homeserver": self.server.uri(),
+            "username": "bot",
+            "password": "password",
+            "db_path": self.store_path,
+            "device_name": "matrixbot-test",
+            "device_id": "DEVICEID",
+        }))
+        .unwrap()
+    }
+    /// Client configuration pointing to an unresolvable homeserver, which is
+    /// only reachable with this homeserver acting as the proxy.
+    pub fn proxied_config(&self) -> MatrixConfig {
+        serde_json::from_value(json!({
+            "homeserver": "http://homeserver.invalid",
+            "username": "bot",
+            "password": "password",
+            "db_path": self.store_path,
+            "device_name": "matrixbot-test",
+            "device_id": "DEVICEID",
+            "proxy": self.server.uri(),
+        }))
+        .unwrap()
+    }
+    /// Delivers a text message to the given room with the next background
+    /// sync. Must be called before the client is created.
+    pub async fn receive_message(&self, room_id: &str, sender: &str, body: &str) {
+        let response = json!({
+            "next_batch": EVENT_SYNC_TOKEN,
+            "rooms": {
+                "join": {
+                    room_id: {
+                        "timeline": {
+                            "events": [{
+                                "type": "m.room.message",
+                                "event_id": "$message:localhost",
+                                "sender": sender,
+                                "origin_server_ts": 1,
+                                "content": {
+                                    "msgtype": "m.text",
+                                    "body": body,
+                                },
+                            }],
+                            "limited": false,
+                        },
+                    },
+                },
+            },
+        });
+
+        Mock::given(method("GET"))
+            .and(path(SYNC_PATH))
+            .and(query_param("since", SYNC_TOKEN))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+    /// Rejects messages to the room, as if the bot had been removed from it.
+    pub async fn forbid_room(&self, room_id: &str) {
+        let room_id = room_id.to_string();
+        let to_room = move |req: &Request| {
+            req.url
+                .path_segments()
+                .and_then(|mut segments| segments.nth(4))
+                .and_then(|segment| percent_decode_str(segment).decode_utf8().ok())
+                == Some(Cow::from(room_id.as_str()))
+        };
+
+        Mock::given(method("PUT"))
+            .and(path_regex(SEND_PATH))
+            .and(to_room)
+            .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+                "errcode": "M_FORBIDDEN",
+                "error": "You are not in this room",
+            })))
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+    /// Answers all further requests with 503, as if the homeserver went down.
+    pub async fn go_down(&self) {
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+    /// Answers requests for the state of any room with the `m.space.child`
+    /// events of the given rooms and their `order`. Children without servers
+    /// count as removed.
+    pub async fn space_children(&self, children: &[(&str, Option<&str>, bool)]) {
+        let events: Vec<Value> = children
+            .iter()
+            .map(|(room_id, order, removed)| {
+                let mut content = json!({ "order": order });
+                if !removed {
+                    content["via"] = json!(["localhost"]);
+                }
+
+                json!({
+                    "type": "m.space.child",
+                    "event_id": format!("$child-{}", room_id),
+                    "sender": OTHER_USER,
+                    "origin_server_ts": 1,
+                    "state_key": room_id,
+                    "content": content,
+                })
+            })
+            .collect();
+
+        Mock::given(method("GET"))
+            .and(path_regex(STATE_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(events))
+            .mount(&self.server)
+            .await;
+    }
+    /// All messages sent by the client, in order.
+    pub async fn sent_messages(&self) -> Vec<SentMessage> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|req| req.method.as_ref() == "PUT")
+            .filter_map(|req| {
+                let segments: Vec<&str> = req.url.path_segments()?.collect();
+                let room_id = percent_decode_str(segments.get(4)?)
+                    .decode_utf8()
+                    .ok()?
+                    .to_string();
+
+                let content: Value = serde_json::from_slice(&req.body).ok()?;
+                let body = content.get("body")?.as_str()?.to_string();
+
+                Some(SentMessage { room_id, body })
+            })
+            .collect()
+    }
+    /// The number of rooms created by the client.
+    pub async fn created_rooms(&self) -> usize {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|req| req.url.path() == CREATE_ROOM_PATH)
+            .count()
+    }
+    /// The requests to create rooms, in order.
+    pub async fn room_requests(&self) -> Vec<Value> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|req| req.url.path() == CREATE_ROOM_PATH)
+            .filter_map(|req| serde_json::from_slice(&req.body).ok())
+            .collect()
+    }
+    /// The rooms left by the client, in order.
+    pub async fn left_rooms(&self) -> Vec<String> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|req| req.method.as_ref() == "POST" && req.url.path().ends_with("/leave"))
+            .filter_map(|req| {
+                let segments: Vec<&str> = req.url.path_segments()?.collect();
+                Some(
+                    percent_decode_str(segments.get(4)?)
+                        .decode_utf8()
+                        .ok()?
+                        .to_string(),
+                )
+            })
+            .collect()
+    }
+    /// Waits until the client has sent at least `count` messages.
+    pub async fn wait_for_messages(&self, count: usize) -> Vec<SentMessage> {
+        for _ in 0..50 {
+            let sent = self.sent_messages().await;
+            if sent.len() >= count {
+                return sent;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        panic!("Timed out waiting for {} sent message(s)", count);
+    }
+}
+
+impl Drop for MockHomeserver {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.store_path);
+    }
+}
+
+/// Connects to a fresh database on the MongoDB instance of `MONGODB_URI`, or
+/// a local one, e.g. `docker run -p 27017:27017 mongo`. Tests using it are
+/// ignored by default, run them with `cargo test -- --include-ignored` as CI
+/// does.
+pub async fn test_database() -> Database {
+    let config = serde_json::from_value(json!({
+        "uri": std::env::var("MONGODB_URI")
+            .unwrap_or_else(|_| String::from("mongodb://localhost:27017")),
+        "name": format!(
+            "matrixbot-test-{}-{}",
+            std::process::id(),
+            DATABASE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ),
+    }))
+    .unwrap();
+
+    Database::new(config).await.unwrap()
+}
+
+/// Creates an escalating alert with the given Id.
+pub fn alert_context(id: u64, route: &str) -> AlertContext {
+    AlertContext::new(
+        Alert {
+            annotations: Annotations {
+                message: Some(format!("Message of alert {}", id)),
+                description: None,
+                ..Default::default()
+            },
+            labels: Labels {
+                severity: String::from("critical"),
+                alert_name: format!("Alert{}", id),
+                team: None,
+                ..Default::default()
+            },
+            generator_url: None,
+        },
+        AlertId::from(id),
+        route.to_string(),
+        true,
+    )
+}
+
+/// Escalation settings without any routes, to be adjusted by tests.
+pub fn escalation_settings() -> EscalationSettings {
+    EscalationSettings {
+        enabled: true,
+        window: 600,
+        check_frequency: 20,
+        dedup_window: 30,
+        catch_up: Default::default(),
+        entry_levels: Default::default(),
+        duplicates: Default::default(),
+        priorities: Default::default(),
+        severities: Default::default(),
+        ack_scopes: Default::default(),
+        ack_ttls: Default::default(),
+        rooms: Default::default(),
+        business_hours: Default::default(),
+        adaptive: None,
+        warning: None,
+        noise_report: false,
+        compliance_room: None,
+        configured_routes: vec![],
+        slos: Default::default(),
+    }
+}
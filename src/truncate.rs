@@ -0,0 +1,155 @@
+//! Length limits of rendered annotations and messages, so a pathological alert
+//! (e.g. with a megabyte description) cannot exceed the size limit of Matrix
+//! events or webhook payloads.
+use crate::{AlertId, Error, Result};
+use once_cell::sync::OnceCell;
+use std::borrow::Cow;
+
+// The size limit of Matrix events is 64 KiB, including the event envelope.
+const DEFAULT_MAX_ANNOTATION: usize = 2000;
+const DEFAULT_MAX_MESSAGE: usize = 32 * 1024;
+// Shorter limits would leave nothing but the suffix.
+const MIN_LIMIT: usize = 100;
+// Text is cut at the last whitespace within this many bytes of the limit.
+const WORD_BOUNDARY_WINDOW: usize = 80;
+
+static LIMITS: OnceCell<Limits> = OnceCell::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TruncationConfig {
+    // Maximum length (bytes) of the message and description of an alert in
+    // notifications, defaults to 2000.
+    max_annotation: Option<usize>,
+    // Maximum length (bytes) of a room message, defaults to 32768.
+    max_message: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Limits {
+    annotation: usize,
+    message: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            annotation: DEFAULT_MAX_ANNOTATION,
+            message: DEFAULT_MAX_MESSAGE,
+        }
+    }
+}
+
+impl Limits {
+    fn new(config: &TruncationConfig) -> Result<Self> {
+        let limits = Limits {
+            annotation: config.max_annotation.unwrap_or(DEFAULT_MAX_ANNOTATION),
+            message: config.max_message.unwrap_or(DEFAULT_MAX_MESSAGE),
+        };
+
+        if limits.annotation < MIN_LIMIT || limits.message < MIN_LIMIT {
+            return Err(Error::Config(format!(
+                "Truncation limits must be at least {} bytes",
+                MIN_LIMIT
+            )));
+        }
+
+        if limits.annotation > limits.message {
+            return Err(Error::Config(String::from(
+                "The maximum annotation length must not exceed the maximum message length",
+            )));
+        }
+
+        Ok(limits)
+    }
+}
+
+/// Applies the limits of the config. Must only be called once, on startup.
+/// The defaults apply until then.
+pub fn configure(config: &TruncationConfig) -> Result<()> {
+    LIMITS
+        .set(Limits::new(config)?)
+        .map_err(|_| Error::Internal(String::from("Truncation limits are already configured")))
+}
+
+fn limits() -> Limits {
+    LIMITS.get().copied().unwrap_or_default()
+}
+
+/// Truncates an annotation of the alert, pointing to `details` for the full
+/// text. Alerts without an ID (non-escalating ones) have no details.
+pub fn annotation(text: &str, id: Option<AlertId>) -> Cow<'_, str> {
+    let suffix = match id {
+        Some(id) => format!(" (truncated, see `details {}`)", id),
+        None => String::from(" (truncated)"),
+    };
+
+    truncate(text, limits().annotation, &suffix)
+}
+
+/// Truncates a whole message before it is sent.
+pub fn message(text: &str) -> Cow<'_, str> {
+    truncate(text, limits().message, "\n(truncated)")
+}
+
+/// Truncates a whole message to the limit of the service sending it, unless
+/// the configured limit is lower.
+pub fn message_within(text: &str, max: usize) -> Cow<'_, str> {
+    truncate(text, limits().message.min(max), "\n(truncated)")
+}
+
+/// Cuts the text to at most `max` bytes, including the suffix. The text is cut
+/// at a whitespace close to the limit, if any, and never within a character.
+fn truncate<'a>(text: &'a str, max: usize, suffix: &str) -> Cow<'a, str> {
+    if text.len() <= max {
+        return Cow::Borrowed(text);
+    }
+
+    let mut end = max.saturating_sub(suffix.len() + '…'.len_utf8());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let head = &text[..end];
+    let head = match head.rfind(char::is_whitespace) {
+        Some(idx) if end - idx <= WORD_BOUNDARY_WINDOW => &head[..idx],
+        _ => head,
+    };
+
+    Cow::Owned(format!("{}…{}", head.trim_end(), suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_at_word_and_char_boundaries() {
+        assert_eq!(truncate("short", 10, " (cut)"), "short");
+
+        let text = "word ".repeat(100);
+        let truncated = truncate(&text, 120, " (truncated, see `details 5`)");
+        assert!(truncated.len() <= 120);
+        assert!(truncated.ends_with("word… (truncated, see `details 5`)"));
+
+        // Multi-byte characters without whitespace.
+        let text = "ä".repeat(500);
+        let truncated = truncate(&text, 101, " (truncated)");
+        assert!(truncated.len() <= 101);
+        assert!(truncated.ends_with("ä… (truncated)"));
+
+        assert!(Limits::new(&TruncationConfig {
+            max_annotation: Some(50),
+            max_message: None,
+        })
+        .is_err());
+        assert!(Limits::new(&TruncationConfig {
+            max_annotation: Some(5000),
+            max_message: Some(1000),
+        })
+        .is_err());
+        assert_eq!(
+            Limits::new(&TruncationConfig::default()).unwrap(),
+            Limits::default()
+        );
+    }
+}
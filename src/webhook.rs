@@ -1,54 +1,2952 @@
-use crate::processor::{InsertAlerts, Processor};
-use crate::Result;
+use crate::adapter::discord::{Discord, Interaction};
+use crate::adapter::mattermost::{
+    Mattermost, MattermostAction, MattermostCommand, MattermostContext,
+};
+use crate::adapter::opsgenie::{Opsgenie, OpsgenieAlert, OpsgenieEvent, OpsgenieSource};
+use crate::adapter::twilio::voice::{TwilioGather, TwilioVoice, VoiceQuery};
+use crate::adapter::twilio::{self, TwilioMessage, TwilioSms};
+use crate::adapter::AdapterState;
+use crate::calendar::BusinessHoursConfig;
+use crate::cloud::{
+    AzureAlert, AzureAlertData, AzureEssentials, CloudConfig, GcpIncident, GcpMetadata,
+    GcpNotification, GcpResource,
+};
+use crate::database::{AlertAcknowledged, ApiKeyInfo};
+use crate::healthchecks::{HealthcheckPing, HealthchecksConfig};
+use crate::matrix::{IsSyncHealthy, MatrixClient};
+use crate::processor::{
+    AckAlerts, AckScope, AckTarget, AlertContext, BatchAckResult, BatchAckStatus, Command,
+    CreateApiKey, DeleteApiKeys, DeleteOverride, DeleteRoute, DeployWindow, GetAlert, ImportAlerts,
+    ImportSummary, ImportedAlert, InsertAlerts, InsertedAlert, IsStandby, Latency, ListAdapters,
+    ListApiKeys, ListHistory, ListOverrides, ListPending, ListRoutes, ListUserStats,
+    PolicyOverride, Processor, Promote, PutOverride, PutRoute, RemoteAck, ResolveApiKey,
+    SetAdapterEnabled, Simulate, Simulation, SimulationStep, TimelineEvent, TimelineKind,
+    UserAction, UserConfirmation, UserStats,
+};
+use crate::render::Format;
+use crate::sentry::{SentryConfig, SentryEvent, SentryIssue};
+use crate::sns::{Sns, SnsMessage};
+use crate::{unix_time, AlertId, Error, Result, RouteConfig, DEFAULT_ROUTE};
 use actix::prelude::*;
-use actix_web::dev::Server;
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::dev::Service;
+use actix_web::dev::{Decompress, Payload, Server};
+use actix_web::guard::{self, GuardContext};
+use actix_web::http::header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use actix_web::middleware::Compress;
+use actix_web::HttpMessage;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use bson::oid::ObjectId;
+use openssl::memcmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{OpenApi, ToSchema};
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+const WEBHOOK_PATH: &str = "/webhook-ack";
+const SENTRY_PATH: &str = "/webhook-sentry";
+const HEALTHCHECKS_PATH: &str = "/webhook-healthchecks";
+const SNS_PATH: &str = "/webhook-sns";
+const AZURE_PATH: &str = "/webhook-azure";
+const GCP_PATH: &str = "/webhook-gcp";
+const DISCORD_PATH: &str = "/webhook-discord";
+const MATTERMOST_ACTION_PATH: &str = "/webhook-mattermost-action";
+const MATTERMOST_COMMAND_PATH: &str = "/webhook-mattermost-command";
+const OPSGENIE_PATH: &str = "/webhook-opsgenie";
+const TWILIO_SMS_PATH: &str = "/webhook-twilio-sms";
+const TWILIO_VOICE_PATH: &str = "/webhook-twilio-voice";
+const REQUEST_LOG_SIZE: usize = 100;
+const REQUEST_ID_HEADER: &str = "x-request-id";
+// Longer (or otherwise unusual) request IDs of clients are replaced.
+const MAX_REQUEST_ID_LEN: usize = 64;
+const NDJSON: &str = "application/x-ndjson";
+// Same as the default limit of JSON payloads.
+const MAX_NDJSON_LINE: usize = 256 * 1024;
+// Number of imported alerts which are stored at once.
+const IMPORT_BATCH_SIZE: usize = 100;
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+const DEFAULT_STATS_PERIOD: u64 = 7 * 24 * 60 * 60; // one week
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "matrixbot-ack"),
+    paths(
+        healthcheck,
+        insert_alerts,
+        insert_sentry_issue,
+        insert_healthcheck,
+        insert_sns_message,
+        insert_azure_alert,
+        insert_gcp_incident,
+        handle_discord_interaction,
+        handle_mattermost_action,
+        handle_mattermost_command,
+        handle_opsgenie_event,
+        handle_twilio_sms,
+        handle_twilio_voice,
+        openapi_spec,
+        export_metrics,
+        promote,
+        get_alert,
+        list_alerts,
+        list_history,
+        list_user_stats,
+        recent_requests,
+        simulate,
+        import_alerts,
+        list_routes,
+        put_route,
+        delete_route,
+        list_overrides,
+        put_override,
+        delete_override,
+        list_adapters,
+        disable_adapter,
+        enable_adapter,
+        ack_alert,
+        ack_alerts,
+        start_deploy_window,
+        list_api_keys,
+        create_api_key,
+        delete_api_keys
+    ),
+    components(schemas(
+        InsertAlerts,
+        InsertResponse,
+        InsertedAlert,
+        Alert,
+        Annotations,
+        Labels,
+        AlertContext,
+        AlertId,
+        AlertAcknowledged,
+        UserStats,
+        Page,
+        TimelineEvent,
+        TimelineKind,
+        RequestLogEntry,
+        RequestResult,
+        SimulationRequest,
+        Simulation,
+        SimulationStep,
+        ImportedAlert,
+        ImportSummary,
+        SentryIssue,
+        SentryEvent,
+        HealthcheckPing,
+        SnsMessage,
+        AzureAlert,
+        AzureAlertData,
+        AzureEssentials,
+        GcpNotification,
+        GcpIncident,
+        GcpResource,
+        GcpMetadata,
+        MattermostAction,
+        MattermostContext,
+        MattermostCommand,
+        OpsgenieEvent,
+        OpsgenieAlert,
+        OpsgenieSource,
+        TwilioMessage,
+        TwilioGather,
+        RouteConfig,
+        AckScope,
+        BusinessHoursConfig,
+        Format,
+        PolicyOverride,
+        AdapterState,
+        Latency,
+        ApiKeyInfo,
+        ApiKeyRequest,
+        ApiKeyResponse,
+        AckAlerts,
+        BatchAckResult,
+        BatchAckStatus,
+        DeployWindow,
+        DeployWindowStarted
+    ))
+)]
+struct ApiDoc;
+
+/// Generates the OpenAPI document, including the paths of all additional
+/// webhook listeners.
+fn api_doc(listeners: &[ListenerConfig]) -> utoipa::openapi::OpenApi {
+    let mut doc = ApiDoc::openapi();
+
+    if let Some(components) = doc.components.as_mut() {
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+
+    if let Some(webhook) = doc.paths.paths.get(WEBHOOK_PATH).cloned() {
+        for (idx, listener) in listeners.iter().enumerate() {
+            let mut path = webhook.clone();
+            for operation in path.operations.values_mut() {
+                // Operation Ids must be unique within the document.
+                operation.operation_id = operation
+                    .operation_id
+                    .as_ref()
+                    .map(|id| format!("{}_{}", id, idx + 1));
+            }
+
+            doc.paths.paths.insert(listener.path.clone(), path);
+        }
+    }
+
+    doc
+}
+
+/// An additional webhook endpoint which assigns all received alerts to the
+/// configured route.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerConfig {
+    endpoint: String,
+    path: String,
+    route: Option<String>,
+    token: Option<String>,
+}
+
+impl ListenerConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn route(&self) -> &str {
+        self.route.as_deref().unwrap_or(DEFAULT_ROUTE)
+    }
+}
+
+/// Enables the admin API, which requires the configured bearer token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConfig {
+    token: String,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestResult {
+    Accepted,
+    Replay,
+    Unauthorized,
+    Standby,
+    Failed,
+}
+
+/// A webhook request, as listed by the admin API.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RequestLogEntry {
+    timestamp: u64,
+    request_id: String,
+    source: Option<String>,
+    path: String,
+    payload_hash: String,
+    result: RequestResult,
+}
+
+/// The most recent webhook requests of all listeners.
+#[derive(Debug)]
+struct RequestLog {
+    entries: Mutex<VecDeque<RequestLogEntry>>,
+    // Identical payloads are rejected within this window, if set.
+    replay_window: Option<u64>,
+}
+
+impl RequestLog {
+    fn new(replay_window: Option<u64>) -> Self {
+        RequestLog {
+            entries: Mutex::new(VecDeque::with_capacity(REQUEST_LOG_SIZE)),
+            replay_window,
+        }
+    }
+    fn is_replay(&self, path: &str, payload_hash: &str) -> bool {
+        let replay_window = match self.replay_window {
+            Some(window) => window,
+            None => return false,
+        };
+
+        let since = unix_time().saturating_sub(replay_window);
+        self.entries.lock().unwrap().iter().any(|entry| {
+            entry.result == RequestResult::Accepted
+                && entry.timestamp >= since
+                && entry.path == path
+                && entry.payload_hash == payload_hash
+        })
+    }
+    fn record(&self, entry: RequestLogEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == REQUEST_LOG_SIZE {
+            entries.pop_front();
+        }
+
+        entries.push_back(entry);
+    }
+    fn entries(&self) -> Vec<RequestLogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Correlates a webhook request with the alerts it inserted, stored in the
+/// extensions of the request.
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// The ID of the webhook request, taken from the `X-Request-Id` header of the
+/// client or generated. It is returned in the same header of the response.
+fn request_id(http: &HttpRequest) -> String {
+    if let Some(RequestId(id)) = http.extensions().get::<RequestId>() {
+        return id.clone();
+    }
+
+    let id = http
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(String::from)
+        .unwrap_or_else(|| ObjectId::new().to_hex());
+
+    http.extensions_mut().insert(RequestId(id.clone()));
+    id
+}
+
+/// Per-listener context which is passed on to the webhook handler.
+#[derive(Debug, Clone)]
+struct WebhookContext {
+    route: String,
+    token: Option<String>,
+}
+
+impl WebhookContext {
+    fn is_authorized(&self, req: &HttpRequest) -> bool {
+        match &self.token {
+            Some(token) => has_bearer_token(req, token),
+            None => true,
+        }
+    }
+}
+
+fn has_bearer_token(req: &HttpRequest, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "));
+
+    is_token(provided, token)
+}
+
+/// Compares shared secrets in constant time, so that they cannot be guessed by
+/// timing responses.
+fn is_token(provided: Option<&str>, token: &str) -> bool {
+    match provided {
+        Some(provided) => {
+            provided.len() == token.len() && memcmp::eq(provided.as_bytes(), token.as_bytes())
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct Alert {
     pub annotations: Annotations,
     pub labels: Labels,
+    // Link to the expression in Prometheus, used for trends.
+    #[serde(rename = "generatorURL", default)]
+    pub generator_url: Option<String>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+// The conventional annotations and labels of Prometheus alerting rules are
+// shown as fields of their own, see `Alert::conventions`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct Annotations {
     pub message: Option<String>,
     pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runbook_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dashboard: Option<String>,
+    // The value of the expression, e.g. `{{ $value }}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // Of SLO burn-rate alerts, e.g. `42%` and `14.4`, see `SloConfig`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget_remaining: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burn_rate: Option<String>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct Labels {
     pub severity: String,
     #[serde(rename = "alertname")]
     pub alert_name: String,
+    // Mentions the members of the team on the first notification, see
+    // `TeamConfig`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    // All other labels, kept so they identify the alert, see
+    // `Alert::fingerprint`.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, String>,
+}
+
+/// A conventional annotation or label of an alert, e.g. its runbook.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Convention<'a> {
+    pub label: &'static str,
+    pub value: &'a str,
+    // Rendered as a link by formats which support it.
+    pub is_link: bool,
+}
+
+/// An annotation which changed when the alert fired again, see
+/// `Annotations::changes`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AnnotationChange {
+    pub label: &'static str,
+    // Unset if the annotation was added or removed.
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Alert {
+    /// The conventional annotations and labels which are set, in the order
+    /// they are shown after the message and description.
+    pub fn conventions(&self) -> Vec<Convention<'_>> {
+        let annotations = &self.annotations;
+        let labels = &self.labels;
+
+        [
+            ("Summary", &annotations.summary, false),
+            ("Value", &annotations.value, false),
+            ("Instance", &labels.instance, false),
+            ("Job", &labels.job, false),
+            ("Namespace", &labels.namespace, false),
+            ("Runbook", &annotations.runbook_url, true),
+            ("Dashboard", &annotations.dashboard, true),
+        ]
+        .iter()
+        .filter_map(|&(label, value, is_link)| {
+            value.as_deref().map(|value| Convention {
+                label,
+                value,
+                is_link,
+            })
+        })
+        .collect()
+    }
+    /// Identifies the underlying alert by its full label set, e.g. when
+    /// received by multiple routes.
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "{:x}",
+            md5::compute(serde_json::to_vec(&self.labels.all()).unwrap_or_default())
+        )
+    }
+}
+
+impl Annotations {
+    /// The annotations which differ in `new`, e.g. when the alert fires again
+    /// with a new value. Labels identify the alert, so they never differ.
+    pub fn changes(&self, new: &Annotations) -> Vec<AnnotationChange> {
+        [
+            ("Message", &self.message, &new.message),
+            ("Description", &self.description, &new.description),
+            ("Summary", &self.summary, &new.summary),
+            ("Value", &self.value, &new.value),
+            (
+                "Budget remaining",
+                &self.budget_remaining,
+                &new.budget_remaining,
+            ),
+            ("Burn rate", &self.burn_rate, &new.burn_rate),
+            ("Runbook", &self.runbook_url, &new.runbook_url),
+            ("Dashboard", &self.dashboard, &new.dashboard),
+        ]
+        .iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|&(label, old, new)| AnnotationChange {
+            label,
+            old: old.clone(),
+            new: new.clone(),
+        })
+        .collect()
+    }
+}
+
+impl Labels {
+    /// Label names which can be matched, e.g. by `watch`.
+    pub const NAMES: &'static [&'static str] = &[
+        "severity",
+        "alertname",
+        "team",
+        "instance",
+        "job",
+        "namespace",
+    ];
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "severity" => Some(&self.severity),
+            "alertname" => Some(&self.alert_name),
+            "team" => self.team.as_deref(),
+            "instance" => self.instance.as_deref(),
+            "job" => self.job.as_deref(),
+            "namespace" => self.namespace.as_deref(),
+            _ => self.other.get(name).map(String::as_str),
+        }
+    }
+    /// All labels which are set, sorted by name.
+    pub fn all(&self) -> BTreeMap<&str, &str> {
+        let mut labels: BTreeMap<&str, &str> = self
+            .other
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+
+        for name in Self::NAMES {
+            if let Some(value) = self.get(name) {
+                labels.insert(name, value);
+            }
+        }
+
+        labels
+    }
+    /// Whether all of the given labels (see `NAMES`) have the given values,
+    /// ignoring case.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        labels.iter().all(|(name, value)| {
+            self.get(name)
+                .map(|actual| actual.eq_ignore_ascii_case(value))
+                .unwrap_or(false)
+        })
+    }
 }
 
-pub async fn run_api_server(endpoint: &str) -> Result<Server> {
-    let server = HttpServer::new(move || {
-        App::new()
-            .route("/healthcheck", web::get().to(healthcheck))
-            .route("/webhook-ack", web::post().to(insert_alerts))
-    })
-    .bind(endpoint)?;
+/// Webhooks of third-party services, served on the main endpoint.
+pub struct Integrations {
+    pub sentry: Option<SentryConfig>,
+    pub healthchecks: Option<HealthchecksConfig>,
+    pub sns: Option<Sns>,
+    pub azure: Option<CloudConfig>,
+    pub gcp: Option<CloudConfig>,
+    // Only served if slash commands are enabled.
+    pub discord: Option<Arc<Discord>>,
+    pub mattermost: Option<Arc<Mattermost>>,
+    pub opsgenie: Option<Arc<Opsgenie>>,
+    pub twilio_sms: Option<Arc<TwilioSms>>,
+    pub twilio_voice: Option<Arc<TwilioVoice>>,
+}
+
+pub async fn run_api_server(
+    endpoint: &str,
+    listeners: Vec<ListenerConfig>,
+    admin: Option<AdminConfig>,
+    integrations: Integrations,
+    replay_window: Option<u64>,
+) -> Result<Vec<Server>> {
+    let Integrations {
+        sentry,
+        healthchecks,
+        sns,
+        azure,
+        gcp,
+        discord,
+        mattermost,
+        opsgenie,
+        twilio_sms,
+        twilio_voice,
+    } = integrations;
+
+    // Group listeners by endpoint, each endpoint is served by its own server.
+    // The main endpoint always serves the healthcheck and the default webhook.
+    let mut endpoints: BTreeMap<String, Vec<ListenerConfig>> = BTreeMap::new();
+    endpoints.insert(endpoint.to_string(), vec![]);
+
+    for listener in listeners {
+        endpoints
+            .entry(listener.endpoint.clone())
+            .or_default()
+            .push(listener);
+    }
+
+    let doc = web::Data::new(api_doc(
+        &endpoints.values().flatten().cloned().collect::<Vec<_>>(),
+    ));
+
+    let log = web::Data::new(RequestLog::new(replay_window));
+    let sns = sns.map(web::Data::new);
+    let discord = discord
+        .filter(|discord| discord.has_interactions())
+        .map(web::Data::from);
+    let mattermost = mattermost.map(web::Data::from);
+    let opsgenie = opsgenie.map(web::Data::from);
+    let twilio_sms = twilio_sms.map(web::Data::from);
+    let twilio_voice = twilio_voice.map(web::Data::from);
+
+    let mut servers = vec![];
+    for (addr, listeners) in endpoints {
+        let is_main = addr == endpoint;
+        let doc = doc.clone();
+        let log = log.clone();
+        let admin = admin.clone();
+        let sentry = sentry.clone();
+        let healthchecks = healthchecks.clone();
+        let sns = sns.clone();
+        let azure = azure.clone();
+        let gcp = gcp.clone();
+        let discord = discord.clone();
+        let mattermost = mattermost.clone();
+        let opsgenie = opsgenie.clone();
+        let twilio_sms = twilio_sms.clone();
+        let twilio_voice = twilio_voice.clone();
+
+        let server = HttpServer::new(move || {
+            let mut app = App::new().app_data(log.clone());
+
+            if is_main {
+                app = app
+                    .app_data(doc.clone())
+                    .route("/healthcheck", web::get().to(healthcheck))
+                    .route("/openapi.json", web::get().to(openapi_spec))
+                    .route("/metrics", web::get().to(export_metrics))
+                    // Before `/alerts/{id}`, which would otherwise match.
+                    .route("/alerts/ack", web::post().to(ack_alerts))
+                    .route("/alerts/{id}/ack", web::post().to(ack_alert))
+                    .route("/deploy-window", web::post().to(start_deploy_window))
+                    .service(
+                        web::resource(WEBHOOK_PATH)
+                            .app_data(web::Data::new(WebhookContext {
+                                route: DEFAULT_ROUTE.to_string(),
+                                token: None,
+                            }))
+                            .route(
+                                web::post()
+                                    .guard(guard::fn_guard(is_ndjson))
+                                    .to(insert_alerts_ndjson),
+                            )
+                            .route(web::post().to(insert_alerts)),
+                    );
+
+                if let Some(sentry) = &sentry {
+                    app = app.service(
+                        web::resource(SENTRY_PATH)
+                            .app_data(web::Data::new(WebhookContext {
+                                route: sentry.route().to_string(),
+                                token: None,
+                            }))
+                            .app_data(web::Data::new(sentry.clone()))
+                            .route(web::post().to(insert_sentry_issue)),
+                    );
+                }
+
+                if let Some(healthchecks) = &healthchecks {
+                    app = app.service(
+                        web::resource(HEALTHCHECKS_PATH)
+                            .app_data(web::Data::new(healthchecks.clone()))
+                            .route(web::post().to(insert_healthcheck)),
+                    );
+                }
+
+                if let Some(sns) = &sns {
+                    app = app.service(
+                        web::resource(SNS_PATH)
+                            .app_data(web::Data::new(WebhookContext {
+                                route: sns.route().to_string(),
+                                token: None,
+                            }))
+                            .app_data(sns.clone())
+                            .route(web::post().to(insert_sns_message)),
+                    );
+                }
+
+                if let Some(azure) = &azure {
+                    app = app.service(
+                        web::resource(AZURE_PATH)
+                            .app_data(web::Data::new(WebhookContext {
+                                route: azure.route().to_string(),
+                                token: None,
+                            }))
+                            .app_data(web::Data::new(azure.clone()))
+                            .route(web::post().to(insert_azure_alert)),
+                    );
+                }
+
+                if let Some(gcp) = &gcp {
+                    app = app.service(
+                        web::resource(GCP_PATH)
+                            .app_data(web::Data::new(WebhookContext {
+                                route: gcp.route().to_string(),
+                                token: None,
+                            }))
+                            .app_data(web::Data::new(gcp.clone()))
+                            .route(web::post().to(insert_gcp_incident)),
+                    );
+                }
+
+                if let Some(discord) = &discord {
+                    app = app.service(
+                        web::resource(DISCORD_PATH)
+                            .app_data(discord.clone())
+                            .route(web::post().to(handle_discord_interaction)),
+                    );
+                }
+
+                // Anyone could acknowledge alerts otherwise.
+                if let Some(mattermost) = mattermost
+                    .as_ref()
+                    .filter(|mattermost| mattermost.token().is_some())
+                {
+                    app = app
+                        .service(
+                            web::resource(MATTERMOST_ACTION_PATH)
+                                .app_data(mattermost.clone())
+                                .route(web::post().to(handle_mattermost_action)),
+                        )
+                        .service(
+                            web::resource(MATTERMOST_COMMAND_PATH)
+                                .app_data(mattermost.clone())
+                                .route(web::post().to(handle_mattermost_command)),
+                        );
+                }
+
+                // Anyone could acknowledge alerts otherwise.
+                if let Some(opsgenie) = opsgenie
+                    .as_ref()
+                    .filter(|opsgenie| opsgenie.token().is_some())
+                {
+                    app = app.service(
+                        web::resource(OPSGENIE_PATH)
+                            .app_data(opsgenie.clone())
+                            .route(web::post().to(handle_opsgenie_event)),
+                    );
+                }
+
+                if let Some(twilio_sms) = &twilio_sms {
+                    app = app.service(
+                        web::resource(TWILIO_SMS_PATH)
+                            .app_data(twilio_sms.clone())
+                            .route(web::post().to(handle_twilio_sms)),
+                    );
+                }
+
+                if let Some(twilio_voice) = &twilio_voice {
+                    app = app.service(
+                        web::resource(TWILIO_VOICE_PATH)
+                            .app_data(twilio_voice.clone())
+                            .route(web::post().to(handle_twilio_voice)),
+                    );
+                }
 
-    Ok(server.run())
+                if let Some(admin) = &admin {
+                    app = app
+                        .app_data(web::Data::new(admin.clone()))
+                        .route("/admin/promote", web::post().to(promote))
+                        .route("/admin/requests", web::get().to(recent_requests))
+                        .route("/simulate", web::post().to(simulate))
+                        .route("/import", web::post().to(import_alerts))
+                        .route("/alerts", web::get().to(list_alerts))
+                        .route("/alerts/{id}", web::get().to(get_alert))
+                        .route("/history", web::get().to(list_history))
+                        .route("/stats/users", web::get().to(list_user_stats))
+                        .route("/admin/routes", web::get().to(list_routes))
+                        .route("/admin/routes", web::put().to(put_route))
+                        .route("/admin/routes/{name}", web::delete().to(delete_route))
+                        .route("/admin/overrides", web::get().to(list_overrides))
+                        .route("/admin/overrides", web::put().to(put_override))
+                        .route("/admin/overrides/{name}", web::delete().to(delete_override))
+                        .route("/admin/adapters", web::get().to(list_adapters))
+                        .route(
+                            "/admin/adapters/{name}/disable",
+                            web::post().to(disable_adapter),
+                        )
+                        .route(
+                            "/admin/adapters/{name}/enable",
+                            web::post().to(enable_adapter),
+                        )
+                        .route("/admin/api-keys", web::get().to(list_api_keys))
+                        .route("/admin/api-keys", web::post().to(create_api_key))
+                        .route("/admin/api-keys/{user}", web::delete().to(delete_api_keys));
+                }
+            }
+
+            for listener in &listeners {
+                app = app.service(
+                    web::resource(listener.path.as_str())
+                        .app_data(web::Data::new(WebhookContext {
+                            route: listener.route().to_string(),
+                            token: listener.token.clone(),
+                        }))
+                        .route(
+                            web::post()
+                                .guard(guard::fn_guard(is_ndjson))
+                                .to(insert_alerts_ndjson),
+                        )
+                        .route(web::post().to(insert_alerts)),
+                );
+            }
+
+            // Responses are compressed if the client accepts it. The ID of
+            // webhook requests is returned, see `request_id`.
+            app.wrap(Compress::default()).wrap_fn(|req, srv| {
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    let request_id = res.request().extensions().get::<RequestId>().cloned();
+                    if let Some(RequestId(id)) = request_id {
+                        if let Ok(val) = HeaderValue::from_str(&id) {
+                            res.headers_mut()
+                                .insert(HeaderName::from_static(REQUEST_ID_HEADER), val);
+                        }
+                    }
+
+                    Ok(res)
+                }
+            })
+        })
+        .bind(&addr)
+        .map_err(Error::Webhook)?;
+
+        info!("API server listening on {}", addr);
+        servers.push(server.run());
+    }
+
+    Ok(servers)
+}
+
+async fn is_standby() -> bool {
+    Processor::from_registry().send(IsStandby).await.unwrap()
 }
 
+#[utoipa::path(
+    get,
+    path = "/healthcheck",
+    responses(
+        (status = 200, description = "Service is running", body = String),
+        (status = 503, description = "Service is running in standby mode or the Matrix sync is down", body = String)
+    )
+)]
 async fn healthcheck() -> HttpResponse {
+    if is_standby().await {
+        return HttpResponse::ServiceUnavailable().body("STANDBY");
+    }
+
+    // Acks are not received while the sync is restarting.
+    if !MatrixClient::from_registry()
+        .send(IsSyncHealthy)
+        .await
+        .unwrap_or(false)
+    {
+        return HttpResponse::ServiceUnavailable().body("SYNC DOWN");
+    }
+
     HttpResponse::Ok().body("OK")
 }
 
-async fn insert_alerts(req: web::Json<InsertAlerts>) -> HttpResponse {
-    let alerts = req.into_inner();
-    debug!("New alerts received from webhook: {:?}", alerts);
+/// Latency histograms of the paging pipeline in the Prometheus text format:
+/// from webhook receipt to the first successful notification per channel, and
+/// from the first notification to the acknowledgement.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "The metrics", body = String)
+    )
+)]
+async fn export_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(crate::metrics::render())
+}
+
+/// Promotes a standby instance, which then starts processing alerts.
+#[utoipa::path(
+    post,
+    path = "/admin/promote",
+    responses(
+        (status = 200, description = "Instance is active", body = String),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to promote instance")
+    ),
+    security(("bearer" = []))
+)]
+async fn promote(http: HttpRequest, admin: web::Data<AdminConfig>) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
 
-    let res = Processor::from_registry().send(alerts).await.unwrap();
+    let res = Processor::from_registry().send(Promote).await.unwrap();
 
     match res {
         Ok(_) => HttpResponse::Ok().body("OK"),
         Err(err) => {
-            error!("Failed to process new alerts: {:?}", err);
+            error!("Failed to promote instance: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI document of this service"))
+)]
+async fn openapi_spec(doc: web::Data<utoipa::openapi::OpenApi>) -> HttpResponse {
+    HttpResponse::Ok().json(doc.get_ref())
+}
+
+/// Shows a pending or acknowledged alert, including its escalation timeline.
+///
+/// Only available if an admin token is configured, which is required as
+/// bearer token.
+#[utoipa::path(
+    get,
+    path = "/alerts/{id}",
+    params(("id" = u64, Path, description = "Id of the alert")),
+    responses(
+        (status = 200, description = "The alert", body = AlertContext),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "Alert not found"),
+        (status = 500, description = "Failed to retrieve alert")
+    ),
+    security(("bearer" = []))
+)]
+async fn get_alert(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    id: web::Path<u64>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let res = Processor::from_registry()
+        .send(GetAlert(AlertId::from(id.into_inner())))
+        .await
+        .unwrap();
+
+    match res {
+        Ok(Some(alert)) => HttpResponse::Ok().json(alert),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            error!("Failed to retrieve alert: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Acknowledges a pending alert on behalf of the user of the API key, just
+/// like `ack` in Matrix. The room of the alert is informed.
+///
+/// Requires an API key (see `/admin/api-keys`) as bearer token and a database.
+#[utoipa::path(
+    post,
+    path = "/alerts/{id}/ack",
+    params(("id" = u64, Path, description = "Id of the alert")),
+    responses(
+        (status = 200, description = "The alert has been acknowledged", body = String),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 404, description = "No pending alert with that Id"),
+        (status = 500, description = "Failed to acknowledge the alert")
+    ),
+    security(("bearer" = []))
+)]
+async fn ack_alert(http: HttpRequest, id: web::Path<u64>) -> HttpResponse {
+    let user = match api_key_user(&http).await {
+        Ok(user) => user,
+        Err(res) => return res,
+    };
+
+    let id = AlertId::from(id.into_inner());
+    let alert = match Processor::from_registry().send(GetAlert(id)).await.unwrap() {
+        Ok(Some(alert)) => alert,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            error!("Failed to retrieve alert: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Acked from the alert's own level, the ack scope always allows that.
+    let confirmation = Processor::from_registry()
+        .send(UserAction {
+            route: alert.route.clone(),
+            escalation_idx: alert.escalation_idx,
+            command: Command::Ack(AckTarget::Id(id), user.clone()),
+        })
+        .await
+        .unwrap();
+
+    match confirmation {
+        UserConfirmation::AlertAcknowledged(_) => {
+            info!("Alert {} acknowledged by {} via the API", id, user);
+
+            let ack = RemoteAck {
+                route: alert.route,
+                escalation_idx: alert.escalation_idx,
+                id,
+                user,
+                via: String::from("the API"),
+            };
+
+            Processor::from_registry().do_send(ack.clone());
+            let res = MatrixClient::from_registry().send(ack).await;
+
+            if let Err(err) = res.map_err(|err| err.into()).and_then(|res| res) {
+                error!("Failed to inform room about ack of alert {}: {:?}", id, err);
+            }
+
+            HttpResponse::Ok().body("OK")
+        }
+        UserConfirmation::AlertNotFound => HttpResponse::NotFound().finish(),
+        UserConfirmation::InternalError => HttpResponse::InternalServerError().finish(),
+        other => HttpResponse::Conflict().body(other.to_string()),
+    }
+}
+
+/// Acknowledges pending alerts at once, selected either by their IDs or by
+/// their labels, e.g. after a fix has been deployed. The rooms of the alerts
+/// are informed.
+///
+/// Requires an API key (see `/admin/api-keys`) as bearer token and a database.
+#[utoipa::path(
+    post,
+    path = "/alerts/ack",
+    request_body = AckAlerts,
+    responses(
+        (status = 200, description = "The outcome for each selected alert, by ID", body = [BatchAckResult]),
+        (status = 400, description = "Neither or both of IDs and labels given, or an unknown label"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 500, description = "Failed to acknowledge the alerts, those acknowledged so far are returned to pending")
+    ),
+    security(("bearer" = []))
+)]
+async fn ack_alerts(http: HttpRequest, req: web::Json<AckAlerts>) -> HttpResponse {
+    let key_user = match api_key_user(&http).await {
+        Ok(user) => user,
+        Err(res) => return res,
+    };
+
+    let mut req = req.into_inner();
+    let user = req.user.get_or_insert(key_user.clone()).clone();
+
+    let results = match Processor::from_registry().send(req).await.unwrap() {
+        Ok(results) => results,
+        Err(Error::Config(msg)) => return HttpResponse::BadRequest().body(msg),
+        Err(err) => {
+            error!("Failed to acknowledge alerts: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    for result in &results {
+        let (route, escalation_idx) = match (&result.route, result.escalation_idx) {
+            (Some(route), Some(escalation_idx))
+                if result.status == BatchAckStatus::Acknowledged =>
+            {
+                (route.clone(), escalation_idx)
+            }
+            _ => continue,
+        };
+
+        info!(
+            "Alert {} acknowledged by {} via the API (key of {})",
+            result.id, user, key_user
+        );
+
+        let ack = RemoteAck {
+            route,
+            escalation_idx,
+            id: result.id,
+            user: user.clone(),
+            via: String::from("the API"),
+        };
+
+        Processor::from_registry().do_send(ack.clone());
+        let res = MatrixClient::from_registry().send(ack).await;
+
+        if let Err(err) = res.map_err(|err| err.into()).and_then(|res| res) {
+            error!(
+                "Failed to inform room about ack of alert {}: {:?}",
+                result.id, err
+            );
+        }
+    }
+
+    HttpResponse::Ok().json(results)
+}
+
+/// Silences alerts with matching labels during a deployment, e.g. started by
+/// a CD pipeline. The first rooms of the routes are informed when the window
+/// starts and ends, including the alerts which were silenced. Silenced alerts
+/// are not inserted. Windows are stored, so they continue after a restart.
+///
+/// Requires an API key (see `/admin/api-keys`) as bearer token.
+#[utoipa::path(
+    post,
+    path = "/deploy-window",
+    request_body = DeployWindow,
+    responses(
+        (status = 200, description = "The window has been started, or changed if it has the name of an active one", body = DeployWindowStarted),
+        (status = 400, description = "Invalid window, e.g. an unknown route"),
+        (status = 401, description = "Missing or invalid API key"),
+        (status = 500, description = "Failed to store the window")
+    ),
+    security(("bearer" = []))
+)]
+async fn start_deploy_window(http: HttpRequest, req: web::Json<DeployWindow>) -> HttpResponse {
+    let user = match api_key_user(&http).await {
+        Ok(user) => user,
+        Err(res) => return res,
+    };
+
+    let mut window = req.into_inner();
+    window.started_by = user;
+
+    match Processor::from_registry().send(window).await.unwrap() {
+        Ok(ends_at) => HttpResponse::Ok().json(DeployWindowStarted { ends_at }),
+        Err(Error::Config(msg)) => HttpResponse::BadRequest().body(msg),
+        Err(err) => {
+            error!("Failed to start deploy window: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeployWindowStarted {
+    // Unix timestamp (seconds).
+    ends_at: u64,
+}
+
+/// The user of the API key passed as bearer token.
+async fn api_key_user(http: &HttpRequest) -> std::result::Result<String, HttpResponse> {
+    let key = http
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .to_string();
+
+    match Processor::from_registry()
+        .send(ResolveApiKey(key))
+        .await
+        .unwrap()
+    {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => {
+            warn!("Rejected unauthorized request on {}", http.path());
+            Err(HttpResponse::Unauthorized().finish())
+        }
+        Err(err) => {
+            error!("Failed to resolve API key: {:?}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    // Comma separated, nested fields are separated by dots (e.g. `alert.labels`).
+    fields: Option<String>,
+}
+
+/// A page of a listing, ordered by alert ID.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Page {
+    #[schema(value_type = Vec<Object>)]
+    items: Vec<serde_json::Value>,
+    // Continuation token for the next page, if there is one.
+    next: Option<String>,
+}
+
+/// Cursors are opaque to clients, but are just the last returned alert ID.
+fn encode_cursor(id: AlertId) -> String {
+    format!("{:016x}", id.0)
+}
+
+fn decode_cursor(cursor: &str) -> Option<AlertId> {
+    u64::from_str_radix(cursor, 16).ok().map(AlertId::from)
+}
+
+/// Copies only the given (dotted) fields of an object.
+fn project(value: serde_json::Value, fields: &[&str]) -> serde_json::Value {
+    use serde_json::{Map, Value};
+
+    fn insert(target: &mut Value, path: &[&str], value: Value) {
+        if let Value::Object(obj) = target {
+            match path {
+                [key] => {
+                    obj.insert(key.to_string(), value);
+                }
+                [key, rest @ ..] => insert(
+                    obj.entry(key.to_string())
+                        .or_insert_with(|| Value::Object(Map::new())),
+                    rest,
+                    value,
+                ),
+                [] => {}
+            }
+        }
+    }
+
+    let mut projected = Value::Object(Map::new());
+    for field in fields {
+        let path: Vec<&str> = field.split('.').collect();
+        if let Some(found) = path.iter().try_fold(&value, |value, key| value.get(key)) {
+            insert(&mut projected, &path, found.clone());
+        }
+    }
+
+    projected
+}
+
+/// Turns the query result into a page. The query must have fetched one item
+/// more than the page size, to tell whether there is a next page.
+fn paginate<T: serde::Serialize>(
+    mut items: Vec<T>,
+    limit: i64,
+    id: impl Fn(&T) -> AlertId,
+    fields: Option<&str>,
+) -> Page {
+    let next = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|item| encode_cursor(id(item)))
+    } else {
+        None
+    };
+
+    let fields: Option<Vec<&str>> = fields.map(|fields| fields.split(',').map(str::trim).collect());
+
+    Page {
+        items: items
+            .iter()
+            .filter_map(|item| serde_json::to_value(item).ok())
+            .map(|value| match &fields {
+                Some(fields) => project(value, fields),
+                None => value,
+            })
+            .collect(),
+        next,
+    }
+}
+
+/// Validates the cursor and page size of a listing request.
+fn page_bounds(query: &PageQuery) -> std::result::Result<(Option<AlertId>, i64), HttpResponse> {
+    let after = match query.cursor.as_deref() {
+        Some(cursor) => Some(
+            decode_cursor(cursor)
+                .ok_or_else(|| HttpResponse::BadRequest().body("Invalid cursor"))?,
+        ),
+        None => None,
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    Ok((after, limit))
+}
+
+/// Lists pending alerts, ordered by ID.
+///
+/// Only available if an admin token is configured, which is required as
+/// bearer token.
+#[utoipa::path(
+    get,
+    path = "/alerts",
+    params(
+        ("cursor" = Option<String>, Query, description = "Continuation token of the previous page"),
+        ("limit" = Option<i64>, Query, description = "Page size, defaults to 50 (max. 500)"),
+        ("fields" = Option<String>, Query, description = "Comma separated fields to return, e.g. `id,alert.labels`")
+    ),
+    responses(
+        (status = 200, description = "A page of pending alerts", body = Page),
+        (status = 400, description = "Invalid cursor"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to retrieve alerts")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_alerts(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    query: web::Query<PageQuery>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let (after, limit) = match page_bounds(&query) {
+        Ok(bounds) => bounds,
+        Err(res) => return res,
+    };
+
+    let res = Processor::from_registry()
+        .send(ListPending {
+            after,
+            limit: limit + 1,
+        })
+        .await
+        .unwrap();
+
+    match res {
+        Ok(alerts) => HttpResponse::Ok().json(paginate(
+            alerts,
+            limit,
+            |alert| alert.id,
+            query.fields.as_deref(),
+        )),
+        Err(err) => {
+            error!("Failed to retrieve alerts: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Lists acknowledged alerts, ordered by ID.
+///
+/// Only available if an admin token is configured, which is required as
+/// bearer token.
+#[utoipa::path(
+    get,
+    path = "/history",
+    params(
+        ("cursor" = Option<String>, Query, description = "Continuation token of the previous page"),
+        ("limit" = Option<i64>, Query, description = "Page size, defaults to 50 (max. 500)"),
+        ("fields" = Option<String>, Query, description = "Comma separated fields to return, e.g. `alert.id,acked_by`")
+    ),
+    responses(
+        (status = 200, description = "A page of acknowledged alerts", body = Page),
+        (status = 400, description = "Invalid cursor"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to retrieve alerts")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_history(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    query: web::Query<PageQuery>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let (after, limit) = match page_bounds(&query) {
+        Ok(bounds) => bounds,
+        Err(res) => return res,
+    };
+
+    let res = Processor::from_registry()
+        .send(ListHistory {
+            after,
+            limit: limit + 1,
+        })
+        .await
+        .unwrap();
+
+    match res {
+        Ok(acked) => HttpResponse::Ok().json(paginate(
+            acked,
+            limit,
+            |acked| acked.alert.id,
+            query.fields.as_deref(),
+        )),
+        Err(err) => {
+            error!("Failed to retrieve history: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserStatsQuery {
+    route: Option<String>,
+    // Seconds, defaults to a week.
+    period: Option<u64>,
+}
+
+/// Lists how many alerts each user acknowledged and their median response
+/// time, ranked by the number of acknowledged alerts.
+///
+/// Only available if an admin token is configured, which is required as
+/// bearer token.
+#[utoipa::path(
+    get,
+    path = "/stats/users",
+    params(
+        ("route" = Option<String>, Query, description = "Only count the alerts of this route"),
+        ("period" = Option<u64>, Query, description = "Seconds to look back, defaults to a week")
+    ),
+    responses(
+        (status = 200, description = "Acknowledgements per user", body = [UserStats]),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to retrieve stats")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_user_stats(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    query: web::Query<UserStatsQuery>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let query = query.into_inner();
+    let res = Processor::from_registry()
+        .send(ListUserStats {
+            route: query.route,
+            since: unix_time().saturating_sub(query.period.unwrap_or(DEFAULT_STATS_PERIOD)),
+        })
+        .await
+        .unwrap();
+
+    match res {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(err) => {
+            error!("Failed to retrieve user stats: {:?}", err);
             HttpResponse::InternalServerError().finish()
         }
     }
 }
+
+/// Inserts alerts sent by Alertmanager.
+///
+/// Additional listeners share this handler and may require a bearer token.
+/// Compressed bodies (`Content-Encoding`) are accepted, as well as several
+/// newline-delimited payloads (`application/x-ndjson`).
+///
+/// Responses carry the ID of the request in `X-Request-Id`, which is either
+/// passed by the client or generated. It is stored with the inserted alerts
+/// and logged along with them.
+#[utoipa::path(
+    post,
+    path = "/webhook-ack",
+    request_body = InsertAlerts,
+    responses(
+        (status = 200, description = "The IDs and routes assigned to the alerts, or `DUPLICATE` if the request was ignored as a replay. Newline-delimited payloads are answered with `OK`", body = InsertResponse),
+        (status = 400, description = "Invalid newline-delimited payload"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to process alerts"),
+        (status = 503, description = "Service is running in standby mode")
+    ),
+    security((), ("bearer" = []))
+)]
+async fn insert_alerts(
+    http: HttpRequest,
+    ctx: web::Data<WebhookContext>,
+    log: web::Data<RequestLog>,
+    req: web::Json<InsertAlerts>,
+) -> HttpResponse {
+    match insert_payload_with_ids(&http, &ctx, &log, req.into_inner()).await {
+        (RequestResult::Accepted, alerts) => HttpResponse::Ok().json(InsertResponse { alerts }),
+        (result, _) => webhook_response(result),
+    }
+}
+
+/// The alerts of an accepted webhook request, in the order of the payload.
+/// Alerts silenced by a deploy window are not part of it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InsertResponse {
+    alerts: Vec<InsertedAlert>,
+}
+
+fn webhook_response(result: RequestResult) -> HttpResponse {
+    match result {
+        RequestResult::Accepted => HttpResponse::Ok().body("OK"),
+        // Acknowledge the request, so it is not retried.
+        RequestResult::Replay => HttpResponse::Ok().body("DUPLICATE"),
+        RequestResult::Unauthorized => HttpResponse::Unauthorized().finish(),
+        RequestResult::Standby => HttpResponse::ServiceUnavailable().body("STANDBY"),
+        RequestResult::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Inserts an issue alert sent by Sentry, translated into an alert of the
+/// configured route.
+#[utoipa::path(
+    post,
+    path = "/webhook-sentry",
+    request_body = SentryIssue,
+    params(("token" = Option<String>, Query, description = "Token, if configured")),
+    responses(
+        (status = 200, description = "The issue has been inserted, or ignored as a replay (`DUPLICATE`)", body = String),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to process the issue"),
+        (status = 503, description = "Service is running in standby mode")
+    )
+)]
+async fn insert_sentry_issue(
+    http: HttpRequest,
+    ctx: web::Data<WebhookContext>,
+    sentry: web::Data<SentryConfig>,
+    log: web::Data<RequestLog>,
+    query: web::Query<TokenQuery>,
+    req: web::Json<SentryIssue>,
+) -> HttpResponse {
+    if let Some(token) = sentry.token() {
+        if !is_token(query.token.as_deref(), token) {
+            warn!("Rejected unauthorized webhook request on {}", http.path());
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let alerts = InsertAlerts::new(vec![Alert::from(req.into_inner())]);
+    webhook_response(insert_payload(&http, &ctx, &log, alerts).await)
+}
+
+/// Inserts a failing check reported by healthchecks.io, assigned to a route
+/// by the name of the check. Recoveries are ignored.
+#[utoipa::path(
+    post,
+    path = "/webhook-healthchecks",
+    request_body = HealthcheckPing,
+    params(("token" = Option<String>, Query, description = "Token, if configured")),
+    responses(
+        (status = 200, description = "The check has been inserted, or ignored as a replay (`DUPLICATE`) or recovery (`IGNORED`)", body = String),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to process the check"),
+        (status = 503, description = "Service is running in standby mode")
+    )
+)]
+async fn insert_healthcheck(
+    http: HttpRequest,
+    healthchecks: web::Data<HealthchecksConfig>,
+    log: web::Data<RequestLog>,
+    query: web::Query<TokenQuery>,
+    req: web::Json<HealthcheckPing>,
+) -> HttpResponse {
+    if let Some(token) = healthchecks.token() {
+        if !is_token(query.token.as_deref(), token) {
+            warn!("Rejected unauthorized webhook request on {}", http.path());
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let ping = req.into_inner();
+    if !ping.is_down() {
+        return HttpResponse::Ok().body("IGNORED");
+    }
+
+    let alert = healthchecks.alert(ping);
+    let ctx = WebhookContext {
+        route: healthchecks.route(&alert.labels.alert_name).to_string(),
+        token: None,
+    };
+
+    let alerts = InsertAlerts::new(vec![alert]);
+    webhook_response(insert_payload(&http, &ctx, &log, alerts).await)
+}
+
+/// Inserts a notification delivered by AWS SNS, e.g. of a CloudWatch alarm.
+/// Subscriptions of accepted topics are confirmed automatically.
+///
+/// Messages must carry a valid SNS signature.
+#[utoipa::path(
+    post,
+    path = "/webhook-sns",
+    request_body = SnsMessage,
+    responses(
+        (status = 200, description = "The notification has been inserted, or ignored as a replay (`DUPLICATE`) or non-alarm (`IGNORED`)", body = String),
+        (status = 400, description = "Invalid message"),
+        (status = 401, description = "Invalid signature"),
+        (status = 403, description = "Topic is not accepted"),
+        (status = 500, description = "Failed to process the notification"),
+        (status = 503, description = "Service is running in standby mode")
+    )
+)]
+async fn insert_sns_message(
+    http: HttpRequest,
+    ctx: web::Data<WebhookContext>,
+    sns: web::Data<Sns>,
+    log: web::Data<RequestLog>,
+    body: web::Bytes,
+) -> HttpResponse {
+    // SNS sends JSON as `text/plain`.
+    let msg: SnsMessage = match serde_json::from_slice(&body) {
+        Ok(msg) => msg,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    if let Err(err) = sns.verify(&msg).await {
+        warn!("Rejected SNS message on {}: {:?}", http.path(), err);
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if !sns.accepts_topic(msg.topic_arn()) {
+        warn!("Rejected SNS message of topic {}", msg.topic_arn());
+        return HttpResponse::Forbidden().finish();
+    }
+
+    if msg.is_subscription_confirmation() {
+        return match sns.confirm(&msg).await {
+            Ok(_) => HttpResponse::Ok().body("CONFIRMED"),
+            Err(err) => {
+                error!("Failed to confirm SNS subscription: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        };
+    }
+
+    match sns.alert(&msg).filter(|_| msg.is_notification()) {
+        Some(alert) => {
+            let alerts = InsertAlerts::new(vec![alert]);
+            webhook_response(insert_payload(&http, &ctx, &log, alerts).await)
+        }
+        None => HttpResponse::Ok().body("IGNORED"),
+    }
+}
+
+/// Runs a slash command of Discord in the channel it was issued in, see
+/// `DiscordConfig`.
+///
+/// Interactions must carry a valid signature of the Discord application.
+#[utoipa::path(
+    post,
+    path = "/webhook-discord",
+    responses(
+        (status = 200, description = "The response to the interaction"),
+        (status = 400, description = "Invalid interaction"),
+        (status = 401, description = "Invalid signature"),
+        (status = 500, description = "Unsupported interaction or failed to run the command")
+    )
+)]
+async fn handle_discord_interaction(
+    http: HttpRequest,
+    discord: web::Data<Discord>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let header = |name: &str| {
+        http.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+
+    if let Err(err) = discord.verify(
+        header("x-signature-ed25519"),
+        header("x-signature-timestamp"),
+        &body,
+    ) {
+        warn!("Rejected Discord interaction: {:?}", err);
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let interaction: Interaction = match serde_json::from_slice(&body) {
+        Ok(interaction) => interaction,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    match discord.interaction(interaction).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(err) => {
+            error!("Failed to handle Discord interaction: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Acknowledges the alert of a button of a Mattermost notification, in the
+/// channel it was clicked in. Only served if a token is configured.
+#[utoipa::path(
+    post,
+    path = "/webhook-mattermost-action",
+    request_body = MattermostAction,
+    params(("token" = String, Query, description = "Token")),
+    responses(
+        (status = 200, description = "The reply, shown to the user who clicked"),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to acknowledge the alert")
+    )
+)]
+async fn handle_mattermost_action(
+    http: HttpRequest,
+    mattermost: web::Data<Mattermost>,
+    query: web::Query<TokenQuery>,
+    req: web::Json<MattermostAction>,
+) -> HttpResponse {
+    if !matches!(mattermost.token(), Some(token) if is_token(query.token.as_deref(), token)) {
+        warn!("Rejected unauthorized webhook request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match mattermost.handle_action(req.into_inner()).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(err) => {
+            error!("Failed to handle Mattermost action: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Runs a Mattermost slash command, e.g. `/matrixbot ack 1`, in the channel it
+/// was issued in. Only served if a token is configured.
+#[utoipa::path(
+    post,
+    path = "/webhook-mattermost-command",
+    request_body(content = MattermostCommand, content_type = "application/x-www-form-urlencoded"),
+    params(("token" = String, Query, description = "Token")),
+    responses(
+        (status = 200, description = "The reply, posted to the channel"),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to run the command")
+    )
+)]
+async fn handle_mattermost_command(
+    http: HttpRequest,
+    mattermost: web::Data<Mattermost>,
+    query: web::Query<TokenQuery>,
+    req: web::Form<MattermostCommand>,
+) -> HttpResponse {
+    if !matches!(mattermost.token(), Some(token) if is_token(query.token.as_deref(), token)) {
+        warn!("Rejected unauthorized webhook request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match mattermost.handle_command(req.into_inner()).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(err) => {
+            error!("Failed to handle Mattermost command: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Acknowledges an alert of the bot which was acknowledged in Opsgenie, sent
+/// by its webhook integration. Other events are ignored. Only served if a
+/// token is configured.
+#[utoipa::path(
+    post,
+    path = "/webhook-opsgenie",
+    request_body = OpsgenieEvent,
+    params(("token" = String, Query, description = "Token")),
+    responses(
+        (status = 200, description = "The reply to the ack, or `IGNORED`", body = String),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to acknowledge the alert")
+    )
+)]
+async fn handle_opsgenie_event(
+    http: HttpRequest,
+    opsgenie: web::Data<Opsgenie>,
+    query: web::Query<TokenQuery>,
+    req: web::Json<OpsgenieEvent>,
+) -> HttpResponse {
+    if !matches!(opsgenie.token(), Some(token) if is_token(query.token.as_deref(), token)) {
+        warn!("Rejected unauthorized webhook request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match opsgenie.handle_event(req.into_inner()).await {
+        Ok(Some(reply)) => HttpResponse::Ok().body(reply),
+        Ok(None) => HttpResponse::Ok().body("IGNORED"),
+        Err(err) => {
+            error!("Failed to handle Opsgenie event: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Runs the command of an SMS reply, e.g. `ack 1`, sent by the messaging
+/// webhook of the Twilio number. The reply is answered by SMS, messages of
+/// unknown numbers are ignored.
+#[utoipa::path(
+    post,
+    path = "/webhook-twilio-sms",
+    request_body(content = TwilioMessage, content_type = "application/x-www-form-urlencoded"),
+    params(("token" = Option<String>, Query, description = "Token, if configured")),
+    responses(
+        (status = 200, description = "The answer to the reply (TwiML), if any", body = String),
+        (status = 401, description = "Missing or invalid token or signature"),
+        (status = 500, description = "Failed to run the command")
+    )
+)]
+async fn handle_twilio_sms(
+    http: HttpRequest,
+    sms: web::Data<TwilioSms>,
+    query: web::Query<TokenQuery>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if let Some(token) = sms.token() {
+        if !is_token(query.token.as_deref(), token) {
+            warn!("Rejected unauthorized webhook request on {}", http.path());
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let url = twilio_url(&http, sms.public_url());
+    if let Err(err) = sms.verify(&url, &body, twilio_signature(&http)) {
+        warn!("Rejected webhook request on {}: {}", http.path(), err);
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let req: TwilioMessage = match serde_urlencoded::from_bytes(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    match sms.handle_message(req).await {
+        Ok(Some(answer)) => HttpResponse::Ok()
+            .content_type("text/xml")
+            .body(twilio::reply(&answer)),
+        // An empty response sends no answer.
+        Ok(None) => HttpResponse::Ok()
+            .content_type("text/xml")
+            .body("<Response></Response>"),
+        Err(err) => {
+            error!("Failed to handle SMS: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Acknowledges the alert of a call if 1 was pressed, sent by Twilio once the
+/// callee pressed a key or the call timed out. Keypresses of numbers outside
+/// the route of the alert are ignored.
+#[utoipa::path(
+    post,
+    path = "/webhook-twilio-voice",
+    request_body(content = TwilioGather, content_type = "application/x-www-form-urlencoded"),
+    params(
+        ("alert" = u64, Query, description = "The alert the call is about"),
+        ("token" = Option<String>, Query, description = "Token, if configured")
+    ),
+    responses(
+        (status = 200, description = "The rest of the call (TwiML)", body = String),
+        (status = 401, description = "Missing or invalid token or signature"),
+        (status = 500, description = "Failed to acknowledge the alert")
+    )
+)]
+async fn handle_twilio_voice(
+    http: HttpRequest,
+    voice: web::Data<TwilioVoice>,
+    query: web::Query<VoiceQuery>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if let Some(token) = voice.token() {
+        if !is_token(query.token.as_deref(), token) {
+            warn!("Rejected unauthorized webhook request on {}", http.path());
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let url = twilio_url(&http, Some(voice.public_url()));
+    if let Err(err) = voice.verify(&url, &body, twilio_signature(&http)) {
+        warn!("Rejected webhook request on {}: {}", http.path(), err);
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let req: TwilioGather = match serde_urlencoded::from_bytes(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    match voice.handle_gather(query.into_inner(), req).await {
+        Ok(answer) => HttpResponse::Ok().content_type("text/xml").body(answer),
+        Err(err) => {
+            error!("Failed to handle keypress: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The URL Twilio requested, which is part of the signature. The public URL
+/// replaces the scheme and host of the request, e.g. behind a proxy.
+fn twilio_url(http: &HttpRequest, public_url: Option<&str>) -> String {
+    let base = match public_url {
+        Some(public_url) => public_url.trim_end_matches('/').to_string(),
+        None => {
+            let info = http.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        }
+    };
+
+    match http.uri().path_and_query() {
+        Some(path) => format!("{}{}", base, path),
+        None => format!("{}{}", base, http.path()),
+    }
+}
+
+fn twilio_signature(http: &HttpRequest) -> &str {
+    http.headers()
+        .get("x-twilio-signature")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
+
+/// Inserts a fired alert of Azure Monitor, sent in the common alert schema.
+/// Resolutions are ignored.
+#[utoipa::path(
+    post,
+    path = "/webhook-azure",
+    request_body = AzureAlert,
+    params(("token" = Option<String>, Query, description = "Token, if configured")),
+    responses(
+        (status = 200, description = "The alert has been inserted, or ignored as a replay (`DUPLICATE`) or resolution (`IGNORED`)", body = String),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to process the alert"),
+        (status = 503, description = "Service is running in standby mode")
+    )
+)]
+async fn insert_azure_alert(
+    http: HttpRequest,
+    ctx: web::Data<WebhookContext>,
+    cloud: web::Data<CloudConfig>,
+    log: web::Data<RequestLog>,
+    query: web::Query<TokenQuery>,
+    req: web::Json<AzureAlert>,
+) -> HttpResponse {
+    if let Some(token) = cloud.token() {
+        if !is_token(query.token.as_deref(), token) {
+            warn!("Rejected unauthorized webhook request on {}", http.path());
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let alert = req.into_inner();
+    if !alert.is_fired() {
+        return HttpResponse::Ok().body("IGNORED");
+    }
+
+    let alerts = InsertAlerts::new(vec![Alert::from(alert)]);
+    webhook_response(insert_payload(&http, &ctx, &log, alerts).await)
+}
+
+/// Inserts an open incident of Google Cloud Monitoring. Closed incidents are
+/// ignored.
+#[utoipa::path(
+    post,
+    path = "/webhook-gcp",
+    request_body = GcpNotification,
+    params(("token" = Option<String>, Query, description = "Token, if configured")),
+    responses(
+        (status = 200, description = "The incident has been inserted, or ignored as a replay (`DUPLICATE`) or closed incident (`IGNORED`)", body = String),
+        (status = 401, description = "Missing or invalid token"),
+        (status = 500, description = "Failed to process the incident"),
+        (status = 503, description = "Service is running in standby mode")
+    )
+)]
+async fn insert_gcp_incident(
+    http: HttpRequest,
+    ctx: web::Data<WebhookContext>,
+    cloud: web::Data<CloudConfig>,
+    log: web::Data<RequestLog>,
+    query: web::Query<TokenQuery>,
+    req: web::Json<GcpNotification>,
+) -> HttpResponse {
+    if let Some(token) = cloud.token() {
+        if !is_token(query.token.as_deref(), token) {
+            warn!("Rejected unauthorized webhook request on {}", http.path());
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let notification = req.into_inner();
+    if !notification.is_open() {
+        return HttpResponse::Ok().body("IGNORED");
+    }
+
+    let alerts = InsertAlerts::new(vec![Alert::from(notification)]);
+    webhook_response(insert_payload(&http, &ctx, &log, alerts).await)
+}
+
+/// Inserts newline-delimited webhook payloads, processed as they are
+/// streamed. Stops at the first payload which is not accepted.
+async fn insert_alerts_ndjson(
+    http: HttpRequest,
+    ctx: web::Data<WebhookContext>,
+    log: web::Data<RequestLog>,
+    payload: web::Payload,
+) -> HttpResponse {
+    if !ctx.is_authorized(&http) {
+        warn!("Rejected unauthorized webhook request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut reader = NdjsonReader::new(&http, payload);
+    let mut inserted = 0;
+
+    while let Some(lines) = reader.next_lines().await {
+        let lines = match lines {
+            Ok(lines) => lines,
+            Err(res) => return res,
+        };
+
+        for line in lines {
+            let alerts: InsertAlerts = match serde_json::from_slice(&line) {
+                Ok(alerts) => alerts,
+                Err(err) => {
+                    return HttpResponse::BadRequest().body(format!(
+                        "Invalid payload after {} inserted: {}",
+                        inserted, err
+                    ))
+                }
+            };
+
+            match insert_payload(&http, &ctx, &log, alerts).await {
+                RequestResult::Accepted | RequestResult::Replay => inserted += 1,
+                RequestResult::Standby => {
+                    return HttpResponse::ServiceUnavailable().body("STANDBY")
+                }
+                _ => return HttpResponse::InternalServerError().finish(),
+            }
+        }
+    }
+
+    HttpResponse::Ok().body("OK")
+}
+
+/// Reads newline-delimited payloads from a (possibly compressed) request
+/// body as it is streamed.
+struct NdjsonReader {
+    stream: Decompress<Payload>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl NdjsonReader {
+    fn new(http: &HttpRequest, payload: web::Payload) -> Self {
+        NdjsonReader {
+            stream: Decompress::from_headers(payload.into_inner(), http.headers()),
+            buf: vec![],
+            done: false,
+        }
+    }
+    /// Returns the next complete lines, or `None` at the end of the body.
+    async fn next_lines(&mut self) -> Option<std::result::Result<Vec<Vec<u8>>, HttpResponse>> {
+        use futures::stream::StreamExt;
+
+        while !self.done {
+            match self.stream.next().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(err)) => {
+                    warn!("Failed to read request payload: {:?}", err);
+                    return Some(Err(HttpResponse::BadRequest().finish()));
+                }
+                // Process the last line, even without a trailing newline.
+                None => {
+                    self.buf.push(b'\n');
+                    self.done = true;
+                }
+            }
+
+            if self.buf.len() > MAX_NDJSON_LINE && !self.buf.contains(&b'\n') {
+                return Some(Err(HttpResponse::PayloadTooLarge().finish()));
+            }
+
+            let lines = take_lines(&mut self.buf);
+            if !lines.is_empty() {
+                return Some(Ok(lines));
+            }
+        }
+
+        None
+    }
+}
+
+/// Splits the complete, non-empty lines off the buffer, keeping a trailing
+/// partial line.
+fn take_lines(buf: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let end = match buf.iter().rposition(|byte| *byte == b'\n') {
+        Some(end) => end,
+        None => return vec![],
+    };
+
+    let rest = buf.split_off(end + 1);
+    let lines = std::mem::replace(buf, rest);
+
+    lines
+        .split(|byte| *byte == b'\n')
+        .filter(|line| line.iter().any(|byte| !byte.is_ascii_whitespace()))
+        .map(|line| line.to_vec())
+        .collect()
+}
+
+fn is_ndjson(ctx: &GuardContext) -> bool {
+    ctx.head()
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .map(|val| val.starts_with(NDJSON))
+        .unwrap_or(false)
+}
+
+/// Inserts a single webhook payload and records it in the request log.
+async fn insert_payload(
+    http: &HttpRequest,
+    ctx: &WebhookContext,
+    log: &RequestLog,
+    alerts: InsertAlerts,
+) -> RequestResult {
+    insert_payload_with_ids(http, ctx, log, alerts).await.0
+}
+
+/// Same as `insert_payload`, but also returns the IDs and routes assigned to
+/// the alerts if they were accepted.
+async fn insert_payload_with_ids(
+    http: &HttpRequest,
+    ctx: &WebhookContext,
+    log: &RequestLog,
+    mut alerts: InsertAlerts,
+) -> (RequestResult, Vec<InsertedAlert>) {
+    let payload_hash = format!(
+        "{:x}",
+        md5::compute(serde_json::to_vec(&alerts).unwrap_or_default())
+    );
+
+    let request_id = request_id(http);
+
+    let mut inserted = vec![];
+    let result = if !ctx.is_authorized(http) {
+        warn!(
+            "Rejected unauthorized webhook request {} on {}",
+            request_id,
+            http.path()
+        );
+        RequestResult::Unauthorized
+    } else if is_standby().await {
+        // Alerts are delivered to the active instance only.
+        RequestResult::Standby
+    } else if log.is_replay(http.path(), &payload_hash) {
+        warn!(
+            "Ignoring replayed webhook request {} on {}",
+            request_id,
+            http.path()
+        );
+        RequestResult::Replay
+    } else {
+        alerts.route = ctx.route.clone();
+        alerts.request_id = Some(request_id.clone());
+        debug!(
+            "New alerts received from webhook request {}: {:?}",
+            request_id, alerts
+        );
+
+        match Processor::from_registry().send(alerts).await.unwrap() {
+            Ok(alerts) => {
+                inserted = alerts;
+                RequestResult::Accepted
+            }
+            Err(err) => {
+                error!(
+                    "Failed to process new alerts of request {}: {:?}",
+                    request_id, err
+                );
+                RequestResult::Failed
+            }
+        }
+    };
+
+    log.record(RequestLogEntry {
+        timestamp: unix_time(),
+        request_id,
+        source: http.peer_addr().map(|addr| addr.ip().to_string()),
+        path: http.path().to_string(),
+        payload_hash,
+        result,
+    });
+
+    (result, inserted)
+}
+
+/// Lists the most recent webhook requests.
+#[utoipa::path(
+    get,
+    path = "/admin/requests",
+    responses(
+        (status = 200, description = "Recent webhook requests, oldest first", body = [RequestLogEntry]),
+        (status = 401, description = "Missing or invalid bearer token")
+    ),
+    security(("bearer" = []))
+)]
+async fn recent_requests(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    log: web::Data<RequestLog>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    HttpResponse::Ok().json(log.entries())
+}
+
+/// A synthetic alert, see `simulate`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SimulationRequest {
+    // Defaults to the default route.
+    route: Option<String>,
+    severity: String,
+    #[serde(rename = "alertname")]
+    alert_name: String,
+}
+
+/// Shows how an alert would be routed and escalated, without notifying
+/// anyone.
+#[utoipa::path(
+    post,
+    path = "/simulate",
+    request_body = SimulationRequest,
+    responses(
+        (status = 200, description = "The rooms the alert would be sent to", body = Simulation),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "Route not found")
+    ),
+    security(("bearer" = []))
+)]
+async fn simulate(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    req: web::Json<SimulationRequest>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let req = req.into_inner();
+    let res = Processor::from_registry()
+        .send(Simulate {
+            route: req.route.unwrap_or_else(|| DEFAULT_ROUTE.to_string()),
+            severity: req.severity,
+            alert_name: req.alert_name,
+        })
+        .await
+        .unwrap();
+
+    match res {
+        Some(simulation) => HttpResponse::Ok().json(simulation),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Imports historical alerts, e.g. from a previous system, without notifying
+/// anyone.
+///
+/// The body is newline-delimited (`application/x-ndjson`), one alert per line.
+/// Acknowledged alerts are stored as history, all others as pending.
+#[utoipa::path(
+    post,
+    path = "/import",
+    request_body(content = ImportedAlert, content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, description = "The number of imported alerts", body = ImportSummary),
+        (status = 400, description = "Invalid alert or unknown route"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to store alerts")
+    ),
+    security(("bearer" = []))
+)]
+async fn import_alerts(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    payload: web::Payload,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut reader = NdjsonReader::new(&http, payload);
+    let mut summary = ImportSummary::default();
+    let mut batch = vec![];
+
+    loop {
+        let lines = match reader.next_lines().await.transpose() {
+            Ok(lines) => lines,
+            Err(res) => return res,
+        };
+        let done = lines.is_none();
+
+        for line in lines.unwrap_or_default() {
+            match serde_json::from_slice::<ImportedAlert>(&line) {
+                Ok(alert) => batch.push(alert),
+                Err(err) => {
+                    return HttpResponse::BadRequest().body(format!(
+                        "Invalid alert after {} imported: {}",
+                        summary.pending + summary.history + batch.len(),
+                        err
+                    ))
+                }
+            }
+        }
+
+        if batch.len() >= IMPORT_BATCH_SIZE || (done && !batch.is_empty()) {
+            let res = Processor::from_registry()
+                .send(ImportAlerts(std::mem::take(&mut batch)))
+                .await
+                .unwrap();
+
+            match res {
+                Ok(imported) => {
+                    summary.pending += imported.pending;
+                    summary.history += imported.history;
+                }
+                Err(Error::Config(msg)) => {
+                    return HttpResponse::BadRequest().body(format!(
+                        "Failed after {} imported: {}",
+                        summary.pending + summary.history,
+                        msg
+                    ))
+                }
+                Err(err) => {
+                    error!("Failed to import alerts: {:?}", err);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    info!(
+        "Imported {} pending and {} acknowledged alerts",
+        summary.pending, summary.history
+    );
+
+    HttpResponse::Ok().json(summary)
+}
+
+/// Lists the routes, including those stored via this API, which replace
+/// configured routes of the same name.
+///
+/// Requires a database.
+#[utoipa::path(
+    get,
+    path = "/admin/routes",
+    responses(
+        (status = 200, description = "The routes", body = [RouteConfig]),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to retrieve routes")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_routes(http: HttpRequest, admin: web::Data<AdminConfig>) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match Processor::from_registry().send(ListRoutes).await.unwrap() {
+        Ok(routes) => HttpResponse::Ok().json(routes),
+        Err(err) => {
+            error!("Failed to retrieve routes: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Stores a route, e.g. to onboard the rooms of a new team. A configured
+/// route of the same name is replaced.
+///
+/// Stored routes are validated like the config file and take effect once the
+/// service is restarted. Webhooks and listeners can only reference routes of
+/// the config file. Requires a database.
+#[utoipa::path(
+    put,
+    path = "/admin/routes",
+    request_body = RouteConfig,
+    responses(
+        (status = 200, description = "The route has been stored", body = String),
+        (status = 400, description = "Invalid route"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to store the route")
+    ),
+    security(("bearer" = []))
+)]
+async fn put_route(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    req: web::Json<RouteConfig>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let res = Processor::from_registry()
+        .send(PutRoute(req.into_inner()))
+        .await
+        .unwrap();
+
+    update_response(res.map(|_| true), "routes")
+}
+
+/// Removes a route stored via this API. A configured route of the same name
+/// takes effect again once the service is restarted.
+#[utoipa::path(
+    delete,
+    path = "/admin/routes/{name}",
+    params(("name" = String, Path, description = "Name of the route")),
+    responses(
+        (status = 200, description = "The route has been removed", body = String),
+        (status = 400, description = "The remaining routes would be invalid, or the route is referenced by the config"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "No route of that name has been stored"),
+        (status = 500, description = "Failed to remove the route")
+    ),
+    security(("bearer" = []))
+)]
+async fn delete_route(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let res = Processor::from_registry()
+        .send(DeleteRoute(name.into_inner()))
+        .await
+        .unwrap();
+
+    update_response(res, "routes")
+}
+
+/// Lists the policy overrides, including upcoming ones. Overrides which have
+/// ended are removed by the escalation loop.
+///
+/// Requires a database.
+#[utoipa::path(
+    get,
+    path = "/admin/overrides",
+    responses(
+        (status = 200, description = "The overrides", body = [PolicyOverride]),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to retrieve overrides")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_overrides(http: HttpRequest, admin: web::Data<AdminConfig>) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match Processor::from_registry()
+        .send(ListOverrides)
+        .await
+        .unwrap()
+    {
+        Ok(overrides) => HttpResponse::Ok().json(overrides),
+        Err(err) => {
+            error!("Failed to retrieve overrides: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Stores a temporary override of the escalation policy of a route, e.g. to
+/// send all alerts of a team to another route during a freeze. An override of
+/// the same name is replaced.
+///
+/// Alerts received between `starts_at` and `ends_at` are affected, no restart
+/// is required. Requires a database.
+#[utoipa::path(
+    put,
+    path = "/admin/overrides",
+    request_body = PolicyOverride,
+    responses(
+        (status = 200, description = "The override has been stored", body = String),
+        (status = 400, description = "Invalid override"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to store the override")
+    ),
+    security(("bearer" = []))
+)]
+async fn put_override(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    req: web::Json<PolicyOverride>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let res = Processor::from_registry()
+        .send(PutOverride(req.into_inner()))
+        .await
+        .unwrap();
+
+    update_response(res.map(|_| true), "overrides")
+}
+
+/// Removes an override before it ends.
+#[utoipa::path(
+    delete,
+    path = "/admin/overrides/{name}",
+    params(("name" = String, Path, description = "Name of the override")),
+    responses(
+        (status = 200, description = "The override has been removed", body = String),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "No override of that name has been stored"),
+        (status = 500, description = "Failed to remove the override")
+    ),
+    security(("bearer" = []))
+)]
+async fn delete_override(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let res = Processor::from_registry()
+        .send(DeleteOverride(name.into_inner()))
+        .await
+        .unwrap();
+
+    update_response(res, "overrides")
+}
+
+/// Lists the adapters, e.g. Telegram, and whether they are enabled.
+#[utoipa::path(
+    get,
+    path = "/admin/adapters",
+    responses(
+        (status = 200, description = "The adapters", body = [AdapterState]),
+        (status = 401, description = "Missing or invalid bearer token")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_adapters(http: HttpRequest, admin: web::Data<AdminConfig>) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let adapters = Processor::from_registry().send(ListAdapters).await.unwrap();
+    HttpResponse::Ok().json(adapters)
+}
+
+/// Stops notifying an adapter, e.g. during an outage of the service, until
+/// it is enabled again or the bot restarts. Alerts keep their escalation
+/// levels, failed notifications are retried once it is enabled.
+#[utoipa::path(
+    post,
+    path = "/admin/adapters/{name}/disable",
+    params(("name" = String, Path, description = "Name of the adapter, case-insensitive")),
+    responses(
+        (status = 200, description = "The adapter has been disabled", body = String),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "No adapter of that name is configured")
+    ),
+    security(("bearer" = []))
+)]
+async fn disable_adapter(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    set_adapter_enabled(http, admin, name.into_inner(), false).await
+}
+
+/// Notifies a disabled adapter again.
+#[utoipa::path(
+    post,
+    path = "/admin/adapters/{name}/enable",
+    params(("name" = String, Path, description = "Name of the adapter, case-insensitive")),
+    responses(
+        (status = 200, description = "The adapter has been enabled", body = String),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "No adapter of that name is configured")
+    ),
+    security(("bearer" = []))
+)]
+async fn enable_adapter(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    name: web::Path<String>,
+) -> HttpResponse {
+    set_adapter_enabled(http, admin, name.into_inner(), true).await
+}
+
+async fn set_adapter_enabled(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    name: String,
+    enabled: bool,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let found = Processor::from_registry()
+        .send(SetAdapterEnabled { name, enabled })
+        .await
+        .unwrap();
+
+    update_response(Ok(found), "adapters")
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct ApiKeyRequest {
+    // Matrix user ID, e.g. `@alice:matrix.org`.
+    user: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiKeyResponse {
+    user: String,
+    // Only returned once, the key is not stored.
+    key: String,
+}
+
+/// Lists the users with API keys and when each key was created.
+///
+/// Requires a database.
+#[utoipa::path(
+    get,
+    path = "/admin/api-keys",
+    responses(
+        (status = 200, description = "The API keys, without the keys themselves", body = [ApiKeyInfo]),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to retrieve API keys")
+    ),
+    security(("bearer" = []))
+)]
+async fn list_api_keys(http: HttpRequest, admin: web::Data<AdminConfig>) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match Processor::from_registry().send(ListApiKeys).await.unwrap() {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(err) => {
+            error!("Failed to retrieve API keys: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Creates an API key for a Matrix user, which acknowledges alerts on their
+/// behalf via `/alerts/{id}/ack`. A user may have multiple keys.
+///
+/// Requires a database.
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys",
+    request_body = ApiKeyRequest,
+    responses(
+        (status = 200, description = "The new key", body = ApiKeyResponse),
+        (status = 400, description = "Invalid user ID"),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 500, description = "Failed to create the key")
+    ),
+    security(("bearer" = []))
+)]
+async fn create_api_key(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    req: web::Json<ApiKeyRequest>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let user = req.into_inner().user;
+    match Processor::from_registry()
+        .send(CreateApiKey(user.clone()))
+        .await
+        .unwrap()
+    {
+        Ok(key) => HttpResponse::Ok().json(ApiKeyResponse { user, key }),
+        Err(Error::Config(msg)) => HttpResponse::BadRequest().body(msg),
+        Err(err) => {
+            error!("Failed to create API key: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Revokes all API keys of a user.
+#[utoipa::path(
+    delete,
+    path = "/admin/api-keys/{user}",
+    params(("user" = String, Path, description = "Matrix user ID")),
+    responses(
+        (status = 200, description = "The keys have been revoked", body = String),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "The user has no API keys"),
+        (status = 500, description = "Failed to revoke the keys")
+    ),
+    security(("bearer" = []))
+)]
+async fn delete_api_keys(
+    http: HttpRequest,
+    admin: web::Data<AdminConfig>,
+    user: web::Path<String>,
+) -> HttpResponse {
+    if !has_bearer_token(&http, &admin.token) {
+        warn!("Rejected unauthorized admin request on {}", http.path());
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let res = Processor::from_registry()
+        .send(DeleteApiKeys(user.into_inner()))
+        .await
+        .unwrap();
+
+    update_response(res, "API keys")
+}
+
+fn update_response(res: Result<bool>, what: &str) -> HttpResponse {
+    match res {
+        Ok(true) => HttpResponse::Ok().body("OK"),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        // Rejected routes and overrides are reported to the caller.
+        Err(Error::Config(msg)) => HttpResponse::BadRequest().body(msg),
+        Err(err) => {
+            error!("Failed to update {}: {:?}", what, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, payload_hash: &str, result: RequestResult) -> RequestLogEntry {
+        RequestLogEntry {
+            timestamp: unix_time(),
+            request_id: String::from("test"),
+            source: None,
+            path: path.to_string(),
+            payload_hash: payload_hash.to_string(),
+            result,
+        }
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(is_token(Some("secret"), "secret"));
+        assert!(!is_token(Some("secreT"), "secret"));
+        // Different lengths must not reach the comparison, which would panic.
+        assert!(!is_token(Some("secret2"), "secret"));
+        assert!(!is_token(Some(""), "secret"));
+        assert!(!is_token(None, "secret"));
+    }
+
+    #[test]
+    fn assigns_request_ids() {
+        let http = actix_web::test::TestRequest::default()
+            .insert_header((REQUEST_ID_HEADER, "am-1234_5"))
+            .to_http_request();
+        assert_eq!(request_id(&http), "am-1234_5");
+
+        // Unusual IDs are replaced, the generated one is kept for the request.
+        let http = actix_web::test::TestRequest::default()
+            .insert_header((REQUEST_ID_HEADER, "id with spaces"))
+            .to_http_request();
+        let id = request_id(&http);
+        assert_eq!(id.len(), 24);
+        assert_eq!(request_id(&http), id);
+    }
+
+    #[test]
+    fn detects_replays_of_accepted_requests() {
+        let log = RequestLog::new(Some(60));
+        log.record(entry("/webhook-ack", "a", RequestResult::Accepted));
+        log.record(entry("/webhook-ack", "b", RequestResult::Failed));
+
+        assert!(log.is_replay("/webhook-ack", "a"));
+        assert!(!log.is_replay("/webhook-ack/team-a", "a"));
+        // Failed requests may be retried.
+        assert!(!log.is_replay("/webhook-ack", "b"));
+
+        assert!(!RequestLog::new(None).is_replay("/webhook-ack", "a"));
+    }
+
+    #[test]
+    fn keeps_most_recent_requests() {
+        let log = RequestLog::new(None);
+        for idx in 0..REQUEST_LOG_SIZE + 1 {
+            log.record(entry(
+                "/webhook-ack",
+                &idx.to_string(),
+                RequestResult::Accepted,
+            ));
+        }
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), REQUEST_LOG_SIZE);
+        assert_eq!(entries[0].payload_hash, "1");
+    }
+
+    #[test]
+    fn paginates_with_cursor_and_projection() {
+        assert_eq!(
+            decode_cursor(&encode_cursor(AlertId::from(42))),
+            Some(42.into())
+        );
+        assert_eq!(decode_cursor("not-a-cursor"), None);
+
+        let items: Vec<serde_json::Value> = (1..=3)
+            .map(|id| serde_json::json!({ "id": id, "alert": { "labels": { "severity": "critical" }, "status": "firing" } }))
+            .collect();
+
+        let id = |item: &serde_json::Value| AlertId::from(item["id"].as_u64().unwrap());
+        let page = paginate(items.clone(), 2, id, Some("id, alert.labels, missing"));
+        assert_eq!(page.next, Some(encode_cursor(AlertId::from(2))));
+        assert_eq!(
+            page.items,
+            vec![
+                serde_json::json!({ "id": 1, "alert": { "labels": { "severity": "critical" } } }),
+                serde_json::json!({ "id": 2, "alert": { "labels": { "severity": "critical" } } }),
+            ]
+        );
+
+        let page = paginate(items, 3, id, None);
+        assert_eq!(page.next, None);
+        assert_eq!(page.items.len(), 3);
+    }
+
+    #[test]
+    fn responds_with_assigned_ids() {
+        let response = InsertResponse {
+            alerts: vec![InsertedAlert {
+                index: 0,
+                fingerprint: String::from("abc"),
+                id: AlertId::from(7),
+                route: String::from("team-a"),
+                duplicate: false,
+            }],
+        };
+
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({
+                "alerts": [{
+                    "index": 0,
+                    "fingerprint": "abc",
+                    "id": 7,
+                    "route": "team-a",
+                    "duplicate": false,
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn fingerprints_the_full_label_set() {
+        let alert = |pod: &str| -> Alert {
+            serde_json::from_value(serde_json::json!({
+                "annotations": {"message": "Pod is crash looping"},
+                "labels": {
+                    "severity": "critical",
+                    "alertname": "KubePodCrashLooping",
+                    "namespace": "default",
+                    "pod": pod,
+                },
+            }))
+            .unwrap()
+        };
+
+        let first = alert("api-0");
+        assert_eq!(first.labels.get("pod"), Some("api-0"));
+        assert_eq!(
+            serde_json::to_value(&first.labels).unwrap()["pod"],
+            serde_json::json!("api-0")
+        );
+
+        assert_eq!(first.fingerprint(), alert("api-0").fingerprint());
+        assert_ne!(first.fingerprint(), alert("api-1").fingerprint());
+    }
+
+    #[test]
+    fn diffs_annotations() {
+        let old = Annotations {
+            message: Some(String::from("Disk is 91% full")),
+            value: Some(String::from("91")),
+            runbook_url: Some(String::from("https://runbooks.example.com/disk")),
+            ..Default::default()
+        };
+        assert!(old.changes(&old).is_empty());
+
+        let new = Annotations {
+            message: Some(String::from("Disk is 97% full")),
+            value: Some(String::from("97")),
+            dashboard: Some(String::from("https://grafana.example.com/disk")),
+            ..Default::default()
+        };
+        let change = |label, old: Option<&str>, new: Option<&str>| AnnotationChange {
+            label,
+            old: old.map(String::from),
+            new: new.map(String::from),
+        };
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                change(
+                    "Message",
+                    Some("Disk is 91% full"),
+                    Some("Disk is 97% full")
+                ),
+                change("Value", Some("91"), Some("97")),
+                change("Runbook", Some("https://runbooks.example.com/disk"), None),
+                change("Dashboard", None, Some("https://grafana.example.com/disk")),
+            ]
+        );
+    }
+
+    #[test]
+    fn takes_complete_lines() {
+        let mut buf = b"{\"a\":1}\n\n  \n{\"b\":2}\n{\"c\"".to_vec();
+        assert_eq!(
+            take_lines(&mut buf),
+            vec![b"{\"a\":1}".to_vec(), b"{\"b\":2}".to_vec()]
+        );
+        assert_eq!(buf, b"{\"c\"");
+
+        assert!(take_lines(&mut buf).is_empty());
+        buf.extend_from_slice(b":3}\n");
+        assert_eq!(take_lines(&mut buf), vec![b"{\"c\":3}".to_vec()]);
+        assert!(buf.is_empty());
+    }
+}